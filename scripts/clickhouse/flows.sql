@@ -0,0 +1,38 @@
+-- Raw enriched flows, one partition per day. Loaded by the clickhouse service of
+-- docker-compose.yml, or run with `clickhouse-client --multiquery < flows.sql`.
+-- `ta` creates the table itself when `clickhouse.create_table` is set.
+
+CREATE DATABASE IF NOT EXISTS ta;
+
+CREATE TABLE IF NOT EXISTS ta.flows
+(
+    time DateTime64(3, 'UTC'),
+    exporter LowCardinality(String),
+    -- IPv4 addresses are stored mapped, query them with toIPv6('192.0.2.1')
+    src_ip IPv6,
+    dst_ip IPv6,
+    src_port UInt16,
+    dst_port UInt16,
+    protocol UInt8,
+    tcp_flags UInt8,
+    packets UInt64,
+    bytes UInt64,
+    first_switched UInt64,
+    last_switched UInt64,
+    input_if UInt32,
+    output_if UInt32,
+    -- Every tag of the enricher, the common ones are also columns below
+    tags Map(LowCardinality(String), String),
+    src_asn LowCardinality(String) MATERIALIZED tags['src_asn'],
+    dst_asn LowCardinality(String) MATERIALIZED tags['dst_asn'],
+    src_country LowCardinality(String) MATERIALIZED tags['src_country'],
+    dst_country LowCardinality(String) MATERIALIZED tags['dst_country'],
+    service LowCardinality(String) MATERIALIZED tags['service']
+)
+ENGINE = MergeTree
+PARTITION BY toDate(time)
+ORDER BY (exporter, time)
+-- Uncomment to drop old days, e.g. after half a year
+-- TTL toDate(time) + INTERVAL 180 DAY DELETE
+-- A retried batch is identical to the failed one, this makes the retry a no-op if the first insert did land
+SETTINGS non_replicated_deduplication_window = 1000;
@@ -0,0 +1,25 @@
+use std::sync::Arc;
+
+use clap::Parser;
+use ta::cmd::api::Args;
+use ta::config::Config;
+use ta::db::flow_store::InfluxStore;
+use ta::process::api;
+use tokio::signal;
+
+
+#[tokio::main]
+async fn main() -> std::io::Result<()> {
+    let args = Args::parse();
+    let config = Config::load(args.config.as_deref()).expect("Failed to load config");
+    let mut api_config = config.api.unwrap_or_default();
+    if let Some(listen) = args.listen {
+        api_config.listen = listen;
+    }
+
+    let store = Arc::new(InfluxStore::new(&config.influx.unwrap_or_default()));
+    tokio::select! {
+        _ = api::serve(store, api_config) => Ok(()),
+        _ = signal::ctrl_c() => Ok(()),
+    }
+}
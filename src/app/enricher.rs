@@ -1,21 +1,24 @@
-#![allow(unused_imports)]
-
-use chrono::Utc;
-use netflow_parser::{NetflowPacketResult, NetflowParser};
-use rdkafka::message::OwnedMessage;
-use ta::db::cidr_lookup::CidrLookup;
-use ta::kafka::consumer::start_listener_to_enricher;
+use clap::Parser;
+use ta::cmd::enricher::Args;
+use ta::config::Config;
+use ta::process::enricher;
+use ta::transport;
 use tokio::signal;
 
 
 
 #[tokio::main]
 async fn main() -> std::io::Result<()> {
-    for _ in 0..10{
-        tokio::spawn(async move {
-            start_listener_to_enricher().await;
-        });
+    let args = Args::parse();
+    let mut config = Config::load(args.config.as_deref()).expect("Failed to load config");
+    args.transport.apply(&mut config.transport);
+    if let Some(workers) = args.workers {
+        config.enricher.workers = workers;
     }
+
+    let transport = transport::build_standalone(&config.transport)?;
+    enricher::spawn_workers(transport.clone(), &config)?;
+    enricher::spawn_sinks(transport, &config)?;
     signal::ctrl_c().await.expect("failed to listen for event");
     Ok(())
-}
\ No newline at end of file
+}
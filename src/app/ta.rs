@@ -0,0 +1,142 @@
+use std::sync::Arc;
+
+use clap::Parser;
+use ta::cmd::billing::billing;
+use ta::cmd::check_config::check_config;
+use ta::cmd::lookup::lookup;
+use ta::cmd::replay::replay;
+use ta::cmd::ta::{Cli, Commands};
+use ta::config::Config;
+use ta::db::flow_store::InfluxStore;
+use ta::db::prometheus;
+use ta::process::detector::{self, Detector};
+use ta::process::{accounting, anomaly, api, enricher, listener, stitcher};
+use ta::transport::{self, TransportKind};
+use tokio::signal;
+
+
+#[tokio::main]
+async fn main() -> std::io::Result<()> {
+    let cli = Cli::parse();
+    let mut config = match Config::load(cli.config.as_deref()) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(2);
+        }
+    };
+
+    match cli.command {
+        Commands::Listen { port, transport } => {
+            transport.apply(&mut config.transport);
+            if let Some(port) = port {
+                config.listener.set_port(port);
+            }
+            let transport = transport::build_standalone(&config.transport)?;
+            listener::listen(&config.listener, &config.tenants, transport).await
+        }
+        Commands::Enrich { workers, transport } => {
+            transport.apply(&mut config.transport);
+            if let Some(workers) = workers {
+                config.enricher.workers = workers;
+            }
+            let transport = transport::build_standalone(&config.transport)?;
+            enricher::spawn_workers(transport.clone(), &config)?;
+            enricher::spawn_sinks(transport, &config)?;
+            signal::ctrl_c().await.expect("failed to listen for event");
+            Ok(())
+        }
+        Commands::Detect { transport } => {
+            transport.apply(&mut config.transport);
+            let detector = Detector::new(config.detector.unwrap_or_default())
+                .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+            let transport = transport::build_standalone(&config.transport)?;
+            tokio::select! {
+                _ = detector::run(transport, detector) => Ok(()),
+                _ = signal::ctrl_c() => Ok(()),
+            }
+        }
+        Commands::Anomaly { transport } => {
+            transport.apply(&mut config.transport);
+            let transport = transport::build_standalone(&config.transport)?;
+            tokio::select! {
+                _ = anomaly::run(transport, config.anomaly.unwrap_or_default()) => Ok(()),
+                _ = signal::ctrl_c() => Ok(()),
+            }
+        }
+        Commands::Stitch { transport } => {
+            transport.apply(&mut config.transport);
+            let transport = transport::build_standalone(&config.transport)?;
+            tokio::select! {
+                _ = stitcher::run(transport, config.stitcher.unwrap_or_default()) => Ok(()),
+                _ = signal::ctrl_c() => Ok(()),
+            }
+        }
+        Commands::Account { transport } => {
+            transport.apply(&mut config.transport);
+            let transport = transport::build_standalone(&config.transport)?;
+            tokio::select! {
+                _ = accounting::run(transport, config.accounting.unwrap_or_default()) => Ok(()),
+                _ = signal::ctrl_c() => Ok(()),
+            }
+        }
+        Commands::Metrics { transport } => {
+            transport.apply(&mut config.transport);
+            let transport = transport::build_standalone(&config.transport)?;
+            tokio::select! {
+                _ = prometheus::run(transport, config.prometheus.unwrap_or_default()) => Ok(()),
+                _ = signal::ctrl_c() => Ok(()),
+            }
+        }
+        Commands::Run { port, workers, transport } => {
+            transport.apply(&mut config.transport);
+            if let Some(workers) = workers {
+                config.enricher.workers = workers;
+            }
+            let transport = transport::build(&config.transport)?;
+            // Subscribe the consumers before the listener starts producing
+            enricher::spawn_workers(transport.clone(), &config)?;
+            enricher::spawn_sinks(transport.clone(), &config)?;
+            if config.influx.is_none() && config.transport.kind != TransportKind::Kafka && config.clickhouse.is_none() && config.archive.is_none() {
+                println!("No influx section configured, enriched flows are only kept in the {:?} transport", config.transport.kind);
+            }
+
+            if let Some(port) = port {
+                config.listener.set_port(port);
+            }
+            tokio::select! {
+                result = listener::listen(&config.listener, &config.tenants, transport) => result,
+                _ = signal::ctrl_c() => Ok(()),
+            }
+        }
+        Commands::Api { listen } => {
+            let mut api_config = config.api.unwrap_or_default();
+            if let Some(listen) = listen {
+                api_config.listen = listen;
+            }
+            let store = Arc::new(InfluxStore::new(&config.influx.unwrap_or_default()));
+            tokio::select! {
+                _ = api::serve(store, api_config) => Ok(()),
+                _ = signal::ctrl_c() => Ok(()),
+            }
+        }
+        Commands::Replay(args) => replay(config, args).await,
+        Commands::Billing(args) => {
+            if let Err(e) = billing(config, args) {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+            Ok(())
+        }
+        Commands::Lookup(args) => {
+            lookup(config, args);
+            Ok(())
+        }
+        Commands::CheckConfig(args) => {
+            if !check_config(&config, &args) {
+                std::process::exit(1);
+            }
+            Ok(())
+        }
+    }
+}
@@ -0,0 +1,14 @@
+use std::net::SocketAddr;
+
+use clap::Parser;
+
+#[derive(Parser, Debug)]
+#[command(version, about, long_about = None)]
+pub struct Args {
+    /// YAML config file, see `ta --help`
+    #[clap(short, long)]
+    pub config: Option<String>,
+    /// Address to serve the query API on
+    #[clap(short, long)]
+    pub listen: Option<SocketAddr>,
+}
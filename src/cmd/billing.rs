@@ -0,0 +1,57 @@
+use std::fs::File;
+use std::io::{self, Write};
+
+use chrono::Utc;
+use clap::{Args, ValueEnum};
+
+use crate::config::Config;
+use crate::process::accounting::{self, MonthlyUsage};
+
+#[derive(Args, Debug)]
+pub struct BillingArgs {
+    /// Month to report as YYYY-MM [default: the current month]
+    #[clap(short, long)]
+    pub month: Option<String>,
+    /// Output format
+    #[clap(short, long, value_enum, default_value_t = BillingFormat::Csv)]
+    pub format: BillingFormat,
+    /// File to write the report to instead of stdout
+    #[clap(short, long)]
+    pub output: Option<String>,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BillingFormat {
+    Csv,
+    Json,
+}
+
+// Print the monthly volume and 95th percentile of every customer
+pub fn billing(config: Config, args: BillingArgs) -> Result<(), String> {
+    let accounting = config.accounting.unwrap_or_default();
+    let now = Utc::now();
+    let month = args.month.unwrap_or_else(|| now.format("%Y-%m").to_string());
+    let usage = accounting::monthly_usage(&accounting, &month, now)?;
+
+    let out: Box<dyn Write> = match &args.output {
+        Some(path) => Box::new(File::create(path).map_err(|e| format!("can't create {}: {}", path, e))?),
+        None => Box::new(io::stdout().lock()),
+    };
+    write_report(out, &usage, args.format).map_err(|e| format!("can't write report: {}", e))
+}
+
+fn write_report(mut out: impl Write, usage: &[MonthlyUsage], format: BillingFormat) -> io::Result<()> {
+    match format {
+        BillingFormat::Csv => {
+            let mut writer = csv::Writer::from_writer(out);
+            for customer in usage {
+                writer.serialize(customer)?;
+            }
+            writer.flush()
+        }
+        BillingFormat::Json => {
+            serde_json::to_writer_pretty(&mut out, usage)?;
+            writeln!(out)
+        }
+    }
+}
@@ -0,0 +1,26 @@
+use clap::Args;
+
+use crate::config::Config;
+
+#[derive(Args, Debug)]
+pub struct CheckConfigArgs {
+    /// Print the effective config with defaults filled in
+    #[clap(long)]
+    pub print: bool,
+}
+
+// Returns false when the config has problems
+pub fn check_config(config: &Config, args: &CheckConfigArgs) -> bool {
+    if args.print {
+        print!("{}", serde_yaml::to_string(config).expect("Config can always be serialized"));
+    }
+
+    let problems = config.check();
+    for problem in &problems {
+        println!("error: {}", problem);
+    }
+    if problems.is_empty() {
+        println!("config OK");
+    }
+    problems.is_empty()
+}
@@ -0,0 +1,16 @@
+use clap::Parser;
+
+use super::transport::TransportArgs;
+
+#[derive(Parser, Debug)]
+#[command(version, about, long_about = None)]
+pub struct Args {
+    /// YAML config file, see `ta --help`
+    #[clap(short, long)]
+    pub config: Option<String>,
+    /// Number of enrichment tasks
+    #[clap(short, long)]
+    pub workers: Option<usize>,
+    #[clap(flatten)]
+    pub transport: TransportArgs,
+}
@@ -1,11 +1,16 @@
-use clap::{command, Parser};
+use clap::Parser;
+
+use super::transport::TransportArgs;
 
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None)]
 pub struct Args {
-    // #[clap(subcommand)]
-    // pub command: Commands,
+    /// YAML config file, see `ta --help`
+    #[clap(short, long)]
+    pub config: Option<String>,
     /// Port to listen for packets
-    #[clap(short, long, default_value = None, short = 'P')]
-    pub port: u128,
-}
\ No newline at end of file
+    #[clap(short, long, short = 'P')]
+    pub port: Option<u16>,
+    #[clap(flatten)]
+    pub transport: TransportArgs,
+}
@@ -0,0 +1,218 @@
+use std::collections::HashMap;
+use std::io::{self, BufRead};
+use std::net::Ipv4Addr;
+
+use clap::Args;
+
+use crate::config::Config;
+use crate::db::bgp_lookup::{RouteLookup, RouteMatch};
+use crate::db::cidr_lookup::CidrLookup;
+use crate::db::ip_lookup::{address_class, classify_direction, AddressClass};
+
+// Used as the other end of a flow when showing how an address is classified
+const EXTERNAL_PEER: &str = "192.0.2.1";
+const INTERNAL_PEER: &str = "10.0.0.1";
+
+#[derive(Args, Debug)]
+pub struct LookupArgs {
+    /// IP addresses to look up, read one per line from stdin when empty or `-`
+    pub ips: Vec<String>,
+    /// Only print coverage statistics for the whole list
+    #[clap(long)]
+    pub stats: bool,
+    /// Print one tab separated line per address instead of a block
+    #[clap(long)]
+    pub tsv: bool,
+}
+
+// Everything the enricher would attach to one address
+struct LookupResult {
+    ip: Ipv4Addr,
+    class: AddressClass,
+    country: Option<(Ipv4Addr, Ipv4Addr, String)>,
+    asn: Option<(Ipv4Addr, Ipv4Addr, String, String)>,
+    route: Option<RouteMatch>,
+}
+
+#[derive(Default)]
+struct Coverage {
+    total: usize,
+    invalid: usize,
+    with_country: usize,
+    with_as: usize,
+    not_routed: usize,
+    // Public addresses the country table has no range for
+    public_without_country: usize,
+    classes: HashMap<AddressClass, usize>,
+    countries: HashMap<String, usize>,
+}
+
+pub fn lookup(config: Config, args: LookupArgs) {
+    let cidr_lookup = CidrLookup::new(&config.enricher.country_map, &config.enricher.as_map);
+    let country_stats = cidr_lookup.country_stats();
+    let as_stats = cidr_lookup.as_stats();
+    if !args.tsv {
+        println!(
+            "loaded {} country ranges ({} lines skipped) from {}",
+            country_stats.loaded, country_stats.skipped, config.enricher.country_map
+        );
+        println!(
+            "loaded {} AS ranges ({} lines skipped) from {}",
+            as_stats.loaded, as_stats.skipped, config.enricher.as_map
+        );
+        println!();
+    }
+    let routes = config.bgp.as_ref().map(RouteLookup::new);
+
+    let ips: Box<dyn Iterator<Item = String>> = if args.ips.is_empty() || args.ips == ["-"] {
+        Box::new(io::stdin().lock().lines().map_while(Result::ok))
+    } else {
+        Box::new(args.ips.into_iter())
+    };
+
+    let mut coverage = Coverage::default();
+    for line in ips {
+        let ip = line.trim();
+        if ip.is_empty() || ip.starts_with('#') {
+            continue;
+        }
+        coverage.total += 1;
+
+        let result = match lookup_one(&cidr_lookup, routes.as_ref(), ip) {
+            Some(result) => result,
+            None => {
+                coverage.invalid += 1;
+                if !args.stats {
+                    println!("{}\tinvalid IPv4 address", ip);
+                }
+                continue;
+            }
+        };
+        coverage.add(&result);
+
+        if args.stats {
+            continue;
+        }
+        if args.tsv {
+            print_tsv(&result);
+        } else {
+            print_block(&result);
+        }
+    }
+
+    if args.stats {
+        coverage.print();
+    }
+}
+
+fn lookup_one(cidr_lookup: &CidrLookup, routes: Option<&RouteLookup>, ip: &str) -> Option<LookupResult> {
+    let addr: Ipv4Addr = ip.parse().ok()?;
+    let country = cidr_lookup
+        .lookup_country_range(ip)
+        .map(|range| (range.start, range.end, range.value.clone()));
+    let asn = cidr_lookup
+        .lookup_as_range(ip)
+        .map(|range| (range.start, range.end, range.value.0.clone(), range.value.1.clone()));
+    let route = routes.and_then(|routes| routes.lookup(ip));
+    Some(LookupResult { ip: addr, class: address_class(addr), country, asn, route })
+}
+
+fn direction(ip: &str) -> String {
+    let peer = if address_class(ip.parse().unwrap()) == AddressClass::Private {
+        EXTERNAL_PEER
+    } else {
+        INTERNAL_PEER
+    };
+    format!(
+        "as source: {:?}, as destination: {:?}",
+        classify_direction(ip, peer),
+        classify_direction(peer, ip)
+    )
+}
+
+fn print_block(result: &LookupResult) {
+    let ip = result.ip.to_string();
+    println!("{}", ip);
+    println!("  class:     {}", result.class);
+    match &result.country {
+        Some((start, end, country)) => println!("  country:   {} ({} - {})", country, start, end),
+        None => println!("  country:   no matching range"),
+    }
+    match &result.asn {
+        Some((start, end, asn, as_name)) => println!("  as:        AS{} {} ({} - {})", asn, as_name, start, end),
+        None => println!("  as:        no matching range"),
+    }
+    if let Some(found) = &result.route {
+        let origin = found.route.origin_as.map_or("?".to_string(), |asn| asn.to_string());
+        println!(
+            "  route:     {} origin AS{} path {} via peer AS{}",
+            found.prefix, origin, found.route.as_path_string(), found.route.peer_as
+        );
+    }
+    println!("  direction: {}", direction(&ip));
+    println!();
+}
+
+fn print_tsv(result: &LookupResult) {
+    let ip = result.ip.to_string();
+    let (country_range, country) = match &result.country {
+        Some((start, end, country)) => (format!("{}-{}", start, end), country.clone()),
+        None => ("-".to_string(), "Unknown".to_string()),
+    };
+    let (as_range, asn, as_name) = match &result.asn {
+        Some((start, end, asn, as_name)) => (format!("{}-{}", start, end), asn.clone(), as_name.clone()),
+        None => ("-".to_string(), "Unknown".to_string(), "Unknown".to_string()),
+    };
+    println!(
+        "{}\t{}\t{}\t{}\t{}\t{}\t{}\t{:?}",
+        ip, result.class, country_range, country, as_range, asn, as_name,
+        classify_direction(&ip, EXTERNAL_PEER)
+    );
+}
+
+impl Coverage {
+    fn add(&mut self, result: &LookupResult) {
+        *self.classes.entry(result.class).or_insert(0) += 1;
+        match &result.country {
+            Some((_, _, country)) => {
+                self.with_country += 1;
+                *self.countries.entry(country.clone()).or_insert(0) += 1;
+            }
+            None if result.class == AddressClass::Public => self.public_without_country += 1,
+            None => {}
+        }
+        if let Some((_, _, asn, _)) = &result.asn {
+            self.with_as += 1;
+            // iptoasn marks unannounced space with AS 0
+            if asn == "0" {
+                self.not_routed += 1;
+            }
+        }
+    }
+
+    fn print(&self) {
+        let valid = self.total - self.invalid;
+        let percent = |n: usize| if valid == 0 { 0.0 } else { n as f64 * 100.0 / valid as f64 };
+
+        println!("addresses:            {}", self.total);
+        println!("invalid:              {}", self.invalid);
+        println!("with country:         {} ({:.1}%)", self.with_country, percent(self.with_country));
+        println!("with AS:              {} ({:.1}%)", self.with_as, percent(self.with_as));
+        println!("  not routed (AS 0):  {}", self.not_routed);
+        println!("public, no country:   {}", self.public_without_country);
+
+        println!("by class:");
+        let mut classes: Vec<_> = self.classes.iter().collect();
+        classes.sort_by(|a, b| b.1.cmp(a.1));
+        for (class, count) in classes {
+            println!("  {:<18}  {} ({:.1}%)", class.to_string(), count, percent(*count));
+        }
+
+        println!("top countries:");
+        let mut countries: Vec<_> = self.countries.iter().collect();
+        countries.sort_by(|a, b| b.1.cmp(a.1).then(a.0.cmp(b.0)));
+        for (country, count) in countries.into_iter().take(10) {
+            println!("  {:<18}  {} ({:.1}%)", country, count, percent(*count));
+        }
+    }
+}
@@ -1 +1,9 @@
+pub mod api;
+pub mod billing;
+pub mod check_config;
+pub mod enricher;
 pub mod listener;
+pub mod lookup;
+pub mod replay;
+pub mod ta;
+pub mod transport;
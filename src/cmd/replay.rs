@@ -0,0 +1,102 @@
+use std::io;
+use std::time::Duration;
+
+use clap::Args;
+
+use super::transport::TransportArgs;
+use crate::config::Config;
+use crate::process::filter::Filter;
+use crate::process::flow::EnrichedFlow;
+use crate::transport::{self, LogScanner, LISTENER_TO_ENRICHER};
+
+// One record per nanosecond, the finest tick there is
+const MAX_RATE: u64 = 1_000_000_000;
+
+#[derive(Args, Debug)]
+pub struct ReplayArgs {
+    /// Segment log to read from [default: transport.log_dir]
+    #[clap(long)]
+    pub from_dir: Option<String>,
+    /// Topic to read from the segment log
+    #[clap(long, default_value = LISTENER_TO_ENRICHER)]
+    pub topic: String,
+    /// Topic to publish to [default: same as --topic]
+    #[clap(long)]
+    pub to_topic: Option<String>,
+    /// First offset to replay
+    #[clap(long, default_value_t = 0)]
+    pub from_offset: i64,
+    /// Only replay enriched flows matching a filter like "dst net 10.0.0.0/8 and port 53"
+    #[clap(long)]
+    pub filter: Option<String>,
+    /// Records per second, 0 replays as fast as possible
+    #[clap(long, default_value_t = 0, value_parser = clap::value_parser!(u64).range(0..=MAX_RATE))]
+    pub rate: u64,
+    #[clap(flatten)]
+    pub transport: TransportArgs,
+}
+
+pub async fn replay(mut config: Config, args: ReplayArgs) -> std::io::Result<()> {
+    let from_dir = args.from_dir.clone().unwrap_or_else(|| config.transport.log_dir.clone());
+    args.transport.apply(&mut config.transport);
+    let transport = transport::build_standalone(&config.transport)?;
+    let to_topic = args.to_topic.as_deref().unwrap_or(&args.topic);
+    let filter = match args.filter.as_deref().map(Filter::parse).transpose() {
+        Ok(filter) => filter,
+        Err(e) => return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("invalid --filter: {}", e))),
+    };
+
+    // The scanner stops where the log ended when it was opened, so replaying
+    // into the log we read from does not loop forever
+    let scanner = LogScanner::open(&from_dir, &args.topic, args.from_offset)?;
+    let mut ticker = (args.rate > 0).then(|| tokio::time::interval(Duration::from_nanos(MAX_RATE / args.rate)));
+
+    let mut count = 0u64;
+    let mut skipped = 0u64;
+    for record in scanner {
+        let record = record?;
+        // Raw datagrams don't parse as enriched flows and never match
+        if let Some(filter) = &filter {
+            if !EnrichedFlow::from_json(&record.payload).is_some_and(|flow| filter.matches(&flow)) {
+                skipped += 1;
+                continue;
+            }
+        }
+        if let Some(ticker) = ticker.as_mut() {
+            ticker.tick().await;
+        }
+        if let Err(e) = transport.send(to_topic, record.key.as_deref(), &record.payload).await {
+            println!("Error replaying offset {}: {}", record.offset, e);
+            continue;
+        }
+        count += 1;
+    }
+
+    println!("Replayed {} records from {}/{} to {}", count, from_dir, args.topic, to_topic);
+    if filter.is_some() {
+        println!("Skipped {} records not matching the filter", skipped);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+
+    use super::*;
+
+    #[derive(Parser)]
+    struct Cli {
+        #[clap(flatten)]
+        replay: ReplayArgs,
+    }
+
+    #[test]
+    fn rate_is_bounded() {
+        let rate = |rate: &str| Cli::try_parse_from(["replay", "--rate", rate]).map(|cli| cli.replay.rate);
+        assert_eq!(rate("0").unwrap(), 0);
+        assert_eq!(rate("1000000000").unwrap(), 1_000_000_000);
+        assert!(rate("1000000001").is_err());
+        assert!(rate("-1").is_err());
+    }
+}
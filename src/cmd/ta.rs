@@ -0,0 +1,89 @@
+use std::net::SocketAddr;
+
+use clap::{Parser, Subcommand};
+
+use super::billing::BillingArgs;
+use super::check_config::CheckConfigArgs;
+use super::lookup::LookupArgs;
+use super::replay::ReplayArgs;
+use super::transport::TransportArgs;
+
+#[derive(Parser, Debug)]
+#[command(name = "ta", version, about = "Live network traffic analyzer", long_about = None)]
+pub struct Cli {
+    /// YAML config file shared by all subcommands
+    #[clap(short, long, global = true)]
+    pub config: Option<String>,
+    #[clap(subcommand)]
+    pub command: Commands,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Commands {
+    /// Receive NetFlow/IPFIX datagrams and forward them to the enricher
+    Listen {
+        /// Port to listen for packets
+        #[clap(short = 'P', long)]
+        port: Option<u16>,
+        #[clap(flatten)]
+        transport: TransportArgs,
+    },
+    /// Enrich flows with country and AS information
+    Enrich {
+        /// Number of enrichment tasks
+        #[clap(short, long)]
+        workers: Option<usize>,
+        #[clap(flatten)]
+        transport: TransportArgs,
+    },
+    /// Watch the enriched flows for DDoS attacks and publish alerts
+    Detect {
+        #[clap(flatten)]
+        transport: TransportArgs,
+    },
+    /// Compare traffic per country, ASN and direction with its baseline and publish anomalies
+    Anomaly {
+        #[clap(flatten)]
+        transport: TransportArgs,
+    },
+    /// Pair the two directions of every session and publish conversations
+    Stitch {
+        #[clap(flatten)]
+        transport: TransportArgs,
+    },
+    /// Count the bytes to and from every customer's prefixes for billing
+    Account {
+        #[clap(flatten)]
+        transport: TransportArgs,
+    },
+    /// Count traffic per direction, country, ASN and service for Prometheus
+    Metrics {
+        #[clap(flatten)]
+        transport: TransportArgs,
+    },
+    /// Listen, enrich and write to InfluxDB in one process
+    Run {
+        /// Port to listen for packets
+        #[clap(short = 'P', long)]
+        port: Option<u16>,
+        /// Number of enrichment tasks
+        #[clap(short, long)]
+        workers: Option<usize>,
+        #[clap(flatten)]
+        transport: TransportArgs,
+    },
+    /// Answer HTTP/JSON queries over the flows stored in InfluxDB
+    Api {
+        /// Address to serve the query API on
+        #[clap(short, long)]
+        listen: Option<SocketAddr>,
+    },
+    /// Send records stored in a file transport segment log through the pipeline again
+    Replay(ReplayArgs),
+    /// Export the monthly volume and 95th percentile of every customer
+    Billing(BillingArgs),
+    /// Show what the enrichment tables know about IP addresses
+    Lookup(LookupArgs),
+    /// Validate the config file and the files it points to
+    CheckConfig(CheckConfigArgs),
+}
@@ -0,0 +1,31 @@
+use clap::Args;
+
+use crate::transport::{TransportConfig, TransportKind};
+
+#[derive(Args, Debug, Clone, Default)]
+pub struct TransportArgs {
+    /// How records move between the pipeline stages [default: kafka]
+    #[clap(long, value_enum)]
+    pub transport: Option<TransportKind>,
+    /// Kafka bootstrap servers [default: localhost:9092]
+    #[clap(long)]
+    pub brokers: Option<String>,
+    /// Directory of the segment log used by the file transport [default: data/log]
+    #[clap(long)]
+    pub log_dir: Option<String>,
+}
+
+impl TransportArgs {
+    // Flags given on the command line win over the config file
+    pub fn apply(&self, config: &mut TransportConfig) {
+        if let Some(kind) = self.transport {
+            config.kind = kind;
+        }
+        if let Some(brokers) = &self.brokers {
+            config.brokers = brokers.clone();
+        }
+        if let Some(log_dir) = &self.log_dir {
+            config.log_dir = log_dir.clone();
+        }
+    }
+}
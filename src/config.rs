@@ -0,0 +1,327 @@
+use std::fs;
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use crate::db::archive::ArchiveConfig;
+use crate::db::bgp_lookup::BgpConfig;
+use crate::db::clickhouse::ClickHouseConfig;
+use crate::db::influx_db::InfluxConfig;
+use crate::db::interface_lookup::InterfaceConfig;
+use crate::db::prometheus::{PrometheusConfig, PROMETHEUS_DIMENSIONS};
+use crate::db::reverse_dns::ReverseDnsConfig;
+use crate::db::service_lookup::ServiceConfig;
+use crate::db::threat_lookup::ThreatConfig;
+use crate::process::accounting::{AccountingConfig, CustomerMap};
+use crate::process::aggregate::AggregatesConfig;
+use crate::process::anomaly::AnomalyConfig;
+use crate::process::api::ApiConfig;
+use crate::process::cardinality::CARDINALITY_DIMENSIONS;
+use crate::process::detector::DetectorConfig;
+use crate::process::enricher::EnricherConfig;
+use crate::process::inventory::InventoryConfig;
+use crate::process::listener::{self, ListenerConfig};
+use crate::process::router::{self, RouteConfig};
+use crate::process::rules::{self, RulesConfig};
+use crate::process::stitcher::StitcherConfig;
+use crate::process::tenant::{self, TenantConfig};
+use crate::process::topn::TOPN_DIMENSIONS;
+use crate::sketch::hyperloglog::{MAX_PRECISION, MIN_PRECISION};
+use crate::transport::{TransportConfig, TransportKind};
+
+// Settings shared by every `ta` subcommand and the standalone binaries.
+// Every section is optional in the YAML file and falls back to the defaults
+// the project used before it had a config file.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+#[serde(default)]
+pub struct Config {
+    pub listener: ListenerConfig,
+    pub transport: TransportConfig,
+    pub enricher: EnricherConfig,
+    pub services: ServiceConfig,
+    pub interfaces: InterfaceConfig,
+    // Routes from MRT RIB dumps, off when not set
+    pub bgp: Option<BgpConfig>,
+    // IP/CIDR blocklists flows are checked against
+    pub threats: ThreatConfig,
+    // PTR lookups of flow addresses, off when not set
+    pub reverse_dns: Option<ReverseDnsConfig>,
+    // Local tags and drop/keep decisions for flows
+    pub rules: RulesConfig,
+    // Extra topics for the enriched flows matching a filter
+    pub routes: Vec<RouteConfig>,
+    // Business units with their own exporters, rules and outputs
+    pub tenants: Vec<TenantConfig>,
+    // Exporter inventory and health, tracked by the enricher
+    pub inventory: Option<InventoryConfig>,
+    // Per window summaries computed by the enricher
+    pub aggregates: AggregatesConfig,
+    // Built-in InfluxDB writer, replaces telegraf when set
+    pub influx: Option<InfluxConfig>,
+    // Raw flow retention in ClickHouse, off when not set
+    pub clickhouse: Option<ClickHouseConfig>,
+    // Hourly Parquet files of the enriched flows, off when not set
+    pub archive: Option<ArchiveConfig>,
+    // Traffic counters for Prometheus scrapes and remote-write, off when not set
+    pub prometheus: Option<PrometheusConfig>,
+    // DDoS detection on the enriched flows, off when not set
+    pub detector: Option<DetectorConfig>,
+    // Baselines per country, ASN and direction, off when not set
+    pub anomaly: Option<AnomalyConfig>,
+    // Per customer byte counters for 95th percentile billing, off when not set
+    pub accounting: Option<AccountingConfig>,
+    // Bidirectional conversations from the enriched flows, off when not set
+    pub stitcher: Option<StitcherConfig>,
+    // HTTP/JSON queries over the stored flows, served by `ta api`
+    pub api: Option<ApiConfig>,
+}
+
+impl Config {
+    // Load the config file, or the defaults when no path is given
+    pub fn load(path: Option<&str>) -> Result<Config, String> {
+        match path {
+            Some(path) => {
+                let text = fs::read_to_string(path)
+                    .map_err(|e| format!("can't read config {}: {}", path, e))?;
+                serde_yaml::from_str(&text)
+                    .map_err(|e| format!("can't parse config {}: {}", path, e))
+            }
+            None => Ok(Config::default()),
+        }
+    }
+
+    // Problems that would stop the pipeline from starting or make it silently useless
+    pub fn check(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+
+        problems.extend(listener::check(&self.listener));
+        if self.enricher.workers == 0 {
+            problems.push("enricher.workers must be at least 1".to_string());
+        }
+        for (name, path) in [
+            ("enricher.country_map", &self.enricher.country_map),
+            ("enricher.as_map", &self.enricher.as_map),
+            ("services.iana_file", &self.services.iana_file),
+        ] {
+            if !Path::new(path).is_file() {
+                problems.push(format!("{} {} does not exist", name, path));
+            }
+        }
+
+        if let Some(file) = &self.interfaces.file {
+            if !Path::new(file).is_file() {
+                problems.push(format!("interfaces.file {} does not exist", file));
+            }
+        }
+        if let Some(snmp) = &self.interfaces.snmp {
+            if snmp.exporters.is_empty() && !snmp.poll_seen {
+                problems.push("interfaces.snmp polls nothing, set exporters or poll_seen".to_string());
+            }
+        }
+
+        if let Some(bgp) = &self.bgp {
+            if bgp.mrt_files.is_empty() && bgp.bmp_listen.is_none() {
+                problems.push("bgp needs mrt_files or bmp_listen".to_string());
+            }
+            for file in &bgp.mrt_files {
+                if !Path::new(file).is_file() {
+                    problems.push(format!("bgp.mrt_files {} does not exist", file));
+                }
+            }
+            if bgp.refresh_secs == 0 {
+                problems.push("bgp.refresh_secs must be at least 1".to_string());
+            }
+        }
+
+        for list in &self.threats.lists {
+            if !Path::new(&list.file).is_file() {
+                problems.push(format!("threats list {} file {} does not exist", list.name, list.file));
+            }
+        }
+        if self.threats.refresh_secs == 0 {
+            problems.push("threats.refresh_secs must be at least 1".to_string());
+        }
+
+        if let Some(reverse_dns) = &self.reverse_dns {
+            if reverse_dns.cache_size == 0 || reverse_dns.concurrency == 0 {
+                problems.push("reverse_dns.cache_size and reverse_dns.concurrency must be at least 1".to_string());
+            }
+            if reverse_dns.timeout_ms == 0 {
+                problems.push("reverse_dns.timeout_ms must not be 0".to_string());
+            }
+        }
+
+        problems.extend(rules::check(&self.rules));
+        problems.extend(router::check(&self.routes));
+        problems.extend(tenant::check(&self.tenants, self.influx.is_some()));
+
+        if let Some(accounting) = &self.accounting {
+            match CustomerMap::load(accounting) {
+                Ok(customers) if customers.is_empty() => {
+                    problems.push("accounting has no customers or customers_file".to_string());
+                }
+                Ok(_) => {}
+                Err(e) => problems.push(format!("accounting: {}", e)),
+            }
+            if accounting.interval_secs == 0 {
+                problems.push("accounting.interval_secs must be at least 1".to_string());
+            }
+        }
+
+        if let Some(stitcher) = &self.stitcher {
+            if stitcher.window_secs == 0 || stitcher.max_pending == 0 {
+                problems.push("stitcher.window_secs and stitcher.max_pending must be at least 1".to_string());
+            }
+        }
+
+        if let Some(api) = &self.api {
+            if api.max_rows == 0 || api.max_points == 0 || api.default_range_secs == 0 {
+                problems.push("api.max_rows, api.max_points and api.default_range_secs must be at least 1".to_string());
+            }
+            if api.cors_origin.as_deref().is_some_and(|origin| origin.is_empty() || origin.contains(['\r', '\n'])) {
+                problems.push("api.cors_origin must be an origin like https://grafana.example.net or *".to_string());
+            }
+        }
+        if let Some(inventory) = &self.inventory {
+            if inventory.interval_secs == 0 || inventory.silent_secs == 0 {
+                problems.push("inventory.interval_secs and inventory.silent_secs must be at least 1".to_string());
+            }
+        }
+
+        match self.transport.kind {
+            TransportKind::Kafka if self.transport.brokers.is_empty() => {
+                problems.push("transport.brokers must be set for the kafka transport".to_string());
+            }
+            TransportKind::File if self.transport.segment_bytes == 0 => {
+                problems.push("transport.segment_bytes must not be 0".to_string());
+            }
+            TransportKind::Channel if self.transport.channel_capacity == 0 => {
+                problems.push("transport.channel_capacity must not be 0".to_string());
+            }
+            _ => {}
+        }
+
+        if let Some(influx) = &self.influx {
+            if influx.url.is_empty() || influx.bucket.is_empty() {
+                problems.push("influx.url and influx.bucket must be set".to_string());
+            }
+            if influx.flush_ms == 0 {
+                problems.push("influx.flush_ms must not be 0".to_string());
+            }
+        }
+
+        if let Some(clickhouse) = &self.clickhouse {
+            if !clickhouse.url.starts_with("http://") && !clickhouse.url.starts_with("https://") {
+                problems.push("clickhouse.url must be an http(s) URL".to_string());
+            }
+            if clickhouse.database.is_empty() || clickhouse.table.is_empty() {
+                problems.push("clickhouse.database and clickhouse.table must be set".to_string());
+            }
+            if clickhouse.batch_size == 0 || clickhouse.flush_ms == 0 {
+                problems.push("clickhouse.batch_size and clickhouse.flush_ms must be at least 1".to_string());
+            }
+        }
+
+        if let Some(archive) = &self.archive {
+            if archive.dir.is_empty() {
+                problems.push("archive.dir must be set".to_string());
+            }
+            if archive.max_file_bytes == 0 || archive.batch_rows == 0 || archive.row_group_rows == 0 {
+                problems.push("archive.max_file_bytes, archive.batch_rows and archive.row_group_rows must be at least 1".to_string());
+            }
+            if !(1..=22).contains(&archive.zstd_level) {
+                problems.push("archive.zstd_level must be between 1 and 22".to_string());
+            }
+            if archive.tag_columns.iter().any(|tag| tag == "tags" || tag == "exporter") {
+                problems.push("archive.tag_columns can't contain tags or exporter".to_string());
+            }
+            if let Some(s3) = &archive.s3 {
+                if s3.bucket.is_empty() {
+                    problems.push("archive.s3.bucket must be set".to_string());
+                }
+            }
+        }
+
+        if let Some(prometheus) = &self.prometheus {
+            if prometheus.listen.is_none() && prometheus.remote_write.is_none() {
+                problems.push("prometheus needs listen or remote_write".to_string());
+            }
+            if prometheus.max_series == 0 || prometheus.expire_secs == 0 {
+                problems.push("prometheus.max_series and prometheus.expire_secs must be at least 1".to_string());
+            }
+            for dimension in &prometheus.dimensions {
+                if !PROMETHEUS_DIMENSIONS.contains(&dimension.as_str()) {
+                    problems.push(format!("prometheus.dimensions {} is not one of {}", dimension, PROMETHEUS_DIMENSIONS.join(", ")));
+                }
+            }
+            if let Some(remote_write) = &prometheus.remote_write {
+                if !remote_write.url.starts_with("http://") && !remote_write.url.starts_with("https://") {
+                    problems.push("prometheus.remote_write.url must be an http(s) URL".to_string());
+                }
+                if remote_write.interval_secs == 0 {
+                    problems.push("prometheus.remote_write.interval_secs must be at least 1".to_string());
+                }
+            }
+        }
+
+        if self.aggregates.window_secs == 0 {
+            problems.push("aggregates.window_secs must be at least 1".to_string());
+        }
+        if let Some(topn) = &self.aggregates.topn {
+            if topn.n == 0 || topn.capacity < topn.n {
+                problems.push("aggregates.topn.capacity must be at least aggregates.topn.n, and n at least 1".to_string());
+            }
+            for dimension in &topn.dimensions {
+                if !TOPN_DIMENSIONS.contains(&dimension.as_str()) {
+                    problems.push(format!("aggregates.topn.dimensions {} is not one of {}", dimension, TOPN_DIMENSIONS.join(", ")));
+                }
+            }
+        }
+
+        if let Some(cardinality) = &self.aggregates.cardinality {
+            if !(MIN_PRECISION..=MAX_PRECISION).contains(&cardinality.precision) {
+                problems.push(format!("aggregates.cardinality.precision must be between {} and {}", MIN_PRECISION, MAX_PRECISION));
+            }
+            if cardinality.prefix_len > 32 {
+                problems.push("aggregates.cardinality.prefix_len must be at most 32".to_string());
+            }
+            for dimension in &cardinality.dimensions {
+                if !CARDINALITY_DIMENSIONS.contains(&dimension.as_str()) {
+                    problems.push(format!("aggregates.cardinality.dimensions {} is not one of {}", dimension, CARDINALITY_DIMENSIONS.join(", ")));
+                }
+            }
+        }
+
+        if let Some(detector) = &self.detector {
+            if detector.prefix_len > 32 {
+                problems.push("detector.prefix_len must be at most 32".to_string());
+            }
+            if detector.window_secs == 0 {
+                problems.push("detector.window_secs must be at least 1".to_string());
+            }
+            for network in &detector.protected {
+                if network.parse::<cidr::Ipv4Cidr>().is_err() {
+                    problems.push(format!("detector.protected {} is not an IPv4 network", network));
+                }
+            }
+            if let Some(webhook) = &detector.webhook {
+                if !webhook.starts_with("http://") && !webhook.starts_with("https://") {
+                    problems.push(format!("detector.webhook {} is not an http(s) URL", webhook));
+                }
+            }
+        }
+
+        if let Some(anomaly) = &self.anomaly {
+            if anomaly.window_secs == 0 {
+                problems.push("anomaly.window_secs must be at least 1".to_string());
+            }
+            if [anomaly.alpha, anomaly.seasonal_alpha].iter().any(|alpha| *alpha <= 0.0 || *alpha > 1.0) {
+                problems.push("anomaly.alpha and anomaly.seasonal_alpha must be in (0, 1]".to_string());
+            }
+            if anomaly.state_file.is_empty() {
+                problems.push("anomaly.state_file must be set".to_string());
+            }
+        }
+        problems
+    }
+}
@@ -0,0 +1,572 @@
+// Long-term archive of the enriched flows as hourly Parquet files, partitioned Hive style
+// by date, hour and exporter, on the local disk or in an S3 compatible bucket.
+
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use arrow_array::builder::{
+    MapBuilder, StringBuilder, StringDictionaryBuilder, TimestampMillisecondBuilder, UInt16Builder, UInt32Builder,
+    UInt64Builder, UInt8Builder,
+};
+use arrow_array::types::Int32Type;
+use arrow_array::{Array, ArrayRef, RecordBatch};
+use arrow_schema::{DataType, Field, Schema, SchemaRef, TimeUnit};
+use async_trait::async_trait;
+use chrono::{DateTime, Timelike, Utc};
+use object_store::aws::AmazonS3Builder;
+use object_store::buffered::BufWriter;
+use object_store::ObjectStore;
+use parquet::arrow::ArrowWriter;
+use parquet::basic::{Compression, ZstdLevel};
+use parquet::file::properties::WriterProperties;
+use serde::{Deserialize, Serialize};
+use tokio::io::AsyncWriteExt;
+
+use crate::process::flow::EnrichedFlow;
+use crate::transport::{consume_windows, Done, SharedTransport, Windowed, ENRICHER_TO_TSDB};
+
+pub const ARCHIVE_GROUP: &str = "ta-archive";
+// Files of an hour are finalized this long after it ended, for the flows still in flight
+const HOUR_GRACE_SECS: i64 = 60;
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct ArchiveConfig {
+    // Where the files go, or are staged before the upload when `s3` is set
+    pub dir: String,
+    pub s3: Option<S3Config>,
+    // Tags stored as dictionary encoded columns, every other tag goes to the `tags` map column
+    pub tag_columns: Vec<String>,
+    // A file this large is finalized and the next one started
+    pub max_file_bytes: u64,
+    // Flows per record batch and per row group
+    pub batch_rows: usize,
+    pub row_group_rows: usize,
+    pub zstd_level: i32,
+    // Topic of the flows to archive, a route's topic to archive only some
+    pub topic: String,
+}
+
+impl Default for ArchiveConfig {
+    fn default() -> Self {
+        ArchiveConfig {
+            dir: "data/archive".to_string(),
+            s3: None,
+            tag_columns: [
+                "src_ip", "dst_ip", "type", "src_country", "dst_country", "src_asn", "dst_asn", "src_as_name",
+                "dst_as_name", "service", "app_port", "protocol_name", "in_if_name", "out_if_name",
+            ]
+            .iter()
+            .map(|tag| tag.to_string())
+            .collect(),
+            max_file_bytes: 128 * 1024 * 1024,
+            batch_rows: 8192,
+            row_group_rows: 1024 * 1024,
+            zstd_level: 3,
+            topic: ENRICHER_TO_TSDB.to_string(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct S3Config {
+    pub bucket: String,
+    // Key prefix in front of the partitions
+    pub prefix: String,
+    pub region: String,
+    // e.g. http://localhost:9000 for MinIO, AWS when not set
+    pub endpoint: Option<String>,
+    // The AWS_* environment variables are used when not set
+    pub access_key_id: Option<String>,
+    pub secret_access_key: Option<String>,
+    pub allow_http: bool,
+}
+
+impl Default for S3Config {
+    fn default() -> Self {
+        S3Config {
+            bucket: String::new(),
+            prefix: String::new(),
+            region: "us-east-1".to_string(),
+            endpoint: None,
+            access_key_id: None,
+            secret_access_key: None,
+            allow_http: false,
+        }
+    }
+}
+
+// Hive style directory of the flows of an hour, date=2026-10-19/hour=07
+pub fn hour_partition(time: DateTime<Utc>) -> String {
+    format!("date={}/hour={:02}", time.format("%Y-%m-%d"), time.hour())
+}
+
+// and of one exporter in it, date=2026-10-19/hour=07/exporter=192.0.2.1
+pub fn partition(time: DateTime<Utc>, exporter: &str) -> String {
+    format!("{}/exporter={}", hour_partition(time), escape_partition(exporter))
+}
+
+// Percent-encode what can't appear in a path segment, like the ':' of IPv6 exporters
+fn escape_partition(value: &str) -> String {
+    value
+        .bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'.' | b'-' | b'_' => (b as char).to_string(),
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+fn tags_type() -> DataType {
+    MapBuilder::new(None, StringBuilder::new(), StringBuilder::new())
+        .finish()
+        .data_type()
+        .clone()
+}
+
+// The columns of EnrichedFlow: time, the numeric fields, the configured tags and the rest of the tags
+pub fn schema(config: &ArchiveConfig) -> SchemaRef {
+    let dictionary = DataType::Dictionary(Box::new(DataType::Int32), Box::new(DataType::Utf8));
+    let mut fields = vec![
+        Field::new("time", DataType::Timestamp(TimeUnit::Millisecond, Some("UTC".into())), false),
+        Field::new("packets", DataType::UInt64, false),
+        Field::new("bytes", DataType::UInt64, false),
+        Field::new("first_switched", DataType::UInt64, false),
+        Field::new("last_switched", DataType::UInt64, false),
+        Field::new("src_port", DataType::UInt16, false),
+        Field::new("dst_port", DataType::UInt16, false),
+        Field::new("protocol", DataType::UInt8, false),
+        Field::new("tcp_flags", DataType::UInt8, false),
+        Field::new("input_if", DataType::UInt32, false),
+        Field::new("output_if", DataType::UInt32, false),
+    ];
+    for tag in &config.tag_columns {
+        fields.push(Field::new(tag, dictionary.clone(), true));
+    }
+    fields.push(Field::new("tags", tags_type(), false));
+    Arc::new(Schema::new(fields))
+}
+
+pub fn record_batch(schema: &SchemaRef, config: &ArchiveConfig, flows: &[EnrichedFlow]) -> Result<RecordBatch, String> {
+    let mut time = TimestampMillisecondBuilder::with_capacity(flows.len()).with_timezone("UTC");
+    let mut packets = UInt64Builder::with_capacity(flows.len());
+    let mut bytes = UInt64Builder::with_capacity(flows.len());
+    let mut first_switched = UInt64Builder::with_capacity(flows.len());
+    let mut last_switched = UInt64Builder::with_capacity(flows.len());
+    let mut src_port = UInt16Builder::with_capacity(flows.len());
+    let mut dst_port = UInt16Builder::with_capacity(flows.len());
+    let mut protocol = UInt8Builder::with_capacity(flows.len());
+    let mut tcp_flags = UInt8Builder::with_capacity(flows.len());
+    let mut input_if = UInt32Builder::with_capacity(flows.len());
+    let mut output_if = UInt32Builder::with_capacity(flows.len());
+    let mut tag_columns: Vec<StringDictionaryBuilder<Int32Type>> =
+        config.tag_columns.iter().map(|_| StringDictionaryBuilder::new()).collect();
+    let mut tags = MapBuilder::new(None, StringBuilder::new(), StringBuilder::new());
+
+    for flow in flows {
+        let fields = &flow.fields;
+        time.append_value(flow.time.timestamp_millis());
+        packets.append_value(fields.packets);
+        bytes.append_value(fields.bytes);
+        first_switched.append_value(fields.first_switched);
+        last_switched.append_value(fields.last_switched);
+        src_port.append_value(fields.src_port);
+        dst_port.append_value(fields.dst_port);
+        protocol.append_value(fields.protocol);
+        tcp_flags.append_value(fields.tcp_flags);
+        input_if.append_value(fields.input_if);
+        output_if.append_value(fields.output_if);
+        for (name, column) in config.tag_columns.iter().zip(tag_columns.iter_mut()) {
+            column.append_option(flow.tag(name));
+        }
+        for (name, value) in &flow.tags {
+            // The exporter is in the partition path
+            if name != "exporter" && !config.tag_columns.contains(name) {
+                tags.keys().append_value(name);
+                tags.values().append_value(value);
+            }
+        }
+        tags.append(true).map_err(|e| e.to_string())?;
+    }
+
+    let mut columns: Vec<ArrayRef> = vec![
+        Arc::new(time.finish()),
+        Arc::new(packets.finish()),
+        Arc::new(bytes.finish()),
+        Arc::new(first_switched.finish()),
+        Arc::new(last_switched.finish()),
+        Arc::new(src_port.finish()),
+        Arc::new(dst_port.finish()),
+        Arc::new(protocol.finish()),
+        Arc::new(tcp_flags.finish()),
+        Arc::new(input_if.finish()),
+        Arc::new(output_if.finish()),
+    ];
+    columns.extend(tag_columns.iter_mut().map(|column| Arc::new(column.finish()) as ArrayRef));
+    columns.push(Arc::new(tags.finish()));
+    RecordBatch::try_new(schema.clone(), columns).map_err(|e| e.to_string())
+}
+
+// A file being written, under a hidden name until it is finalized
+struct OpenFile {
+    hour: String,
+    writer: ArrowWriter<File>,
+    temporary: PathBuf,
+    path: PathBuf,
+    pending: Vec<EnrichedFlow>,
+    rows: usize,
+}
+
+// The open files of every partition
+pub struct Archiver {
+    config: ArchiveConfig,
+    schema: SchemaRef,
+    properties: WriterProperties,
+    files: HashMap<String, OpenFile>,
+}
+
+impl Archiver {
+    pub fn new(config: ArchiveConfig) -> Result<Self, String> {
+        let level = ZstdLevel::try_new(config.zstd_level).map_err(|e| e.to_string())?;
+        let properties = WriterProperties::builder()
+            .set_compression(Compression::ZSTD(level))
+            .set_dictionary_enabled(true)
+            .set_max_row_group_size(config.row_group_rows.max(1))
+            .build();
+        Ok(Archiver {
+            schema: schema(&config),
+            config,
+            properties,
+            files: HashMap::new(),
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.files.is_empty()
+    }
+
+    // The earliest hour with an open file
+    pub fn oldest_hour(&self) -> Option<&str> {
+        self.files.values().map(|file| file.hour.as_str()).min()
+    }
+
+    // Add a flow, true when a file grew past `max_file_bytes` and should be rolled
+    pub fn write(&mut self, flow: EnrichedFlow) -> Result<bool, String> {
+        let partition = partition(flow.time, flow.tag("exporter").unwrap_or("Unknown"));
+        if !self.files.contains_key(&partition) {
+            let file = self.open(&partition, hour_partition(flow.time))?;
+            self.files.insert(partition.clone(), file);
+        }
+        let file = self.files.get_mut(&partition).unwrap();
+        file.pending.push(flow);
+        if file.pending.len() < self.config.batch_rows.max(1) {
+            return Ok(false);
+        }
+        let batch = record_batch(&self.schema, &self.config, &std::mem::take(&mut file.pending))?;
+        file.rows += batch.num_rows();
+        file.writer.write(&batch).map_err(|e| e.to_string())?;
+        Ok(file.writer.bytes_written() + file.writer.in_progress_size() >= self.config.max_file_bytes as usize)
+    }
+
+    fn open(&self, partition: &str, hour: String) -> Result<OpenFile, String> {
+        let dir = Path::new(&self.config.dir).join(partition);
+        fs::create_dir_all(&dir).map_err(|e| format!("can't create {}: {}", dir.display(), e))?;
+        let name = format!("part-{}.parquet", uuid::Uuid::new_v4());
+        let temporary = dir.join(format!(".{}.tmp", name));
+        let file = File::create(&temporary).map_err(|e| format!("can't create {}: {}", temporary.display(), e))?;
+        let writer = ArrowWriter::try_new(file, self.schema.clone(), Some(self.properties.clone())).map_err(|e| e.to_string())?;
+        Ok(OpenFile { hour, writer, temporary, path: dir.join(name), pending: Vec::new(), rows: 0 })
+    }
+
+    // Finalize the files of the hours before `before` (all files when None), returns their paths
+    pub fn finalize(&mut self, before: Option<&str>) -> Result<Vec<PathBuf>, String> {
+        let partitions: Vec<String> = self
+            .files
+            .iter()
+            .filter(|(_, file)| before.is_none_or(|before| file.hour.as_str() < before))
+            .map(|(partition, _)| partition.clone())
+            .collect();
+        let mut finalized = Vec::new();
+        for partition in partitions {
+            let mut file = self.files.remove(&partition).unwrap();
+            if !file.pending.is_empty() {
+                let batch = record_batch(&self.schema, &self.config, &file.pending)?;
+                file.rows += batch.num_rows();
+                file.writer.write(&batch).map_err(|e| e.to_string())?;
+            }
+            file.writer.close().map_err(|e| e.to_string())?;
+            // Readers only ever see complete files
+            fs::rename(&file.temporary, &file.path).map_err(|e| format!("can't finalize {}: {}", file.path.display(), e))?;
+            println!("Archived {} flows to {}", file.rows, file.path.display());
+            finalized.push(file.path);
+        }
+        Ok(finalized)
+    }
+}
+
+// Files an earlier run left behind: unfinished ones and finalized ones
+fn leftover_files(dir: &Path, unfinished: &mut Vec<PathBuf>, finished: &mut Vec<PathBuf>) -> io::Result<()> {
+    if !dir.is_dir() {
+        return Ok(());
+    }
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let name = path.file_name().and_then(|n| n.to_str()).unwrap_or("");
+        if path.is_dir() {
+            leftover_files(&path, unfinished, finished)?;
+        } else if name.starts_with('.') && name.ends_with(".tmp") {
+            unfinished.push(path);
+        } else if name.ends_with(".parquet") {
+            finished.push(path);
+        }
+    }
+    Ok(())
+}
+
+fn build_store(config: &S3Config) -> Result<Arc<dyn ObjectStore>, String> {
+    let mut builder = AmazonS3Builder::from_env()
+        .with_bucket_name(&config.bucket)
+        .with_region(&config.region)
+        .with_allow_http(config.allow_http);
+    if let Some(endpoint) = &config.endpoint {
+        builder = builder.with_endpoint(endpoint);
+    }
+    if let Some(key) = &config.access_key_id {
+        builder = builder.with_access_key_id(key);
+    }
+    if let Some(secret) = &config.secret_access_key {
+        builder = builder.with_secret_access_key(secret);
+    }
+    Ok(Arc::new(builder.build().map_err(|e| e.to_string())?))
+}
+
+// Copy a finalized file to the bucket under the same partition and remove the local copy
+async fn upload(store: &Arc<dyn ObjectStore>, config: &ArchiveConfig, s3: &S3Config, path: &Path) -> Result<(), String> {
+    let relative = path.strip_prefix(&config.dir).map_err(|e| e.to_string())?;
+    let key = match s3.prefix.trim_matches('/') {
+        "" => relative.to_string_lossy().to_string(),
+        prefix => format!("{}/{}", prefix, relative.to_string_lossy()),
+    };
+    let data = tokio::fs::read(path).await.map_err(|e| e.to_string())?;
+    // Multipart for large files, a single PUT otherwise. The object only appears when complete.
+    // Taken as is, the partitions are percent-encoded already
+    let location = object_store::path::Path::parse(&key).map_err(|e| e.to_string())?;
+    let mut writer = BufWriter::new(store.clone(), location);
+    writer.write_all(&data).await.map_err(|e| e.to_string())?;
+    writer.shutdown().await.map_err(|e| format!("s3://{}/{}: {}", s3.bucket, key, e))?;
+    tokio::fs::remove_file(path).await.map_err(|e| e.to_string())?;
+    println!("Uploaded s3://{}/{}", s3.bucket, key);
+    Ok(())
+}
+
+// Archive every enriched flow. Offsets are committed only when no file is open, so a
+// restart reads again exactly the flows of the files it never finalized.
+pub async fn run_writer(transport: SharedTransport, config: ArchiveConfig) {
+    let archiver = match Archiver::new(config.clone()) {
+        Ok(archiver) => archiver,
+        Err(e) => {
+            println!("Archive disabled: {}", e);
+            return;
+        }
+    };
+    let store = match &config.s3 {
+        Some(s3) => match build_store(s3) {
+            Ok(store) => Some((store, s3.clone())),
+            Err(e) => {
+                println!("Archive disabled, can't use bucket {}: {}", s3.bucket, e);
+                return;
+            }
+        },
+        None => None,
+    };
+    // Flows of unfinished files are read again from the transport, finished files still need their upload
+    let mut unfinished = Vec::new();
+    let mut uploads = Vec::new();
+    if let Err(e) = leftover_files(Path::new(&config.dir), &mut unfinished, &mut uploads) {
+        println!("Can't look for earlier archive files in {}: {}", config.dir, e);
+    }
+    for path in &unfinished {
+        if let Err(e) = fs::remove_file(path) {
+            println!("Can't remove unfinished {}: {}", path.display(), e);
+        }
+    }
+
+    let mut subscription = transport
+        .subscribe(&config.topic, ARCHIVE_GROUP)
+        .await
+        .expect("Can't subscribe to specified topic");
+
+    let mut archiving = Archiving { archiver, config, store, uploads, roll: false };
+    consume_windows(subscription.as_mut(), Duration::from_secs(10), &mut archiving).await;
+
+    if let Err(e) = archiving.archiver.finalize(None) {
+        println!("Can't finalize archive files: {}", e);
+    }
+}
+
+struct Archiving {
+    archiver: Archiver,
+    config: ArchiveConfig,
+    store: Option<(Arc<dyn ObjectStore>, S3Config)>,
+    // Finalized files still to upload
+    uploads: Vec<PathBuf>,
+    // A file grew too large, every open file is finalized
+    roll: bool,
+}
+
+#[async_trait]
+impl Windowed for Archiving {
+    type Item = EnrichedFlow;
+
+    fn observe(&mut self, flow: EnrichedFlow, _offset: i64) {
+        match self.archiver.write(flow) {
+            Ok(roll) => self.roll |= roll,
+            Err(e) => println!("Can't write archive file: {}", e),
+        }
+    }
+
+    fn full(&self) -> bool {
+        self.roll
+    }
+
+    async fn flush(&mut self) -> Done {
+        // A roll finalizes every open file, so that the offset can be committed
+        let roll = std::mem::take(&mut self.roll);
+        let before = hour_partition(Utc::now() - chrono::Duration::seconds(HOUR_GRACE_SECS));
+        let finalize = roll || self.archiver.oldest_hour().is_some_and(|hour| hour < before.as_str());
+        if finalize {
+            let result = self.archiver.finalize(if roll { None } else { Some(&before) });
+            match result {
+                Ok(finalized) => self.uploads.extend(finalized),
+                Err(e) => println!("Can't finalize archive files: {}", e),
+            }
+        }
+        if let Some((store, s3)) = &self.store {
+            let mut failed = Vec::new();
+            for path in self.uploads.drain(..) {
+                if let Err(e) = upload(store, &self.config, s3, &path).await {
+                    println!("Upload of {} failed, retrying later: {}", path.display(), e);
+                    failed.push(path);
+                }
+            }
+            self.uploads = failed;
+        } else {
+            self.uploads.clear();
+        }
+        if self.archiver.is_empty() {
+            Done::All
+        } else {
+            Done::Nothing
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use arrow_array::cast::AsArray;
+    use arrow_array::types::UInt64Type;
+    use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+
+    use crate::process::flow::FlowRecord;
+
+    fn flows() -> Vec<EnrichedFlow> {
+        (1..=3u64)
+            .map(|bytes| {
+                let record = FlowRecord { src_ip: "192.0.2.1".to_string(), dst_ip: "198.51.100.1".to_string(), bytes, ..FlowRecord::default() };
+                let mut flow = EnrichedFlow::new(&record, Utc::now());
+                flow.set_tag("exporter", "2001:db8::9");
+                flow.set_tag("site", "ams");
+                flow
+            })
+            .collect()
+    }
+
+    fn config(name: &str) -> ArchiveConfig {
+        let dir = std::env::temp_dir().join(format!("archive-{}-{}", name, std::process::id()));
+        ArchiveConfig { dir: dir.to_str().unwrap().to_string(), ..ArchiveConfig::default() }
+    }
+
+    // The bytes column and the tags map of the first row group
+    fn read_back(data: impl parquet::file::reader::ChunkReader + 'static) -> (Vec<u64>, usize) {
+        let mut reader = ParquetRecordBatchReaderBuilder::try_new(data).unwrap().build().unwrap();
+        let batch = reader.next().unwrap().unwrap();
+        let bytes = batch.column_by_name("bytes").unwrap().as_primitive::<UInt64Type>().values().to_vec();
+        let tags = batch.column_by_name("tags").unwrap().as_map().value(0).len();
+        (bytes, tags)
+    }
+
+    #[test]
+    fn finalized_files_are_readable() {
+        let config = config("local");
+        let mut archiver = Archiver::new(config.clone()).unwrap();
+        for flow in flows() {
+            assert!(!archiver.write(flow).unwrap());
+        }
+        assert!(!archiver.is_empty());
+        let finalized = archiver.finalize(None).unwrap();
+        assert!(archiver.is_empty());
+
+        assert_eq!(finalized.len(), 1);
+        let path = finalized[0].to_str().unwrap();
+        assert!(path.contains("/exporter=2001%3Adb8%3A%3A9/part-"), "{}", path);
+        // Only `site` is neither a tag column nor the exporter
+        assert_eq!(read_back(File::open(path).unwrap()), (vec![1, 2, 3], 1));
+        fs::remove_dir_all(&config.dir).unwrap();
+    }
+
+    // Needs the minio service of docker-compose.yml with the bucket `flows` of config/ta.yaml,
+    // or ARCHIVE_S3_ENDPOINT and ARCHIVE_S3_BUCKET
+    #[tokio::test]
+    #[ignore]
+    async fn uploads_to_minio() {
+        let s3 = S3Config {
+            bucket: std::env::var("ARCHIVE_S3_BUCKET").unwrap_or_else(|_| "flows".to_string()),
+            prefix: format!("flows-{}", std::process::id()),
+            endpoint: Some(std::env::var("ARCHIVE_S3_ENDPOINT").unwrap_or_else(|_| "http://localhost:9000".to_string())),
+            access_key_id: Some("minioadmin".to_string()),
+            secret_access_key: Some("minioadmin".to_string()),
+            allow_http: true,
+            ..S3Config::default()
+        };
+        let config = ArchiveConfig { s3: Some(s3.clone()), ..config("s3") };
+        let store = build_store(&s3).unwrap();
+        let mut archiving = Archiving {
+            archiver: Archiver::new(config.clone()).unwrap(),
+            config: config.clone(),
+            store: Some((store.clone(), s3.clone())),
+            uploads: Vec::new(),
+            roll: false,
+        };
+        for flow in flows() {
+            archiving.observe(flow, 0);
+        }
+        // A roll finalizes and uploads every open file
+        archiving.roll = true;
+        assert_eq!(archiving.flush().await, Done::All);
+        assert!(archiving.uploads.is_empty());
+
+        let mut local = Vec::new();
+        leftover_files(Path::new(&config.dir), &mut Vec::new(), &mut local).unwrap();
+        assert!(local.is_empty());
+        // Down the date, hour and exporter directories
+        let mut found = Vec::new();
+        let mut prefixes = vec![object_store::path::Path::from(s3.prefix.as_str())];
+        while let Some(prefix) = prefixes.pop() {
+            let listing = store.list_with_delimiter(Some(&prefix)).await.unwrap();
+            found.extend(listing.objects.into_iter().map(|meta| meta.location));
+            prefixes.extend(listing.common_prefixes);
+        }
+        assert_eq!(found.len(), 1);
+        assert!(found[0].as_ref().contains("/exporter=2001%3Adb8%3A%3A9/part-"), "{}", found[0]);
+        let data = store.get(&found[0]).await.unwrap().bytes().await.unwrap();
+        assert_eq!(read_back(data), (vec![1, 2, 3], 1));
+        store.delete(&found[0]).await.unwrap();
+        fs::remove_dir_all(&config.dir).unwrap();
+    }
+}
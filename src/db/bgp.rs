@@ -0,0 +1,294 @@
+// Pieces of the BGP wire format (RFC 4271) shared by the MRT reader and the BMP listener.
+
+use std::net::{IpAddr, Ipv4Addr};
+
+const ATTR_FLAG_EXTENDED_LENGTH: u8 = 0x10;
+const ATTR_AS_PATH: u8 = 2;
+const ATTR_NEXT_HOP: u8 = 3;
+const ATTR_COMMUNITIES: u8 = 8;
+const ATTR_AS4_PATH: u8 = 17;
+const AS_SET: u8 = 1;
+const AS_SEQUENCE: u8 = 2;
+// Placeholder for 4 byte ASNs in 2 byte AS paths (RFC 6793)
+const AS_TRANS: u32 = 23456;
+const MARKER_LEN: usize = 16;
+const MESSAGE_HEADER_LEN: usize = 19;
+pub const MESSAGE_UPDATE: u8 = 2;
+
+// A route to a prefix as one peer announced it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Route {
+    // Peer the route was learned from
+    pub peer_ip: IpAddr,
+    pub peer_as: u32,
+    // Neighbor first, origin last. Members of an AS_SET follow in their order.
+    pub as_path: Vec<u32>,
+    // Not known when the path ends in an AS_SET of several ASes
+    pub origin_as: Option<u32>,
+    pub next_hop: Option<Ipv4Addr>,
+    // Standard communities, high 16 bits the AS
+    pub communities: Vec<u32>,
+}
+
+impl Route {
+    // The AS the traffic is handed to, the first one of the path
+    pub fn next_hop_as(&self) -> Option<u32> {
+        self.as_path.first().copied()
+    }
+
+    pub fn as_path_string(&self) -> String {
+        self.as_path.iter().map(|asn| asn.to_string()).collect::<Vec<_>>().join(" ")
+    }
+
+    // "65000:100,65000:200"
+    pub fn communities_string(&self) -> String {
+        self.communities
+            .iter()
+            .map(|community| format!("{}:{}", community >> 16, community & 0xffff))
+            .collect::<Vec<_>>()
+            .join(",")
+    }
+}
+
+// What a route takes from the path attributes of an UPDATE or a RIB entry
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PathAttributes {
+    pub as_path: Vec<u32>,
+    pub origin_as: Option<u32>,
+    pub next_hop: Option<Ipv4Addr>,
+    pub communities: Vec<u32>,
+}
+
+// The IPv4 unicast part of an UPDATE message
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Update {
+    pub withdrawn: Vec<(Ipv4Addr, u8)>,
+    pub attributes: PathAttributes,
+    pub announced: Vec<(Ipv4Addr, u8)>,
+}
+
+pub fn read_u16(data: &[u8], pos: usize) -> Result<u16, String> {
+    data.get(pos..pos + 2)
+        .map(|b| u16::from_be_bytes([b[0], b[1]]))
+        .ok_or_else(|| "truncated BGP data".to_string())
+}
+
+pub fn read_u32(data: &[u8], pos: usize) -> Result<u32, String> {
+    data.get(pos..pos + 4)
+        .map(|b| u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+        .ok_or_else(|| "truncated BGP data".to_string())
+}
+
+// A length-prefixed IPv4 prefix as in NLRI and RIB entries, with the position after it
+pub fn read_prefix(data: &[u8], pos: usize) -> Result<((Ipv4Addr, u8), usize), String> {
+    let length = *data.get(pos).ok_or("truncated prefix")?;
+    if length > 32 {
+        return Err(format!("invalid IPv4 prefix length {}", length));
+    }
+    let bytes = (length as usize).div_ceil(8);
+    let mut octets = [0u8; 4];
+    octets[..bytes].copy_from_slice(data.get(pos + 1..pos + 1 + bytes).ok_or("truncated prefix")?);
+    let mask = u32::MAX.checked_shl(32 - length as u32).unwrap_or(0);
+    let network = Ipv4Addr::from(u32::from_be_bytes(octets) & mask);
+    Ok(((network, length), pos + 1 + bytes))
+}
+
+// Every prefix of an NLRI or withdrawn routes field
+pub fn read_prefixes(data: &[u8]) -> Result<Vec<(Ipv4Addr, u8)>, String> {
+    let mut prefixes = Vec::new();
+    let mut pos = 0;
+    while pos < data.len() {
+        let (prefix, next) = read_prefix(data, pos)?;
+        prefixes.push(prefix);
+        pos = next;
+    }
+    Ok(prefixes)
+}
+
+// A whole BGP message starting with its marker, returns its type and the bytes after the header
+pub fn read_message(data: &[u8]) -> Result<(u8, &[u8]), String> {
+    if data.len() < MESSAGE_HEADER_LEN || data[..MARKER_LEN].iter().any(|b| *b != 0xff) {
+        return Err("not a BGP message".to_string());
+    }
+    let length = read_u16(data, MARKER_LEN)? as usize;
+    let body = data.get(MESSAGE_HEADER_LEN..length).ok_or("truncated BGP message")?;
+    Ok((data[MARKER_LEN + 2], body))
+}
+
+// Body of an UPDATE message. IPv6 and other families in MP_REACH_NLRI are ignored.
+pub fn parse_update(body: &[u8], four_byte_asn: bool) -> Result<Update, String> {
+    let withdrawn_length = read_u16(body, 0)? as usize;
+    let withdrawn = body.get(2..2 + withdrawn_length).ok_or("truncated withdrawn routes")?;
+    let pos = 2 + withdrawn_length;
+    let attributes_length = read_u16(body, pos)? as usize;
+    let attributes = body.get(pos + 2..pos + 2 + attributes_length).ok_or("truncated path attributes")?;
+    let nlri = &body[pos + 2 + attributes_length..];
+    Ok(Update {
+        withdrawn: read_prefixes(withdrawn)?,
+        attributes: parse_path_attributes(attributes, four_byte_asn)?,
+        announced: read_prefixes(nlri)?,
+    })
+}
+
+// Path attributes, with 4 byte ASNs in AS_PATH unless the session didn't negotiate them
+pub fn parse_path_attributes(data: &[u8], four_byte_asn: bool) -> Result<PathAttributes, String> {
+    let mut attributes = PathAttributes::default();
+    let mut as4_path = None;
+    let mut pos = 0;
+    while pos < data.len() {
+        let flags = *data.get(pos).ok_or("truncated attribute")?;
+        let kind = *data.get(pos + 1).ok_or("truncated attribute")?;
+        let (length, header) = if flags & ATTR_FLAG_EXTENDED_LENGTH != 0 {
+            (read_u16(data, pos + 2)? as usize, 4)
+        } else {
+            (*data.get(pos + 2).ok_or("truncated attribute")? as usize, 3)
+        };
+        let value = data
+            .get(pos + header..pos + header + length)
+            .ok_or_else(|| format!("attribute {} overruns its message", kind))?;
+        match kind {
+            ATTR_AS_PATH => attributes.set_path(value, if four_byte_asn { 4 } else { 2 })?,
+            ATTR_AS4_PATH => as4_path = Some(value),
+            ATTR_NEXT_HOP if length == 4 => {
+                attributes.next_hop = Some(Ipv4Addr::new(value[0], value[1], value[2], value[3]));
+            }
+            ATTR_COMMUNITIES => {
+                attributes.communities = value
+                    .chunks_exact(4)
+                    .map(|c| u32::from_be_bytes([c[0], c[1], c[2], c[3]]))
+                    .collect();
+            }
+            _ => {}
+        }
+        pos += header + length;
+    }
+
+    // A 2 byte session carries the real path of 4 byte ASes in AS4_PATH
+    if let Some(as4_path) = as4_path {
+        if !four_byte_asn && attributes.as_path.contains(&AS_TRANS) {
+            let mut merged = PathAttributes::default();
+            merged.set_path(as4_path, 4)?;
+            // AS4_PATH may be shorter, the leading ASes of AS_PATH are kept
+            let keep = attributes.as_path.len().saturating_sub(merged.as_path.len());
+            let mut as_path = attributes.as_path[..keep].to_vec();
+            as_path.extend(&merged.as_path);
+            attributes.as_path = as_path;
+            attributes.origin_as = merged.origin_as;
+        }
+    }
+    Ok(attributes)
+}
+
+impl PathAttributes {
+    fn set_path(&mut self, value: &[u8], asn_size: usize) -> Result<(), String> {
+        self.as_path.clear();
+        self.origin_as = None;
+        let mut pos = 0;
+        while pos < value.len() {
+            let kind = *value.get(pos).ok_or("truncated AS path")?;
+            let count = *value.get(pos + 1).ok_or("truncated AS path")? as usize;
+            let mut segment = Vec::with_capacity(count);
+            for index in 0..count {
+                let at = pos + 2 + index * asn_size;
+                let asn = match asn_size {
+                    4 => read_u32(value, at)?,
+                    _ => read_u16(value, at)? as u32,
+                };
+                segment.push(asn);
+            }
+            pos += 2 + count * asn_size;
+            self.origin_as = match kind {
+                AS_SEQUENCE => segment.last().copied(),
+                AS_SET if segment.len() == 1 => segment.first().copied(),
+                AS_SET => None,
+                // Confederation segments never leave the confederation
+                _ => continue,
+            };
+            self.as_path.extend(segment);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn attribute(kind: u8, value: &[u8]) -> Vec<u8> {
+        let mut out = vec![0x40, kind, value.len() as u8];
+        out.extend(value);
+        out
+    }
+
+    fn segment(kind: u8, asns: &[u32], asn_size: usize) -> Vec<u8> {
+        let mut out = vec![kind, asns.len() as u8];
+        for asn in asns {
+            out.extend(&asn.to_be_bytes()[4 - asn_size..]);
+        }
+        out
+    }
+
+    #[test]
+    fn reads_prefixes() {
+        assert_eq!(read_prefixes(&[24, 192, 0, 2, 0]).unwrap(), [(Ipv4Addr::new(192, 0, 2, 0), 24), (Ipv4Addr::UNSPECIFIED, 0)]);
+        // Host bits are cleared
+        assert_eq!(read_prefix(&[12, 10, 255], 0).unwrap(), ((Ipv4Addr::new(10, 240, 0, 0), 12), 3));
+        assert!(read_prefix(&[33, 10, 0, 0, 0, 0], 0).is_err());
+        assert!(read_prefix(&[24, 10, 0], 0).is_err());
+    }
+
+    #[test]
+    fn merges_as4_path_of_a_two_byte_session() {
+        let mut attributes = attribute(ATTR_AS_PATH, &segment(AS_SEQUENCE, &[65001, AS_TRANS, AS_TRANS], 2));
+        attributes.extend(attribute(ATTR_NEXT_HOP, &[192, 0, 2, 254]));
+        attributes.extend(attribute(ATTR_COMMUNITIES, &[0xfd, 0xe9, 0x00, 0x64, 0xfd, 0xe9, 0x00, 0xc8]));
+        // Optional transitive, with an extended length
+        attributes.extend([0xd0, ATTR_AS4_PATH, 0x00, 0x0a]);
+        attributes.extend(segment(AS_SEQUENCE, &[4_200_000_001, 4_200_000_002], 4));
+
+        let mut body = vec![0x00, 0x02, 8, 10];
+        body.extend((attributes.len() as u16).to_be_bytes());
+        body.extend(&attributes);
+        body.extend([24, 198, 51, 100]);
+        let mut message = vec![0xff; MARKER_LEN];
+        message.extend(((MESSAGE_HEADER_LEN + body.len()) as u16).to_be_bytes());
+        message.push(MESSAGE_UPDATE);
+        message.extend(&body);
+
+        let (kind, body) = read_message(&message).unwrap();
+        assert_eq!(kind, MESSAGE_UPDATE);
+        let update = parse_update(body, false).unwrap();
+        assert_eq!(update.withdrawn, [(Ipv4Addr::new(10, 0, 0, 0), 8)]);
+        assert_eq!(update.announced, [(Ipv4Addr::new(198, 51, 100, 0), 24)]);
+        assert_eq!(update.attributes.as_path, [65001, 4_200_000_001, 4_200_000_002]);
+        assert_eq!(update.attributes.origin_as, Some(4_200_000_002));
+        assert_eq!(update.attributes.next_hop, Some(Ipv4Addr::new(192, 0, 2, 254)));
+        let route = Route {
+            peer_ip: IpAddr::V4(Ipv4Addr::new(192, 0, 2, 254)),
+            peer_as: 65001,
+            as_path: update.attributes.as_path,
+            origin_as: update.attributes.origin_as,
+            next_hop: update.attributes.next_hop,
+            communities: update.attributes.communities,
+        };
+        assert_eq!(route.as_path_string(), "65001 4200000001 4200000002");
+        assert_eq!(route.communities_string(), "65001:100,65001:200");
+
+        // A 4 byte session ignores AS4_PATH
+        let attributes = parse_path_attributes(&attribute(ATTR_AS_PATH, &segment(AS_SEQUENCE, &[65001, AS_TRANS], 4)), true).unwrap();
+        assert_eq!(attributes.as_path, [65001, AS_TRANS]);
+    }
+
+    #[test]
+    fn origin_of_an_as_set() {
+        let mut path = segment(AS_SEQUENCE, &[65001], 4);
+        path.extend(segment(AS_SET, &[65002, 65003], 4));
+        let attributes = parse_path_attributes(&attribute(ATTR_AS_PATH, &path), true).unwrap();
+        assert_eq!(attributes.as_path, [65001, 65002, 65003]);
+        assert_eq!(attributes.origin_as, None);
+
+        let path = segment(AS_SET, &[65002], 4);
+        assert_eq!(parse_path_attributes(&attribute(ATTR_AS_PATH, &path), true).unwrap().origin_as, Some(65002));
+        assert!(parse_path_attributes(&[0x40, ATTR_AS_PATH, 6, AS_SEQUENCE, 1], true).is_err());
+    }
+}
@@ -0,0 +1,269 @@
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+
+use serde::{Deserialize, Serialize};
+
+use super::bgp::Route;
+use super::mrt;
+use super::prefix_trie::PrefixTrie;
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct BgpConfig {
+    // Uncompressed MRT TABLE_DUMP_V2 RIB dumps, later files win for the same prefix
+    pub mrt_files: Vec<String>,
+    // Only use the routes of these peers (address or AS), all peers when empty
+    pub peers: Vec<String>,
+    // Files are re-read when they changed, checked this often
+    pub refresh_secs: u64,
+    // Accept BMP sessions from routers on this address, their routes win over the dumps
+    pub bmp_listen: Option<SocketAddr>,
+}
+
+impl Default for BgpConfig {
+    fn default() -> Self {
+        BgpConfig {
+            mrt_files: Vec::new(),
+            peers: Vec::new(),
+            refresh_secs: 3600,
+            bmp_listen: None,
+        }
+    }
+}
+
+// The route a flow address was matched against
+#[derive(Debug, Clone)]
+pub struct RouteMatch {
+    pub prefix: String,
+    pub route: Arc<Route>,
+}
+
+#[derive(Debug, Default)]
+struct RouteTable {
+    routes: PrefixTrie<Arc<Route>>,
+    modified: Vec<Option<SystemTime>>,
+}
+
+// A BGP session as a BMP router reports it: the BMP session, peer, route distinguisher
+// and whether the routes are post-policy. The BMP session is a number of its own, a
+// router reconnecting has two sessions for a while.
+pub type PeerKey = (u64, IpAddr, u64, bool);
+type Prefix = (Ipv4Addr, u8);
+
+// Routes learned over BMP, every peer's route to a prefix and the best of them
+#[derive(Debug, Default)]
+struct LiveRib {
+    candidates: HashMap<Prefix, Vec<(PeerKey, Arc<Route>)>>,
+    best: PrefixTrie<Arc<Route>>,
+    peer_prefixes: HashMap<PeerKey, HashSet<Prefix>>,
+}
+
+impl LiveRib {
+    fn announce(&mut self, peer: PeerKey, prefix: Prefix, route: Arc<Route>) {
+        let candidates = self.candidates.entry(prefix).or_default();
+        match candidates.iter_mut().find(|(key, _)| *key == peer) {
+            Some(candidate) => candidate.1 = route,
+            None => candidates.push((peer, route)),
+        }
+        self.peer_prefixes.entry(peer).or_default().insert(prefix);
+        self.select(prefix);
+    }
+
+    fn withdraw(&mut self, peer: PeerKey, prefix: Prefix) {
+        if let Some(candidates) = self.candidates.get_mut(&prefix) {
+            candidates.retain(|(key, _)| *key != peer);
+        }
+        if let Some(prefixes) = self.peer_prefixes.get_mut(&peer) {
+            prefixes.remove(&prefix);
+        }
+        self.select(prefix);
+    }
+
+    fn remove_peers(&mut self, matches: impl Fn(&PeerKey) -> bool) -> usize {
+        let peers: Vec<PeerKey> = self.peer_prefixes.keys().filter(|peer| matches(peer)).copied().collect();
+        let mut removed = 0;
+        for peer in peers {
+            for prefix in self.peer_prefixes.remove(&peer).unwrap_or_default() {
+                if let Some(candidates) = self.candidates.get_mut(&prefix) {
+                    candidates.retain(|(key, _)| *key != peer);
+                }
+                self.select(prefix);
+                removed += 1;
+            }
+        }
+        removed
+    }
+
+    // Shortest AS path wins, the route heard first on a tie
+    fn select(&mut self, prefix: Prefix) {
+        let best = self
+            .candidates
+            .get(&prefix)
+            .and_then(|candidates| candidates.iter().min_by_key(|(_, route)| route.as_path.len()))
+            .map(|(_, route)| route.clone());
+        match best {
+            Some(route) => {
+                self.best.insert(prefix.0, prefix.1, route);
+            }
+            None => {
+                self.candidates.remove(&prefix);
+                self.best.remove(prefix.0, prefix.1);
+            }
+        }
+    }
+}
+
+// Best route of every prefix, from BMP sessions and RIB dumps. Cloning is cheap
+// and every clone sees the routes the refresher and the BMP sessions change.
+#[derive(Clone, Debug, Default)]
+pub struct RouteLookup {
+    table: Arc<RwLock<RouteTable>>,
+    live: Arc<RwLock<LiveRib>>,
+    peers: Arc<PeerFilter>,
+}
+
+impl RouteLookup {
+    pub fn new(config: &BgpConfig) -> Self {
+        let lookup = RouteLookup {
+            peers: Arc::new(PeerFilter::parse(&config.peers)),
+            ..RouteLookup::default()
+        };
+        lookup.refresh(config);
+        lookup
+    }
+
+    // The live route in effect now, the route of the dumps when no session has one
+    pub fn lookup(&self, ip: &str) -> Option<RouteMatch> {
+        let ip: Ipv4Addr = ip.parse().ok()?;
+        let found = |(network, length, route): (Ipv4Addr, u8, &Arc<Route>)| RouteMatch {
+            prefix: format!("{}/{}", network, length),
+            route: route.clone(),
+        };
+        if let Some(live) = self.live.read().unwrap().best.longest_match(ip).map(found) {
+            return Some(live);
+        }
+        self.table.read().unwrap().routes.longest_match(ip).map(found)
+    }
+
+    pub fn len(&self) -> usize {
+        self.table.read().unwrap().routes.len() + self.live.read().unwrap().best.len()
+    }
+
+    pub fn announce(&self, peer: PeerKey, prefix: Prefix, route: Arc<Route>) {
+        if self.peers.accepts(&route) {
+            self.live.write().unwrap().announce(peer, prefix, route);
+        }
+    }
+
+    pub fn withdraw(&self, peer: PeerKey, prefix: Prefix) {
+        self.live.write().unwrap().withdraw(peer, prefix);
+    }
+
+    // Forget the routes of a peer that went down, returns how many
+    pub fn peer_down(&self, peer: PeerKey) -> usize {
+        self.live.write().unwrap().remove_peers(|key| *key == peer)
+    }
+
+    // Forget every route a BMP session reported when it ends
+    pub fn session_down(&self, session: u64) -> usize {
+        self.live.write().unwrap().remove_peers(|key| key.0 == session)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // Rebuild the table when one of the dumps changed since the last load
+    pub fn refresh(&self, config: &BgpConfig) {
+        let modified: Vec<Option<SystemTime>> = config
+            .mrt_files
+            .iter()
+            .map(|file| fs::metadata(file).and_then(|m| m.modified()).ok())
+            .collect();
+        if self.table.read().unwrap().modified == modified {
+            return;
+        }
+
+        let mut routes = PrefixTrie::new();
+        for file in &config.mrt_files {
+            let result = mrt::read_rib(file, |network, length, candidates| {
+                if let Some(best) = best_route(candidates, &self.peers) {
+                    routes.insert(network, length, Arc::new(best));
+                }
+            });
+            match result {
+                Ok(stats) => println!(
+                    "Loaded {} routes to {} prefixes from {} ({} records skipped, {} bad entries)",
+                    stats.routes, stats.prefixes, file, stats.skipped_records, stats.bad_entries
+                ),
+                // Keep what we have rather than enrich without routes
+                Err(e) => {
+                    println!("Can't load {}: {}", file, e);
+                    return;
+                }
+            }
+        }
+        if !config.mrt_files.is_empty() {
+            println!("Route table has {} prefixes", routes.len());
+        }
+        *self.table.write().unwrap() = RouteTable { routes, modified };
+    }
+}
+
+// Peers whose routes are used
+#[derive(Debug, Default)]
+struct PeerFilter {
+    ips: Vec<IpAddr>,
+    asns: Vec<u32>,
+}
+
+impl PeerFilter {
+    fn parse(peers: &[String]) -> Self {
+        let mut filter = PeerFilter::default();
+        for peer in peers {
+            let peer = peer.trim().trim_start_matches("AS");
+            if let Ok(ip) = peer.parse() {
+                filter.ips.push(ip);
+            } else if let Ok(asn) = peer.parse() {
+                filter.asns.push(asn);
+            } else {
+                println!("Ignoring BGP peer {}, expected an address or an AS number", peer);
+            }
+        }
+        filter
+    }
+
+    fn accepts(&self, route: &Route) -> bool {
+        (self.ips.is_empty() && self.asns.is_empty())
+            || self.ips.contains(&route.peer_ip)
+            || self.asns.contains(&route.peer_as)
+    }
+}
+
+// Shortest AS path of the accepted peers, the first peer of the dump on a tie
+fn best_route(candidates: Vec<Route>, peers: &PeerFilter) -> Option<Route> {
+    candidates
+        .into_iter()
+        .filter(|route| peers.accepts(route))
+        .min_by_key(|route| route.as_path.len())
+}
+
+// Re-read changed RIB dumps every `refresh_secs`
+pub async fn run_refresher(routes: RouteLookup, config: BgpConfig) {
+    let mut ticker = tokio::time::interval(Duration::from_secs(config.refresh_secs.max(1)));
+    // The dumps were loaded when the lookup was created
+    ticker.tick().await;
+
+    loop {
+        ticker.tick().await;
+        let routes = routes.clone();
+        let config = config.clone();
+        // A full table takes a while to parse, keep that off the enrichment workers
+        if let Err(e) = tokio::task::spawn_blocking(move || routes.refresh(&config)).await {
+            println!("Route table refresh failed: {}", e);
+        }
+    }
+}
@@ -0,0 +1,314 @@
+// BGP Monitoring Protocol (RFC 7854) listener. Routers stream the UPDATEs of their
+// BGP sessions to us and the routes go straight into the live RIB of the lookup.
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Arc;
+
+use tokio::io::{AsyncReadExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+
+use super::bgp::{self, read_u32, Route, MESSAGE_UPDATE};
+use super::bgp_lookup::{PeerKey, RouteLookup};
+use crate::process::listener::SourceFilter;
+
+const VERSION: u8 = 3;
+const COMMON_HEADER_LEN: usize = 6;
+const PEER_HEADER_LEN: usize = 42;
+// Nobody sends more than a BGP message with some headers, don't allocate whatever the length says
+const MAX_MESSAGE_LEN: usize = 1 << 20;
+
+const ROUTE_MONITORING: u8 = 0;
+const PEER_DOWN: u8 = 2;
+const PEER_UP: u8 = 3;
+const INITIATION: u8 = 4;
+const TERMINATION: u8 = 5;
+
+const PEER_FLAG_IPV6: u8 = 0x80;
+const PEER_FLAG_POST_POLICY: u8 = 0x40;
+const PEER_FLAG_AS2: u8 = 0x20;
+
+// The per-peer header in front of route monitoring and peer messages
+#[derive(Debug, Clone, Copy)]
+struct PeerHeader {
+    key: PeerKey,
+    asn: u32,
+    four_byte_asn: bool,
+}
+
+// Accept BMP sessions from the routers `sources` allows and keep their routes in
+// `routes` until the session or its peer goes away
+pub async fn run_listener(routes: RouteLookup, listen: SocketAddr, sources: Arc<SourceFilter>) {
+    let listener = match TcpListener::bind(listen).await {
+        Ok(listener) => listener,
+        Err(e) => {
+            println!("Can't listen for BMP on {}: {}", listen, e);
+            return;
+        }
+    };
+    println!("Listening for BMP on {}", listen);
+    accept_sessions(listener, routes, sources).await;
+}
+
+async fn accept_sessions(listener: TcpListener, routes: RouteLookup, sources: Arc<SourceFilter>) {
+    let mut next_session = 0u64;
+    loop {
+        let (stream, address) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                println!("BMP accept failed: {}", e);
+                continue;
+            }
+        };
+        let router = address.ip();
+        if !sources.accepts(router) {
+            println!("Refusing BMP session from {}, not an allowed source", router);
+            continue;
+        }
+        next_session += 1;
+        let session = next_session;
+        let routes = routes.clone();
+        tokio::spawn(async move {
+            println!("BMP session from {}", router);
+            if let Err(e) = run_session(stream, router, session, &routes).await {
+                println!("BMP session from {} failed: {}", router, e);
+            }
+            // Without the session we no longer hear about changes, its routes would go stale.
+            // A newer session of the same router keeps its own.
+            let removed = routes.session_down(session);
+            println!("BMP session from {} closed, dropped {} routes", router, removed);
+        });
+    }
+}
+
+async fn run_session(stream: TcpStream, router: IpAddr, session: u64, routes: &RouteLookup) -> Result<(), String> {
+    let mut reader = BufReader::new(stream);
+    let mut header = [0u8; COMMON_HEADER_LEN];
+    let mut body = Vec::new();
+
+    loop {
+        match reader.read_exact(&mut header).await {
+            Ok(_) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(()),
+            Err(e) => return Err(e.to_string()),
+        }
+        if header[0] != VERSION {
+            return Err(format!("unsupported BMP version {}", header[0]));
+        }
+        let length = u32::from_be_bytes([header[1], header[2], header[3], header[4]]) as usize;
+        if !(COMMON_HEADER_LEN..=MAX_MESSAGE_LEN).contains(&length) {
+            return Err(format!("invalid BMP message length {}", length));
+        }
+        body.resize(length - COMMON_HEADER_LEN, 0);
+        reader.read_exact(&mut body).await.map_err(|e| e.to_string())?;
+
+        match header[5] {
+            ROUTE_MONITORING => {
+                // One bad UPDATE shouldn't cost us the whole session
+                if let Err(e) = route_monitoring(&body, session, routes) {
+                    println!("Skipping BMP route monitoring from {}: {}", router, e);
+                }
+            }
+            PEER_UP => {
+                let peer = parse_peer_header(&body, session)?;
+                println!("BGP peer {} AS{} of {} is up", peer.key.1, peer.asn, router);
+            }
+            PEER_DOWN => {
+                let peer = parse_peer_header(&body, session)?;
+                let removed = routes.peer_down(peer.key);
+                println!("BGP peer {} AS{} of {} is down, dropped {} routes", peer.key.1, peer.asn, router, removed);
+            }
+            INITIATION => {}
+            TERMINATION => return Ok(()),
+            // Statistics reports and route mirroring
+            _ => {}
+        }
+    }
+}
+
+fn route_monitoring(body: &[u8], session: u64, routes: &RouteLookup) -> Result<(), String> {
+    let peer = parse_peer_header(body, session)?;
+    let (kind, message) = bgp::read_message(&body[PEER_HEADER_LEN..])?;
+    if kind != MESSAGE_UPDATE {
+        return Ok(());
+    }
+    let update = bgp::parse_update(message, peer.four_byte_asn)?;
+
+    for prefix in update.withdrawn {
+        routes.withdraw(peer.key, prefix);
+    }
+    if update.announced.is_empty() {
+        return Ok(());
+    }
+    let route = Arc::new(Route {
+        peer_ip: peer.key.1,
+        peer_as: peer.asn,
+        as_path: update.attributes.as_path,
+        origin_as: update.attributes.origin_as,
+        next_hop: update.attributes.next_hop,
+        communities: update.attributes.communities,
+    });
+    for prefix in update.announced {
+        routes.announce(peer.key, prefix, route.clone());
+    }
+    Ok(())
+}
+
+// Peer type, flags, route distinguisher, address, AS, BGP ID and timestamp
+fn parse_peer_header(body: &[u8], session: u64) -> Result<PeerHeader, String> {
+    let header = body.get(..PEER_HEADER_LEN).ok_or("truncated BMP per-peer header")?;
+    let flags = header[1];
+    let distinguisher = u64::from_be_bytes(header[2..10].try_into().unwrap());
+    let address: [u8; 16] = header[10..26].try_into().unwrap();
+    let peer_ip = if flags & PEER_FLAG_IPV6 != 0 {
+        IpAddr::V6(Ipv6Addr::from(address))
+    } else {
+        IpAddr::V4(Ipv4Addr::new(address[12], address[13], address[14], address[15]))
+    };
+    Ok(PeerHeader {
+        key: (session, peer_ip, distinguisher, flags & PEER_FLAG_POST_POLICY != 0),
+        asn: read_u32(header, 26)?,
+        four_byte_asn: flags & PEER_FLAG_AS2 == 0,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    use tokio::io::AsyncWriteExt;
+
+    use crate::process::listener::ListenerConfig;
+
+    fn message(kind: u8, body: &[u8]) -> Vec<u8> {
+        let mut out = vec![VERSION];
+        out.extend(((COMMON_HEADER_LEN + body.len()) as u32).to_be_bytes());
+        out.push(kind);
+        out.extend(body);
+        out
+    }
+
+    fn peer_header(flags: u8, peer: IpAddr, asn: u32) -> Vec<u8> {
+        let mut out = vec![0, flags];
+        out.extend(7u64.to_be_bytes());
+        out.extend(match peer {
+            IpAddr::V4(ip) => ip.to_ipv6_compatible().octets(),
+            IpAddr::V6(ip) => ip.octets(),
+        });
+        out.extend(asn.to_be_bytes());
+        out.extend([10, 0, 0, 1]);
+        out.extend([0; 8]);
+        out
+    }
+
+    // Route monitoring of a 4 byte session announcing `prefix` with the path `as_path`
+    fn announce(peer: IpAddr, prefix: (Ipv4Addr, u8), as_path: &[u32]) -> Vec<u8> {
+        let mut attributes = vec![0x40, 2, 2 + 4 * as_path.len() as u8, 2, as_path.len() as u8];
+        for asn in as_path {
+            attributes.extend(asn.to_be_bytes());
+        }
+        let mut update = vec![0, 0];
+        update.extend((attributes.len() as u16).to_be_bytes());
+        update.extend(attributes);
+        update.push(prefix.1);
+        update.extend(&prefix.0.octets()[..(prefix.1 as usize).div_ceil(8)]);
+
+        let mut body = peer_header(0, peer, as_path[0]);
+        body.extend([0xff; 16]);
+        body.extend((19 + update.len() as u16).to_be_bytes());
+        body.push(MESSAGE_UPDATE);
+        body.extend(update);
+        message(ROUTE_MONITORING, &body)
+    }
+
+    #[test]
+    fn parses_peer_headers() {
+        let peer = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+        let header = parse_peer_header(&peer_header(PEER_FLAG_POST_POLICY | PEER_FLAG_AS2, peer, 65001), 3).unwrap();
+        assert_eq!(header.key, (3, peer, 7, true));
+        assert_eq!(header.asn, 65001);
+        assert!(!header.four_byte_asn);
+
+        let peer: IpAddr = "2001:db8::1".parse().unwrap();
+        let header = parse_peer_header(&peer_header(PEER_FLAG_IPV6, peer, 4_200_000_001), 3).unwrap();
+        assert_eq!(header.key, (3, peer, 7, false));
+        assert!(header.four_byte_asn);
+        assert!(parse_peer_header(&[0; PEER_HEADER_LEN - 1], 3).is_err());
+    }
+
+    #[test]
+    fn route_monitoring_announces_and_withdraws() {
+        let routes = RouteLookup::default();
+        let peer = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+        let prefix = (Ipv4Addr::new(198, 51, 100, 0), 24);
+        let announcement = announce(peer, prefix, &[65001, 65002]);
+        route_monitoring(&announcement[COMMON_HEADER_LEN..], 1, &routes).unwrap();
+        let found = routes.lookup("198.51.100.7").unwrap();
+        assert_eq!(found.prefix, "198.51.100.0/24");
+        assert_eq!(found.route.peer_ip, peer);
+        assert_eq!(found.route.as_path, [65001, 65002]);
+
+        // The same UPDATE withdrawing the prefix instead
+        let mut withdrawal = peer_header(0, peer, 65001);
+        withdrawal.extend([0xff; 16]);
+        withdrawal.extend([0, 27, MESSAGE_UPDATE, 0, 4, 24, 198, 51, 100, 0, 0]);
+        route_monitoring(&withdrawal, 1, &routes).unwrap();
+        assert!(routes.lookup("198.51.100.7").is_none());
+    }
+
+    async fn wait_for(condition: impl Fn() -> bool) -> bool {
+        for _ in 0..200 {
+            if condition() {
+                return true;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        false
+    }
+
+    // Scripted routers over TCP on localhost
+    #[tokio::test]
+    #[ignore]
+    async fn sessions_keep_their_own_routes() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let routes = RouteLookup::default();
+        tokio::spawn(accept_sessions(listener, routes.clone(), Arc::new(SourceFilter::default())));
+
+        let peer = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+        let mut old = TcpStream::connect(address).await.unwrap();
+        old.write_all(&message(INITIATION, &[])).await.unwrap();
+        old.write_all(&message(PEER_UP, &peer_header(0, peer, 65001))).await.unwrap();
+        old.write_all(&announce(peer, (Ipv4Addr::new(198, 51, 100, 0), 24), &[65001])).await.unwrap();
+        assert!(wait_for(|| routes.lookup("198.51.100.1").is_some()).await);
+
+        // The router reconnects before the old session is noticed to be gone
+        let mut new = TcpStream::connect(address).await.unwrap();
+        new.write_all(&announce(peer, (Ipv4Addr::new(203, 0, 113, 0), 24), &[65001])).await.unwrap();
+        assert!(wait_for(|| routes.lookup("203.0.113.1").is_some()).await);
+        drop(old);
+        assert!(wait_for(|| routes.lookup("198.51.100.1").is_none()).await);
+        assert!(routes.lookup("203.0.113.1").is_some());
+
+        new.write_all(&message(TERMINATION, &[])).await.unwrap();
+        assert!(wait_for(|| routes.is_empty()).await);
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn refuses_sources_not_allowed() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let config = ListenerConfig { allow: vec!["192.0.2.0/24".to_string()], ..ListenerConfig::default() };
+        let routes = RouteLookup::default();
+        tokio::spawn(accept_sessions(listener, routes.clone(), Arc::new(SourceFilter::new(&config).unwrap())));
+
+        let mut stream = TcpStream::connect(address).await.unwrap();
+        let peer = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+        let _ = stream.write_all(&announce(peer, (Ipv4Addr::new(198, 51, 100, 0), 24), &[65001])).await;
+        // Closed without reading
+        let mut buf = [0u8; 1];
+        assert_eq!(stream.read(&mut buf).await.unwrap_or(0), 0);
+        assert!(routes.is_empty());
+    }
+}
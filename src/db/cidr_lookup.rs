@@ -1,27 +1,38 @@
-#![allow(unused_imports)]
-use std::collections::HashMap;
 use std::fs::File;
-use std::hash::Hash;
 use std::io::{self, BufRead};
 use std::net::Ipv4Addr;
 use std::path::Path;
-use cidr::Ipv4Cidr;
 
-use clap::builder::Str;
+// An IP range from one of the tables, start and end are inclusive
+#[derive(Debug, Clone)]
+pub struct IpRange<T> {
+    pub start: Ipv4Addr,
+    pub end: Ipv4Addr,
+    pub value: T,
+}
+
+// How many lines of a table were usable
+#[derive(Debug, Clone, Default)]
+pub struct LoadStats {
+    pub loaded: usize,
+    pub skipped: usize,
+}
 
 #[derive(Debug, Clone)]
 pub struct CidrLookup {
-    // country code to country name
-    country_map: HashMap<String, String>,
-    // AS number to AS name
-    as_map: HashMap<String, (String, String)>,
+    // IP range to country code, sorted by range start
+    country_map: Vec<IpRange<String>>,
+    // IP range to (AS number, AS name), sorted by range start
+    as_map: Vec<IpRange<(String, String)>>,
+    country_stats: LoadStats,
+    as_stats: LoadStats,
 }
 
 impl CidrLookup {
     pub fn new(country_file: &str, as_file: &str) -> Self {
-        let country_map = Self::load_country_cidr_map(country_file);
-        let as_map = Self::load_as_cidr_map(as_file);
-        CidrLookup { country_map, as_map }
+        let (country_map, country_stats) = Self::load_country_cidr_map(country_file);
+        let (as_map, as_stats) = Self::load_as_cidr_map(as_file);
+        CidrLookup { country_map, as_map, country_stats, as_stats }
     }
 
     fn read_lines<P>(filename: P) -> io::Result<io::Lines<io::BufReader<File>>>
@@ -32,82 +43,125 @@ impl CidrLookup {
         Ok(io::BufReader::new(file).lines())
     }
 
-    // Load a CIDR map for countries from a TSV file
-    fn load_country_cidr_map(file: &str) -> HashMap<String, String> {
-        let mut map = HashMap::new();
-        if let Ok(lines) = Self::read_lines(file) {
-            for line in lines {
-                if let Ok(line) = line {
+    // Load a range map from a TSV file whose first two columns are the range start and end
+    fn load_range_map<T, F>(file: &str, min_columns: usize, value: F) -> (Vec<IpRange<T>>, LoadStats)
+    where
+        F: Fn(&[&str]) -> T,
+    {
+        let mut map = Vec::new();
+        let mut stats = LoadStats::default();
+        match Self::read_lines(file) {
+            Ok(lines) => {
+                for line in lines.map_while(Result::ok) {
                     let parts: Vec<&str> = line.split('\t').collect(); // Use '\t' for tab-separated files
-                    if parts.len() >= 3 {
-                        let ip_range = format!("{}-{}", parts[0], parts[1]);
-                        let value = parts[2].to_string();
-                        map.insert(ip_range, value);
+                    if parts.len() < min_columns {
+                        stats.skipped += 1;
+                        continue;
+                    }
+                    match (parts[0].trim().parse(), parts[1].trim().parse()) {
+                        (Ok(start), Ok(end)) if start <= end => {
+                            map.push(IpRange { start, end, value: value(&parts) });
+                            stats.loaded += 1;
+                        }
+                        _ => stats.skipped += 1,
                     }
                 }
             }
+            Err(e) => println!("Can't read {}: {}", file, e),
         }
-        map
+        map.sort_by_key(|range| range.start);
+        (map, stats)
+    }
+
+    // Load a CIDR map for countries from a TSV file
+    fn load_country_cidr_map(file: &str) -> (Vec<IpRange<String>>, LoadStats) {
+        Self::load_range_map(file, 3, |parts| parts[2].to_string())
     }
 
     // Load a CIDR map for AS numbers from a TSV file
-    fn load_as_cidr_map(file: &str) -> HashMap<String, (String, String)> {
-        let mut map = HashMap::new();
-        if let Ok(lines) = Self::read_lines(file) {
-            for line in lines {
-                if let Ok(line) = line {
-                    let parts: Vec<&str> = line.split('\t').collect(); // Use '\t' for tab-separated files
-                    if parts.len() >= 5 {
-                        let ip_range = format!("{}-{}", parts[0], parts[1]);
-                        let asn = parts[2].to_string();
-                        let as_name = parts[4].to_string();
-                        map.insert(ip_range, (asn, as_name));
-                    }
-                }
-            }
-        }
-        map
+    fn load_as_cidr_map(file: &str) -> (Vec<IpRange<(String, String)>>, LoadStats) {
+        Self::load_range_map(file, 5, |parts| (parts[2].to_string(), parts[4].to_string()))
     }
 
     // Lookup the country for an IP address
     pub fn lookup_country(&self, ip: &str) -> Option<&String> {
-        self.lookup_country_map(&self.country_map, ip)
+        self.lookup_country_range(ip).map(|range| &range.value)
     }
 
     // Lookup the AS for an IP address
     pub fn lookup_as(&self, ip: &str) -> Option<&(String, String)> {
-        self.lookup_as_map(&self.as_map, ip)
-    }
-
-    // Lookup a value in a country CIDR map
-    fn lookup_country_map<'a>(&'a self, map: &'a HashMap<String, String>, ip: &str) -> Option<&String> {
-        let ip_addr: Ipv4Addr = ip.parse().unwrap();
-        for (ip_range, value) in map.iter() {
-            let range_parts: Vec<&str> = ip_range.split('-').collect();
-            if range_parts.len() == 2 {
-                let start_ip: Ipv4Addr = range_parts[0].parse().unwrap();
-                let end_ip: Ipv4Addr = range_parts[1].parse().unwrap();
-                if ip_addr >= start_ip && ip_addr <= end_ip {
-                    return Some(value);
-                }
-            }
-        }
-        None
-    }
-
-    // Lookup a value in an AS CIDR map
-    fn lookup_as_map<'a>(&'a self, map: &'a HashMap<String, (String, String)>, ip: &str) -> Option<&(String, String)> {
-        let ip_addr: Ipv4Addr = ip.parse().unwrap();
-        for (ip_range, value) in map.iter() {
-            let range_parts: Vec<&str> = ip_range.split('-').collect();
-            if range_parts.len() == 2 {
-                let start_ip: Ipv4Addr = range_parts[0].parse().unwrap();
-                let end_ip: Ipv4Addr = range_parts[1].parse().unwrap();
-                if ip_addr >= start_ip && ip_addr <= end_ip {
-                    return Some(value);
-                }
-            }
-        }
-        None
+        self.lookup_as_range(ip).map(|range| &range.value)
     }
-}
\ No newline at end of file
+
+    // Lookup the country range an IP address falls into
+    pub fn lookup_country_range(&self, ip: &str) -> Option<&IpRange<String>> {
+        Self::lookup_range(&self.country_map, ip.parse().ok()?)
+    }
+
+    // Lookup the AS range an IP address falls into
+    pub fn lookup_as_range(&self, ip: &str) -> Option<&IpRange<(String, String)>> {
+        Self::lookup_range(&self.as_map, ip.parse().ok()?)
+    }
+
+    pub fn country_stats(&self) -> &LoadStats {
+        &self.country_stats
+    }
+
+    pub fn as_stats(&self) -> &LoadStats {
+        &self.as_stats
+    }
+
+    // Binary search for the last range starting at or before the address
+    fn lookup_range<T>(map: &[IpRange<T>], ip: Ipv4Addr) -> Option<&IpRange<T>> {
+        let index = map.partition_point(|range| range.start <= ip);
+        let range = map.get(index.checked_sub(1)?)?;
+        (ip <= range.end).then_some(range)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn range(start: &str, end: &str, value: &str) -> IpRange<String> {
+        IpRange { start: start.parse().unwrap(), end: end.parse().unwrap(), value: value.to_string() }
+    }
+
+    fn lookup<'a>(map: &'a [IpRange<String>], ip: &str) -> Option<&'a str> {
+        CidrLookup::lookup_range(map, ip.parse().unwrap()).map(|range| range.value.as_str())
+    }
+
+    #[test]
+    fn finds_the_range_of_an_address() {
+        let map = [
+            range("1.0.0.0", "1.0.0.255", "AU"),
+            // Adjacent to the first
+            range("1.0.1.0", "1.0.3.255", "CN"),
+            range("2.0.0.0", "2.0.0.0", "FR"),
+            range("255.255.255.0", "255.255.255.255", "ZZ"),
+        ];
+        assert_eq!(lookup(&map, "0.255.255.255"), None);
+        assert_eq!(lookup(&map, "1.0.0.0"), Some("AU"));
+        assert_eq!(lookup(&map, "1.0.0.255"), Some("AU"));
+        assert_eq!(lookup(&map, "1.0.1.0"), Some("CN"));
+        assert_eq!(lookup(&map, "1.0.3.255"), Some("CN"));
+        // Between ranges
+        assert_eq!(lookup(&map, "1.0.4.0"), None);
+        assert_eq!(lookup(&map, "1.255.255.255"), None);
+        assert_eq!(lookup(&map, "2.0.0.0"), Some("FR"));
+        assert_eq!(lookup(&map, "2.0.0.1"), None);
+        assert_eq!(lookup(&map, "255.255.255.255"), Some("ZZ"));
+        assert_eq!(lookup(&[], "1.0.0.0"), None);
+    }
+
+    #[test]
+    fn loads_sorted_ranges_and_counts_bad_lines() {
+        let path = std::env::temp_dir().join(format!("cidr-lookup-{}.tsv", std::process::id()));
+        std::fs::write(&path, "1.0.1.0\t1.0.3.255\tCN\n1.0.0.0\t1.0.0.255\tAU\n1.0.9.0\t1.0.8.0\tXX\nnot\tan\taddress\n1.0.4.0\t1.0.4.255\n").unwrap();
+        let (map, stats) = CidrLookup::load_country_cidr_map(path.to_str().unwrap());
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!((stats.loaded, stats.skipped), (2, 3));
+        assert_eq!(map[0].value, "AU");
+        assert_eq!(lookup(&map, "1.0.2.0"), Some("CN"));
+    }
+}
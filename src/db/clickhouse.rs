@@ -0,0 +1,312 @@
+// Raw flow retention in ClickHouse. Batches of enriched flows are inserted over the
+// HTTP interface in RowBinary, into the table of scripts/clickhouse/flows.sql.
+
+use std::net::{IpAddr, Ipv6Addr};
+use std::time::Duration;
+
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+
+use crate::process::flow::EnrichedFlow;
+use crate::transport::{consume_windows, Done, SharedTransport, Windowed, ENRICHER_TO_TSDB};
+
+pub const CLICKHOUSE_GROUP: &str = "ta-clickhouse";
+const TABLE_DDL: &str = include_str!("../../scripts/clickhouse/flows.sql");
+// Columns we insert, in RowBinary order. The materialized ones are computed by ClickHouse.
+const COLUMNS: &str = "time, exporter, src_ip, dst_ip, src_port, dst_port, protocol, tcp_flags, packets, bytes, \
+    first_switched, last_switched, input_if, output_if, tags";
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct ClickHouseConfig {
+    // HTTP interface, port 8123 by default
+    pub url: String,
+    pub database: String,
+    pub table: String,
+    pub user: String,
+    pub password: String,
+    // Rows are inserted when the batch is full or `flush_ms` passed. ClickHouse wants
+    // few large inserts rather than many small ones.
+    pub batch_size: usize,
+    pub flush_ms: u64,
+    // Failed inserts are retried with a doubling delay, the batch is dropped after `max_retries`
+    pub max_retries: u32,
+    pub retry_backoff_ms: u64,
+    pub max_backoff_ms: u64,
+    pub timeout_secs: u64,
+    // Run the DDL of scripts/clickhouse/flows.sql at startup
+    pub create_table: bool,
+    // Topic of the flows to insert, a route's topic to keep only some
+    pub topic: String,
+}
+
+impl Default for ClickHouseConfig {
+    fn default() -> Self {
+        ClickHouseConfig {
+            url: "http://localhost:8123".to_string(),
+            database: "ta".to_string(),
+            table: "flows".to_string(),
+            user: "default".to_string(),
+            password: String::new(),
+            batch_size: 10000,
+            flush_ms: 5000,
+            max_retries: 10,
+            retry_backoff_ms: 500,
+            max_backoff_ms: 30000,
+            timeout_secs: 30,
+            create_table: false,
+            topic: ENRICHER_TO_TSDB.to_string(),
+        }
+    }
+}
+
+// The statements of the bundled DDL for the configured database and table
+pub fn table_ddl(config: &ClickHouseConfig) -> Vec<String> {
+    let sql: String = TABLE_DDL
+        .lines()
+        .filter(|line| !line.trim_start().starts_with("--"))
+        .collect::<Vec<_>>()
+        .join("\n");
+    sql.split(';')
+        .map(|statement| statement.trim())
+        .filter(|statement| !statement.is_empty())
+        .map(|statement| match statement.strip_prefix("CREATE DATABASE IF NOT EXISTS ") {
+            Some(_) => format!("CREATE DATABASE IF NOT EXISTS {}", config.database),
+            None => statement.replacen("ta.flows", &format!("{}.{}", config.database, config.table), 1),
+        })
+        .collect()
+}
+
+// Append one flow in RowBinary, in the order of COLUMNS
+pub fn write_row(out: &mut Vec<u8>, flow: &EnrichedFlow) {
+    let fields = &flow.fields;
+    out.extend_from_slice(&flow.time.timestamp_millis().to_le_bytes());
+    put_string(out, flow.tag("exporter").unwrap_or(""));
+    out.extend_from_slice(&ipv6_octets(flow.tag("src_ip")));
+    out.extend_from_slice(&ipv6_octets(flow.tag("dst_ip")));
+    out.extend_from_slice(&fields.src_port.to_le_bytes());
+    out.extend_from_slice(&fields.dst_port.to_le_bytes());
+    out.push(fields.protocol);
+    out.push(fields.tcp_flags);
+    out.extend_from_slice(&fields.packets.to_le_bytes());
+    out.extend_from_slice(&fields.bytes.to_le_bytes());
+    out.extend_from_slice(&fields.first_switched.to_le_bytes());
+    out.extend_from_slice(&fields.last_switched.to_le_bytes());
+    out.extend_from_slice(&fields.input_if.to_le_bytes());
+    out.extend_from_slice(&fields.output_if.to_le_bytes());
+    put_varint(out, flow.tags.len() as u64);
+    for (name, value) in &flow.tags {
+        put_string(out, name);
+        put_string(out, value);
+    }
+}
+
+fn ipv6_octets(ip: Option<&str>) -> [u8; 16] {
+    match ip.and_then(|ip| ip.parse::<IpAddr>().ok()) {
+        Some(IpAddr::V4(ip)) => ip.to_ipv6_mapped().octets(),
+        Some(IpAddr::V6(ip)) => ip.octets(),
+        None => Ipv6Addr::UNSPECIFIED.octets(),
+    }
+}
+
+fn put_varint(out: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        out.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+fn put_string(out: &mut Vec<u8>, value: &str) {
+    put_varint(out, value.len() as u64);
+    out.extend_from_slice(value.as_bytes());
+}
+
+struct Client {
+    http: reqwest::Client,
+    config: ClickHouseConfig,
+}
+
+impl Client {
+    async fn execute(&self, query: &str, body: Vec<u8>) -> Result<(), String> {
+        let response = self
+            .http
+            .post(&self.config.url)
+            .query(&[("query", query)])
+            .header("X-ClickHouse-User", &self.config.user)
+            .header("X-ClickHouse-Key", &self.config.password)
+            .body(body)
+            .send()
+            .await
+            .map_err(|e| e.to_string())?;
+        if response.status().is_success() {
+            return Ok(());
+        }
+        let status = response.status();
+        let text = response.text().await.unwrap_or_default();
+        Err(format!("{}: {}", status, text.trim()))
+    }
+
+    // Insert with retries, the batch is given up after `max_retries`
+    async fn insert(&self, rows: usize, body: Vec<u8>) {
+        let query = format!("INSERT INTO {}.{} ({}) FORMAT RowBinary", self.config.database, self.config.table, COLUMNS);
+        let mut backoff = Duration::from_millis(self.config.retry_backoff_ms.max(1));
+        let max_backoff = Duration::from_millis(self.config.max_backoff_ms.max(1));
+        for attempt in 0..=self.config.max_retries {
+            match self.execute(&query, body.clone()).await {
+                Ok(()) => return,
+                Err(e) if attempt < self.config.max_retries => {
+                    println!("Error inserting {} rows into ClickHouse, retrying in {:?}: {}", rows, backoff, e);
+                    tokio::time::sleep(backoff).await;
+                    backoff = (backoff * 2).min(max_backoff);
+                }
+                Err(e) => println!("Dropping {} rows after {} failed ClickHouse inserts: {}", rows, attempt + 1, e),
+            }
+        }
+    }
+}
+
+// Insert every enriched flow into ClickHouse
+pub async fn run_writer(transport: SharedTransport, config: ClickHouseConfig) {
+    let http = match reqwest::Client::builder().timeout(Duration::from_secs(config.timeout_secs.max(1))).build() {
+        Ok(http) => http,
+        Err(e) => {
+            println!("ClickHouse writer disabled: {}", e);
+            return;
+        }
+    };
+    // Subscribe before the DDL round trips, a late group of the channel transport misses earlier records
+    let mut subscription = transport
+        .subscribe(&config.topic, CLICKHOUSE_GROUP)
+        .await
+        .expect("Can't subscribe to specified topic");
+    let client = Client { http, config: config.clone() };
+    if config.create_table {
+        for statement in table_ddl(&config) {
+            if let Err(e) = client.execute(&statement, Vec::new()).await {
+                println!("Can't create the ClickHouse table: {}", e);
+            }
+        }
+    }
+
+    let mut inserting = Inserting { client, batch: Vec::new(), rows: 0 };
+    consume_windows(subscription.as_mut(), Duration::from_millis(config.flush_ms.max(1)), &mut inserting).await;
+}
+
+// Rows in RowBinary waiting for the next insert
+struct Inserting {
+    client: Client,
+    batch: Vec<u8>,
+    rows: usize,
+}
+
+#[async_trait]
+impl Windowed for Inserting {
+    type Item = EnrichedFlow;
+
+    fn observe(&mut self, flow: EnrichedFlow, _offset: i64) {
+        write_row(&mut self.batch, &flow);
+        self.rows += 1;
+    }
+
+    fn full(&self) -> bool {
+        self.rows >= self.client.config.batch_size
+    }
+
+    async fn flush(&mut self) -> Done {
+        if self.rows > 0 {
+            self.client.insert(self.rows, std::mem::take(&mut self.batch)).await;
+            self.rows = 0;
+        }
+        // A dropped batch is committed too, or every restart would try it again
+        Done::All
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{TimeZone, Utc};
+
+    use crate::process::flow::FlowRecord;
+
+    fn flow() -> EnrichedFlow {
+        let record = FlowRecord {
+            src_ip: "192.0.2.1".to_string(),
+            dst_ip: "2001:db8::1".to_string(),
+            src_port: 443,
+            dst_port: 50000,
+            protocol: 6,
+            tcp_flags: 0x12,
+            packets: 3,
+            bytes: 300,
+            first_switched: 1000,
+            last_switched: 2000,
+            input_if: 1,
+            output_if: 2,
+        };
+        let mut flow = EnrichedFlow::new(&record, Utc.timestamp_millis_opt(1_700_000_000_123).unwrap());
+        flow.set_tag("exporter", "192.0.2.9");
+        flow
+    }
+
+    #[test]
+    fn writes_row_binary() {
+        let mut row = Vec::new();
+        write_row(&mut row, &flow());
+
+        let mut expected = 1_700_000_000_123i64.to_le_bytes().to_vec();
+        expected.extend(b"\x09192.0.2.9");
+        expected.extend([0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0xff, 0xff, 192, 0, 2, 1]);
+        expected.extend([0x20, 0x01, 0x0d, 0xb8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1]);
+        expected.extend([0xbb, 0x01, 0x50, 0xc3, 6, 0x12]);
+        expected.extend(3u64.to_le_bytes());
+        expected.extend(300u64.to_le_bytes());
+        expected.extend(1000u64.to_le_bytes());
+        expected.extend(2000u64.to_le_bytes());
+        expected.extend([1, 0, 0, 0, 2, 0, 0, 0]);
+        // The tags as a Map, sorted by name
+        expected.push(3);
+        expected.extend(b"\x06dst_ip\x0b2001:db8::1\x08exporter\x09192.0.2.9\x06src_ip\x09192.0.2.1");
+        assert_eq!(row, expected);
+    }
+
+    #[test]
+    fn ddl_uses_the_configured_table() {
+        let config = ClickHouseConfig { database: "netflow".to_string(), table: "raw".to_string(), ..ClickHouseConfig::default() };
+        let ddl = table_ddl(&config);
+        assert_eq!(ddl.len(), 2);
+        assert_eq!(ddl[0], "CREATE DATABASE IF NOT EXISTS netflow");
+        assert!(ddl[1].starts_with("CREATE TABLE IF NOT EXISTS netflow.raw"));
+    }
+
+    // Needs the clickhouse service of docker-compose.yml, or CLICKHOUSE_URL
+    #[tokio::test]
+    #[ignore]
+    async fn inserts_into_clickhouse() {
+        let config = ClickHouseConfig {
+            url: std::env::var("CLICKHOUSE_URL").unwrap_or_else(|_| "http://localhost:8123".to_string()),
+            database: "ta_test".to_string(),
+            table: format!("flows_{}", std::process::id()),
+            max_retries: 0,
+            ..ClickHouseConfig::default()
+        };
+        let client = Client { http: reqwest::Client::new(), config: config.clone() };
+        for statement in table_ddl(&config) {
+            client.execute(&statement, Vec::new()).await.unwrap();
+        }
+        let mut flow = flow();
+        flow.set_tag("src_country", "NL");
+        let mut body = Vec::new();
+        write_row(&mut body, &flow);
+        write_row(&mut body, &flow);
+        client.insert(2, body).await;
+
+        let table = format!("{}.{}", config.database, config.table);
+        let query = format!("SELECT count(), sum(bytes), any(src_ip), any(src_country), any(time) FROM {} FORMAT TSV", table);
+        let response = reqwest::Client::new().post(&config.url).query(&[("query", query.as_str())]).send().await.unwrap();
+        let text = response.text().await.unwrap();
+        client.execute(&format!("DROP TABLE {}", table), Vec::new()).await.unwrap();
+        assert_eq!(text.trim(), "2\t600\t::ffff:192.0.2.1\tNL\t2023-11-14 22:13:20.123");
+    }
+}
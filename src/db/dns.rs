@@ -0,0 +1,246 @@
+// Just enough DNS to ask a recursive resolver for the PTR record of an IPv4 address.
+
+use std::fs;
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::time::Duration;
+
+use tokio::net::UdpSocket;
+
+const TYPE_PTR: u16 = 12;
+const CLASS_IN: u16 = 1;
+const FLAG_RESPONSE: u16 = 0x8000;
+const FLAG_RECURSION_DESIRED: u16 = 0x0100;
+const RCODE_NXDOMAIN: u16 = 3;
+const HEADER_LEN: usize = 12;
+
+// What the resolver said about an address
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PtrAnswer {
+    // The host name and how long it may be cached
+    Name(String, u32),
+    // No PTR record (NXDOMAIN or an empty answer)
+    NotFound,
+}
+
+// First nameserver of /etc/resolv.conf, where the system resolver would ask
+pub fn system_resolver() -> Option<SocketAddr> {
+    let text = fs::read_to_string("/etc/resolv.conf").ok()?;
+    text.lines()
+        .filter_map(|line| line.trim().strip_prefix("nameserver"))
+        .filter_map(|address| address.trim().parse().ok())
+        .map(|ip| SocketAddr::new(ip, 53))
+        .next()
+}
+
+pub async fn lookup_ptr(resolver: SocketAddr, ip: Ipv4Addr, timeout: Duration) -> io::Result<PtrAnswer> {
+    let socket = UdpSocket::bind(if resolver.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" }).await?;
+    socket.connect(resolver).await?;
+
+    let id = (uuid::Uuid::new_v4().as_u128() & 0xffff) as u16;
+    let query = encode_query(id, &reverse_name(ip));
+    socket.send(&query).await?;
+
+    let mut buf = vec![0u8; 4096];
+    let deadline = tokio::time::Instant::now() + timeout;
+    loop {
+        let len = match tokio::time::timeout_at(deadline, socket.recv(&mut buf)).await {
+            Ok(len) => len?,
+            Err(_) => return Err(io::Error::new(io::ErrorKind::TimedOut, format!("no DNS answer from {}", resolver))),
+        };
+        match decode_response(&buf[..len], id) {
+            Ok(Some(answer)) => return Ok(answer),
+            // Not the answer to our query, keep waiting
+            Ok(None) => continue,
+            Err(e) => return Err(io::Error::new(io::ErrorKind::InvalidData, e)),
+        }
+    }
+}
+
+fn reverse_name(ip: Ipv4Addr) -> String {
+    let [a, b, c, d] = ip.octets();
+    format!("{}.{}.{}.{}.in-addr.arpa", d, c, b, a)
+}
+
+fn encode_query(id: u16, name: &str) -> Vec<u8> {
+    let mut out = Vec::with_capacity(64);
+    out.extend(id.to_be_bytes());
+    out.extend(FLAG_RECURSION_DESIRED.to_be_bytes());
+    out.extend(1u16.to_be_bytes()); // questions
+    out.extend([0u8; 6]); // answers, authorities, additionals
+    for label in name.split('.') {
+        out.push(label.len() as u8);
+        out.extend(label.as_bytes());
+    }
+    out.push(0);
+    out.extend(TYPE_PTR.to_be_bytes());
+    out.extend(CLASS_IN.to_be_bytes());
+    out
+}
+
+fn read_u16(data: &[u8], pos: usize) -> Result<u16, String> {
+    data.get(pos..pos + 2)
+        .map(|b| u16::from_be_bytes([b[0], b[1]]))
+        .ok_or_else(|| "truncated DNS message".to_string())
+}
+
+fn read_u32(data: &[u8], pos: usize) -> Result<u32, String> {
+    data.get(pos..pos + 4)
+        .map(|b| u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+        .ok_or_else(|| "truncated DNS message".to_string())
+}
+
+// Reads a possibly compressed name, returns it and the position after it
+fn read_name(data: &[u8], mut pos: usize) -> Result<(String, usize), String> {
+    let mut labels: Vec<String> = Vec::new();
+    let mut end = None;
+    // Compression pointers must not loop
+    for _ in 0..128 {
+        let len = *data.get(pos).ok_or("truncated DNS name")? as usize;
+        match len {
+            0 => {
+                return Ok((labels.join("."), end.unwrap_or(pos + 1)));
+            }
+            len if len & 0xc0 == 0xc0 => {
+                let target = (read_u16(data, pos)? & 0x3fff) as usize;
+                end.get_or_insert(pos + 2);
+                pos = target;
+            }
+            len => {
+                let label = data.get(pos + 1..pos + 1 + len).ok_or("truncated DNS label")?;
+                labels.push(String::from_utf8_lossy(label).to_string());
+                pos += 1 + len;
+            }
+        }
+    }
+    Err("DNS name compression loop".to_string())
+}
+
+// Returns None when the message is not the response to query `id`
+fn decode_response(data: &[u8], id: u16) -> Result<Option<PtrAnswer>, String> {
+    if data.len() < HEADER_LEN || read_u16(data, 0)? != id {
+        return Ok(None);
+    }
+    let flags = read_u16(data, 2)?;
+    if flags & FLAG_RESPONSE == 0 {
+        return Ok(None);
+    }
+    match flags & 0x000f {
+        0 => {}
+        RCODE_NXDOMAIN => return Ok(Some(PtrAnswer::NotFound)),
+        rcode => return Err(format!("DNS error code {}", rcode)),
+    }
+
+    let questions = read_u16(data, 4)?;
+    let answers = read_u16(data, 6)?;
+    let mut pos = HEADER_LEN;
+    for _ in 0..questions {
+        pos = read_name(data, pos)?.1 + 4;
+    }
+    for _ in 0..answers {
+        let (_, next) = read_name(data, pos)?;
+        let record_type = read_u16(data, next)?;
+        let ttl = read_u32(data, next + 4)?;
+        let rdlength = read_u16(data, next + 8)? as usize;
+        let rdata = next + 10;
+        if record_type == TYPE_PTR {
+            let (name, _) = read_name(data, rdata)?;
+            return Ok(Some(PtrAnswer::Name(name.trim_end_matches('.').to_string(), ttl)));
+        }
+        // CNAMEs of classless delegations are followed by the resolver, skip them
+        pos = rdata + rdlength;
+    }
+    Ok(Some(PtrAnswer::NotFound))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The query turned into a response with one PTR answer, the owner name a pointer to the question
+    fn respond(query: &[u8], ttl: u32, rdata: &[u8]) -> Vec<u8> {
+        let mut out = query.to_vec();
+        out[2..4].copy_from_slice(&(FLAG_RESPONSE | FLAG_RECURSION_DESIRED | 0x0080).to_be_bytes());
+        out[6..8].copy_from_slice(&1u16.to_be_bytes());
+        out.extend([0xc0, HEADER_LEN as u8]);
+        out.extend(TYPE_PTR.to_be_bytes());
+        out.extend(CLASS_IN.to_be_bytes());
+        out.extend(ttl.to_be_bytes());
+        out.extend((rdata.len() as u16).to_be_bytes());
+        out.extend(rdata);
+        out
+    }
+
+    #[test]
+    fn encodes_a_ptr_query() {
+        let query = encode_query(0x1234, &reverse_name(Ipv4Addr::new(192, 0, 2, 1)));
+        let mut expected = vec![0x12, 0x34, 0x01, 0x00, 0x00, 0x01, 0, 0, 0, 0, 0, 0];
+        expected.extend(b"\x011\x012\x010\x03192\x07in-addr\x04arpa\x00");
+        expected.extend([0x00, 0x0c, 0x00, 0x01]);
+        assert_eq!(query, expected);
+    }
+
+    #[test]
+    fn decodes_a_compressed_ptr_answer() {
+        let query = encode_query(7, &reverse_name(Ipv4Addr::new(192, 0, 2, 1)));
+        let response = respond(&query, 3600, b"\x04host\x07example\x03com\x00");
+        assert_eq!(decode_response(&response, 7), Ok(Some(PtrAnswer::Name("host.example.com".to_string(), 3600))));
+
+        // The host name itself ends with a pointer, here into the question's "in-addr.arpa"
+        let in_addr = query.windows(8).position(|w| w == b"\x07in-addr").unwrap() as u8;
+        let response = respond(&query, 60, &[2, b'g', b'w', 0xc0, in_addr]);
+        assert_eq!(decode_response(&response, 7), Ok(Some(PtrAnswer::Name("gw.in-addr.arpa".to_string(), 60))));
+    }
+
+    #[test]
+    fn decodes_other_responses() {
+        let query = encode_query(7, &reverse_name(Ipv4Addr::new(192, 0, 2, 1)));
+        // Another query's answer, or the query itself
+        assert_eq!(decode_response(&respond(&query, 60, b"\x00"), 8), Ok(None));
+        assert_eq!(decode_response(&query, 7), Ok(None));
+
+        let mut nxdomain = query.clone();
+        nxdomain[2..4].copy_from_slice(&(FLAG_RESPONSE | RCODE_NXDOMAIN).to_be_bytes());
+        assert_eq!(decode_response(&nxdomain, 7), Ok(Some(PtrAnswer::NotFound)));
+
+        let mut servfail = query.clone();
+        servfail[2..4].copy_from_slice(&(FLAG_RESPONSE | 2).to_be_bytes());
+        assert!(decode_response(&servfail, 7).is_err());
+
+        // A pointer to itself
+        let looping = respond(&query, 60, &[0xc0, query.len() as u8 + 12]);
+        assert!(decode_response(&looping, 7).is_err());
+
+        let response = respond(&query, 60, b"\x04host\x00");
+        assert!(decode_response(&response[..response.len() - 3], 7).is_err());
+    }
+
+    // Talks UDP to a stub resolver on localhost
+    #[tokio::test]
+    #[ignore]
+    async fn looks_up_from_a_stub_server() {
+        let server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let resolver = server.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0u8; 512];
+            loop {
+                let (len, peer) = server.recv_from(&mut buf).await.unwrap();
+                let query = &buf[..len];
+                let response = if query.windows(4).any(|w| w == b"\x03192") {
+                    respond(query, 300, b"\x04host\x07example\x03com\x00")
+                } else {
+                    let mut nxdomain = query.to_vec();
+                    nxdomain[2..4].copy_from_slice(&(FLAG_RESPONSE | RCODE_NXDOMAIN).to_be_bytes());
+                    nxdomain
+                };
+                server.send_to(&response, peer).await.unwrap();
+            }
+        });
+
+        let timeout = Duration::from_secs(2);
+        let answer = lookup_ptr(resolver, Ipv4Addr::new(192, 0, 2, 1), timeout).await.unwrap();
+        assert_eq!(answer, PtrAnswer::Name("host.example.com".to_string(), 300));
+        let answer = lookup_ptr(resolver, Ipv4Addr::new(198, 51, 100, 1), timeout).await.unwrap();
+        assert_eq!(answer, PtrAnswer::NotFound);
+    }
+}
@@ -0,0 +1,340 @@
+// Queries over the stored enriched flows, for the HTTP API. InfluxDB is the only store
+// so far, queried in InfluxQL through the names the writer and telegraf produce.
+
+use std::collections::BTreeMap;
+
+use async_trait::async_trait;
+use chrono::{DateTime, SecondsFormat, Utc};
+use cidr::Ipv4Cidr;
+use influxdb::{Client, ReadQuery};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use super::influx_db::{InfluxConfig, TELEGRAF_MEASUREMENT};
+use super::ip_lookup::IPtype;
+use crate::process::filter::{Filter, Side};
+
+#[derive(Debug, Clone)]
+pub struct FlowFilter {
+    pub start: DateTime<Utc>,
+    pub end: DateTime<Utc>,
+    // Expression flows must match, see process::filter
+    pub filter: Option<Filter>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Metric {
+    Bytes,
+    Packets,
+    Flows,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct TopEntry {
+    pub value: String,
+    pub total: f64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Point {
+    pub time: String,
+    pub value: f64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Series {
+    // Value of the group_by tag, none without grouping
+    pub group: Option<String>,
+    pub points: Vec<Point>,
+}
+
+// A flow as stored, in the layout of EnrichedFlow
+#[derive(Debug, Clone, Serialize)]
+pub struct StoredFlow {
+    pub time: String,
+    pub tags: BTreeMap<String, String>,
+    pub fields: BTreeMap<String, f64>,
+}
+
+#[async_trait]
+pub trait FlowStore: Send + Sync {
+    // The `limit` values of a tag with the most traffic
+    async fn top(&self, filter: &FlowFilter, dimension: &str, metric: Metric, limit: usize) -> Result<Vec<TopEntry>, String>;
+    // Traffic per `interval_secs`, one series per value of `group_by`
+    async fn timeseries(
+        &self,
+        filter: &FlowFilter,
+        metric: Metric,
+        interval_secs: u64,
+        group_by: Option<&str>,
+    ) -> Result<Vec<Series>, String>;
+    // The latest `limit` flows
+    async fn flows(&self, filter: &FlowFilter, limit: usize) -> Result<Vec<StoredFlow>, String>;
+}
+
+// Tag names end up in queries, only allow what the enricher and the rules produce
+pub fn valid_tag_name(name: &str) -> bool {
+    !name.is_empty() && name.bytes().all(|b| b.is_ascii_lowercase() || b.is_ascii_digit() || b == b'_')
+}
+
+pub struct InfluxStore {
+    client: Client,
+}
+
+#[derive(Deserialize, Debug)]
+struct InfluxSeries {
+    #[serde(default)]
+    tags: BTreeMap<String, String>,
+    columns: Vec<String>,
+    #[serde(default)]
+    values: Vec<Vec<Value>>,
+}
+
+impl InfluxStore {
+    pub fn new(config: &InfluxConfig) -> Self {
+        InfluxStore {
+            client: Client::new(config.url.as_str(), config.bucket.as_str()).with_token(config.token.as_str()),
+        }
+    }
+
+    async fn query(&self, query: String) -> Result<Vec<InfluxSeries>, String> {
+        let text = self.client.query(ReadQuery::new(query)).await.map_err(|e| e.to_string())?;
+        let response: Value = serde_json::from_str(&text).map_err(|e| format!("unexpected InfluxDB response: {}", e))?;
+        let result = response.get("results").and_then(|results| results.get(0)).cloned().unwrap_or(Value::Null);
+        if let Some(error) = result.get("error").and_then(|e| e.as_str()) {
+            return Err(error.to_string());
+        }
+        match result.get("series") {
+            Some(series) => serde_json::from_value(series.clone()).map_err(|e| format!("unexpected InfluxDB series: {}", e)),
+            None => Ok(Vec::new()),
+        }
+    }
+}
+
+#[async_trait]
+impl FlowStore for InfluxStore {
+    async fn top(&self, filter: &FlowFilter, dimension: &str, metric: Metric, limit: usize) -> Result<Vec<TopEntry>, String> {
+        let tag = format!("tags_{}", dimension);
+        let query = format!(
+            "SELECT {} AS total FROM \"{}\" WHERE {} GROUP BY \"{}\"",
+            aggregate(metric),
+            TELEGRAF_MEASUREMENT,
+            where_clause(filter),
+            tag
+        );
+        let mut entries: Vec<TopEntry> = self
+            .query(query)
+            .await?
+            .into_iter()
+            .filter_map(|series| {
+                let total = series.values.first()?.get(1)?.as_f64()?;
+                let value = series.tags.get(&tag).cloned().unwrap_or_default();
+                Some(TopEntry { value, total })
+            })
+            .collect();
+        // InfluxQL can't order groups by their aggregate
+        entries.sort_by(|a, b| b.total.total_cmp(&a.total));
+        entries.truncate(limit);
+        Ok(entries)
+    }
+
+    async fn timeseries(
+        &self,
+        filter: &FlowFilter,
+        metric: Metric,
+        interval_secs: u64,
+        group_by: Option<&str>,
+    ) -> Result<Vec<Series>, String> {
+        let mut group = format!("time({}s)", interval_secs);
+        if let Some(tag) = group_by {
+            group.push_str(&format!(", \"tags_{}\"", tag));
+        }
+        let query = format!(
+            "SELECT {} AS value FROM \"{}\" WHERE {} GROUP BY {} fill(0)",
+            aggregate(metric),
+            TELEGRAF_MEASUREMENT,
+            where_clause(filter),
+            group
+        );
+        Ok(self
+            .query(query)
+            .await?
+            .into_iter()
+            .map(|series| Series {
+                group: group_by.map(|tag| series.tags.get(&format!("tags_{}", tag)).cloned().unwrap_or_default()),
+                points: series
+                    .values
+                    .iter()
+                    .filter_map(|row| {
+                        Some(Point {
+                            time: row.first()?.as_str()?.to_string(),
+                            value: row.get(1).and_then(|v| v.as_f64()).unwrap_or(0.0),
+                        })
+                    })
+                    .collect(),
+            })
+            .collect())
+    }
+
+    async fn flows(&self, filter: &FlowFilter, limit: usize) -> Result<Vec<StoredFlow>, String> {
+        let query = format!(
+            "SELECT * FROM \"{}\" WHERE {} ORDER BY time DESC LIMIT {}",
+            TELEGRAF_MEASUREMENT,
+            where_clause(filter),
+            limit
+        );
+        let mut flows = Vec::new();
+        for series in self.query(query).await? {
+            for row in &series.values {
+                let mut flow = StoredFlow { time: String::new(), tags: BTreeMap::new(), fields: BTreeMap::new() };
+                for (column, value) in series.columns.iter().zip(row) {
+                    if column == "time" {
+                        flow.time = value.as_str().unwrap_or_default().to_string();
+                    } else if let (Some(name), Some(tag)) = (column.strip_prefix("tags_"), value.as_str()) {
+                        flow.tags.insert(name.to_string(), tag.to_string());
+                    } else if let (Some(name), Some(field)) = (column.strip_prefix("fields_"), value.as_f64()) {
+                        flow.fields.insert(name.to_string(), field);
+                    }
+                }
+                flows.push(flow);
+            }
+        }
+        Ok(flows)
+    }
+}
+
+fn aggregate(metric: Metric) -> &'static str {
+    match metric {
+        Metric::Bytes => "sum(\"fields_bytes\")",
+        Metric::Packets => "sum(\"fields_packets\")",
+        Metric::Flows => "count(\"fields_bytes\")",
+    }
+}
+
+fn quote(value: &str) -> String {
+    format!("'{}'", value.replace('\\', "\\\\").replace('\'', "\\'"))
+}
+
+fn where_clause(filter: &FlowFilter) -> String {
+    let mut clauses = vec![
+        format!("time >= '{}'", filter.start.to_rfc3339_opts(SecondsFormat::Millis, true)),
+        format!("time < '{}'", filter.end.to_rfc3339_opts(SecondsFormat::Millis, true)),
+    ];
+    if let Some(expression) = &filter.filter {
+        clauses.push(filter_clause(expression, false));
+    }
+    clauses.join(" AND ")
+}
+
+fn tag_column(name: &str) -> String {
+    format!("\"tags_{}\"", name.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+// InfluxQL has no NOT, negations are pushed down to the comparisons
+fn filter_clause(filter: &Filter, negated: bool) -> String {
+    let (equal, matches) = if negated { ("!=", "!~") } else { ("=", "=~") };
+    let join = |clauses: Vec<String>, and: bool| {
+        format!("({})", clauses.join(if and != negated { " AND " } else { " OR " }))
+    };
+    let sides = |side: Side, clause: &dyn Fn(&str) -> String| join(side.prefixes().iter().map(|prefix| clause(prefix)).collect(), false);
+    let address = |column: String, cidr: &Ipv4Cidr| match cidr.network_length() {
+        32 => format!("{} {} {}", column, equal, quote(&cidr.first_address().to_string())),
+        _ => format!("{} {} /{}/", column, matches, prefix_regex(cidr)),
+    };
+    match filter {
+        Filter::And(left, right) => join(vec![filter_clause(left, negated), filter_clause(right, negated)], true),
+        Filter::Or(left, right) => join(vec![filter_clause(left, negated), filter_clause(right, negated)], false),
+        Filter::Not(filter) => filter_clause(filter, !negated),
+        Filter::Any => format!("\"fields_bytes\" {} 0", if negated { "<" } else { ">=" }),
+        Filter::Address(side, cidr) => sides(*side, &|prefix| address(tag_column(&format!("{}_ip", prefix)), cidr)),
+        Filter::Port(side, first, last) if first == last => {
+            sides(*side, &|prefix| format!("\"fields_{}_port\" {} {}", prefix, equal, first))
+        }
+        Filter::Port(side, first, last) => sides(*side, &|prefix| {
+            let (low, high, and) = if negated { ("<", ">", "OR") } else { (">=", "<=", "AND") };
+            format!("(\"fields_{0}_port\" {1} {2} {3} \"fields_{0}_port\" {4} {5})", prefix, low, first, and, high, last)
+        }),
+        Filter::Asn(side, asn) => sides(*side, &|prefix| format!("\"tags_{}_asn\" {} '{}'", prefix, equal, asn)),
+        Filter::Country(side, country) => {
+            sides(*side, &|prefix| format!("\"tags_{}_country\" {} {}", prefix, equal, quote(country)))
+        }
+        Filter::Protocol(protocol) => format!("\"fields_protocol\" {} {}", equal, protocol),
+        Filter::Exporter(cidr) => address(tag_column("exporter"), cidr),
+        Filter::Counter(counter, comparison, value) => {
+            let comparison = if negated { comparison.negate() } else { *comparison };
+            format!("\"fields_{}\" {} {}", counter.name(), comparison.symbol(), value)
+        }
+        // A missing tag reads as an empty string
+        Filter::Tag(name, Some(value)) => format!("{} {} {}", tag_column(name), equal, quote(value)),
+        Filter::Tag(name, None) => format!("{} {} ''", tag_column(name), if negated { "=" } else { "!=" }),
+        Filter::Direction(direction) => {
+            let direction = if *direction == IPtype::Incoming { "Incoming" } else { "Outgoing" };
+            format!("\"tags_type\" {} '{}'", equal, direction)
+        }
+    }
+}
+
+// Regex matching the dotted addresses of a prefix: the whole octets literally and
+// the values of the partial octet as an alternation
+pub fn prefix_regex(cidr: &Ipv4Cidr) -> String {
+    let octets = cidr.first_address().octets();
+    let whole = (cidr.network_length() / 8) as usize;
+    let mut parts: Vec<String> = octets[..whole].iter().map(|octet| octet.to_string()).collect();
+    let remaining = cidr.network_length() % 8;
+    if remaining > 0 {
+        let low = octets[whole] as u16;
+        let high = low + (1 << (8 - remaining)) - 1;
+        let values: Vec<String> = (low..=high).map(|value| value.to_string()).collect();
+        parts.push(format!("({})", values.join("|")));
+    }
+    // A whole address ends the string, a prefix is followed by the next octet
+    let end = match parts.len() {
+        0 => "",
+        4 => "$",
+        _ => "\\.",
+    };
+    format!("^{}{}", parts.join("\\."), end)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn clause(text: &str) -> String {
+        filter_clause(&Filter::parse(text).unwrap(), false)
+    }
+
+    #[test]
+    fn pushes_negations_down() {
+        assert_eq!(clause("src port 53"), "(\"fields_src_port\" = 53)");
+        assert_eq!(clause("not src port 53"), "(\"fields_src_port\" != 53)");
+        assert_eq!(
+            clause("not (proto udp and dst net 10.0.0.0/8)"),
+            "(\"fields_protocol\" != 17 OR (\"tags_dst_ip\" !~ /^10\\./))"
+        );
+        assert_eq!(
+            clause("not (host 192.0.2.1 or country CN)"),
+            "((\"tags_src_ip\" != '192.0.2.1' AND \"tags_dst_ip\" != '192.0.2.1') AND (\"tags_src_country\" != 'CN' AND \"tags_dst_country\" != 'CN'))"
+        );
+        assert_eq!(clause("not not bytes > 1k"), "\"fields_bytes\" > 1000");
+        assert_eq!(clause("not bytes > 1k"), "\"fields_bytes\" <= 1000");
+        assert_eq!(
+            clause("not dst port 8000-8100"),
+            "((\"fields_dst_port\" < 8000 OR \"fields_dst_port\" > 8100))"
+        );
+        assert_eq!(clause("tag customer != \"o'neil\""), "\"tags_customer\" != 'o\\'neil'");
+        assert_eq!(clause("not tag customer"), "\"tags_customer\" = ''");
+    }
+
+    #[test]
+    fn regexes_of_prefixes() {
+        let regex = |cidr: &str| prefix_regex(&cidr.parse().unwrap());
+        assert_eq!(regex("0.0.0.0/0"), "^");
+        assert_eq!(regex("10.0.0.0/8"), "^10\\.");
+        assert_eq!(regex("192.0.2.0/24"), "^192\\.0\\.2\\.");
+        assert_eq!(regex("192.0.2.1/32"), "^192\\.0\\.2\\.1$");
+        assert_eq!(regex("10.16.0.0/14"), "^10\\.(16|17|18|19)\\.");
+        assert_eq!(regex("192.0.2.8/30"), "^192\\.0\\.2\\.(8|9|10|11)$");
+    }
+}
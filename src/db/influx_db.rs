@@ -1,10 +1,56 @@
+use std::time::Duration;
+
 use chrono::{DateTime, Utc};
 use influxdb::{InfluxDbWriteable};
-use influxdb::{Client, Query};
+use influxdb::{Client, ReadQuery, Timestamp, WriteQuery};
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tokio::task::JoinSet;
+use tokio::time::Instant;
 
 use super::ip_lookup::IPtype;
+use crate::process::cardinality::SketchMerger;
+use crate::process::flow::Aggregate;
+use crate::process::tenant::TenantConfig;
+use crate::transport::{SharedTransport, TransportError, ENRICHER_AGGREGATES, ENRICHER_TO_TSDB};
+
+// The dashboard queries the names telegraf's kafka_consumer input produces,
+// so the built-in writer uses the same measurement and tag/field prefixes.
+pub const TELEGRAF_MEASUREMENT: &str = "kafka_consumer";
+pub const TSDB_WRITER_GROUP: &str = "ta-tsdb-writer";
+// Aggregate windows remembered for merging late points of other enrichers
+const MERGE_WINDOWS: usize = 3;
+// A failed write is retried after this long, doubling up to the maximum
+const RETRY_MIN: Duration = Duration::from_secs(1);
+const RETRY_MAX: Duration = Duration::from_secs(60);
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct InfluxConfig {
+    pub url: String,
+    pub bucket: String,
+    pub token: String,
+    // Points are written when the batch is full or `flush_ms` passed
+    pub batch_size: usize,
+    pub flush_ms: u64,
+    // Topic of the flows to write, a route's topic to write only some
+    pub topic: String,
+}
+
+impl Default for InfluxConfig {
+    fn default() -> Self {
+        InfluxConfig {
+            url: "http://localhost:8086".to_string(),
+            bucket: "db".to_string(),
+            token: "ball".to_string(),
+            batch_size: 500,
+            flush_ms: 1000,
+            topic: ENRICHER_TO_TSDB.to_string(),
+        }
+    }
+}
 
+#[allow(non_snake_case)]
 #[derive(Clone, Debug)]
 #[derive(InfluxDbWriteable)]
 #[derive(Serialize, Deserialize)]
@@ -27,57 +73,190 @@ pub struct CustomMessage{
 
 
 pub fn create_client(bucket:&str, token: &str) -> Client {
-    let client = Client::new("http://localhost:8086", bucket)
-        .with_token(token);
-    client
+    Client::new("http://localhost:8086", bucket)
+        .with_token(token)
+}
+
+// Write the enricher output to InfluxDB, replacing telegraf when there is no Kafka
+pub async fn run_writer(transport: SharedTransport, config: InfluxConfig) {
+    let topic = config.topic.clone();
+    tokio::join!(
+        write_topic(transport.clone(), &topic, config.clone(), enriched_to_query),
+        write_topic(transport, ENRICHER_AGGREGATES, config, merged_aggregate_to_query()),
+    );
+}
+
+// Flows of the tenants with a bucket of their own, each written from its topic
+pub async fn run_tenant_writers(transport: SharedTransport, config: InfluxConfig, tenants: Vec<TenantConfig>) {
+    let mut writers = JoinSet::new();
+    for tenant in tenants {
+        let Some(bucket) = tenant.influx_bucket.clone() else {
+            continue;
+        };
+        let config = InfluxConfig { bucket, topic: tenant.output_topic().to_string(), ..config.clone() };
+        let transport = transport.clone();
+        writers.spawn(async move {
+            let topic = config.topic.clone();
+            write_topic(transport, &topic, config, enriched_to_query).await;
+        });
+    }
+    while writers.join_next().await.is_some() {}
+}
+
+async fn write_topic(transport: SharedTransport, topic: &str, config: InfluxConfig, mut to_query: impl FnMut(&[u8]) -> Option<WriteQuery>) {
+    let client = Client::new(config.url.as_str(), config.bucket.as_str())
+        .with_token(config.token.as_str());
+    let mut subscription = transport
+        .subscribe(topic, TSDB_WRITER_GROUP)
+        .await
+        .expect("Can't subscribe to specified topic");
+
+    let flush_interval = Duration::from_millis(config.flush_ms);
+    let mut batch = Vec::new();
+    let mut last = None;
+    // A batch is written when full or `flush_ms` after its first record, however slowly
+    // the others trickle in
+    let mut deadline: Option<Instant> = None;
+    loop {
+        let received = match deadline {
+            Some(deadline) => tokio::time::timeout_at(deadline, subscription.recv()).await,
+            None => Ok(subscription.recv().await),
+        };
+        match received {
+            Ok(Ok(record)) => {
+                match to_query(&record.payload) {
+                    Some(query) => batch.push(query),
+                    None => println!("Skipping malformed {} record at offset {}", topic, record.offset),
+                }
+                last = Some(record);
+                let deadline = *deadline.get_or_insert_with(|| Instant::now() + flush_interval);
+                if batch.len() < config.batch_size && Instant::now() < deadline {
+                    continue;
+                }
+            }
+            Ok(Err(TransportError::Closed)) => break,
+            Ok(Err(e)) => {
+                println!("Error receiving message: {:?}", e);
+                if deadline.is_none_or(|deadline| Instant::now() < deadline) {
+                    continue;
+                }
+            }
+            Err(_) => {}
+        }
+        deadline = None;
+
+        if batch.is_empty() {
+            continue;
+        }
+        // Nothing is read or committed until the batch is written, so an InfluxDB
+        // outage holds the records back instead of losing them
+        let mut retry = RETRY_MIN;
+        while let Err(e) = client.query(&batch).await {
+            println!("Error writing to InfluxDB, retrying in {}s: {}", retry.as_secs(), e);
+            tokio::time::sleep(retry).await;
+            retry = (retry * 2).min(RETRY_MAX);
+        }
+        batch.clear();
+        if let Some(record) = last.take() {
+            if let Err(e) = subscription.commit(&record).await {
+                println!("Error committing message: {:?}", e);
+            }
+        }
+    }
+}
+
+// Turn one enriched JSON record into a point named like telegraf would name it
+pub fn enriched_to_query(payload: &[u8]) -> Option<WriteQuery> {
+    json_to_query(payload, Some(TELEGRAF_MEASUREMENT))
 }
+
+// Aggregates keep their own measurement, like telegraf's `json_name_key = "measurement"`
+pub fn aggregate_to_query(payload: &[u8]) -> Option<WriteQuery> {
+    json_to_query(payload, None)
+}
+
+// Cardinality points of several enrichers are unioned before they are written
+fn merged_aggregate_to_query() -> impl FnMut(&[u8]) -> Option<WriteQuery> {
+    let mut merger = SketchMerger::new(MERGE_WINDOWS);
+    move |payload| {
+        let aggregate = merger.merge(Aggregate::from_json(payload)?);
+        aggregate_to_query(&aggregate.to_json())
+    }
+}
+
+fn json_to_query(payload: &[u8], measurement: Option<&str>) -> Option<WriteQuery> {
+    let value: Value = serde_json::from_slice(payload).ok()?;
+    let time = value.get("time")?.as_str()?.parse::<DateTime<Utc>>().ok()?;
+    let measurement = match measurement {
+        Some(measurement) => measurement,
+        None => value.get("measurement")?.as_str()?,
+    };
+    let mut query = WriteQuery::new(Timestamp::Nanoseconds(time.timestamp_nanos_opt()? as u128), measurement);
+
+    for (name, tag) in value.get("tags")?.as_object()? {
+        let tag = match tag {
+            Value::String(s) => s.clone(),
+            other => other.to_string(),
+        };
+        query = query.add_tag(format!("tags_{}", name), tag);
+    }
+    for (name, field) in value.get("fields")?.as_object()? {
+        // telegraf's json parser stores every number as a float
+        if let Some(field) = field.as_f64() {
+            query = query.add_field(format!("fields_{}", name), field);
+        }
+    }
+    Some(query)
+}
+
+#[allow(dead_code)]
 async fn read_all_table_query(client: Client, table: &str) -> Result<String, influxdb::Error> {
-    let query = <dyn Query>::raw_read_query(
+    let query = ReadQuery::new(
         format!("SELECT * FROM {}", table).as_str(),
     );
     let read_result = client.query(query).await?;
-    return Ok(read_result.to_string());
+    Ok(read_result.to_string())
 }
 pub async fn write_data(client: Client, pack: Package, iptype: IPtype) -> Result<(), influxdb::Error> {
-    let mut write_query;
-    match iptype {
+    let write_query = match iptype {
         IPtype::Incoming => {
-            write_query = pack.into_query("incoming");
+            pack.into_query("incoming")
         }
         IPtype::Outgoing => {
-            write_query = pack.into_query("outgoing");
+            pack.into_query("outgoing")
         }
-    }
+    };
     client.query(write_query).await?;
     Ok(())
 }
+#[allow(non_snake_case)]
 pub async fn make_package(time: DateTime<Utc>, IP: &str, AS: &str, Country: &str, bytes: i32) -> Package {
-    let pack = Package {
-        time: time,
+    Package {
+        time,
         IP: IP.to_string(),
         AS: AS.to_string(),
         location: Country.to_string(),
         bytes_count: bytes,
-    };
-    pack
+    }
 }
+#[allow(dead_code)]
 async fn write_datas(client: Client, packvec: Vec<Package>, iptype: IPtype) -> Result<(), influxdb::Error> {
     let mut vec_query = Vec::new();
     for pack in packvec {
-        let mut write_query;
-        match iptype {
+        let write_query = match iptype {
             IPtype::Incoming => {
-                write_query = pack.into_query("incoming");
+                pack.into_query("incoming")
             }
             IPtype::Outgoing => {
-                write_query = pack.into_query("outgoing");
+                pack.into_query("outgoing")
             }
-        }
+        };
         vec_query.push(write_query);
     }
     client.query(vec_query).await?;
     Ok(())
 }
+#[allow(dead_code, non_snake_case)]
 async fn makeThaipackage()-> Result<Vec<Package>, influxdb::Error>{
     let mut packvec = Vec::new();
     packvec.push(
@@ -145,9 +324,10 @@ async fn makeThaipackage()-> Result<Vec<Package>, influxdb::Error>{
             .await
     );
 
-    return Ok(packvec);
+    Ok(packvec)
 
 }
+#[allow(dead_code, non_snake_case)]
 async fn makeAustraliapackage()-> Result<Vec<Package>, influxdb::Error>{
     let mut packvec = Vec::new();
     packvec.push(
@@ -225,7 +405,7 @@ async fn makeAustraliapackage()-> Result<Vec<Package>, influxdb::Error>{
         )
             .await
     );
-    return Ok(packvec);
+    Ok(packvec)
 }
 
 
@@ -261,3 +441,74 @@ async fn makeAustraliapackage()-> Result<Vec<Package>, influxdb::Error>{
    
 // }
 
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+    use tokio::sync::mpsc;
+
+    use super::*;
+    use crate::transport::ChannelTransport;
+
+    // Answers every write with 204 and reports when it arrived
+    async fn stub_influx() -> (String, mpsc::UnboundedReceiver<Instant>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let (writes, received) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let writes = writes.clone();
+                tokio::spawn(async move {
+                    let mut request = Vec::new();
+                    let mut buf = [0u8; 4096];
+                    loop {
+                        let n = stream.read(&mut buf).await.unwrap();
+                        if n == 0 {
+                            return;
+                        }
+                        request.extend_from_slice(&buf[..n]);
+                        let text = String::from_utf8_lossy(&request);
+                        let Some(end) = text.find("\r\n\r\n") else {
+                            continue;
+                        };
+                        let length = text[..end]
+                            .lines()
+                            .find_map(|line| line.to_ascii_lowercase().strip_prefix("content-length:").map(|v| v.trim().parse::<usize>().unwrap()))
+                            .unwrap_or(0);
+                        if request.len() < end + 4 + length {
+                            continue;
+                        }
+                        let _ = writes.send(Instant::now());
+                        request.clear();
+                        stream.write_all(b"HTTP/1.1 204 No Content\r\ncontent-length: 0\r\n\r\n").await.unwrap();
+                    }
+                });
+            }
+        });
+        (url, received)
+    }
+
+    #[tokio::test]
+    async fn a_trickle_is_flushed_after_flush_ms() {
+        let (url, mut writes) = stub_influx().await;
+        let transport: SharedTransport = Arc::new(ChannelTransport::new(100));
+        let config = InfluxConfig { url, batch_size: 100, flush_ms: 300, topic: "trickle".to_string(), ..InfluxConfig::default() };
+        let writer_transport = transport.clone();
+        tokio::spawn(async move { write_topic(writer_transport, "trickle", config, aggregate_to_query).await });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        // A record every 100ms never fills the batch, and no gap is as long as flush_ms
+        let start = Instant::now();
+        for _ in 0..8 {
+            let point = Aggregate::new("trickle", Utc::now()).field("n", 1);
+            transport.send("trickle", None, &point.to_json()).await.unwrap();
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        let first = tokio::time::timeout(Duration::from_secs(1), writes.recv()).await.unwrap().unwrap();
+        assert!(first - start < Duration::from_millis(600));
+    }
+}
@@ -0,0 +1,199 @@
+use std::collections::{BTreeSet, HashMap};
+use std::fs;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+use super::snmp::{self, SnmpValue};
+
+// IF-MIB columns, the last arc of every row is the ifIndex
+const IF_NAME: &str = "1.3.6.1.2.1.31.1.1.1.1";
+const IF_ALIAS: &str = "1.3.6.1.2.1.31.1.1.1.18";
+// Megabits per second, used before ifSpeed which tops out at 4.29 Gbit/s
+const IF_HIGH_SPEED: &str = "1.3.6.1.2.1.31.1.1.1.15";
+const IF_SPEED: &str = "1.3.6.1.2.1.2.2.1.5";
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+#[serde(default)]
+pub struct InterfaceConfig {
+    // Static interface list, .csv (exporter,ifindex,name,description,speed) or .yaml
+    pub file: Option<String>,
+    pub snmp: Option<SnmpConfig>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct SnmpConfig {
+    pub community: String,
+    pub port: u16,
+    pub interval_secs: u64,
+    pub timeout_ms: u64,
+    // Exporters to poll, in addition to every exporter seen in the flows if `poll_seen` is set
+    pub exporters: Vec<IpAddr>,
+    pub poll_seen: bool,
+}
+
+impl Default for SnmpConfig {
+    fn default() -> Self {
+        SnmpConfig {
+            community: "public".to_string(),
+            port: 161,
+            interval_secs: 300,
+            timeout_ms: 2000,
+            exporters: Vec::new(),
+            poll_seen: true,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+pub struct InterfaceInfo {
+    pub name: String,
+    #[serde(default)]
+    pub description: String,
+    // Bits per second
+    #[serde(default)]
+    pub speed: u64,
+}
+
+// One line of the static interface file
+#[derive(Deserialize)]
+struct InterfaceEntry {
+    exporter: IpAddr,
+    ifindex: u32,
+    #[serde(flatten)]
+    info: InterfaceInfo,
+}
+
+type InterfaceMap = HashMap<(IpAddr, u32), InterfaceInfo>;
+
+// Interface metadata keyed by (exporter, ifIndex). Static entries win over polled ones.
+// Cloning is cheap and every clone sees the updates of the SNMP poller.
+#[derive(Clone, Debug, Default)]
+pub struct InterfaceLookup {
+    static_map: Arc<InterfaceMap>,
+    polled: Arc<RwLock<InterfaceMap>>,
+    seen_exporters: Arc<RwLock<BTreeSet<IpAddr>>>,
+}
+
+impl InterfaceLookup {
+    pub fn new(config: &InterfaceConfig) -> Self {
+        let static_map = match &config.file {
+            Some(file) => match Self::load_file(file) {
+                Ok(map) => map,
+                Err(e) => {
+                    println!("Can't load interfaces from {}: {}", file, e);
+                    HashMap::new()
+                }
+            },
+            None => HashMap::new(),
+        };
+        InterfaceLookup {
+            static_map: Arc::new(static_map),
+            ..Default::default()
+        }
+    }
+
+    fn load_file(file: &str) -> Result<InterfaceMap, String> {
+        let entries: Vec<InterfaceEntry> = if file.ends_with(".yaml") || file.ends_with(".yml") {
+            let text = fs::read_to_string(file).map_err(|e| e.to_string())?;
+            serde_yaml::from_str(&text).map_err(|e| e.to_string())?
+        } else {
+            let mut reader = csv::ReaderBuilder::new()
+                .has_headers(false)
+                .flexible(true)
+                .comment(Some(b'#'))
+                .trim(csv::Trim::All)
+                .from_path(file)
+                .map_err(|e| e.to_string())?;
+            let mut entries = Vec::new();
+            for row in reader.records() {
+                let row = row.map_err(|e| e.to_string())?;
+                let field = |i: usize| row.get(i).unwrap_or("").to_string();
+                entries.push(InterfaceEntry {
+                    exporter: field(0).parse().map_err(|_| format!("bad exporter address {:?}", field(0)))?,
+                    ifindex: field(1).parse().map_err(|_| format!("bad ifIndex {:?}", field(1)))?,
+                    info: InterfaceInfo {
+                        name: field(2),
+                        description: field(3),
+                        speed: field(4).parse().unwrap_or(0),
+                    },
+                });
+            }
+            entries
+        };
+
+        Ok(entries.into_iter().map(|e| ((e.exporter, e.ifindex), e.info)).collect())
+    }
+
+    pub fn lookup(&self, exporter: IpAddr, if_index: u32) -> Option<InterfaceInfo> {
+        if let Some(info) = self.static_map.get(&(exporter, if_index)) {
+            return Some(info.clone());
+        }
+        self.polled.read().unwrap().get(&(exporter, if_index)).cloned()
+    }
+
+    // Remember an exporter so the poller can ask it for its interfaces
+    pub fn note_exporter(&self, exporter: IpAddr) {
+        if !self.seen_exporters.read().unwrap().contains(&exporter) {
+            self.seen_exporters.write().unwrap().insert(exporter);
+        }
+    }
+
+    fn replace_polled(&self, exporter: IpAddr, interfaces: HashMap<u32, InterfaceInfo>) {
+        let mut polled = self.polled.write().unwrap();
+        polled.retain(|(e, _), _| *e != exporter);
+        polled.extend(interfaces.into_iter().map(|(index, info)| ((exporter, index), info)));
+    }
+}
+
+// Poll the ifTable of every exporter and refresh the interface lookup
+pub async fn run_poller(interfaces: InterfaceLookup, config: SnmpConfig) {
+    let mut ticker = tokio::time::interval(Duration::from_secs(config.interval_secs.max(1)));
+    let timeout = Duration::from_millis(config.timeout_ms);
+
+    loop {
+        ticker.tick().await;
+
+        let mut exporters: BTreeSet<IpAddr> = config.exporters.iter().copied().collect();
+        if config.poll_seen {
+            exporters.extend(interfaces.seen_exporters.read().unwrap().iter().copied());
+        }
+
+        for exporter in exporters {
+            let target = SocketAddr::new(exporter, config.port);
+            match poll_exporter(target, &config.community, timeout).await {
+                Ok(polled) => {
+                    println!("Polled {} interfaces from {}", polled.len(), exporter);
+                    interfaces.replace_polled(exporter, polled);
+                }
+                Err(e) => println!("SNMP poll of {} failed: {}", exporter, e),
+            }
+        }
+    }
+}
+
+async fn poll_exporter(target: SocketAddr, community: &str, timeout: Duration) -> std::io::Result<HashMap<u32, InterfaceInfo>> {
+    let mut interfaces: HashMap<u32, InterfaceInfo> = HashMap::new();
+
+    for (column, base) in [IF_NAME, IF_ALIAS, IF_SPEED, IF_HIGH_SPEED].iter().enumerate() {
+        let base = snmp::parse_oid(base).expect("IF-MIB OIDs are valid");
+        for (oid, value) in snmp::walk(target, community, &base, timeout).await? {
+            let index = match oid.last() {
+                Some(index) if oid.len() == base.len() + 1 => *index,
+                _ => continue,
+            };
+            let info = interfaces.entry(index).or_default();
+            match (column, value) {
+                (0, value) => info.name = value.as_string().unwrap_or_default(),
+                (1, value) => info.description = value.as_string().unwrap_or_default(),
+                (2, value) => info.speed = value.as_u64().unwrap_or(0),
+                (3, SnmpValue::Unsigned(mbps)) if mbps > 0 => info.speed = mbps * 1_000_000,
+                _ => {}
+            }
+        }
+    }
+    Ok(interfaces)
+}
@@ -1,39 +1,137 @@
 // Returns whether a packet is Incoming or Outgoing
 // i.e if the src IP = private -> outgoing
-// else if dst IP = private -> outgoing
+// else if dst IP = private -> incoming
+
+use std::fmt;
+use std::net::Ipv4Addr;
 
 use serde::{Deserialize, Serialize};
 
 #[derive(Serialize, Deserialize)]
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum IPtype {
     Incoming,
     Outgoing,
 }
 
-pub fn is_private_ip(ip: &String) ->  bool {
-    let ip_parts: Vec<u8> = ip.split('.').into_iter().map(|c| c.parse::<u8>().unwrap()).collect();
-    // println!("{:?}", ip_parts);
-    match ip_parts.get(0) {
-        Some(&ip0) if ip0 == 10 => {
-            // Any IP starting with 10 is private
-            true
-        }
-        Some(&ip0) if ip0 == 127 => {
-            match ip_parts.get(1) {
-                Some(&ip1) if (ip1 >= 16 && ip1 <= 31) => true,
-                Some(_) => false,
-                None => false
-            }
+// What kind of address an IPv4 address is, according to the IANA special-purpose registry
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum AddressClass {
+    Private,
+    SharedAddressSpace,
+    Loopback,
+    LinkLocal,
+    Documentation,
+    Multicast,
+    Broadcast,
+    Unspecified,
+    Reserved,
+    Public,
+}
+
+impl fmt::Display for AddressClass {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            AddressClass::Private => "private",
+            AddressClass::SharedAddressSpace => "shared (CGNAT)",
+            AddressClass::Loopback => "loopback",
+            AddressClass::LinkLocal => "link-local",
+            AddressClass::Documentation => "documentation",
+            AddressClass::Multicast => "multicast",
+            AddressClass::Broadcast => "broadcast",
+            AddressClass::Unspecified => "unspecified",
+            AddressClass::Reserved => "reserved",
+            AddressClass::Public => "public",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+pub fn address_class(ip: Ipv4Addr) -> AddressClass {
+    let [a, b, c, _] = ip.octets();
+    if ip.is_unspecified() {
+        AddressClass::Unspecified
+    } else if ip.is_broadcast() {
+        AddressClass::Broadcast
+    } else if ip.is_private() {
+        AddressClass::Private
+    } else if a == 100 && (64..=127).contains(&b) {
+        AddressClass::SharedAddressSpace
+    } else if ip.is_loopback() {
+        AddressClass::Loopback
+    } else if ip.is_link_local() {
+        AddressClass::LinkLocal
+    } else if ip.is_documentation() {
+        AddressClass::Documentation
+    } else if ip.is_multicast() {
+        AddressClass::Multicast
+    } else if a == 0 || a >= 240 || (a == 192 && b == 0 && c == 0) || (a == 198 && (18..=19).contains(&b)) {
+        AddressClass::Reserved
+    } else {
+        AddressClass::Public
+    }
+}
+
+// RFC 1918 addresses, anything that doesn't parse as IPv4 is not private
+pub fn is_private_ip(ip: &str) -> bool {
+    ip.parse::<Ipv4Addr>().map(|ip| ip.is_private()).unwrap_or(false)
+}
+
+// Direction of a flow as seen from the private network
+pub fn classify_direction(src_ip: &str, dst_ip: &str) -> IPtype {
+    direction_from_home(is_private_ip(src_ip), is_private_ip(dst_ip))
+}
+
+// Direction of a flow given which of its addresses are in the home network
+pub fn direction_from_home(src_home: bool, dst_home: bool) -> IPtype {
+    match (src_home, dst_home) {
+        (true, true) => IPtype::Incoming,
+        (true, _) => IPtype::Outgoing,
+        (_, true) => IPtype::Incoming,
+        (_, _) => IPtype::Outgoing
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn class(ip: &str) -> AddressClass {
+        address_class(ip.parse().unwrap())
+    }
+
+    #[test]
+    fn classifies_the_edges_of_the_special_ranges() {
+        for ip in ["10.0.0.0", "10.255.255.255", "172.16.0.0", "172.31.255.255", "192.168.0.0", "192.168.255.255"] {
+            assert_eq!(class(ip), AddressClass::Private, "{}", ip);
         }
-        Some(&ip0) if ip0 == 192 => {
-            match ip_parts.get(1) {
-                Some(&ip1) if (ip1 == 168) => true,
-                Some(_) => false,
-                None => false
-            }        
+        for ip in ["9.255.255.255", "11.0.0.0", "172.15.255.255", "172.32.0.0", "192.167.255.255", "192.169.0.0"] {
+            assert_eq!(class(ip), AddressClass::Public, "{}", ip);
         }
-        Some(_) => false,
-        None => false
+        assert_eq!(class("100.64.0.0"), AddressClass::SharedAddressSpace);
+        assert_eq!(class("100.127.255.255"), AddressClass::SharedAddressSpace);
+        assert_eq!(class("100.63.255.255"), AddressClass::Public);
+        assert_eq!(class("100.128.0.0"), AddressClass::Public);
+        assert_eq!(class("0.0.0.0"), AddressClass::Unspecified);
+        assert_eq!(class("0.0.0.1"), AddressClass::Reserved);
+        assert_eq!(class("127.0.0.1"), AddressClass::Loopback);
+        assert_eq!(class("169.254.1.1"), AddressClass::LinkLocal);
+        assert_eq!(class("192.0.2.1"), AddressClass::Documentation);
+        assert_eq!(class("198.18.0.0"), AddressClass::Reserved);
+        assert_eq!(class("224.0.0.1"), AddressClass::Multicast);
+        assert_eq!(class("240.0.0.1"), AddressClass::Reserved);
+        assert_eq!(class("255.255.255.255"), AddressClass::Broadcast);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn only_rfc_1918_is_private() {
+        assert!(is_private_ip("10.0.0.0"));
+        assert!(is_private_ip("172.31.255.255"));
+        assert!(!is_private_ip("172.32.0.0"));
+        assert!(!is_private_ip("100.64.0.1"));
+        assert!(!is_private_ip("2001:db8::1"));
+        assert!(!is_private_ip("not an address"));
+        assert_eq!(classify_direction("192.168.1.1", "8.8.8.8"), IPtype::Outgoing);
+        assert_eq!(classify_direction("8.8.8.8", "192.168.1.1"), IPtype::Incoming);
+    }
+}
@@ -1,3 +1,18 @@
+pub mod archive;
+pub mod bgp;
+pub mod bgp_lookup;
+pub mod bmp;
 pub mod influx_db;
 pub mod ip_lookup;
-pub mod cidr_lookup;
\ No newline at end of file
+pub mod cidr_lookup;
+pub mod clickhouse;
+pub mod dns;
+pub mod flow_store;
+pub mod interface_lookup;
+pub mod mrt;
+pub mod prefix_trie;
+pub mod prometheus;
+pub mod reverse_dns;
+pub mod service_lookup;
+pub mod snmp;
+pub mod threat_lookup;
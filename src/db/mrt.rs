@@ -0,0 +1,244 @@
+// Reader for MRT TABLE_DUMP_V2 RIB dumps (RFC 6396), the format route collectors
+// such as RouteViews and RIPE RIS archive their tables in.
+
+use std::fs::File;
+use std::io::{self, BufReader, Read};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+use super::bgp::{self, read_u16, read_u32, Route};
+
+const TYPE_TABLE_DUMP_V2: u16 = 13;
+const PEER_INDEX_TABLE: u16 = 1;
+const RIB_IPV4_UNICAST: u16 = 2;
+// RFC 8050, every RIB entry carries a path identifier
+const RIB_IPV4_UNICAST_ADDPATH: u16 = 8;
+const PEER_TYPE_IPV6: u8 = 0x01;
+const PEER_TYPE_AS4: u8 = 0x02;
+const HEADER_LEN: usize = 12;
+
+// A peer of the collector, RIB entries refer to it by its index
+#[derive(Debug, Clone)]
+pub struct Peer {
+    pub ip: IpAddr,
+    pub asn: u32,
+}
+
+// What a dump contained
+#[derive(Debug, Clone, Default)]
+pub struct MrtStats {
+    pub prefixes: usize,
+    pub routes: usize,
+    // IPv6 and other records we don't use
+    pub skipped_records: usize,
+    pub bad_entries: usize,
+}
+
+// Call `routes` with every IPv4 prefix of the dump and the routes the peers have to it
+pub fn read_rib(path: &str, mut routes: impl FnMut(Ipv4Addr, u8, Vec<Route>)) -> io::Result<MrtStats> {
+    let mut reader = BufReader::with_capacity(1 << 20, File::open(path)?);
+    let mut stats = MrtStats::default();
+    let mut peers: Vec<Peer> = Vec::new();
+    let mut header = [0u8; HEADER_LEN];
+    let mut body = Vec::new();
+    let mut first = true;
+
+    loop {
+        match reader.read_exact(&mut header) {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(e),
+        }
+        if first {
+            check_not_compressed(&header)?;
+            first = false;
+        }
+        let kind = u16::from_be_bytes([header[4], header[5]]);
+        let subtype = u16::from_be_bytes([header[6], header[7]]);
+        let length = u32::from_be_bytes([header[8], header[9], header[10], header[11]]) as usize;
+        body.resize(length, 0);
+        reader.read_exact(&mut body)?;
+
+        let result = match (kind, subtype) {
+            (TYPE_TABLE_DUMP_V2, PEER_INDEX_TABLE) => parse_peer_index(&body).map(|table| peers = table),
+            (TYPE_TABLE_DUMP_V2, RIB_IPV4_UNICAST) => parse_rib(&body, &peers, false, &mut stats, &mut routes),
+            (TYPE_TABLE_DUMP_V2, RIB_IPV4_UNICAST_ADDPATH) => parse_rib(&body, &peers, true, &mut stats, &mut routes),
+            _ => {
+                stats.skipped_records += 1;
+                Ok(())
+            }
+        };
+        if let Err(e) = result {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", path, e)));
+        }
+    }
+    Ok(stats)
+}
+
+// Archives are usually gzip or bzip2 compressed, say so instead of failing on garbage
+fn check_not_compressed(header: &[u8]) -> io::Result<()> {
+    let format = match header {
+        [0x1f, 0x8b, ..] => "gzip",
+        [b'B', b'Z', b'h', ..] => "bzip2",
+        _ => return Ok(()),
+    };
+    Err(io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("the MRT file is {} compressed, decompress it first", format),
+    ))
+}
+
+fn parse_peer_index(body: &[u8]) -> Result<Vec<Peer>, String> {
+    // Collector BGP ID, then the view name
+    let name_length = read_u16(body, 4)? as usize;
+    let mut pos = 6 + name_length;
+    let count = read_u16(body, pos)?;
+    pos += 2;
+
+    let mut peers = Vec::with_capacity(count as usize);
+    for _ in 0..count {
+        let kind = *body.get(pos).ok_or("truncated peer entry")?;
+        // Peer type, then the peer's BGP ID
+        pos += 5;
+        let ip = if kind & PEER_TYPE_IPV6 != 0 {
+            let octets: [u8; 16] = body.get(pos..pos + 16).ok_or("truncated peer entry")?.try_into().unwrap();
+            pos += 16;
+            IpAddr::V6(Ipv6Addr::from(octets))
+        } else {
+            let octets: [u8; 4] = body.get(pos..pos + 4).ok_or("truncated peer entry")?.try_into().unwrap();
+            pos += 4;
+            IpAddr::V4(Ipv4Addr::from(octets))
+        };
+        let asn = if kind & PEER_TYPE_AS4 != 0 {
+            pos += 4;
+            read_u32(body, pos - 4)?
+        } else {
+            pos += 2;
+            read_u16(body, pos - 2)? as u32
+        };
+        peers.push(Peer { ip, asn });
+    }
+    Ok(peers)
+}
+
+fn parse_rib(
+    body: &[u8],
+    peers: &[Peer],
+    add_path: bool,
+    stats: &mut MrtStats,
+    routes: &mut impl FnMut(Ipv4Addr, u8, Vec<Route>),
+) -> Result<(), String> {
+    // Sequence number, then the prefix
+    let ((network, length), mut pos) = bgp::read_prefix(body, 4)?;
+    let count = read_u16(body, pos)?;
+    pos += 2;
+
+    let mut found = Vec::with_capacity(count as usize);
+    for _ in 0..count {
+        let peer_index = read_u16(body, pos)? as usize;
+        // Peer index, originated time and the path identifier of ADD-PATH dumps
+        pos += if add_path { 10 } else { 6 };
+        let attributes_length = read_u16(body, pos)? as usize;
+        pos += 2;
+        let attributes = body.get(pos..pos + attributes_length).ok_or("truncated RIB entry")?;
+        pos += attributes_length;
+
+        let peer = match peers.get(peer_index) {
+            Some(peer) => peer,
+            None => {
+                stats.bad_entries += 1;
+                continue;
+            }
+        };
+        // TABLE_DUMP_V2 always stores AS paths with 4 byte ASNs
+        match bgp::parse_path_attributes(attributes, true) {
+            Ok(attributes) => found.push(Route {
+                peer_ip: peer.ip,
+                peer_as: peer.asn,
+                as_path: attributes.as_path,
+                origin_as: attributes.origin_as,
+                next_hop: attributes.next_hop,
+                communities: attributes.communities,
+            }),
+            Err(_) => stats.bad_entries += 1,
+        }
+    }
+
+    stats.prefixes += 1;
+    stats.routes += found.len();
+    routes(network, length, found);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    fn record(kind: u16, subtype: u16, body: &[u8]) -> Vec<u8> {
+        let mut out = vec![0x66, 0x00, 0x00, 0x00];
+        out.extend(kind.to_be_bytes());
+        out.extend(subtype.to_be_bytes());
+        out.extend((body.len() as u32).to_be_bytes());
+        out.extend(body);
+        out
+    }
+
+    fn rib_entry(peer_index: u16, attributes: &[u8]) -> Vec<u8> {
+        let mut out = peer_index.to_be_bytes().to_vec();
+        out.extend([0x66, 0x00, 0x00, 0x00]);
+        out.extend((attributes.len() as u16).to_be_bytes());
+        out.extend(attributes);
+        out
+    }
+
+    #[test]
+    fn reads_a_rib_dump() {
+        // Collector 192.0.2.1 with an unnamed view, an IPv4 peer with a 2 byte ASN
+        // and an IPv6 peer with a 4 byte one
+        let mut peers = vec![192, 0, 2, 1, 0, 0, 0, 2];
+        peers.extend([0x00, 10, 0, 0, 1, 10, 0, 0, 1, 0xfd, 0xe9]);
+        peers.extend([PEER_TYPE_IPV6 | PEER_TYPE_AS4, 10, 0, 0, 2]);
+        peers.extend(Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 2).octets());
+        peers.extend(4_200_000_001u32.to_be_bytes());
+
+        // AS_PATH with 4 byte ASNs, NEXT_HOP and a community
+        let mut attributes = vec![0x40, 2, 10, 2, 2];
+        attributes.extend(65001u32.to_be_bytes());
+        attributes.extend(4_200_000_002u32.to_be_bytes());
+        attributes.extend([0x40, 3, 4, 10, 0, 0, 1]);
+        attributes.extend([0xc0, 8, 4, 0xfd, 0xe9, 0x00, 0x64]);
+        let mut rib = vec![0, 0, 0, 1, 23, 198, 51, 100, 0, 3];
+        rib.extend(rib_entry(0, &attributes));
+        rib.extend(rib_entry(1, &attributes[..13]));
+        rib.extend(rib_entry(7, &attributes));
+
+        let mut dump = record(TYPE_TABLE_DUMP_V2, PEER_INDEX_TABLE, &peers);
+        dump.extend(record(TYPE_TABLE_DUMP_V2, 4, &[0; 8]));
+        dump.extend(record(TYPE_TABLE_DUMP_V2, RIB_IPV4_UNICAST, &rib));
+        let path = std::env::temp_dir().join(format!("rib-{}.mrt", std::process::id()));
+        fs::write(&path, &dump).unwrap();
+
+        let mut found = Vec::new();
+        let stats = read_rib(path.to_str().unwrap(), |network, length, routes| found.push((network, length, routes))).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!((stats.prefixes, stats.routes, stats.skipped_records, stats.bad_entries), (1, 2, 1, 1));
+        let (network, length, routes) = &found[0];
+        assert_eq!((*network, *length), (Ipv4Addr::new(198, 51, 100, 0), 23));
+        assert_eq!(routes[0].peer_ip, IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)));
+        assert_eq!(routes[0].peer_as, 65001);
+        assert_eq!(routes[0].as_path, [65001, 4_200_000_002]);
+        assert_eq!(routes[0].origin_as, Some(4_200_000_002));
+        assert_eq!(routes[0].next_hop, Some(Ipv4Addr::new(10, 0, 0, 1)));
+        assert_eq!(routes[0].communities, [0xfde9_0064]);
+        assert_eq!(routes[1].peer_ip, "2001:db8::2".parse::<IpAddr>().unwrap());
+        assert_eq!(routes[1].peer_as, 4_200_000_001);
+        assert_eq!(routes[1].next_hop, None);
+    }
+
+    #[test]
+    fn rejects_compressed_dumps() {
+        let e = check_not_compressed(&[0x1f, 0x8b, 8, 0, 0, 0, 0, 0, 0, 0, 0, 0]).unwrap_err();
+        assert!(e.to_string().contains("gzip"));
+        assert!(check_not_compressed(&record(TYPE_TABLE_DUMP_V2, PEER_INDEX_TABLE, &[])[..HEADER_LEN]).is_ok());
+    }
+}
@@ -0,0 +1,113 @@
+use std::net::Ipv4Addr;
+
+// Node of the binary trie, children are indexes into `PrefixTrie::nodes`
+#[derive(Debug, Clone)]
+struct Node<T> {
+    children: [Option<u32>; 2],
+    value: Option<T>,
+}
+
+impl<T> Node<T> {
+    fn empty() -> Self {
+        Node { children: [None, None], value: None }
+    }
+}
+
+// IPv4 prefixes to values with longest prefix match, one bit per level
+#[derive(Debug, Clone)]
+pub struct PrefixTrie<T> {
+    nodes: Vec<Node<T>>,
+    len: usize,
+}
+
+impl<T> Default for PrefixTrie<T> {
+    fn default() -> Self {
+        PrefixTrie { nodes: vec![Node::empty()], len: 0 }
+    }
+}
+
+fn bit(addr: u32, depth: u8) -> usize {
+    ((addr >> (31 - depth)) & 1) as usize
+}
+
+impl<T> PrefixTrie<T> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Prefixes with a value
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    // Set the value of network/length, returns the value it replaces
+    pub fn insert(&mut self, network: Ipv4Addr, length: u8, value: T) -> Option<T> {
+        let addr = u32::from(network);
+        let mut node = 0;
+        for depth in 0..length.min(32) {
+            let side = bit(addr, depth);
+            node = match self.nodes[node].children[side] {
+                Some(child) => child as usize,
+                None => {
+                    self.nodes.push(Node::empty());
+                    let child = self.nodes.len() - 1;
+                    self.nodes[node].children[side] = Some(child as u32);
+                    child
+                }
+            };
+        }
+        let old = self.nodes[node].value.replace(value);
+        if old.is_none() {
+            self.len += 1;
+        }
+        old
+    }
+
+    // Remove the value of exactly network/length. The nodes stay, a withdrawn
+    // prefix is usually announced again.
+    pub fn remove(&mut self, network: Ipv4Addr, length: u8) -> Option<T> {
+        let node = self.find(network, length)?;
+        let old = self.nodes[node].value.take();
+        if old.is_some() {
+            self.len -= 1;
+        }
+        old
+    }
+
+    pub fn get(&self, network: Ipv4Addr, length: u8) -> Option<&T> {
+        self.nodes[self.find(network, length)?].value.as_ref()
+    }
+
+    // Value of the most specific prefix containing the address, with that prefix
+    pub fn longest_match(&self, ip: Ipv4Addr) -> Option<(Ipv4Addr, u8, &T)> {
+        let addr = u32::from(ip);
+        let mut node = 0;
+        let mut found = self.nodes[0].value.as_ref().map(|value| (0, value));
+        for depth in 0..32 {
+            node = match self.nodes[node].children[bit(addr, depth)] {
+                Some(child) => child as usize,
+                None => break,
+            };
+            if let Some(value) = &self.nodes[node].value {
+                found = Some((depth + 1, value));
+            }
+        }
+        found.map(|(length, value)| {
+            let mask = u32::MAX.checked_shl(32 - length as u32).unwrap_or(0);
+            (Ipv4Addr::from(addr & mask), length, value)
+        })
+    }
+
+    fn find(&self, network: Ipv4Addr, length: u8) -> Option<usize> {
+        let addr = u32::from(network);
+        let mut node = 0;
+        for depth in 0..length.min(32) {
+            node = self.nodes[node].children[bit(addr, depth)]? as usize;
+        }
+        Some(node)
+    }
+}
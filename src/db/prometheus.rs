@@ -0,0 +1,508 @@
+// Traffic counters for Prometheus, served for scrapes and pushed with remote-write.
+// Counters only go up, `rate()` turns them into windows of any length.
+
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+use super::ip_lookup::IPtype;
+use crate::process::flow::EnrichedFlow;
+use crate::transport::{consume_windows, Done, SharedTransport, Windowed, ENRICHER_TO_TSDB};
+
+pub const PROMETHEUS_GROUP: &str = "ta-prometheus";
+pub const PROMETHEUS_DIMENSIONS: [&str; 3] = ["country", "asn", "service"];
+// Label value the traffic of series over the limit is counted under
+const OTHER: &str = "other";
+const MAX_REQUEST_LEN: usize = 8192;
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct PrometheusConfig {
+    // Serve /metrics here, no endpoint when not set
+    pub listen: Option<SocketAddr>,
+    pub remote_write: Option<RemoteWriteConfig>,
+    // Labels with a counter family each, besides the per direction totals
+    pub dimensions: Vec<String>,
+    // Series per dimension and direction, the traffic of new values goes to "other" beyond that
+    pub max_series: usize,
+    // Series without traffic for this long are dropped and free their slot
+    pub expire_secs: u64,
+}
+
+impl Default for PrometheusConfig {
+    fn default() -> Self {
+        PrometheusConfig {
+            listen: Some("0.0.0.0:9464".parse().unwrap()),
+            remote_write: None,
+            dimensions: PROMETHEUS_DIMENSIONS.iter().map(|d| d.to_string()).collect(),
+            max_series: 500,
+            expire_secs: 3600,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct RemoteWriteConfig {
+    // e.g. http://prometheus:9090/api/v1/write
+    pub url: String,
+    pub interval_secs: u64,
+    pub bearer_token: Option<String>,
+    pub timeout_secs: u64,
+}
+
+impl Default for RemoteWriteConfig {
+    fn default() -> Self {
+        RemoteWriteConfig {
+            url: String::new(),
+            interval_secs: 60,
+            bearer_token: None,
+            timeout_secs: 10,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+struct Counter {
+    bytes: u64,
+    packets: u64,
+    flows: u64,
+    // Only set for the asn dimension
+    as_name: Option<String>,
+    updated: Option<Instant>,
+}
+
+// One sample of the exposition, labels sorted by name
+#[derive(Debug, Clone, PartialEq)]
+pub struct Sample {
+    pub name: String,
+    pub labels: Vec<(String, String)>,
+    pub value: f64,
+}
+
+// Counters per direction and per dimension value, with a series limit per dimension
+#[derive(Debug)]
+pub struct TrafficMetrics {
+    config: PrometheusConfig,
+    totals: BTreeMap<&'static str, Counter>,
+    series: BTreeMap<(String, &'static str), BTreeMap<String, Counter>>,
+    overflow: BTreeMap<String, u64>,
+}
+
+fn direction_label(direction: IPtype) -> &'static str {
+    match direction {
+        IPtype::Incoming => "in",
+        IPtype::Outgoing => "out",
+    }
+}
+
+impl Counter {
+    fn add(&mut self, flow: &EnrichedFlow, now: Instant) {
+        self.bytes += flow.fields.bytes;
+        self.packets += flow.fields.packets;
+        self.flows += 1;
+        self.updated = Some(now);
+    }
+}
+
+impl TrafficMetrics {
+    pub fn new(config: PrometheusConfig) -> Self {
+        TrafficMetrics {
+            config,
+            totals: BTreeMap::new(),
+            series: BTreeMap::new(),
+            overflow: BTreeMap::new(),
+        }
+    }
+
+    pub fn observe(&mut self, flow: &EnrichedFlow) {
+        let direction = match flow.direction() {
+            Some(direction) => direction,
+            None => return,
+        };
+        // The remote side of the flow is the source of incoming and the destination of outgoing traffic
+        let remote = match direction {
+            IPtype::Incoming => "src",
+            IPtype::Outgoing => "dst",
+        };
+        let direction = direction_label(direction);
+        let now = Instant::now();
+        self.totals.entry(direction).or_default().add(flow, now);
+
+        for dimension in &self.config.dimensions {
+            let value = match dimension.as_str() {
+                "country" => flow.tag(&format!("{}_country", remote)),
+                "asn" => flow.tag(&format!("{}_asn", remote)),
+                _ => flow.tag(dimension),
+            };
+            let value = value.filter(|v| !v.is_empty()).unwrap_or("Unknown");
+            let series = self.series.entry((dimension.clone(), direction)).or_default();
+            let key = if series.contains_key(value) || series.len() < self.config.max_series {
+                value
+            } else {
+                *self.overflow.entry(dimension.clone()).or_default() += 1;
+                OTHER
+            };
+            let counter = series.entry(key.to_string()).or_default();
+            if dimension == "asn" && counter.as_name.is_none() && key != OTHER {
+                counter.as_name = flow.tag(&format!("{}_as_name", remote)).map(|name| name.to_string());
+            }
+            counter.add(flow, now);
+        }
+    }
+
+    // Drop the series that saw no traffic for `expire_secs`, returns how many
+    pub fn expire(&mut self) -> usize {
+        let expire = Duration::from_secs(self.config.expire_secs);
+        let mut expired = 0;
+        for series in self.series.values_mut() {
+            let before = series.len();
+            series.retain(|_, counter| counter.updated.is_none_or(|updated| updated.elapsed() < expire));
+            expired += before - series.len();
+        }
+        expired
+    }
+
+    pub fn samples(&self) -> Vec<Sample> {
+        let mut samples = Vec::new();
+        for (direction, counter) in &self.totals {
+            let labels = vec![("direction".to_string(), direction.to_string())];
+            push_counter(&mut samples, "ta_traffic", &labels, counter);
+        }
+        for ((dimension, direction), series) in &self.series {
+            for (value, counter) in series {
+                let mut labels = Vec::new();
+                if let Some(name) = &counter.as_name {
+                    labels.push(("as_name".to_string(), name.clone()));
+                }
+                labels.push((dimension.clone(), value.clone()));
+                labels.push(("direction".to_string(), direction.to_string()));
+                labels.sort();
+                push_counter(&mut samples, &format!("ta_{}", dimension), &labels, counter);
+            }
+        }
+        for (dimension, flows) in &self.overflow {
+            samples.push(Sample {
+                name: "ta_series_overflow_flows_total".to_string(),
+                labels: vec![("dimension".to_string(), dimension.clone())],
+                value: *flows as f64,
+            });
+        }
+        // Every family in one block, series keep their order
+        samples.sort_by(|a, b| a.name.cmp(&b.name));
+        samples
+    }
+}
+
+fn push_counter(samples: &mut Vec<Sample>, family: &str, labels: &[(String, String)], counter: &Counter) {
+    for (unit, value) in [("bytes", counter.bytes), ("packets", counter.packets), ("flows", counter.flows)] {
+        samples.push(Sample {
+            name: format!("{}_{}_total", family, unit),
+            labels: labels.to_vec(),
+            value: value as f64,
+        });
+    }
+}
+
+// Text exposition, OpenMetrics when the scraper asked for it
+pub fn render(samples: &[Sample], openmetrics: bool) -> String {
+    let mut text = String::new();
+    let mut family = "";
+    for sample in samples {
+        let name = sample.name.strip_suffix("_total").unwrap_or(&sample.name);
+        if name != family {
+            family = name;
+            let declared = if openmetrics { name } else { &sample.name };
+            let _ = writeln!(text, "# TYPE {} counter", declared);
+        }
+        text.push_str(&sample.name);
+        if !sample.labels.is_empty() {
+            let labels: Vec<String> = sample
+                .labels
+                .iter()
+                .map(|(name, value)| format!("{}=\"{}\"", name, escape_label(value)))
+                .collect();
+            let _ = write!(text, "{{{}}}", labels.join(","));
+        }
+        let _ = writeln!(text, " {}", sample.value);
+    }
+    if openmetrics {
+        text.push_str("# EOF\n");
+    }
+    text
+}
+
+fn escape_label(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+// Count the enriched flows and serve or push the counters
+pub async fn run(transport: SharedTransport, config: PrometheusConfig) {
+    let metrics = Arc::new(Mutex::new(TrafficMetrics::new(config.clone())));
+    if let Some(listen) = config.listen {
+        tokio::spawn(run_endpoint(metrics.clone(), listen));
+    }
+    if let Some(remote_write) = config.remote_write.clone() {
+        tokio::spawn(run_remote_write(metrics.clone(), remote_write));
+    }
+
+    let mut subscription = transport
+        .subscribe(ENRICHER_TO_TSDB, PROMETHEUS_GROUP)
+        .await
+        .expect("Can't subscribe to specified topic");
+
+    consume_windows(subscription.as_mut(), Duration::from_secs(10), &mut Counting(metrics)).await;
+}
+
+struct Counting(Arc<Mutex<TrafficMetrics>>);
+
+#[async_trait]
+impl Windowed for Counting {
+    type Item = EnrichedFlow;
+
+    fn observe(&mut self, flow: EnrichedFlow, _offset: i64) {
+        self.0.lock().unwrap().observe(&flow);
+    }
+
+    async fn flush(&mut self) -> Done {
+        self.0.lock().unwrap().expire();
+        // Counters restart from zero anyway, Prometheus handles the reset
+        Done::All
+    }
+}
+
+async fn run_endpoint(metrics: Arc<Mutex<TrafficMetrics>>, listen: SocketAddr) {
+    let listener = match TcpListener::bind(listen).await {
+        Ok(listener) => listener,
+        Err(e) => {
+            println!("Can't serve Prometheus metrics on {}: {}", listen, e);
+            return;
+        }
+    };
+    println!("Serving Prometheus metrics on http://{}/metrics", listen);
+
+    loop {
+        let (stream, _) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                println!("Prometheus accept failed: {}", e);
+                continue;
+            }
+        };
+        let metrics = metrics.clone();
+        tokio::spawn(async move {
+            if let Err(e) = serve_scrape(stream, &metrics).await {
+                println!("Prometheus scrape failed: {}", e);
+            }
+        });
+    }
+}
+
+async fn serve_scrape(mut stream: TcpStream, metrics: &Mutex<TrafficMetrics>) -> std::io::Result<()> {
+    let mut request = Vec::new();
+    let mut buffer = [0u8; 1024];
+    while !request.windows(4).any(|w| w == b"\r\n\r\n") && request.len() < MAX_REQUEST_LEN {
+        let read = stream.read(&mut buffer).await?;
+        if read == 0 {
+            break;
+        }
+        request.extend_from_slice(&buffer[..read]);
+    }
+    let request = String::from_utf8_lossy(&request);
+    let path = request.split_whitespace().nth(1).unwrap_or("");
+
+    let response = if request.starts_with("GET ") && (path == "/metrics" || path.starts_with("/metrics?")) {
+        let openmetrics = request
+            .lines()
+            .any(|line| line.to_ascii_lowercase().starts_with("accept:") && line.contains("application/openmetrics-text"));
+        let body = render(&metrics.lock().unwrap().samples(), openmetrics);
+        let content_type = if openmetrics {
+            "application/openmetrics-text; version=1.0.0; charset=utf-8"
+        } else {
+            "text/plain; version=0.0.4; charset=utf-8"
+        };
+        format!(
+            "HTTP/1.1 200 OK\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            content_type,
+            body.len(),
+            body
+        )
+    } else {
+        "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_string()
+    };
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+async fn run_remote_write(metrics: Arc<Mutex<TrafficMetrics>>, config: RemoteWriteConfig) {
+    let client = match reqwest::Client::builder().timeout(Duration::from_secs(config.timeout_secs.max(1))).build() {
+        Ok(client) => client,
+        Err(e) => {
+            println!("Remote-write disabled: {}", e);
+            return;
+        }
+    };
+    println!("Pushing Prometheus metrics to {} every {}s", config.url, config.interval_secs);
+
+    let mut ticker = tokio::time::interval(Duration::from_secs(config.interval_secs.max(1)));
+    loop {
+        ticker.tick().await;
+        let samples = metrics.lock().unwrap().samples();
+        if samples.is_empty() {
+            continue;
+        }
+        let body = snappy_literal(&write_request(&samples, Utc::now().timestamp_millis()));
+        let mut request = client
+            .post(&config.url)
+            .header("Content-Encoding", "snappy")
+            .header("Content-Type", "application/x-protobuf")
+            .header("X-Prometheus-Remote-Write-Version", "0.1.0")
+            .body(body);
+        if let Some(token) = &config.bearer_token {
+            request = request.bearer_auth(token);
+        }
+        // Counters are cumulative, a failed push is made up for by the next one
+        match request.send().await {
+            Ok(response) if response.status().is_success() => {}
+            Ok(response) => println!("Remote-write to {} answered {}", config.url, response.status()),
+            Err(e) => println!("Remote-write to {} failed: {}", config.url, e),
+        }
+    }
+}
+
+// prometheus.WriteRequest protobuf, one sample per series
+pub fn write_request(samples: &[Sample], timestamp_ms: i64) -> Vec<u8> {
+    let mut request = Vec::new();
+    for sample in samples {
+        let mut series = Vec::new();
+        let mut labels = vec![("__name__", sample.name.as_str())];
+        labels.extend(sample.labels.iter().map(|(name, value)| (name.as_str(), value.as_str())));
+        labels.sort();
+        for (name, value) in labels {
+            let mut label = Vec::new();
+            put_bytes(&mut label, 1, name.as_bytes());
+            put_bytes(&mut label, 2, value.as_bytes());
+            put_bytes(&mut series, 1, &label);
+        }
+        let mut point = Vec::new();
+        // value, a double
+        point.push(1 << 3 | 1);
+        point.extend_from_slice(&sample.value.to_le_bytes());
+        // timestamp, a varint
+        point.push(2 << 3);
+        put_varint(&mut point, timestamp_ms as u64);
+        put_bytes(&mut series, 2, &point);
+        put_bytes(&mut request, 1, &series);
+    }
+    request
+}
+
+fn put_varint(out: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        out.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+fn put_bytes(out: &mut Vec<u8>, field: u8, bytes: &[u8]) {
+    out.push(field << 3 | 2);
+    put_varint(out, bytes.len() as u64);
+    out.extend_from_slice(bytes);
+}
+
+// Snappy block format made of literals only. Remote-write requires snappy framing
+// but not compression, and the requests are small.
+pub fn snappy_literal(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(data.len() + data.len() / 65536 * 3 + 8);
+    put_varint(&mut out, data.len() as u64);
+    for chunk in data.chunks(65536) {
+        let length = chunk.len() - 1;
+        if length < 60 {
+            out.push((length as u8) << 2);
+        } else {
+            // Tag 61, the length follows in two little endian bytes
+            out.push(61 << 2);
+            out.extend_from_slice(&(length as u16).to_le_bytes());
+        }
+        out.extend_from_slice(chunk);
+    }
+    out
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encodes_a_write_request() {
+        let sample = Sample {
+            name: "ta_traffic_bytes_total".to_string(),
+            labels: vec![("direction".to_string(), "in".to_string())],
+            value: 1.0,
+        };
+        let mut expected = vec![0x0a, 0x43];
+        // Labels sorted by name, __name__ first
+        expected.extend([0x0a, 0x22, 0x0a, 0x08]);
+        expected.extend(b"__name__");
+        expected.extend([0x12, 0x16]);
+        expected.extend(b"ta_traffic_bytes_total");
+        expected.extend([0x0a, 0x0f, 0x0a, 0x09]);
+        expected.extend(b"direction");
+        expected.extend([0x12, 0x02]);
+        expected.extend(b"in");
+        // Value 1.0 and timestamp 1000 as a two byte varint
+        expected.extend([0x12, 0x0c, 0x09, 0, 0, 0, 0, 0, 0, 0xf0, 0x3f, 0x10, 0xe8, 0x07]);
+        assert_eq!(write_request(&[sample], 1000), expected);
+        assert!(write_request(&[], 1000).is_empty());
+    }
+
+    // Decoder for the literals `snappy_literal` writes
+    fn unsnappy(mut data: &[u8]) -> Vec<u8> {
+        let mut length = 0usize;
+        let mut shift = 0;
+        while let [byte, rest @ ..] = data {
+            data = rest;
+            length |= ((byte & 0x7f) as usize) << shift;
+            shift += 7;
+            if byte & 0x80 == 0 {
+                break;
+            }
+        }
+        let mut out = Vec::with_capacity(length);
+        while let [tag, rest @ ..] = data {
+            assert_eq!(tag & 0x03, 0, "not a literal");
+            let (literal, rest) = match tag >> 2 {
+                60 => (rest[0] as usize + 1, &rest[1..]),
+                61 => (u16::from_le_bytes([rest[0], rest[1]]) as usize + 1, &rest[2..]),
+                short => (short as usize + 1, rest),
+            };
+            out.extend(&rest[..literal]);
+            data = &rest[literal..];
+        }
+        assert_eq!(out.len(), length);
+        out
+    }
+
+    #[test]
+    fn snappy_literals() {
+        assert_eq!(snappy_literal(b"abc"), [0x03, 0x08, b'a', b'b', b'c']);
+        let long: Vec<u8> = (0..100u8).collect();
+        assert_eq!(snappy_literal(&long)[..4], [0x64, 0xf4, 0x63, 0x00]);
+        // Literals of 64 KiB at most
+        let large: Vec<u8> = (0..70_000u32).map(|i| i as u8).collect();
+        let compressed = snappy_literal(&large);
+        assert_eq!(compressed[..6], [0xf0, 0xa2, 0x04, 0xf4, 0xff, 0xff]);
+        assert_eq!(unsnappy(&compressed), large);
+        assert_eq!(unsnappy(&snappy_literal(&long)), long);
+    }
+}
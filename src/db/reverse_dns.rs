@@ -0,0 +1,163 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::Semaphore;
+
+use super::dns::{self, PtrAnswer};
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct ReverseDnsConfig {
+    // Recursive resolver to ask, the first nameserver of /etc/resolv.conf when not set
+    pub resolver: Option<SocketAddr>,
+    // Addresses remembered, least recently used ones are dropped first
+    pub cache_size: usize,
+    // Record TTLs are kept within these bounds
+    pub min_ttl_secs: u64,
+    pub max_ttl_secs: u64,
+    // How long an address without PTR record or whose query failed is not asked again
+    pub negative_ttl_secs: u64,
+    // Queries in flight at once, addresses seen while all are busy are asked later
+    pub concurrency: usize,
+    pub timeout_ms: u64,
+}
+
+impl Default for ReverseDnsConfig {
+    fn default() -> Self {
+        ReverseDnsConfig {
+            resolver: None,
+            cache_size: 100_000,
+            min_ttl_secs: 60,
+            max_ttl_secs: 86_400,
+            negative_ttl_secs: 300,
+            concurrency: 64,
+            timeout_ms: 1000,
+        }
+    }
+}
+
+struct CacheEntry {
+    // None when the address has no name
+    name: Option<String>,
+    expires: Instant,
+    used: u64,
+}
+
+// Least recently used cache of host names with per entry expiry
+struct LruCache {
+    capacity: usize,
+    entries: HashMap<Ipv4Addr, CacheEntry>,
+    // Last use to address, the first entry is the least recently used
+    order: BTreeMap<u64, Ipv4Addr>,
+    clock: u64,
+}
+
+impl LruCache {
+    fn new(capacity: usize) -> Self {
+        LruCache {
+            capacity: capacity.max(1),
+            entries: HashMap::new(),
+            order: BTreeMap::new(),
+            clock: 0,
+        }
+    }
+
+    // Some(name) for a fresh entry, None when the address has to be asked
+    fn get(&mut self, ip: Ipv4Addr, now: Instant) -> Option<Option<String>> {
+        self.clock += 1;
+        let entry = self.entries.get_mut(&ip)?;
+        if entry.expires <= now {
+            self.order.remove(&entry.used);
+            self.entries.remove(&ip);
+            return None;
+        }
+        self.order.remove(&entry.used);
+        entry.used = self.clock;
+        self.order.insert(self.clock, ip);
+        Some(entry.name.clone())
+    }
+
+    fn insert(&mut self, ip: Ipv4Addr, name: Option<String>, expires: Instant) {
+        self.clock += 1;
+        if let Some(old) = self.entries.remove(&ip) {
+            self.order.remove(&old.used);
+        }
+        while self.entries.len() >= self.capacity {
+            match self.order.pop_first() {
+                Some((_, oldest)) => {
+                    self.entries.remove(&oldest);
+                }
+                None => break,
+            }
+        }
+        self.entries.insert(ip, CacheEntry { name, expires, used: self.clock });
+        self.order.insert(self.clock, ip);
+    }
+}
+
+// Host names of addresses, resolved in the background. A lookup never waits for
+// DNS: unknown addresses are queued and get their name on a later flow.
+#[derive(Clone)]
+pub struct ReverseDns {
+    config: ReverseDnsConfig,
+    resolver: Option<SocketAddr>,
+    cache: Arc<Mutex<LruCache>>,
+    pending: Arc<Mutex<HashSet<Ipv4Addr>>>,
+    permits: Arc<Semaphore>,
+}
+
+impl ReverseDns {
+    pub fn new(config: &ReverseDnsConfig) -> Self {
+        let resolver = config.resolver.or_else(dns::system_resolver);
+        match resolver {
+            Some(resolver) => println!("Resolving host names through {}", resolver),
+            None => println!("No DNS resolver configured or in /etc/resolv.conf, host names are disabled"),
+        }
+        ReverseDns {
+            config: config.clone(),
+            resolver,
+            cache: Arc::new(Mutex::new(LruCache::new(config.cache_size))),
+            pending: Arc::new(Mutex::new(HashSet::new())),
+            permits: Arc::new(Semaphore::new(config.concurrency.max(1))),
+        }
+    }
+
+    // The cached name of an address; starts resolving it when it isn't cached.
+    // Must be called from within the tokio runtime.
+    pub fn lookup(&self, ip: &str) -> Option<String> {
+        let ip: Ipv4Addr = ip.parse().ok()?;
+        let resolver = self.resolver?;
+        if let Some(name) = self.cache.lock().unwrap().get(ip, Instant::now()) {
+            return name;
+        }
+
+        // Skip the address for now when every query slot is busy
+        let permit = self.permits.clone().try_acquire_owned().ok()?;
+        if !self.pending.lock().unwrap().insert(ip) {
+            return None;
+        }
+        let this = self.clone();
+        tokio::spawn(async move {
+            let timeout = Duration::from_millis(this.config.timeout_ms);
+            let (name, ttl) = match dns::lookup_ptr(resolver, ip, timeout).await {
+                Ok(PtrAnswer::Name(name, ttl)) => {
+                    let ttl = (ttl as u64).clamp(this.config.min_ttl_secs, this.config.max_ttl_secs.max(this.config.min_ttl_secs));
+                    (Some(name), ttl)
+                }
+                Ok(PtrAnswer::NotFound) => (None, this.config.negative_ttl_secs),
+                Err(e) => {
+                    println!("PTR lookup of {} failed: {}", ip, e);
+                    (None, this.config.negative_ttl_secs)
+                }
+            };
+            let expires = Instant::now() + Duration::from_secs(ttl);
+            this.cache.lock().unwrap().insert(ip, name, expires);
+            this.pending.lock().unwrap().remove(&ip);
+            drop(permit);
+        });
+        None
+    }
+}
@@ -0,0 +1,166 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
+
+use serde::{Deserialize, Serialize};
+
+// Ports at or above this are dynamic/ephemeral and never assigned by IANA
+const EPHEMERAL_PORT_START: u16 = 49152;
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct ServiceConfig {
+    // IANA service-names-port-numbers.csv
+    pub iana_file: String,
+    // Local names that win over the registry, keyed "<protocol>/<port>", e.g. "tcp/8443: admin-ui"
+    pub overrides: BTreeMap<String, String>,
+}
+
+impl Default for ServiceConfig {
+    fn default() -> Self {
+        ServiceConfig {
+            iana_file: "map/service-names-port-numbers.csv".to_string(),
+            overrides: BTreeMap::new(),
+        }
+    }
+}
+
+// The service a flow belongs to, picked from its source or destination port
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Service {
+    pub name: String,
+    pub port: u16,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ServiceLookup {
+    // (IP protocol number, port) to service name
+    services: HashMap<(u8, u16), String>,
+}
+
+impl ServiceLookup {
+    pub fn new(config: &ServiceConfig) -> Self {
+        let mut services = Self::load_iana_csv(&config.iana_file);
+
+        for (key, name) in &config.overrides {
+            match parse_override_key(key) {
+                Some(key) => {
+                    services.insert(key, name.clone());
+                }
+                None => println!("Ignoring service override {:?}, expected <protocol>/<port>", key),
+            }
+        }
+        ServiceLookup { services }
+    }
+
+    // Load the IANA registry. Columns are
+    // Service Name, Port Number, Transport Protocol, Description, ...
+    // and a port number can be a range like "6000-6063".
+    fn load_iana_csv(file: &str) -> HashMap<(u8, u16), String> {
+        let mut map = HashMap::new();
+        let file = match File::open(file) {
+            Ok(f) => f,
+            Err(e) => {
+                println!("Can't read {}: {}", file, e);
+                return map;
+            }
+        };
+
+        let mut reader = csv::ReaderBuilder::new().flexible(true).from_reader(file);
+        for row in reader.records().map_while(Result::ok) {
+            let (name, ports, protocol) = match (row.get(0), row.get(1), row.get(2)) {
+                (Some(name), Some(ports), Some(protocol)) => (name.trim(), ports.trim(), protocol.trim()),
+                _ => continue,
+            };
+            let protocol = match protocol_number(protocol) {
+                Some(p) => p,
+                None => continue,
+            };
+            // Unassigned and reserved ports have no service name
+            if name.is_empty() {
+                continue;
+            }
+
+            let range = match ports.split_once('-') {
+                Some((start, end)) => (start.parse::<u16>(), end.parse::<u16>()),
+                None => (ports.parse::<u16>(), ports.parse::<u16>()),
+            };
+            if let (Ok(start), Ok(end)) = range {
+                for port in start..=end {
+                    // The registry lists the primary name first
+                    map.entry((protocol, port)).or_insert_with(|| name.to_string());
+                }
+            }
+        }
+        map
+    }
+
+    pub fn lookup(&self, protocol: u8, port: u16) -> Option<&String> {
+        self.services.get(&(protocol, port))
+    }
+
+    pub fn len(&self) -> usize {
+        self.services.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.services.is_empty()
+    }
+
+    // Pick the well known side of a connection: the port that is in the registry,
+    // or the lower one when both or neither are. Returns None for portless protocols.
+    pub fn classify(&self, protocol: u8, src_port: u16, dst_port: u16) -> Option<Service> {
+        if src_port == 0 && dst_port == 0 {
+            return None;
+        }
+
+        let known = |port: u16| if port < EPHEMERAL_PORT_START { self.lookup(protocol, port) } else { None };
+        let (port, name) = match (known(src_port), known(dst_port)) {
+            (Some(src), Some(dst)) => if src_port <= dst_port { (src_port, Some(src)) } else { (dst_port, Some(dst)) },
+            (Some(src), None) => (src_port, Some(src)),
+            (None, Some(dst)) => (dst_port, Some(dst)),
+            (None, None) => (src_port.min(dst_port), None),
+        };
+
+        Some(Service {
+            name: name.cloned().unwrap_or_else(|| "Unknown".to_string()),
+            port,
+        })
+    }
+}
+
+fn parse_override_key(key: &str) -> Option<(u8, u16)> {
+    let (protocol, port) = key.split_once('/')?;
+    Some((protocol_number(protocol.trim())?, port.trim().parse().ok()?))
+}
+
+// Transport protocols that have port numbers
+fn protocol_number(name: &str) -> Option<u8> {
+    match name.to_ascii_lowercase().as_str() {
+        "tcp" => Some(6),
+        "udp" => Some(17),
+        "dccp" => Some(33),
+        "sctp" => Some(132),
+        _ => None,
+    }
+}
+
+// Human readable name of an IP protocol number
+pub fn protocol_name(protocol: u8) -> String {
+    match protocol {
+        1 => "icmp".to_string(),
+        2 => "igmp".to_string(),
+        6 => "tcp".to_string(),
+        17 => "udp".to_string(),
+        33 => "dccp".to_string(),
+        41 => "ipv6".to_string(),
+        47 => "gre".to_string(),
+        50 => "esp".to_string(),
+        51 => "ah".to_string(),
+        58 => "ipv6-icmp".to_string(),
+        89 => "ospf".to_string(),
+        103 => "pim".to_string(),
+        112 => "vrrp".to_string(),
+        132 => "sctp".to_string(),
+        other => other.to_string(),
+    }
+}
@@ -0,0 +1,362 @@
+// Just enough SNMPv2c to walk a table: BER encoding of GetBulk requests and
+// decoding of the responses.
+
+use std::io;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use tokio::net::UdpSocket;
+
+const TAG_INTEGER: u8 = 0x02;
+const TAG_OCTET_STRING: u8 = 0x04;
+const TAG_NULL: u8 = 0x05;
+const TAG_OID: u8 = 0x06;
+const TAG_SEQUENCE: u8 = 0x30;
+const TAG_IP_ADDRESS: u8 = 0x40;
+const TAG_COUNTER32: u8 = 0x41;
+const TAG_GAUGE32: u8 = 0x42;
+const TAG_TIMETICKS: u8 = 0x43;
+const TAG_COUNTER64: u8 = 0x46;
+const TAG_NO_SUCH_OBJECT: u8 = 0x80;
+const TAG_NO_SUCH_INSTANCE: u8 = 0x81;
+const TAG_END_OF_MIB_VIEW: u8 = 0x82;
+const TAG_GET_RESPONSE: u8 = 0xA2;
+const TAG_GET_BULK: u8 = 0xA5;
+
+const SNMP_V2C: i64 = 1;
+const MAX_REPETITIONS: i64 = 25;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SnmpValue {
+    Integer(i64),
+    OctetString(Vec<u8>),
+    Unsigned(u64),
+    Oid(Vec<u32>),
+    Null,
+    EndOfMibView,
+    NoSuchObject,
+    Other(u8),
+}
+
+impl SnmpValue {
+    pub fn as_u64(&self) -> Option<u64> {
+        match self {
+            SnmpValue::Integer(n) if *n >= 0 => Some(*n as u64),
+            SnmpValue::Unsigned(n) => Some(*n),
+            _ => None,
+        }
+    }
+
+    pub fn as_string(&self) -> Option<String> {
+        match self {
+            SnmpValue::OctetString(s) => Some(String::from_utf8_lossy(s).to_string()),
+            _ => None,
+        }
+    }
+}
+
+pub fn parse_oid(oid: &str) -> Option<Vec<u32>> {
+    oid.trim_start_matches('.').split('.').map(|n| n.parse().ok()).collect()
+}
+
+// Walk every object below `base` on an SNMPv2c agent
+pub async fn walk(target: SocketAddr, community: &str, base: &[u32], timeout: Duration) -> io::Result<Vec<(Vec<u32>, SnmpValue)>> {
+    let socket = UdpSocket::bind(if target.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" }).await?;
+    socket.connect(target).await?;
+
+    let mut results = Vec::new();
+    let mut next = base.to_vec();
+    let mut request_id: i64 = rand_request_id();
+    let mut buf = vec![0u8; 65535];
+
+    loop {
+        request_id = (request_id + 1) & 0x7fff_ffff;
+        let request = encode_get_bulk(community, request_id, &next);
+
+        // One retry before giving up on the agent
+        let mut response = None;
+        for _ in 0..2 {
+            socket.send(&request).await?;
+            match tokio::time::timeout(timeout, socket.recv(&mut buf)).await {
+                Ok(Ok(len)) => match decode_response(&buf[..len]) {
+                    Ok((id, varbinds)) if id == request_id => {
+                        response = Some(varbinds);
+                        break;
+                    }
+                    Ok(_) => continue,
+                    Err(e) => return Err(io::Error::new(io::ErrorKind::InvalidData, e)),
+                },
+                Ok(Err(e)) => return Err(e),
+                Err(_) => continue,
+            }
+        }
+        let varbinds = response.ok_or_else(|| io::Error::new(io::ErrorKind::TimedOut, format!("no SNMP response from {}", target)))?;
+
+        if varbinds.is_empty() {
+            return Ok(results);
+        }
+        for (oid, value) in varbinds {
+            if !oid.starts_with(base) || oid <= next || matches!(value, SnmpValue::EndOfMibView) {
+                return Ok(results);
+            }
+            next = oid.clone();
+            results.push((oid, value));
+        }
+    }
+}
+
+fn rand_request_id() -> i64 {
+    (uuid::Uuid::new_v4().as_u128() & 0x3fff_ffff) as i64
+}
+
+fn encode_get_bulk(community: &str, request_id: i64, oid: &[u32]) -> Vec<u8> {
+    let varbind = encode_tlv(TAG_SEQUENCE, &[encode_oid(oid), encode_tlv(TAG_NULL, &[])].concat());
+    let pdu = [
+        encode_integer(request_id),
+        encode_integer(0), // non-repeaters
+        encode_integer(MAX_REPETITIONS),
+        encode_tlv(TAG_SEQUENCE, &varbind),
+    ]
+    .concat();
+    let message = [
+        encode_integer(SNMP_V2C),
+        encode_tlv(TAG_OCTET_STRING, community.as_bytes()),
+        encode_tlv(TAG_GET_BULK, &pdu),
+    ]
+    .concat();
+    encode_tlv(TAG_SEQUENCE, &message)
+}
+
+fn encode_tlv(tag: u8, value: &[u8]) -> Vec<u8> {
+    let mut out = vec![tag];
+    let len = value.len();
+    if len < 0x80 {
+        out.push(len as u8);
+    } else {
+        let bytes: Vec<u8> = len.to_be_bytes().into_iter().skip_while(|b| *b == 0).collect();
+        out.push(0x80 | bytes.len() as u8);
+        out.extend(bytes);
+    }
+    out.extend_from_slice(value);
+    out
+}
+
+fn encode_integer(n: i64) -> Vec<u8> {
+    let bytes = n.to_be_bytes();
+    // Drop leading bytes that only repeat the sign
+    let mut start = 0;
+    while start < 7 && ((bytes[start] == 0 && bytes[start + 1] & 0x80 == 0) || (bytes[start] == 0xff && bytes[start + 1] & 0x80 != 0)) {
+        start += 1;
+    }
+    encode_tlv(TAG_INTEGER, &bytes[start..])
+}
+
+fn encode_oid(oid: &[u32]) -> Vec<u8> {
+    let mut out = Vec::new();
+    if oid.len() >= 2 {
+        out.push((oid[0] * 40 + oid[1]) as u8);
+    }
+    for &arc in oid.iter().skip(2) {
+        let mut chunk = vec![(arc & 0x7f) as u8];
+        let mut rest = arc >> 7;
+        while rest > 0 {
+            chunk.push(0x80 | (rest & 0x7f) as u8);
+            rest >>= 7;
+        }
+        chunk.reverse();
+        out.extend(chunk);
+    }
+    encode_tlv(TAG_OID, &out)
+}
+
+// Reads one TLV, returns (tag, value, rest)
+fn decode_tlv(data: &[u8]) -> Result<(u8, &[u8], &[u8]), String> {
+    if data.len() < 2 {
+        return Err("truncated SNMP message".to_string());
+    }
+    let tag = data[0];
+    let (len, header) = if data[1] & 0x80 == 0 {
+        (data[1] as usize, 2)
+    } else {
+        let count = (data[1] & 0x7f) as usize;
+        if count == 0 || count > 4 || data.len() < 2 + count {
+            return Err("bad BER length".to_string());
+        }
+        let len = data[2..2 + count].iter().fold(0usize, |acc, b| (acc << 8) | *b as usize);
+        (len, 2 + count)
+    };
+    if data.len() < header + len {
+        return Err("truncated SNMP message".to_string());
+    }
+    Ok((tag, &data[header..header + len], &data[header + len..]))
+}
+
+fn decode_expect(data: &[u8], expected: u8) -> Result<(&[u8], &[u8]), String> {
+    let (tag, value, rest) = decode_tlv(data)?;
+    if tag != expected {
+        return Err(format!("expected BER tag {:#x}, got {:#x}", expected, tag));
+    }
+    Ok((value, rest))
+}
+
+fn decode_integer(value: &[u8]) -> i64 {
+    let mut n: i64 = if value.first().is_some_and(|b| b & 0x80 != 0) { -1 } else { 0 };
+    for b in value.iter().take(8) {
+        n = (n << 8) | *b as i64;
+    }
+    n
+}
+
+fn decode_unsigned(value: &[u8]) -> u64 {
+    value.iter().take(9).fold(0u64, |acc, b| (acc << 8) | *b as u64)
+}
+
+fn decode_oid(value: &[u8]) -> Vec<u32> {
+    let mut oid = Vec::new();
+    if let Some(first) = value.first() {
+        oid.push((*first / 40) as u32);
+        oid.push((*first % 40) as u32);
+    }
+    let mut arc: u32 = 0;
+    for b in value.iter().skip(1) {
+        arc = (arc << 7) | (b & 0x7f) as u32;
+        if b & 0x80 == 0 {
+            oid.push(arc);
+            arc = 0;
+        }
+    }
+    oid
+}
+
+type VarBinds = Vec<(Vec<u32>, SnmpValue)>;
+
+// Returns the request id and the variable bindings of a GetResponse
+fn decode_response(data: &[u8]) -> Result<(i64, VarBinds), String> {
+    let (message, _) = decode_expect(data, TAG_SEQUENCE)?;
+    let (_version, rest) = decode_expect(message, TAG_INTEGER)?;
+    let (_community, rest) = decode_expect(rest, TAG_OCTET_STRING)?;
+    let (pdu, _) = decode_expect(rest, TAG_GET_RESPONSE)?;
+
+    let (request_id, rest) = decode_expect(pdu, TAG_INTEGER)?;
+    let (error_status, rest) = decode_expect(rest, TAG_INTEGER)?;
+    let (_error_index, rest) = decode_expect(rest, TAG_INTEGER)?;
+    let error_status = decode_integer(error_status);
+    if error_status != 0 {
+        return Err(format!("SNMP error status {}", error_status));
+    }
+
+    let (mut list, _) = decode_expect(rest, TAG_SEQUENCE)?;
+    let mut varbinds = Vec::new();
+    while !list.is_empty() {
+        let (varbind, rest) = decode_expect(list, TAG_SEQUENCE)?;
+        list = rest;
+        let (oid, value) = decode_expect(varbind, TAG_OID)?;
+        let (tag, value, _) = decode_tlv(value)?;
+        let value = match tag {
+            TAG_INTEGER => SnmpValue::Integer(decode_integer(value)),
+            TAG_OCTET_STRING | TAG_IP_ADDRESS => SnmpValue::OctetString(value.to_vec()),
+            TAG_COUNTER32 | TAG_GAUGE32 | TAG_TIMETICKS | TAG_COUNTER64 => SnmpValue::Unsigned(decode_unsigned(value)),
+            TAG_OID => SnmpValue::Oid(decode_oid(value)),
+            TAG_NULL => SnmpValue::Null,
+            TAG_END_OF_MIB_VIEW => SnmpValue::EndOfMibView,
+            TAG_NO_SUCH_OBJECT | TAG_NO_SUCH_INSTANCE => SnmpValue::NoSuchObject,
+            other => SnmpValue::Other(other),
+        };
+        varbinds.push((decode_oid(oid), value));
+    }
+    Ok((decode_integer(request_id), varbinds))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn get_bulk_request_bytes() {
+        let ifdescr = parse_oid(".1.3.6.1.2.1.2.2.1.2").unwrap();
+        let expected = [
+            0x30, 0x27, // message
+            0x02, 0x01, 0x01, // version 2c
+            0x04, 0x06, b'p', b'u', b'b', b'l', b'i', b'c', //
+            0xa5, 0x1a, // GetBulk
+            0x02, 0x01, 0x01, // request id
+            0x02, 0x01, 0x00, // non-repeaters
+            0x02, 0x01, 0x19, // max-repetitions
+            0x30, 0x0f, 0x30, 0x0d, // varbinds
+            0x06, 0x09, 0x2b, 0x06, 0x01, 0x02, 0x01, 0x02, 0x02, 0x01, 0x02, //
+            0x05, 0x00,
+        ];
+        assert_eq!(encode_get_bulk("public", 1, &ifdescr), expected);
+    }
+
+    #[test]
+    fn integers_use_the_shortest_form() {
+        assert_eq!(encode_integer(0), [0x02, 0x01, 0x00]);
+        assert_eq!(encode_integer(128), [0x02, 0x02, 0x00, 0x80]);
+        assert_eq!(encode_integer(-1), [0x02, 0x01, 0xff]);
+        assert_eq!(encode_integer(-129), [0x02, 0x02, 0xff, 0x7f]);
+        for n in [0, 127, 128, 256, -1, -128, -129, 0x7fff_ffff, i64::MIN] {
+            let encoded = encode_integer(n);
+            let (value, _) = decode_expect(&encoded, TAG_INTEGER).unwrap();
+            assert_eq!(decode_integer(value), n);
+        }
+    }
+
+    #[test]
+    fn oids_with_multi_byte_arcs() {
+        let juniper = parse_oid("1.3.6.1.4.1.2636").unwrap();
+        let encoded = encode_oid(&juniper);
+        assert_eq!(encoded, [0x06, 0x07, 0x2b, 0x06, 0x01, 0x04, 0x01, 0x94, 0x4c]);
+        assert_eq!(decode_oid(&encoded[2..]), juniper);
+    }
+
+    #[test]
+    fn long_lengths() {
+        let value = vec![0u8; 200];
+        let encoded = encode_tlv(TAG_OCTET_STRING, &value);
+        assert_eq!(encoded[..3], [0x04, 0x81, 0xc8]);
+        let (tag, decoded, rest) = decode_tlv(&encoded).unwrap();
+        assert_eq!((tag, decoded.len(), rest.len()), (TAG_OCTET_STRING, 200, 0));
+        assert!(decode_tlv(&encoded[..100]).is_err());
+    }
+
+    #[test]
+    fn get_bulk_response() {
+        let response = [
+            0x30, 0x52, // message
+            0x02, 0x01, 0x01, //
+            0x04, 0x06, b'p', b'u', b'b', b'l', b'i', b'c', //
+            0xa2, 0x45, // GetResponse
+            0x02, 0x01, 0x01, // request id
+            0x02, 0x01, 0x00, // error status
+            0x02, 0x01, 0x00, // error index
+            0x30, 0x3a, // varbinds
+            // ifDescr.1 = "eth0"
+            0x30, 0x12, 0x06, 0x0a, 0x2b, 0x06, 0x01, 0x02, 0x01, 0x02, 0x02, 0x01, 0x02, 0x01, //
+            0x04, 0x04, b'e', b't', b'h', b'0', //
+            // ifHCInOctets.1 = Counter64 2^32
+            0x30, 0x14, 0x06, 0x0b, 0x2b, 0x06, 0x01, 0x02, 0x01, 0x1f, 0x01, 0x01, 0x01, 0x06, 0x01, //
+            0x46, 0x05, 0x01, 0x00, 0x00, 0x00, 0x00, //
+            // ifType.1 = endOfMibView
+            0x30, 0x0e, 0x06, 0x0a, 0x2b, 0x06, 0x01, 0x02, 0x01, 0x02, 0x02, 0x01, 0x03, 0x01, //
+            0x82, 0x00,
+        ];
+        let (request_id, varbinds) = decode_response(&response).unwrap();
+        assert_eq!(request_id, 1);
+        assert_eq!(
+            varbinds,
+            [
+                (parse_oid("1.3.6.1.2.1.2.2.1.2.1").unwrap(), SnmpValue::OctetString(b"eth0".to_vec())),
+                (parse_oid("1.3.6.1.2.1.31.1.1.1.6.1").unwrap(), SnmpValue::Unsigned(1 << 32)),
+                (parse_oid("1.3.6.1.2.1.2.2.1.3.1").unwrap(), SnmpValue::EndOfMibView),
+            ]
+        );
+        assert_eq!(varbinds[0].1.as_string().as_deref(), Some("eth0"));
+
+        // tooBig
+        let mut failed = response;
+        failed[20] = 0x01;
+        assert!(decode_response(&failed).is_err());
+        assert!(decode_response(&response[..40]).is_err());
+    }
+}
@@ -0,0 +1,282 @@
+use std::fs;
+use std::io;
+use std::net::Ipv4Addr;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+
+use cidr::Ipv4Cidr;
+use serde::{Deserialize, Serialize};
+
+use super::cidr_lookup::{IpRange, LoadStats};
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct ThreatConfig {
+    pub lists: Vec<ThreatListConfig>,
+    // Files are re-read when they changed, checked this often
+    pub refresh_secs: u64,
+}
+
+impl Default for ThreatConfig {
+    fn default() -> Self {
+        ThreatConfig {
+            lists: Vec::new(),
+            refresh_secs: 3600,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ThreatListConfig {
+    // Written into the `src_threat_list`/`dst_threat_list` tags
+    pub name: String,
+    pub file: String,
+    #[serde(default)]
+    pub format: ThreatFormat,
+    // Category of every entry, csv lists can set it per line
+    #[serde(default)]
+    pub category: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ThreatFormat {
+    // Spamhaus DROP/EDROP: "1.10.16.0/20 ; SBL256894", ';' starts a comment
+    Spamhaus,
+    // FireHOL .netset/.ipset: one address or network per line, '#' starts a comment
+    Firehol,
+    // One address or network per line, '#' and ';' start a comment
+    #[default]
+    Plain,
+    // network,category[,anything else], lines that don't parse (a header) are skipped
+    Csv,
+}
+
+// A listed network and its category
+type ThreatRange = IpRange<String>;
+
+#[derive(Debug, Default)]
+struct ThreatList {
+    name: String,
+    modified: Option<SystemTime>,
+    // Sorted by start, with the largest end of every prefix of the list so that
+    // nested networks are found
+    ranges: Vec<ThreatRange>,
+    max_end: Vec<Ipv4Addr>,
+}
+
+// Every list an address is on, with the categories it is listed for
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ThreatMatch {
+    pub lists: Vec<String>,
+    pub categories: Vec<String>,
+}
+
+// Blocklists loaded from local files. Cloning is cheap and every clone sees the
+// lists the refresher reloads.
+#[derive(Clone, Debug, Default)]
+pub struct ThreatLookup {
+    lists: Arc<RwLock<Vec<ThreatList>>>,
+}
+
+impl ThreatLookup {
+    pub fn new(config: &ThreatConfig) -> Self {
+        let lookup = ThreatLookup::default();
+        lookup.refresh(config);
+        lookup
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.lists.read().unwrap().iter().all(|list| list.ranges.is_empty())
+    }
+
+    pub fn lookup(&self, ip: &str) -> Option<ThreatMatch> {
+        let ip: Ipv4Addr = ip.parse().ok()?;
+        let lists = self.lists.read().unwrap();
+        let mut found = ThreatMatch { lists: Vec::new(), categories: Vec::new() };
+        for list in lists.iter() {
+            if let Some(category) = list.lookup(ip) {
+                found.lists.push(list.name.clone());
+                if !category.is_empty() && !found.categories.contains(category) {
+                    found.categories.push(category.clone());
+                }
+            }
+        }
+        (!found.lists.is_empty()).then_some(found)
+    }
+
+    // Reload the lists whose file changed since the last load
+    pub fn refresh(&self, config: &ThreatConfig) {
+        let current: Vec<(String, Option<SystemTime>)> = self
+            .lists
+            .read()
+            .unwrap()
+            .iter()
+            .map(|list| (list.name.clone(), list.modified))
+            .collect();
+
+        let mut reloaded = Vec::new();
+        let mut changed = current.len() != config.lists.len();
+        for (index, list_config) in config.lists.iter().enumerate() {
+            let modified = fs::metadata(&list_config.file).and_then(|m| m.modified()).ok();
+            let unchanged = current
+                .get(index)
+                .is_some_and(|(name, loaded)| *name == list_config.name && loaded.is_some() && *loaded == modified);
+            if unchanged {
+                reloaded.push(None);
+                continue;
+            }
+            changed = true;
+            match ThreatList::load(list_config, modified) {
+                Ok((list, stats)) => {
+                    println!(
+                        "Loaded {} networks ({} lines skipped) of threat list {} from {}",
+                        stats.loaded, stats.skipped, list_config.name, list_config.file
+                    );
+                    reloaded.push(Some(list));
+                }
+                // Tried again with the next refresh
+                Err(e) => {
+                    println!("Keeping the previous threat list {}, can't read {}: {}", list_config.name, list_config.file, e);
+                    reloaded.push(None);
+                }
+            }
+        }
+        if !changed {
+            return;
+        }
+
+        let mut lists = self.lists.write().unwrap();
+        let mut old = std::mem::take(&mut *lists).into_iter();
+        for (list, list_config) in reloaded.into_iter().zip(&config.lists) {
+            let previous = old.next();
+            lists.push(list.or(previous).unwrap_or_else(|| ThreatList { name: list_config.name.clone(), ..ThreatList::default() }));
+        }
+    }
+}
+
+impl ThreatList {
+    fn load(config: &ThreatListConfig, modified: Option<SystemTime>) -> io::Result<(ThreatList, LoadStats)> {
+        let mut stats = LoadStats::default();
+        let mut ranges = Vec::new();
+        let default_category = config.category.clone().unwrap_or_default();
+
+        let text = fs::read_to_string(&config.file)?;
+        for line in text.lines() {
+            let line = match config.format {
+                ThreatFormat::Spamhaus => line.split(';').next().unwrap_or(""),
+                ThreatFormat::Firehol => line.split('#').next().unwrap_or(""),
+                ThreatFormat::Plain => line.split(['#', ';']).next().unwrap_or(""),
+                ThreatFormat::Csv => line.split('#').next().unwrap_or(""),
+            }
+            .trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            let (network, category) = match config.format {
+                ThreatFormat::Csv => {
+                    let mut columns = line.split(',').map(|c| c.trim().trim_matches('"'));
+                    let network = columns.next().unwrap_or("");
+                    let category = columns.next().filter(|c| !c.is_empty()).unwrap_or(&default_category);
+                    (network, category.to_string())
+                }
+                _ => (line.split_whitespace().next().unwrap_or(""), default_category.clone()),
+            };
+            match parse_network(network) {
+                Some((start, end)) => {
+                    ranges.push(IpRange { start, end, value: category });
+                    stats.loaded += 1;
+                }
+                None => stats.skipped += 1,
+            }
+        }
+
+        ranges.sort_by_key(|range| range.start);
+        let mut max_end = Vec::with_capacity(ranges.len());
+        let mut end = Ipv4Addr::UNSPECIFIED;
+        for range in &ranges {
+            end = end.max(range.end);
+            max_end.push(end);
+        }
+        let list = ThreatList {
+            name: config.name.clone(),
+            modified,
+            ranges,
+            max_end,
+        };
+        Ok((list, stats))
+    }
+
+    // Category of a listed network containing the address
+    fn lookup(&self, ip: Ipv4Addr) -> Option<&String> {
+        let mut index = self.ranges.partition_point(|range| range.start <= ip);
+        while index > 0 && self.max_end[index - 1] >= ip {
+            index -= 1;
+            if self.ranges[index].end >= ip {
+                return Some(&self.ranges[index].value);
+            }
+        }
+        None
+    }
+}
+
+// "a.b.c.d", "a.b.c.d/len" or "a.b.c.d-e.f.g.h" to an inclusive range
+fn parse_network(network: &str) -> Option<(Ipv4Addr, Ipv4Addr)> {
+    if let Some((start, end)) = network.split_once('-') {
+        let (start, end): (Ipv4Addr, Ipv4Addr) = (start.trim().parse().ok()?, end.trim().parse().ok()?);
+        return (start <= end).then_some((start, end));
+    }
+    if let Ok(ip) = network.parse::<Ipv4Addr>() {
+        return Some((ip, ip));
+    }
+    let network: Ipv4Cidr = network.parse().ok()?;
+    Some((network.first_address(), network.last_address()))
+}
+
+// Re-read changed blocklists every `refresh_secs`
+pub async fn run_refresher(threats: ThreatLookup, config: ThreatConfig) {
+    let mut ticker = tokio::time::interval(Duration::from_secs(config.refresh_secs.max(1)));
+    // The lists were loaded when the lookup was created
+    ticker.tick().await;
+
+    loop {
+        ticker.tick().await;
+        let threats = threats.clone();
+        let config = config.clone();
+        // Big lists take a while to parse, keep that off the enrichment workers
+        if let Err(e) = tokio::task::spawn_blocking(move || threats.refresh(&config)).await {
+            println!("Threat list refresh failed: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unreadable_list_keeps_the_previous_one() {
+        let dir = std::env::temp_dir().join(format!("threat-lookup-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let file = dir.join("drop.txt");
+        fs::write(&file, "192.0.2.0/24 ; SBL1\nnot a network\n").unwrap();
+        let config = ThreatConfig {
+            lists: vec![ThreatListConfig {
+                name: "drop".to_string(),
+                file: file.to_string_lossy().to_string(),
+                format: ThreatFormat::Spamhaus,
+                category: Some("hijacked".to_string()),
+            }],
+            ..ThreatConfig::default()
+        };
+        let threats = ThreatLookup::new(&config);
+        let expected = Some(ThreatMatch { lists: vec!["drop".to_string()], categories: vec!["hijacked".to_string()] });
+        assert_eq!(threats.lookup("192.0.2.7"), expected);
+
+        fs::remove_file(&file).unwrap();
+        threats.refresh(&config);
+        assert_eq!(threats.lookup("192.0.2.7"), expected);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
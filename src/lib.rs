@@ -1,4 +1,7 @@
 pub mod cmd;
+pub mod config;
 pub mod kafka;
 pub mod db;
-pub mod process;
\ No newline at end of file
+pub mod process;
+pub mod sketch;
+pub mod transport;
@@ -0,0 +1,409 @@
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, OpenOptions};
+use std::io;
+use std::net::Ipv4Addr;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use async_trait::async_trait;
+use chrono::{DateTime, Datelike, Months, NaiveDate, TimeZone, Utc};
+use cidr::Ipv4Cidr;
+use serde::{Deserialize, Serialize};
+
+use crate::process::flow::EnrichedFlow;
+use crate::transport::{consume_windows, Done, SharedTransport, Windowed, ENRICHER_TO_TSDB};
+
+pub const ACCOUNTING_GROUP: &str = "ta-accounting";
+
+// Share of the samples below the billed rate
+const BILLING_PERCENTILE: f64 = 0.95;
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct AccountingConfig {
+    pub customers: Vec<CustomerConfig>,
+    // More customers, one `customer,prefix` line each
+    pub customers_file: Option<String>,
+    // Length of one billing sample
+    pub interval_secs: u64,
+    // Samples are appended to one YYYY-MM.csv file per month in this directory
+    pub data_dir: String,
+    // Exporters sample 1 in N packets, counters are scaled back up
+    pub sampling_rate: u64,
+}
+
+impl Default for AccountingConfig {
+    fn default() -> Self {
+        AccountingConfig {
+            customers: Vec::new(),
+            customers_file: None,
+            interval_secs: 300,
+            data_dir: "data/accounting".to_string(),
+            sampling_rate: 1,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct CustomerConfig {
+    pub name: String,
+    pub prefixes: Vec<String>,
+}
+
+// Customer of an address, the most specific prefix wins
+#[derive(Debug, Default)]
+pub struct CustomerMap {
+    names: Vec<String>,
+    // Prefix length to network to customer index, longest lengths first
+    by_length: BTreeMap<Reverse<u8>, HashMap<Ipv4Addr, usize>>,
+}
+
+impl CustomerMap {
+    pub fn load(config: &AccountingConfig) -> Result<Self, String> {
+        let mut entries: Vec<(String, String)> = config
+            .customers
+            .iter()
+            .flat_map(|customer| customer.prefixes.iter().map(|prefix| (customer.name.clone(), prefix.clone())))
+            .collect();
+        if let Some(file) = &config.customers_file {
+            let mut reader = csv::ReaderBuilder::new()
+                .has_headers(false)
+                .comment(Some(b'#'))
+                .trim(csv::Trim::All)
+                .from_path(file)
+                .map_err(|e| format!("can't read {}: {}", file, e))?;
+            for record in reader.records() {
+                let record = record.map_err(|e| format!("can't parse {}: {}", file, e))?;
+                match (record.get(0), record.get(1)) {
+                    // A header line
+                    (Some("customer"), _) => {}
+                    (Some(name), Some(prefix)) => entries.push((name.to_string(), prefix.to_string())),
+                    _ => return Err(format!("{}: expected customer,prefix in {:?}", file, record)),
+                }
+            }
+        }
+
+        let mut map = CustomerMap::default();
+        for (name, prefix) in entries {
+            let cidr: Ipv4Cidr = prefix
+                .parse()
+                .map_err(|e| format!("invalid prefix {} of customer {}: {}", prefix, name, e))?;
+            let index = match map.names.iter().position(|n| *n == name) {
+                Some(index) => index,
+                None => {
+                    map.names.push(name.clone());
+                    map.names.len() - 1
+                }
+            };
+            let networks = map.by_length.entry(Reverse(cidr.network_length())).or_default();
+            if let Some(other) = networks.insert(cidr.first_address(), index) {
+                if other != index {
+                    return Err(format!("prefix {} belongs to {} and {}", prefix, map.names[other], name));
+                }
+            }
+        }
+        Ok(map)
+    }
+
+    pub fn len(&self) -> usize {
+        self.names.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.names.is_empty()
+    }
+
+    pub fn lookup(&self, ip: Ipv4Addr) -> Option<&str> {
+        self.by_length.iter().find_map(|(length, networks)| {
+            let mask = u32::MAX.checked_shl(32 - length.0 as u32).unwrap_or(0);
+            let network = Ipv4Addr::from(u32::from(ip) & mask);
+            networks.get(&network).map(|index| self.names[*index].as_str())
+        })
+    }
+}
+
+// One line of a month file
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct UsageSample {
+    pub time: DateTime<Utc>,
+    pub customer: String,
+    // Bytes to and from the customer's prefixes
+    pub in_bytes: u64,
+    pub out_bytes: u64,
+}
+
+// Counters of an interval not written yet
+#[derive(Debug, Default)]
+struct Interval {
+    // Offset of the first record counted, the records from there on are needed again
+    // when the process stops before the interval is written
+    first_offset: i64,
+    customers: BTreeMap<String, (u64, u64)>,
+}
+
+// Counters of the intervals not written yet, keyed by interval start
+pub struct Accountant {
+    config: AccountingConfig,
+    customers: CustomerMap,
+    counters: BTreeMap<DateTime<Utc>, Interval>,
+}
+
+impl Accountant {
+    pub fn new(config: AccountingConfig, customers: CustomerMap) -> Self {
+        Accountant { config, customers, counters: BTreeMap::new() }
+    }
+
+    pub fn observe(&mut self, flow: &EnrichedFlow, offset: i64) {
+        let bytes = flow.fields.bytes.saturating_mul(self.config.sampling_rate.max(1));
+        let to = flow.dst_addr().and_then(|ip| self.customers.lookup(ip));
+        let from = flow.src_addr().and_then(|ip| self.customers.lookup(ip));
+        if to.is_none() && from.is_none() {
+            return;
+        }
+        let interval = interval_start(flow.time, self.config.interval_secs);
+        let pending = self.counters.entry(interval).or_insert_with(|| Interval { first_offset: offset, ..Interval::default() });
+        pending.first_offset = pending.first_offset.min(offset);
+        if let Some(customer) = to {
+            let counters = pending.customers.entry(customer.to_string()).or_default();
+            counters.0 = counters.0.saturating_add(bytes);
+        }
+        if let Some(customer) = from {
+            let counters = pending.customers.entry(customer.to_string()).or_default();
+            counters.1 = counters.1.saturating_add(bytes);
+        }
+    }
+
+    // Offset of the first record of the intervals not written yet
+    pub fn pending_from(&self) -> Option<i64> {
+        self.counters.values().map(|interval| interval.first_offset).min()
+    }
+
+    // Append the intervals that started before `before` to their month files
+    pub fn flush(&mut self, before: DateTime<Utc>) -> io::Result<usize> {
+        let mut written = 0;
+        // An interval is only forgotten once it is on disk
+        while let Some(entry) = self.counters.first_entry() {
+            if *entry.key() >= before {
+                break;
+            }
+            let time = *entry.key();
+            let samples: Vec<UsageSample> = entry
+                .get()
+                .customers
+                .iter()
+                .map(|(customer, (in_bytes, out_bytes))| UsageSample {
+                    time,
+                    customer: customer.clone(),
+                    in_bytes: *in_bytes,
+                    out_bytes: *out_bytes,
+                })
+                .collect();
+            append_samples(&self.config.data_dir, &samples)?;
+            entry.remove();
+            written += samples.len();
+        }
+        Ok(written)
+    }
+}
+
+fn interval_start(time: DateTime<Utc>, interval_secs: u64) -> DateTime<Utc> {
+    let interval = interval_secs.max(1) as i64;
+    let secs = time.timestamp() - time.timestamp().rem_euclid(interval);
+    Utc.timestamp_opt(secs, 0).unwrap()
+}
+
+fn month_file(data_dir: &str, year: i32, month: u32) -> PathBuf {
+    Path::new(data_dir).join(format!("{:04}-{:02}.csv", year, month))
+}
+
+fn append_samples(data_dir: &str, samples: &[UsageSample]) -> io::Result<()> {
+    let first = match samples.first() {
+        Some(first) => first,
+        None => return Ok(()),
+    };
+    fs::create_dir_all(data_dir)?;
+    let path = month_file(data_dir, first.time.year(), first.time.month());
+    let new_file = !path.exists();
+    let file = OpenOptions::new().create(true).append(true).open(&path)?;
+    let mut writer = csv::WriterBuilder::new().has_headers(new_file).from_writer(file);
+    for sample in samples {
+        writer.serialize(sample)?;
+    }
+    writer.flush()
+}
+
+// Billing figures of one customer for one month
+#[derive(Serialize, Debug, Clone)]
+pub struct MonthlyUsage {
+    pub customer: String,
+    pub month: String,
+    // Intervals of the month so far, missing ones count as no traffic
+    pub intervals: usize,
+    pub in_bytes: u64,
+    pub out_bytes: u64,
+    pub p95_in_bps: u64,
+    pub p95_out_bps: u64,
+    // The higher of the two directions, what is usually billed
+    pub p95_bps: u64,
+}
+
+// "2026-10" to the first instant of that month and of the next
+pub fn parse_month(month: &str) -> Option<(DateTime<Utc>, DateTime<Utc>)> {
+    let first = NaiveDate::parse_from_str(&format!("{}-01", month), "%Y-%m-%d").ok()?;
+    let next = first.checked_add_months(Months::new(1))?;
+    Some((
+        first.and_hms_opt(0, 0, 0)?.and_utc(),
+        next.and_hms_opt(0, 0, 0)?.and_utc(),
+    ))
+}
+
+// 95th percentile and volume per customer from the samples of `month`
+pub fn monthly_usage(config: &AccountingConfig, month: &str, now: DateTime<Utc>) -> Result<Vec<MonthlyUsage>, String> {
+    let (start, end) = parse_month(month).ok_or_else(|| format!("invalid month {}, expected YYYY-MM", month))?;
+    let path = month_file(&config.data_dir, start.year(), start.month());
+    let mut reader = match csv::Reader::from_path(&path) {
+        Ok(reader) => reader,
+        Err(e) => return Err(format!("can't read {}: {}", path.display(), e)),
+    };
+
+    // Late flows add a second line for an interval, sum them
+    let mut per_customer: BTreeMap<String, BTreeMap<DateTime<Utc>, (u64, u64)>> = BTreeMap::new();
+    for sample in reader.deserialize::<UsageSample>() {
+        let sample = sample.map_err(|e| format!("can't parse {}: {}", path.display(), e))?;
+        let counters = per_customer.entry(sample.customer).or_default().entry(sample.time).or_default();
+        counters.0 += sample.in_bytes;
+        counters.1 += sample.out_bytes;
+    }
+
+    let interval_secs = config.interval_secs.max(1);
+    let elapsed = (end.min(now.max(start)) - start).num_seconds().max(0) as u64;
+    let intervals = elapsed.div_ceil(interval_secs).max(1) as usize;
+    let to_bps = |bytes: u64| bytes * 8 / interval_secs;
+
+    Ok(per_customer
+        .into_iter()
+        .map(|(customer, samples)| {
+            let mut in_rates: Vec<u64> = samples.values().map(|(bytes, _)| to_bps(*bytes)).collect();
+            let mut out_rates: Vec<u64> = samples.values().map(|(_, bytes)| to_bps(*bytes)).collect();
+            let intervals = intervals.max(samples.len());
+            let p95_in_bps = percentile(&mut in_rates, intervals);
+            let p95_out_bps = percentile(&mut out_rates, intervals);
+            MonthlyUsage {
+                customer,
+                month: month.to_string(),
+                intervals,
+                in_bytes: samples.values().map(|(bytes, _)| bytes).sum(),
+                out_bytes: samples.values().map(|(_, bytes)| bytes).sum(),
+                p95_in_bps,
+                p95_out_bps,
+                p95_bps: p95_in_bps.max(p95_out_bps),
+            }
+        })
+        .collect())
+}
+
+// Nearest rank percentile of `total` samples, the ones not in `rates` being 0
+fn percentile(rates: &mut [u64], total: usize) -> u64 {
+    let rank = ((total as f64 * BILLING_PERCENTILE).ceil() as usize).max(1);
+    let zeros = total - rates.len();
+    if rank <= zeros {
+        return 0;
+    }
+    rates.sort_unstable();
+    rates[rank - zeros - 1]
+}
+
+// Writes every interval shortly after it ended, leaving a few seconds for the enricher to catch up
+struct Recording {
+    accountant: Accountant,
+    interval_secs: u64,
+}
+
+#[async_trait]
+impl Windowed for Recording {
+    type Item = EnrichedFlow;
+
+    fn observe(&mut self, flow: EnrichedFlow, offset: i64) {
+        self.accountant.observe(&flow, offset);
+    }
+
+    async fn flush(&mut self) -> Done {
+        let before = interval_start(Utc::now() - chrono::Duration::seconds(10), self.interval_secs);
+        match self.accountant.flush(before) {
+            Ok(0) => {}
+            Ok(written) => println!("Wrote {} accounting samples", written),
+            Err(e) => {
+                // The samples stay in memory and are written with the next interval
+                println!("Can't write accounting samples: {}", e);
+                return Done::Nothing;
+            }
+        }
+        // Intervals still open are counted again from their first record after a restart
+        self.accountant.pending_from().map_or(Done::All, Done::Before)
+    }
+}
+
+// Count the bytes of every customer per interval and append them to the month files
+pub async fn run(transport: SharedTransport, config: AccountingConfig) {
+    let customers = match CustomerMap::load(&config) {
+        Ok(customers) => customers,
+        Err(e) => {
+            println!("Accounting disabled: {}", e);
+            return;
+        }
+    };
+    println!("Accounting the traffic of {} customers", customers.len());
+
+    let mut subscription = transport
+        .subscribe(ENRICHER_TO_TSDB, ACCOUNTING_GROUP)
+        .await
+        .expect("Can't subscribe to specified topic");
+
+    let interval_secs = config.interval_secs.max(1);
+    let mut recording = Recording { accountant: Accountant::new(config, customers), interval_secs };
+    consume_windows(subscription.as_mut(), Duration::from_secs(interval_secs.min(60)), &mut recording).await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::process::flow::FlowRecord;
+
+    fn flow(dst_ip: &str, bytes: u64, secs: i64) -> EnrichedFlow {
+        let record = FlowRecord { src_ip: "203.0.113.1".to_string(), dst_ip: dst_ip.to_string(), bytes, ..FlowRecord::default() };
+        EnrichedFlow::new(&record, Utc.timestamp_opt(secs, 0).unwrap())
+    }
+
+    #[test]
+    fn commits_up_to_the_first_open_interval() {
+        let data_dir = std::env::temp_dir().join(format!("accounting-{}", std::process::id()));
+        let config = AccountingConfig {
+            customers: vec![CustomerConfig { name: "acme".to_string(), prefixes: vec!["192.0.2.0/24".to_string()] }],
+            interval_secs: 300,
+            data_dir: data_dir.to_str().unwrap().to_string(),
+            sampling_rate: 2,
+            ..AccountingConfig::default()
+        };
+        let customers = CustomerMap::load(&config).unwrap();
+        let mut accountant = Accountant::new(config, customers);
+        accountant.observe(&flow("192.0.2.1", 100, 0), 10);
+        accountant.observe(&flow("192.0.2.1", 100, 300), 11);
+        accountant.observe(&flow("198.51.100.1", 100, 0), 12);
+        accountant.observe(&flow("192.0.2.1", u64::MAX, 0), 13);
+        assert_eq!(accountant.pending_from(), Some(10));
+
+        assert_eq!(accountant.flush(Utc.timestamp_opt(300, 0).unwrap()).unwrap(), 1);
+        assert_eq!(accountant.pending_from(), Some(11));
+        let samples: Vec<UsageSample> = csv::Reader::from_path(month_file(&accountant.config.data_dir, 1970, 1))
+            .unwrap()
+            .deserialize()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(samples[0].in_bytes, u64::MAX);
+
+        assert_eq!(accountant.flush(Utc.timestamp_opt(600, 0).unwrap()).unwrap(), 1);
+        assert_eq!(accountant.pending_from(), None);
+        fs::remove_dir_all(data_dir).unwrap();
+    }
+}
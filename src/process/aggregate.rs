@@ -0,0 +1,165 @@
+use std::any::Any;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::process::cardinality::{CardinalityConfig, DistinctCounters};
+use crate::process::flow::{Aggregate, EnrichedFlow};
+use crate::process::topn::{TopNConfig, TopTalkers};
+use crate::transport::{SharedTransport, ENRICHER_AGGREGATES};
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct AggregatesConfig {
+    // Every aggregate covers the flows enriched in one window of this length
+    pub window_secs: u64,
+    pub topn: Option<TopNConfig>,
+    pub cardinality: Option<CardinalityConfig>,
+}
+
+impl Default for AggregatesConfig {
+    fn default() -> Self {
+        AggregatesConfig {
+            window_secs: 60,
+            topn: None,
+            cardinality: None,
+        }
+    }
+}
+
+impl AggregatesConfig {
+    pub fn is_enabled(&self) -> bool {
+        self.topn.is_some() || self.cardinality.is_some()
+    }
+}
+
+// Something that summarizes the enriched flows of a window
+pub trait Aggregator: Send {
+    fn observe(&mut self, flow: &EnrichedFlow);
+    // Add what `other`, built from the same config, collected and empty it
+    fn merge(&mut self, other: &mut dyn Aggregator);
+    // Return the summary of the window that just ended and start a new one
+    fn flush(&mut self, time: DateTime<Utc>) -> Vec<Aggregate>;
+    fn as_any_mut(&mut self) -> &mut dyn Any;
+}
+
+type AggregatorSet = Arc<Mutex<Vec<Box<dyn Aggregator>>>>;
+
+fn build(config: &AggregatesConfig) -> Vec<Box<dyn Aggregator>> {
+    let mut aggregators: Vec<Box<dyn Aggregator>> = Vec::new();
+    if let Some(topn) = &config.topn {
+        aggregators.push(Box::new(TopTalkers::new(topn)));
+    }
+    if let Some(cardinality) = &config.cardinality {
+        aggregators.push(Box::new(DistinctCounters::new(cardinality)));
+    }
+    aggregators
+}
+
+// Every enrichment worker fills aggregators of its own, so the workers never wait
+// on each other. They are merged when the window is flushed.
+#[derive(Clone)]
+pub struct Aggregators {
+    config: AggregatesConfig,
+    workers: Arc<Mutex<Vec<AggregatorSet>>>,
+    merged: AggregatorSet,
+}
+
+// The aggregators of one worker
+pub struct WorkerAggregators {
+    aggregators: AggregatorSet,
+}
+
+impl Aggregators {
+    pub fn new(config: &AggregatesConfig) -> Self {
+        Aggregators {
+            config: config.clone(),
+            workers: Arc::new(Mutex::new(Vec::new())),
+            merged: Arc::new(Mutex::new(build(config))),
+        }
+    }
+
+    pub fn worker(&self) -> WorkerAggregators {
+        let aggregators = Arc::new(Mutex::new(build(&self.config)));
+        self.workers.lock().unwrap().push(aggregators.clone());
+        WorkerAggregators { aggregators }
+    }
+
+    pub fn flush(&self, time: DateTime<Utc>) -> Vec<Aggregate> {
+        let mut merged = self.merged.lock().unwrap();
+        for worker in self.workers.lock().unwrap().iter() {
+            let mut worker = worker.lock().unwrap();
+            for (into, from) in merged.iter_mut().zip(worker.iter_mut()) {
+                into.merge(from.as_mut());
+            }
+        }
+        merged.iter_mut().flat_map(|aggregator| aggregator.flush(time)).collect()
+    }
+}
+
+impl WorkerAggregators {
+    pub fn observe(&self, flows: &[EnrichedFlow]) {
+        let mut aggregators = self.aggregators.lock().unwrap();
+        for aggregator in aggregators.iter_mut() {
+            for flow in flows {
+                aggregator.observe(flow);
+            }
+        }
+    }
+}
+
+// Publish what the aggregators collected at the end of every window. Windows end on
+// multiples of `window_secs` since the epoch, so every enricher stamps them alike.
+pub async fn run_flusher(transport: SharedTransport, aggregators: Aggregators, window_secs: u64) {
+    let window_ms = window_secs.max(1) as i64 * 1000;
+
+    loop {
+        let now = Utc::now().timestamp_millis();
+        let end = (now / window_ms + 1) * window_ms;
+        tokio::time::sleep(Duration::from_millis((end - now) as u64)).await;
+
+        let time = DateTime::from_timestamp_millis(end).unwrap_or_else(Utc::now);
+        for aggregate in aggregators.flush(time) {
+            if let Err(e) = transport.send(ENRICHER_AGGREGATES, None, &aggregate.to_json()).await {
+                println!("Error publishing {} aggregate: {:?}", aggregate.measurement, e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::process::flow::FlowRecord;
+
+    fn flow(src_ip: &str, bytes: u64) -> EnrichedFlow {
+        let record = FlowRecord { src_ip: src_ip.to_string(), dst_ip: "198.51.100.1".to_string(), bytes, packets: 1, ..FlowRecord::default() };
+        EnrichedFlow::new(&record, Utc::now())
+    }
+
+    #[test]
+    fn workers_are_merged_at_flush() {
+        let config = AggregatesConfig {
+            topn: Some(TopNConfig { dimensions: vec!["src_ip".to_string()], ..TopNConfig::default() }),
+            cardinality: Some(CardinalityConfig { dimensions: vec!["all".to_string()], ..CardinalityConfig::default() }),
+            ..AggregatesConfig::default()
+        };
+        let aggregators = Aggregators::new(&config);
+        let first = aggregators.worker();
+        let second = aggregators.worker();
+        first.observe(&[flow("192.0.2.1", 100), flow("192.0.2.2", 10)]);
+        second.observe(&[flow("192.0.2.1", 50), flow("192.0.2.3", 1)]);
+
+        let aggregates = aggregators.flush(Utc::now());
+        let top = aggregates.iter().find(|a| a.measurement == "topn" && a.tags.get("key").map(String::as_str) == Some("192.0.2.1")).unwrap();
+        assert_eq!(top.fields["bytes"], 150);
+        assert_eq!(top.fields["packets"], 2);
+        let all = aggregates.iter().find(|a| a.measurement == "cardinality").unwrap();
+        assert_eq!(all.fields["unique_src_ips"], 3);
+
+        // The next window starts empty
+        assert!(aggregators.flush(Utc::now()).is_empty());
+    }
+}
@@ -0,0 +1,399 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use chrono::{DateTime, Datelike, Timelike, Utc};
+use serde::{Deserialize, Serialize};
+use tokio::task::JoinHandle;
+
+use crate::db::ip_lookup::IPtype;
+use crate::process::flow::EnrichedFlow;
+use crate::transport::{consume_windows, Done, SharedTransport, Windowed, ANOMALIES, ENRICHER_TO_TSDB};
+
+pub const ANOMALY_GROUP: &str = "ta-anomaly";
+
+// Series without traffic for this long are forgotten
+const FORGET_AFTER_DAYS: i64 = 14;
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct AnomalyConfig {
+    // Bytes are summed over windows of this length
+    pub window_secs: u64,
+    // Models are loaded from and saved to this file after every window
+    pub state_file: String,
+    // Weight of the newest window in the overall model
+    pub alpha: f64,
+    // Also model every hour of the week on its own, updated once a week
+    pub seasonal: bool,
+    pub seasonal_alpha: f64,
+    // Samples a model needs before it is trusted
+    pub warmup: u64,
+    pub seasonal_warmup: u64,
+    // Distance from the expected bytes, in standard deviations, that is anomalous
+    pub z_threshold: f64,
+    // Smallest standard deviation used, so flat series don't alert on a few bytes
+    pub min_std_bytes: u64,
+    // Windows where both the observed and the expected bytes are below this are ignored
+    pub min_bytes: u64,
+}
+
+impl Default for AnomalyConfig {
+    fn default() -> Self {
+        AnomalyConfig {
+            window_secs: 300,
+            state_file: "data/baselines.json".to_string(),
+            alpha: 0.1,
+            seasonal: true,
+            seasonal_alpha: 0.3,
+            warmup: 12,
+            seasonal_warmup: 3,
+            z_threshold: 4.0,
+            min_std_bytes: 1_000_000,
+            min_bytes: 10_000_000,
+        }
+    }
+}
+
+// Exponentially weighted mean and variance
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+struct Ewma {
+    mean: f64,
+    var: f64,
+    samples: u64,
+}
+
+impl Ewma {
+    fn update(&mut self, value: f64, alpha: f64) {
+        if self.samples == 0 {
+            self.mean = value;
+            self.var = 0.0;
+        } else {
+            let diff = value - self.mean;
+            let increment = alpha * diff;
+            self.mean += increment;
+            self.var = (1.0 - alpha) * (self.var + diff * increment);
+        }
+        self.samples += 1;
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+struct Model {
+    overall: Ewma,
+    // Keyed by hour of the week, 0 is Monday 00:00 UTC
+    hours: BTreeMap<u16, Ewma>,
+    last_seen: Option<DateTime<Utc>>,
+}
+
+// Dimension, direction and value of a series, e.g. ("asn", Incoming, "15169")
+type SeriesKey = (String, IPtype, String);
+
+// What is saved in `state_file`
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct State {
+    windows: u64,
+    series: Vec<(SeriesKey, Model)>,
+    #[serde(default)]
+    names: HashMap<String, String>,
+}
+
+// Published on the `anomalies` topic
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AnomalyEvent {
+    pub time: DateTime<Utc>,
+    // country or asn
+    pub dimension: String,
+    pub value: String,
+    // AS name for the asn dimension
+    pub name: Option<String>,
+    pub direction: IPtype,
+    pub window_secs: u64,
+    pub bytes: u64,
+    pub expected_bytes: f64,
+    pub std_bytes: f64,
+    pub z_score: f64,
+    // Whether the expectation came from the hour-of-week model
+    pub seasonal: bool,
+    // No traffic was ever seen for this series before
+    pub new_series: bool,
+}
+
+impl AnomalyEvent {
+    pub fn to_json(&self) -> Vec<u8> {
+        serde_json::to_vec(self).expect("AnomalyEvent can always be serialized")
+    }
+}
+
+// Bytes per country, per ASN and per direction, compared against what the
+// same series did before
+pub struct Baselines {
+    config: AnomalyConfig,
+    windows: u64,
+    models: HashMap<SeriesKey, Model>,
+    window: HashMap<SeriesKey, u64>,
+    // AS names of the asn series, forgotten with their models
+    names: HashMap<String, String>,
+}
+
+impl Baselines {
+    pub fn new(config: AnomalyConfig) -> Self {
+        Baselines {
+            config,
+            windows: 0,
+            models: HashMap::new(),
+            window: HashMap::new(),
+            names: HashMap::new(),
+        }
+    }
+
+    // Start from the models saved by a previous run, if there are any
+    pub fn load(config: AnomalyConfig) -> Self {
+        let mut baselines = Baselines::new(config);
+        match fs::read(&baselines.config.state_file) {
+            Ok(data) => match serde_json::from_slice::<State>(&data) {
+                Ok(state) => {
+                    baselines.windows = state.windows;
+                    baselines.models = state.series.into_iter().collect();
+                    baselines.names = state.names;
+                    println!("Loaded {} baselines from {}", baselines.models.len(), baselines.config.state_file);
+                }
+                Err(e) => println!("Ignoring baselines in {}: {}", baselines.config.state_file, e),
+            },
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => println!("Can't read {}: {}", baselines.config.state_file, e),
+        }
+        baselines
+    }
+
+    // Serializing and writing the models takes a while with many series, so only the copy
+    // is made here and the rest runs on the blocking pool
+    pub fn save(&self) -> JoinHandle<io::Result<()>> {
+        let state = State {
+            windows: self.windows,
+            series: self.models.iter().map(|(key, model)| (key.clone(), model.clone())).collect(),
+            names: self.names.clone(),
+        };
+        let path = PathBuf::from(&self.config.state_file);
+        tokio::task::spawn_blocking(move || {
+            if let Some(dir) = path.parent() {
+                fs::create_dir_all(dir)?;
+            }
+            // Write next to the file and rename, so a crash never leaves half a state file
+            let tmp = path.with_extension("tmp");
+            fs::write(&tmp, serde_json::to_vec(&state)?)?;
+            fs::rename(tmp, path)
+        })
+    }
+
+    pub fn observe(&mut self, flow: &EnrichedFlow) {
+        let direction = match flow.direction() {
+            Some(direction) => direction,
+            None => return,
+        };
+        // The remote side of the flow is the source of incoming and the destination of outgoing traffic
+        let remote = match direction {
+            IPtype::Incoming => "src",
+            IPtype::Outgoing => "dst",
+        };
+        let bytes = flow.fields.bytes;
+
+        *self.window.entry(("direction".to_string(), direction, "all".to_string())).or_default() += bytes;
+        if let Some(country) = flow.tag(&format!("{}_country", remote)).filter(|c| *c != "Unknown") {
+            *self.window.entry(("country".to_string(), direction, country.to_string())).or_default() += bytes;
+        }
+        if let Some(asn) = flow.tag(&format!("{}_asn", remote)).filter(|a| *a != "Unknown") {
+            *self.window.entry(("asn".to_string(), direction, asn.to_string())).or_default() += bytes;
+            if let Some(name) = flow.tag(&format!("{}_as_name", remote)) {
+                if self.names.get(asn).map(String::as_str) != Some(name) {
+                    self.names.insert(asn.to_string(), name.to_string());
+                }
+            }
+        }
+    }
+
+    // Close the window that started at `start`, score every series and learn from it
+    pub fn evaluate(&mut self, start: DateTime<Utc>) -> Vec<AnomalyEvent> {
+        let window = std::mem::take(&mut self.window);
+        let hour = (start.weekday().num_days_from_monday() * 24 + start.hour()) as u16;
+        let config = &self.config;
+        let trusted = self.windows >= config.warmup;
+
+        let mut keys: Vec<SeriesKey> = self.models.keys().cloned().collect();
+        keys.extend(window.keys().filter(|key| !self.models.contains_key(*key)).cloned());
+
+        let mut events = Vec::new();
+        for key in keys {
+            let bytes = window.get(&key).copied().unwrap_or(0);
+            let model = self.models.entry(key.clone()).or_default();
+            let new_series = model.overall.samples == 0;
+
+            let seasonal = model.hours.get(&hour).filter(|m| config.seasonal && m.samples >= config.seasonal_warmup);
+            let expected = match seasonal {
+                Some(seasonal) => Some(seasonal),
+                None if model.overall.samples >= config.warmup => Some(&model.overall),
+                // A series appearing after the warmup was quiet until now
+                None if new_series && trusted => Some(&model.overall),
+                None => None,
+            };
+
+            if let Some(expected) = expected {
+                let std = expected.var.sqrt().max(config.min_std_bytes as f64);
+                let z_score = (bytes as f64 - expected.mean) / std;
+                let relevant = bytes >= config.min_bytes || expected.mean >= config.min_bytes as f64;
+                if relevant && z_score.abs() >= config.z_threshold {
+                    let (dimension, direction, value) = key.clone();
+                    events.push(AnomalyEvent {
+                        time: start,
+                        name: if dimension == "asn" { self.names.get(&value).cloned() } else { None },
+                        dimension,
+                        value,
+                        direction,
+                        window_secs: config.window_secs,
+                        bytes,
+                        expected_bytes: expected.mean,
+                        std_bytes: std,
+                        z_score,
+                        seasonal: seasonal.is_some(),
+                        new_series,
+                    });
+                }
+            }
+
+            model.overall.update(bytes as f64, config.alpha);
+            if config.seasonal {
+                model.hours.entry(hour).or_default().update(bytes as f64, config.seasonal_alpha);
+            }
+            if bytes > 0 {
+                model.last_seen = Some(start);
+            }
+        }
+
+        let forget_before = start - chrono::Duration::days(FORGET_AFTER_DAYS);
+        self.models.retain(|_, model| model.last_seen.is_some_and(|seen| seen >= forget_before));
+        let models = &self.models;
+        self.names.retain(|asn, _| {
+            [IPtype::Incoming, IPtype::Outgoing]
+                .into_iter()
+                .any(|direction| models.contains_key(&("asn".to_string(), direction, asn.clone())))
+        });
+        self.windows += 1;
+        events
+    }
+}
+
+
+// Publishes the anomalies of every window and saves the models
+struct Scoring {
+    baselines: Baselines,
+    transport: SharedTransport,
+    window_start: DateTime<Utc>,
+    saving: Option<JoinHandle<io::Result<()>>>,
+}
+
+#[async_trait]
+impl Windowed for Scoring {
+    type Item = EnrichedFlow;
+
+    fn observe(&mut self, flow: EnrichedFlow, _offset: i64) {
+        self.baselines.observe(&flow);
+    }
+
+    async fn flush(&mut self) -> Done {
+        for event in self.baselines.evaluate(self.window_start) {
+            println!(
+                "Anomaly in {} {} {:?}: {} bytes, expected {:.0} (z {:.1})",
+                event.dimension, event.value, event.direction, event.bytes, event.expected_bytes, event.z_score
+            );
+            let key = format!("{}:{}", event.dimension, event.value);
+            if let Err(e) = self.transport.send(ANOMALIES, Some(key.as_bytes()), &event.to_json()).await {
+                println!("Error publishing anomaly: {:?}", e);
+            }
+        }
+        self.window_start = Utc::now();
+        // A save still running is left to finish, the next window saves newer models
+        if self.saving.as_ref().is_none_or(|saving| saving.is_finished()) {
+            if let Some(saving) = self.saving.take() {
+                match saving.await {
+                    Ok(Ok(())) => {}
+                    Ok(Err(e)) => println!("Can't save baselines: {}", e),
+                    Err(e) => println!("Can't save baselines: {}", e),
+                }
+            }
+            self.saving = Some(self.baselines.save());
+        }
+        Done::All
+    }
+}
+
+// Score the enriched flows against their baselines and publish anomalies on the `anomalies` topic
+pub async fn run(transport: SharedTransport, config: AnomalyConfig) {
+    let mut subscription = transport
+        .subscribe(ENRICHER_TO_TSDB, ANOMALY_GROUP)
+        .await
+        .expect("Can't subscribe to specified topic");
+
+    let window = Duration::from_secs(config.window_secs.max(1));
+    let mut scoring = Scoring {
+        baselines: Baselines::load(config),
+        transport,
+        window_start: Utc::now(),
+        saving: None,
+    };
+    consume_windows(subscription.as_mut(), window, &mut scoring).await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::process::flow::FlowRecord;
+
+    fn flow(bytes: u64, as_name: Option<&str>) -> EnrichedFlow {
+        let mut flow = EnrichedFlow::new(&FlowRecord { bytes, ..FlowRecord::default() }, Utc::now());
+        flow.set_tag("type", "Incoming");
+        flow.set_tag("src_asn", "64500");
+        if let Some(name) = as_name {
+            flow.set_tag("src_as_name", name);
+        }
+        flow
+    }
+
+    #[test]
+    fn as_names_outlive_the_window() {
+        let config = AnomalyConfig { seasonal: false, warmup: 3, min_std_bytes: 1, min_bytes: 1, ..AnomalyConfig::default() };
+        let mut baselines = Baselines::new(config);
+        let start = Utc::now();
+        baselines.observe(&flow(1000, Some("EXAMPLE")));
+        assert!(baselines.evaluate(start).is_empty());
+        for _ in 0..3 {
+            // The name only came with the first flow
+            baselines.observe(&flow(1000, None));
+            assert!(baselines.evaluate(start).is_empty());
+        }
+
+        baselines.observe(&flow(1_000_000, None));
+        let events = baselines.evaluate(start);
+        let asn = events.iter().find(|event| event.dimension == "asn").expect("no asn anomaly");
+        assert_eq!(asn.name.as_deref(), Some("EXAMPLE"));
+    }
+
+    #[tokio::test]
+    async fn saved_models_are_loaded_again() {
+        let dir = std::env::temp_dir().join(format!("anomaly-{}", std::process::id()));
+        let state_file = dir.join("baselines.json").to_str().unwrap().to_string();
+        let config = AnomalyConfig { state_file, ..AnomalyConfig::default() };
+        let mut baselines = Baselines::new(config.clone());
+        baselines.observe(&flow(1000, Some("EXAMPLE")));
+        baselines.evaluate(Utc::now());
+        baselines.save().await.unwrap().unwrap();
+
+        let loaded = Baselines::load(config);
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(loaded.windows, 1);
+        assert_eq!(loaded.models.len(), baselines.models.len());
+        assert_eq!(loaded.names.get("64500").map(String::as_str), Some("EXAMPLE"));
+    }
+}
@@ -0,0 +1,387 @@
+// HTTP/JSON queries over the stored flows: top talkers per tag, traffic over time and
+// raw flow search. Everything is a GET with query string parameters.
+
+use std::collections::BTreeMap;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::Arc;
+
+use chrono::{DateTime, Duration, TimeZone, Utc};
+use cidr::Ipv4Cidr;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+use crate::db::flow_store::{valid_tag_name, FlowFilter, FlowStore, Metric};
+use crate::process::filter::{self, Filter, Side};
+
+const MAX_REQUEST_LEN: usize = 8192;
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct ApiConfig {
+    pub listen: SocketAddr,
+    // Cap on top entries and returned flows
+    pub max_rows: usize,
+    // Cap on the buckets of one time series
+    pub max_points: u64,
+    // Range queried when no start is given
+    pub default_range_secs: u64,
+    // Origin allowed to query from a browser, like a dashboard's "https://grafana.example.net"
+    // or "*". Not set, browsers only allow pages served by the API itself.
+    pub cors_origin: Option<String>,
+}
+
+impl Default for ApiConfig {
+    fn default() -> Self {
+        ApiConfig {
+            listen: "0.0.0.0:8090".parse().unwrap(),
+            max_rows: 1000,
+            max_points: 10000,
+            default_range_secs: 3600,
+            cors_origin: None,
+        }
+    }
+}
+
+struct Response {
+    status: &'static str,
+    body: String,
+}
+
+impl Response {
+    fn ok(body: serde_json::Value) -> Self {
+        Response { status: "200 OK", body: body.to_string() }
+    }
+
+    fn error(status: &'static str, message: String) -> Self {
+        Response { status, body: json!({ "error": message }).to_string() }
+    }
+}
+
+pub async fn serve(store: Arc<dyn FlowStore>, config: ApiConfig) {
+    let listener = match TcpListener::bind(config.listen).await {
+        Ok(listener) => listener,
+        Err(e) => {
+            println!("Can't serve the query API on {}: {}", config.listen, e);
+            return;
+        }
+    };
+    println!("Serving the query API on http://{}/api/v1/", config.listen);
+
+    let config = Arc::new(config);
+    loop {
+        let (stream, _) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                println!("Query API accept failed: {}", e);
+                continue;
+            }
+        };
+        let store = store.clone();
+        let config = config.clone();
+        tokio::spawn(async move {
+            if let Err(e) = serve_request(stream, store.as_ref(), &config).await {
+                println!("Query API request failed: {}", e);
+            }
+        });
+    }
+}
+
+async fn serve_request(mut stream: TcpStream, store: &dyn FlowStore, config: &ApiConfig) -> std::io::Result<()> {
+    let mut request = Vec::new();
+    let mut buffer = [0u8; 1024];
+    while !request.windows(4).any(|w| w == b"\r\n\r\n") && request.len() < MAX_REQUEST_LEN {
+        let read = stream.read(&mut buffer).await?;
+        if read == 0 {
+            break;
+        }
+        request.extend_from_slice(&buffer[..read]);
+    }
+    let request = String::from_utf8_lossy(&request);
+    let mut words = request.split_whitespace();
+    let method = words.next().unwrap_or("");
+    let target = words.next().unwrap_or("");
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+
+    let response = if method != "GET" {
+        Response::error("405 Method Not Allowed", format!("{} is not supported", method))
+    } else {
+        let params = parse_query(query);
+        let result = match path {
+            "/api/v1/top" => Some(top(store, config, &params).await),
+            "/api/v1/timeseries" => Some(timeseries(store, config, &params).await),
+            "/api/v1/flows" => Some(flows(store, config, &params).await),
+            _ => None,
+        };
+        match result {
+            Some(Ok(body)) => Response::ok(body),
+            Some(Err(response)) => response,
+            None => Response::error("404 Not Found", format!("no endpoint {}", path)),
+        }
+    };
+    let cors = match &config.cors_origin {
+        Some(origin) => format!("Access-Control-Allow-Origin: {}\r\nVary: Origin\r\n", origin),
+        None => String::new(),
+    };
+    let head = format!(
+        "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\n{}Connection: close\r\n\r\n",
+        response.status,
+        response.body.len(),
+        cors
+    );
+    stream.write_all(head.as_bytes()).await?;
+    stream.write_all(response.body.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+async fn top(store: &dyn FlowStore, config: &ApiConfig, params: &BTreeMap<String, String>) -> Result<serde_json::Value, Response> {
+    let filter = parse_filter(params, config).map_err(bad_request)?;
+    let metric = parse_metric(params).map_err(bad_request)?;
+    let dimension = params.get("dimension").ok_or_else(|| bad_request("dimension is required".to_string()))?;
+    if !valid_tag_name(dimension) {
+        return Err(bad_request(format!("invalid dimension {}", dimension)));
+    }
+    let limit = parse_limit(params, 10, config.max_rows).map_err(bad_request)?;
+    let entries = store.top(&filter, dimension, metric, limit).await.map_err(store_error)?;
+    Ok(json!({
+        "start": filter.start,
+        "end": filter.end,
+        "dimension": dimension,
+        "metric": metric,
+        "entries": entries,
+    }))
+}
+
+async fn timeseries(
+    store: &dyn FlowStore,
+    config: &ApiConfig,
+    params: &BTreeMap<String, String>,
+) -> Result<serde_json::Value, Response> {
+    let filter = parse_filter(params, config).map_err(bad_request)?;
+    let metric = parse_metric(params).map_err(bad_request)?;
+    let interval = match params.get("interval") {
+        Some(interval) => parse_duration(interval).map_err(bad_request)?,
+        None => 60,
+    };
+    if interval == 0 {
+        return Err(bad_request("interval must be at least 1s".to_string()));
+    }
+    let points = (filter.end - filter.start).num_seconds() as u64 / interval;
+    if points > config.max_points {
+        return Err(bad_request(format!("{} points per series, at most {} are allowed", points, config.max_points)));
+    }
+    let group_by = params.get("group_by").map(|tag| tag.as_str());
+    if let Some(tag) = group_by {
+        if !valid_tag_name(tag) {
+            return Err(bad_request(format!("invalid group_by {}", tag)));
+        }
+    }
+    let series = store.timeseries(&filter, metric, interval, group_by).await.map_err(store_error)?;
+    Ok(json!({
+        "start": filter.start,
+        "end": filter.end,
+        "metric": metric,
+        "interval_secs": interval,
+        "series": series,
+    }))
+}
+
+async fn flows(store: &dyn FlowStore, config: &ApiConfig, params: &BTreeMap<String, String>) -> Result<serde_json::Value, Response> {
+    let filter = parse_filter(params, config).map_err(bad_request)?;
+    let limit = parse_limit(params, 100, config.max_rows).map_err(bad_request)?;
+    let flows = store.flows(&filter, limit).await.map_err(store_error)?;
+    Ok(json!({
+        "start": filter.start,
+        "end": filter.end,
+        "flows": flows,
+    }))
+}
+
+fn bad_request(message: String) -> Response {
+    Response::error("400 Bad Request", message)
+}
+
+fn store_error(message: String) -> Response {
+    Response::error("502 Bad Gateway", format!("flow store query failed: {}", message))
+}
+
+fn parse_metric(params: &BTreeMap<String, String>) -> Result<Metric, String> {
+    match params.get("metric").map(|metric| metric.as_str()) {
+        None | Some("bytes") => Ok(Metric::Bytes),
+        Some("packets") => Ok(Metric::Packets),
+        Some("flows") => Ok(Metric::Flows),
+        Some(metric) => Err(format!("metric {} is not one of bytes, packets, flows", metric)),
+    }
+}
+
+fn parse_limit(params: &BTreeMap<String, String>, default: usize, max: usize) -> Result<usize, String> {
+    match params.get("limit") {
+        Some(limit) => match limit.parse::<usize>() {
+            Ok(limit) if limit > 0 => Ok(limit.min(max)),
+            _ => Err(format!("invalid limit {}", limit)),
+        },
+        None => Ok(default.min(max)),
+    }
+}
+
+// Time range and conditions shared by all endpoints
+fn parse_filter(params: &BTreeMap<String, String>, config: &ApiConfig) -> Result<FlowFilter, String> {
+    let now = Utc::now();
+    let end = match params.get("end") {
+        Some(end) => parse_time(end, now)?,
+        None => now,
+    };
+    let start = match params.get("start") {
+        Some(start) => parse_time(start, now)?,
+        None => i64::try_from(config.default_range_secs)
+            .ok()
+            .and_then(Duration::try_seconds)
+            .and_then(|range| end.checked_sub_signed(range))
+            .ok_or_else(|| format!("api.default_range_secs {} is too long", config.default_range_secs))?,
+    };
+    if start >= end {
+        return Err("start must be before end".to_string());
+    }
+
+    let mut filters = Vec::new();
+    for (name, value) in params {
+        let (side, key) = match name.split_once('_') {
+            Some(("src", key)) => (Side::Src, key),
+            Some(("dst", key)) => (Side::Dst, key),
+            _ => (Side::Either, name.as_str()),
+        };
+        let filter = match key {
+            "ip" | "prefix" => Filter::Address(side, parse_network(value)?),
+            "asn" => Filter::Asn(side, parse_number(name, value.trim_start_matches("AS"))?),
+            "port" => {
+                let port = parse_number(name, value)?;
+                Filter::Port(side, port, port)
+            }
+            "country" => Filter::Country(side, value.to_uppercase()),
+            "protocol" if side == Side::Either => Filter::Protocol(filter::parse_protocol(value)?),
+            "exporter" if side == Side::Either => Filter::Exporter(parse_network(value)?),
+            "service" if side == Side::Either => Filter::Tag(key.to_string(), Some(value.clone())),
+            "filter" if side == Side::Either => Filter::parse(value).map_err(|e| format!("invalid filter: {}", e))?,
+            _ => match name.strip_prefix("tag.") {
+                Some(tag) => Filter::Tag(tag.to_string(), Some(value.clone())),
+                // Endpoint parameters
+                None => continue,
+            },
+        };
+        filters.push(filter);
+    }
+    let filter = filters.into_iter().reduce(|left, right| Filter::And(Box::new(left), Box::new(right)));
+    Ok(FlowFilter { start, end, filter })
+}
+
+// An address or prefix, "192.0.2.1" or "192.0.2.0/24"
+fn parse_network(text: &str) -> Result<Ipv4Cidr, String> {
+    if let Ok(ip) = text.parse::<Ipv4Addr>() {
+        return Ok(Ipv4Cidr::new_host(ip));
+    }
+    text.parse::<Ipv4Cidr>().map_err(|e| format!("invalid prefix {}: {}", text, e))
+}
+
+fn parse_number<T: std::str::FromStr>(name: &str, value: &str) -> Result<T, String> {
+    value.parse().map_err(|_| format!("invalid {} {}", name, value))
+}
+
+// RFC 3339, unix seconds, "now" or an offset from now like "-15m"
+pub fn parse_time(text: &str, now: DateTime<Utc>) -> Result<DateTime<Utc>, String> {
+    if text == "now" {
+        return Ok(now);
+    }
+    if let Some(offset) = text.strip_prefix('-') {
+        return i64::try_from(parse_duration(offset)?)
+            .ok()
+            .and_then(Duration::try_seconds)
+            .and_then(|offset| now.checked_sub_signed(offset))
+            .ok_or_else(|| format!("invalid time {}, too far back", text));
+    }
+    if let Ok(secs) = text.parse::<i64>() {
+        return Utc.timestamp_opt(secs, 0).single().ok_or_else(|| format!("invalid time {}", text));
+    }
+    DateTime::parse_from_rfc3339(text)
+        .map(|time| time.with_timezone(&Utc))
+        .map_err(|_| format!("invalid time {}, expected RFC 3339, unix seconds or an offset like -1h", text))
+}
+
+// "90", "90s", "15m", "2h" or "7d" in seconds
+pub fn parse_duration(text: &str) -> Result<u64, String> {
+    let (number, unit) = match text.find(|c: char| !c.is_ascii_digit()) {
+        Some(index) => text.split_at(index),
+        None => (text, "s"),
+    };
+    let multiplier = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 3600,
+        "d" => 86400,
+        _ => return Err(format!("invalid duration {}", text)),
+    };
+    number
+        .parse::<u64>()
+        .ok()
+        .and_then(|number| number.checked_mul(multiplier))
+        .ok_or_else(|| format!("invalid duration {}", text))
+}
+
+fn parse_query(query: &str) -> BTreeMap<String, String> {
+    query
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+            (percent_decode(name), percent_decode(value))
+        })
+        .collect()
+}
+
+fn percent_decode(text: &str) -> String {
+    let bytes = text.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'+' => out.push(b' '),
+            b'%' if i + 2 < bytes.len() => {
+                match u8::from_str_radix(std::str::from_utf8(&bytes[i + 1..i + 3]).unwrap_or(""), 16) {
+                    Ok(byte) => {
+                        out.push(byte);
+                        i += 2;
+                    }
+                    Err(_) => out.push(b'%'),
+                }
+            }
+            byte => out.push(byte),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_times_and_durations() {
+        let now = Utc.timestamp_opt(1_700_000_000, 0).unwrap();
+        assert_eq!(parse_duration("90"), Ok(90));
+        assert_eq!(parse_duration("15m"), Ok(900));
+        assert_eq!(parse_duration("7d"), Ok(604_800));
+        assert!(parse_duration("2w").is_err());
+        assert!(parse_duration("h").is_err());
+        assert!(parse_duration("18446744073709551615d").is_err());
+
+        assert_eq!(parse_time("now", now), Ok(now));
+        assert_eq!(parse_time("-1h", now), Ok(now - Duration::hours(1)));
+        assert_eq!(parse_time("1700000000", now), Ok(now));
+        assert_eq!(parse_time("2023-11-14T23:13:20+01:00", now), Ok(now));
+        // Past what chrono can represent, an error instead of a panic
+        for text in ["-9223372036854775807s", "-106751991167300d", "-300000000000d", "99999999999999999"] {
+            assert!(parse_time(text, now).is_err(), "{}", text);
+        }
+    }
+}
@@ -0,0 +1,243 @@
+use std::any::Any;
+use std::collections::{BTreeMap, HashMap};
+
+use chrono::{DateTime, Utc};
+use cidr::Ipv4Inet;
+use serde::{Deserialize, Serialize};
+
+use crate::process::aggregate::Aggregator;
+use crate::process::flow::{Aggregate, EnrichedFlow};
+use crate::sketch::HyperLogLog;
+
+pub const CARDINALITY_MEASUREMENT: &str = "cardinality";
+
+// Keys that don't fit in `max_keys` are counted under this one
+const OTHER_KEY: &str = "other";
+
+// What the distinct counts can be kept for. `all` has a single key and answers
+// "how many sources did we see at all".
+pub const CARDINALITY_DIMENSIONS: [&str; 4] = ["all", "dst_prefix", "dst_port", "src_country"];
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct CardinalityConfig {
+    pub dimensions: Vec<String>,
+    // Destination prefixes are this long
+    pub prefix_len: u8,
+    // 2^precision registers per sketch, 10 is 1 KiB and about 3% error
+    pub precision: u8,
+    // Distinct keys per dimension and window
+    pub max_keys: usize,
+    // Publish the HyperLogLog registers with the estimates, needed to merge the
+    // counts of several enricher instances
+    pub emit_sketches: bool,
+}
+
+impl Default for CardinalityConfig {
+    fn default() -> Self {
+        CardinalityConfig {
+            dimensions: CARDINALITY_DIMENSIONS.iter().map(|d| d.to_string()).collect(),
+            prefix_len: 24,
+            precision: 10,
+            max_keys: 1000,
+            emit_sketches: false,
+        }
+    }
+}
+
+#[derive(Clone)]
+struct Distinct {
+    src_ips: HyperLogLog,
+    dst_ips: HyperLogLog,
+}
+
+struct Dimension {
+    name: String,
+    keys: HashMap<String, Distinct>,
+}
+
+// Unique source and destination addresses per dimension key. Published as one
+// `cardinality` point per key with `unique_src_ips` and `unique_dst_ips` fields.
+pub struct DistinctCounters {
+    config: CardinalityConfig,
+    dimensions: Vec<Dimension>,
+}
+
+impl DistinctCounters {
+    pub fn new(config: &CardinalityConfig) -> Self {
+        let dimensions = config
+            .dimensions
+            .iter()
+            .filter(|name| {
+                let known = CARDINALITY_DIMENSIONS.contains(&name.as_str());
+                if !known {
+                    println!("Ignoring unknown cardinality dimension {:?}", name);
+                }
+                known
+            })
+            .map(|name| Dimension {
+                name: name.clone(),
+                keys: HashMap::new(),
+            })
+            .collect();
+        DistinctCounters {
+            config: config.clone(),
+            dimensions,
+        }
+    }
+
+    fn dimension_key(&self, dimension: &str, flow: &EnrichedFlow) -> Option<String> {
+        match dimension {
+            "all" => Some("all".to_string()),
+            "dst_prefix" => {
+                let dst = flow.dst_addr()?;
+                Some(Ipv4Inet::new(dst, self.config.prefix_len.min(32)).ok()?.network().to_string())
+            }
+            // Scans spread over destination ports, so this is the port that was hit
+            "dst_port" => match flow.tag("protocol_name")? {
+                protocol @ ("tcp" | "udp" | "sctp" | "dccp") => Some(format!("{}/{}", protocol, flow.fields.dst_port)),
+                protocol => Some(protocol.to_string()),
+            },
+            "src_country" => flow.tag("src_country").map(|c| c.to_string()),
+            _ => None,
+        }
+    }
+}
+
+impl Aggregator for DistinctCounters {
+    fn observe(&mut self, flow: &EnrichedFlow) {
+        let (src, dst) = match (flow.tag("src_ip"), flow.tag("dst_ip")) {
+            (Some(src), Some(dst)) => (src, dst),
+            _ => return,
+        };
+        for i in 0..self.dimensions.len() {
+            let mut key = match self.dimension_key(&self.dimensions[i].name, flow) {
+                Some(key) => key,
+                None => continue,
+            };
+            let dimension = &mut self.dimensions[i];
+            if !dimension.keys.contains_key(&key) && dimension.keys.len() >= self.config.max_keys {
+                key = OTHER_KEY.to_string();
+            }
+            let precision = self.config.precision;
+            let distinct = dimension.keys.entry(key).or_insert_with(|| Distinct {
+                src_ips: HyperLogLog::new(precision),
+                dst_ips: HyperLogLog::new(precision),
+            });
+            distinct.src_ips.add(src);
+            distinct.dst_ips.add(dst);
+        }
+    }
+
+    fn merge(&mut self, other: &mut dyn Aggregator) {
+        let Some(other) = other.as_any_mut().downcast_mut::<DistinctCounters>() else {
+            return;
+        };
+        for (into, from) in self.dimensions.iter_mut().zip(other.dimensions.iter_mut()) {
+            for (mut key, distinct) in from.keys.drain() {
+                if !into.keys.contains_key(&key) && into.keys.len() >= self.config.max_keys {
+                    key = OTHER_KEY.to_string();
+                }
+                match into.keys.get_mut(&key) {
+                    // Same config, same precision
+                    Some(current) => {
+                        let _ = current.src_ips.merge(&distinct.src_ips);
+                        let _ = current.dst_ips.merge(&distinct.dst_ips);
+                    }
+                    None => {
+                        into.keys.insert(key, distinct);
+                    }
+                }
+            }
+        }
+    }
+
+    fn flush(&mut self, time: DateTime<Utc>) -> Vec<Aggregate> {
+        let mut aggregates = Vec::new();
+        for dimension in self.dimensions.iter_mut() {
+            for (key, distinct) in dimension.keys.drain() {
+                let mut aggregate = Aggregate::new(CARDINALITY_MEASUREMENT, time)
+                    .tag("dimension", dimension.name.as_str())
+                    .tag("key", key)
+                    .field("unique_src_ips", distinct.src_ips.estimate())
+                    .field("unique_dst_ips", distinct.dst_ips.estimate());
+                if self.config.emit_sketches {
+                    aggregate = aggregate
+                        .sketch("unique_src_ips", distinct.src_ips.to_hex())
+                        .sketch("unique_dst_ips", distinct.dst_ips.to_hex());
+                }
+                aggregates.push(aggregate);
+            }
+        }
+        aggregates
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+// Sketches of one point, by field name
+type Sketches = BTreeMap<String, HyperLogLog>;
+
+// Unions the cardinality points of several enrichers. Enrichers flush on the same
+// wall clock boundaries, so points with the same time, dimension and key describe
+// the same window; each one is answered with the union of everything seen so far,
+// which makes writing it over the previous point correct.
+pub struct SketchMerger {
+    // Windows kept, older ones are forgotten
+    keep: usize,
+    windows: BTreeMap<DateTime<Utc>, HashMap<(String, String), Sketches>>,
+}
+
+impl SketchMerger {
+    pub fn new(keep: usize) -> Self {
+        SketchMerger {
+            keep: keep.max(1),
+            windows: BTreeMap::new(),
+        }
+    }
+
+    pub fn merge(&mut self, mut aggregate: Aggregate) -> Aggregate {
+        if aggregate.measurement != CARDINALITY_MEASUREMENT || aggregate.sketches.is_empty() {
+            return aggregate;
+        }
+        if self.windows.len() >= self.keep && !self.windows.contains_key(&aggregate.time) {
+            if self.windows.keys().next().is_some_and(|oldest| *oldest > aggregate.time) {
+                // Older than every window we remember, nothing to merge with
+                return aggregate;
+            }
+            self.windows.pop_first();
+        }
+
+        let id = (
+            aggregate.tags.get("dimension").cloned().unwrap_or_default(),
+            aggregate.tags.get("key").cloned().unwrap_or_default(),
+        );
+        let merged = self.windows.entry(aggregate.time).or_default().entry(id).or_default();
+        for (name, hex) in &aggregate.sketches {
+            let hll = match HyperLogLog::from_hex(hex) {
+                Some(hll) => hll,
+                None => {
+                    println!("Ignoring malformed {} sketch", name);
+                    continue;
+                }
+            };
+            match merged.get_mut(name) {
+                Some(current) => {
+                    if let Err(e) = current.merge(&hll) {
+                        println!("Can't merge {} sketch: {}", name, e);
+                    }
+                }
+                None => {
+                    merged.insert(name.clone(), hll);
+                }
+            }
+        }
+        for (name, hll) in merged.iter() {
+            aggregate.fields.insert(name.clone(), hll.estimate());
+            aggregate.sketches.insert(name.clone(), hll.to_hex());
+        }
+        aggregate
+    }
+}
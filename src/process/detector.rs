@@ -0,0 +1,622 @@
+use std::collections::{BTreeSet, HashMap};
+use std::fmt;
+use std::net::Ipv4Addr;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use cidr::{Ipv4Cidr, Ipv4Inet};
+use serde::{Deserialize, Serialize};
+
+use crate::process::flow::EnrichedFlow;
+use crate::transport::{consume_windows, Done, SharedTransport, Windowed, ALERTS, ENRICHER_TO_TSDB};
+
+pub const DETECTOR_GROUP: &str = "ta-detector";
+
+const IPPROTO_ICMP: u8 = 1;
+const IPPROTO_TCP: u8 = 6;
+const IPPROTO_UDP: u8 = 17;
+const TCP_SYN: u8 = 0x02;
+const TCP_ACK: u8 = 0x10;
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct DetectorConfig {
+    // Destinations are grouped into prefixes of this length
+    pub prefix_len: u8,
+    // Only destinations inside these networks are watched, every destination when empty
+    pub protected: Vec<String>,
+    // The exporters sample 1 in `sampling_rate` packets
+    pub sampling_rate: u64,
+    // Rates are computed over windows of this length
+    pub window_secs: u64,
+    pub thresholds: Thresholds,
+    pub signatures: SignatureConfig,
+    pub baseline: BaselineConfig,
+    // An ongoing attack is reported again every `update_secs`
+    pub update_secs: u64,
+    // and ends once it stayed below every threshold for `end_after_secs`
+    pub end_after_secs: u64,
+    // Every event is also POSTed as JSON to this URL
+    pub webhook: Option<String>,
+}
+
+impl Default for DetectorConfig {
+    fn default() -> Self {
+        DetectorConfig {
+            prefix_len: 24,
+            protected: Vec::new(),
+            sampling_rate: 1,
+            window_secs: 10,
+            thresholds: Thresholds::default(),
+            signatures: SignatureConfig::default(),
+            baseline: BaselineConfig::default(),
+            update_secs: 60,
+            end_after_secs: 120,
+            webhook: None,
+        }
+    }
+}
+
+// Static per prefix limits, 0 disables a limit
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct Thresholds {
+    pub pps: u64,
+    pub bps: u64,
+    pub fps: u64,
+}
+
+impl Default for Thresholds {
+    fn default() -> Self {
+        Thresholds {
+            pps: 100_000,
+            bps: 1_000_000_000,
+            fps: 10_000,
+        }
+    }
+}
+
+// Packet rates above which a kind of traffic counts as an attack on its own
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct SignatureConfig {
+    // UDP source ports of services abused for reflection
+    pub amplification_ports: Vec<u16>,
+    pub amplification_pps: u64,
+    // TCP packets with SYN but without ACK
+    pub syn_pps: u64,
+    pub icmp_pps: u64,
+}
+
+impl Default for SignatureConfig {
+    fn default() -> Self {
+        SignatureConfig {
+            // chargen, dns, portmap, ntp, netbios, snmp, cldap, ssdp, ard, ws-discovery, mdns, memcached
+            amplification_ports: vec![19, 53, 111, 123, 137, 161, 389, 1900, 3283, 3702, 5353, 11211],
+            amplification_pps: 20_000,
+            syn_pps: 20_000,
+            icmp_pps: 20_000,
+        }
+    }
+}
+
+// Learned normal rate of every prefix, an exponentially weighted moving average per window
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct BaselineConfig {
+    pub enabled: bool,
+    // Weight of the newest window
+    pub alpha: f64,
+    // A prefix is under attack when its rate is `factor` times its baseline
+    pub factor: f64,
+    // and at least `min_pps`, so quiet prefixes don't alert on every burst
+    pub min_pps: u64,
+    // Windows to learn before the baseline is trusted
+    pub warmup_windows: u64,
+}
+
+impl Default for BaselineConfig {
+    fn default() -> Self {
+        BaselineConfig {
+            enabled: true,
+            alpha: 0.05,
+            factor: 5.0,
+            min_pps: 5_000,
+            warmup_windows: 30,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum AttackPhase {
+    Start,
+    Update,
+    End,
+}
+
+impl fmt::Display for AttackPhase {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            AttackPhase::Start => "start",
+            AttackPhase::Update => "update",
+            AttackPhase::End => "end",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+// What is published on the `alerts` topic and sent to the webhook
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AttackEvent {
+    pub id: String,
+    pub event: AttackPhase,
+    pub prefix: String,
+    pub started: DateTime<Utc>,
+    pub time: DateTime<Utc>,
+    pub duration_secs: i64,
+    pub pps: u64,
+    pub bps: u64,
+    pub fps: u64,
+    pub peak_pps: u64,
+    pub peak_bps: u64,
+    pub peak_fps: u64,
+    // udp_amplification, syn_flood, icmp_flood
+    pub signatures: Vec<String>,
+    // Why the prefix counts as attacked, e.g. "pps 250000 above 100000"
+    pub reasons: Vec<String>,
+}
+
+impl AttackEvent {
+    pub fn to_json(&self) -> Vec<u8> {
+        serde_json::to_vec(self).expect("AttackEvent can always be serialized")
+    }
+}
+
+#[derive(Debug, Default)]
+struct Counters {
+    packets: u64,
+    bytes: u64,
+    flows: u64,
+    amplification_packets: u64,
+    syn_packets: u64,
+    icmp_packets: u64,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct Rates {
+    pps: u64,
+    bps: u64,
+    fps: u64,
+}
+
+#[derive(Debug, Default)]
+struct Baseline {
+    pps: f64,
+    bps: f64,
+    windows: u64,
+}
+
+#[derive(Debug)]
+struct Attack {
+    id: String,
+    started: DateTime<Utc>,
+    last_over: DateTime<Utc>,
+    last_report: DateTime<Utc>,
+    current: Rates,
+    peak: Rates,
+    signatures: BTreeSet<String>,
+    reasons: Vec<String>,
+}
+
+// Aggregates flows per destination prefix and decides at the end of every window
+// which prefixes are under attack
+pub struct Detector {
+    config: DetectorConfig,
+    protected: Vec<Ipv4Cidr>,
+    window: HashMap<Ipv4Cidr, Counters>,
+    baselines: HashMap<Ipv4Cidr, Baseline>,
+    attacks: HashMap<Ipv4Cidr, Attack>,
+}
+
+impl Detector {
+    // Fails on a protected network that doesn't parse, dropping it would watch more
+    // destinations than configured, or all of them
+    pub fn new(config: DetectorConfig) -> Result<Self, String> {
+        let protected = config
+            .protected
+            .iter()
+            .map(|network| {
+                network
+                    .parse()
+                    .map_err(|_| format!("detector.protected: {:?} is not an IPv4 network a.b.c.d/len", network))
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(Detector {
+            config,
+            protected,
+            window: HashMap::new(),
+            baselines: HashMap::new(),
+            attacks: HashMap::new(),
+        })
+    }
+
+    pub fn observe(&mut self, flow: &EnrichedFlow) {
+        let dst = match flow.dst_addr() {
+            Some(dst) => dst,
+            None => return,
+        };
+        if !self.protected.is_empty() && !self.protected.iter().any(|network| network.contains(&dst)) {
+            return;
+        }
+
+        let prefix = prefix_of(dst, self.config.prefix_len);
+        let fields = &flow.fields;
+        // A record longer than the window, e.g. exported on the active timeout, only
+        // adds the share of its traffic one window of its duration carried
+        let window_ms = self.config.window_secs.max(1).saturating_mul(1000);
+        let duration_ms = fields.last_switched.saturating_sub(fields.first_switched).max(window_ms);
+        let share = |count: u64| (count as u128 * window_ms as u128 / duration_ms as u128) as u64;
+        let (packets, bytes) = (share(fields.packets), share(fields.bytes));
+
+        let counters = self.window.entry(prefix).or_default();
+        counters.packets = counters.packets.saturating_add(packets);
+        counters.bytes = counters.bytes.saturating_add(bytes);
+        counters.flows = counters.flows.saturating_add(1);
+        let kind = match fields.protocol {
+            IPPROTO_UDP if self.config.signatures.amplification_ports.contains(&fields.src_port) => {
+                Some(&mut counters.amplification_packets)
+            }
+            IPPROTO_TCP if fields.tcp_flags & TCP_SYN != 0 && fields.tcp_flags & TCP_ACK == 0 => Some(&mut counters.syn_packets),
+            IPPROTO_ICMP => Some(&mut counters.icmp_packets),
+            _ => None,
+        };
+        if let Some(count) = kind {
+            *count = count.saturating_add(packets);
+        }
+    }
+
+    // Close the current window and return the attack events it caused
+    pub fn evaluate(&mut self, now: DateTime<Utc>) -> Vec<AttackEvent> {
+        let window = std::mem::take(&mut self.window);
+        let scale = self.config.sampling_rate.max(1) as f64 / self.config.window_secs.max(1) as f64;
+        let rate = |count: u64| (count as f64 * scale).round() as u64;
+
+        // Prefixes without traffic in this window count as idle for their baseline
+        for (prefix, baseline) in self.baselines.iter_mut() {
+            if !window.contains_key(prefix) && !self.attacks.contains_key(prefix) {
+                learn(baseline, Rates::default(), self.config.baseline.alpha);
+            }
+        }
+        self.baselines.retain(|_, baseline| baseline.pps >= 0.01 || baseline.bps >= 0.01);
+
+        // Attacked prefixes that went quiet have no rate in this window
+        for attack in self.attacks.values_mut() {
+            attack.current = Rates::default();
+        }
+
+        let mut events = Vec::new();
+        for (prefix, counters) in window {
+            let rates = Rates {
+                pps: rate(counters.packets),
+                bps: rate(counters.bytes.saturating_mul(8)),
+                fps: rate(counters.flows),
+            };
+            let (signatures, mut reasons) = self.signatures(&counters, rate);
+            reasons.extend(self.threshold_reasons(rates));
+
+            let baseline = self.baselines.entry(prefix).or_default();
+            reasons.extend(baseline_reasons(&self.config.baseline, baseline, rates));
+            if reasons.is_empty() {
+                match self.attacks.get_mut(&prefix) {
+                    Some(attack) => attack.current = rates,
+                    None => learn(baseline, rates, self.config.baseline.alpha),
+                }
+                continue;
+            }
+
+            match self.attacks.get_mut(&prefix) {
+                Some(attack) => {
+                    attack.last_over = now;
+                    attack.current = rates;
+                    attack.peak.pps = attack.peak.pps.max(rates.pps);
+                    attack.peak.bps = attack.peak.bps.max(rates.bps);
+                    attack.peak.fps = attack.peak.fps.max(rates.fps);
+                    attack.signatures.extend(signatures);
+                    attack.reasons = reasons;
+                }
+                None => {
+                    let attack = Attack {
+                        id: uuid::Uuid::new_v4().to_string(),
+                        started: now,
+                        last_over: now,
+                        last_report: now,
+                        current: rates,
+                        peak: rates,
+                        signatures,
+                        reasons,
+                    };
+                    events.push(attack_event(prefix, &attack, AttackPhase::Start, now));
+                    self.attacks.insert(prefix, attack);
+                }
+            }
+        }
+
+        let update_after = chrono::Duration::seconds(self.config.update_secs as i64);
+        let end_after = chrono::Duration::seconds(self.config.end_after_secs as i64);
+        let mut ended = Vec::new();
+        for (prefix, attack) in self.attacks.iter_mut() {
+            if now - attack.last_over >= end_after {
+                ended.push(*prefix);
+            } else if now - attack.last_report >= update_after {
+                attack.last_report = now;
+                events.push(attack_event(*prefix, attack, AttackPhase::Update, now));
+            }
+        }
+        for prefix in ended {
+            if let Some(attack) = self.attacks.remove(&prefix) {
+                events.push(attack_event(prefix, &attack, AttackPhase::End, now));
+            }
+        }
+        events
+    }
+
+    fn signatures(&self, counters: &Counters, rate: impl Fn(u64) -> u64) -> (BTreeSet<String>, Vec<String>) {
+        let config = &self.config.signatures;
+        let mut signatures = BTreeSet::new();
+        let mut reasons = Vec::new();
+        for (name, packets, limit) in [
+            ("udp_amplification", counters.amplification_packets, config.amplification_pps),
+            ("syn_flood", counters.syn_packets, config.syn_pps),
+            ("icmp_flood", counters.icmp_packets, config.icmp_pps),
+        ] {
+            let pps = rate(packets);
+            if limit > 0 && pps >= limit {
+                signatures.insert(name.to_string());
+                reasons.push(format!("{} pps {} above {}", name, pps, limit));
+            }
+        }
+        (signatures, reasons)
+    }
+
+    fn threshold_reasons(&self, rates: Rates) -> Vec<String> {
+        let thresholds = &self.config.thresholds;
+        [("pps", rates.pps, thresholds.pps), ("bps", rates.bps, thresholds.bps), ("fps", rates.fps, thresholds.fps)]
+            .into_iter()
+            .filter(|(_, value, limit)| *limit > 0 && value >= limit)
+            .map(|(name, value, limit)| format!("{} {} above {}", name, value, limit))
+            .collect()
+    }
+}
+
+fn prefix_of(addr: Ipv4Addr, len: u8) -> Ipv4Cidr {
+    Ipv4Inet::new(addr, len.min(32)).expect("prefix length is at most 32").network()
+}
+
+fn learn(baseline: &mut Baseline, rates: Rates, alpha: f64) {
+    if baseline.windows == 0 {
+        baseline.pps = rates.pps as f64;
+        baseline.bps = rates.bps as f64;
+    } else {
+        baseline.pps += alpha * (rates.pps as f64 - baseline.pps);
+        baseline.bps += alpha * (rates.bps as f64 - baseline.bps);
+    }
+    baseline.windows += 1;
+}
+
+fn baseline_reasons(config: &BaselineConfig, baseline: &Baseline, rates: Rates) -> Vec<String> {
+    if !config.enabled || baseline.windows < config.warmup_windows || rates.pps < config.min_pps {
+        return Vec::new();
+    }
+    let mut reasons = Vec::new();
+    if rates.pps as f64 > baseline.pps * config.factor {
+        reasons.push(format!("pps {} is {:.1}x the baseline {:.0}", rates.pps, rates.pps as f64 / baseline.pps.max(1.0), baseline.pps));
+    }
+    if rates.bps as f64 > baseline.bps * config.factor {
+        reasons.push(format!("bps {} is {:.1}x the baseline {:.0}", rates.bps, rates.bps as f64 / baseline.bps.max(1.0), baseline.bps));
+    }
+    reasons
+}
+
+fn attack_event(prefix: Ipv4Cidr, attack: &Attack, phase: AttackPhase, now: DateTime<Utc>) -> AttackEvent {
+    AttackEvent {
+        id: attack.id.clone(),
+        event: phase,
+        prefix: prefix.to_string(),
+        started: attack.started,
+        time: now,
+        duration_secs: (now - attack.started).num_seconds(),
+        pps: attack.current.pps,
+        bps: attack.current.bps,
+        fps: attack.current.fps,
+        peak_pps: attack.peak.pps,
+        peak_bps: attack.peak.bps,
+        peak_fps: attack.peak.fps,
+        signatures: attack.signatures.iter().cloned().collect(),
+        reasons: attack.reasons.clone(),
+    }
+}
+
+
+// Publishes what the detector found at the end of every window
+struct Alerting {
+    detector: Detector,
+    transport: SharedTransport,
+    client: reqwest::Client,
+    webhook: Option<String>,
+}
+
+#[async_trait]
+impl Windowed for Alerting {
+    type Item = EnrichedFlow;
+
+    fn observe(&mut self, flow: EnrichedFlow, _offset: i64) {
+        self.detector.observe(&flow);
+    }
+
+    async fn flush(&mut self) -> Done {
+        for event in self.detector.evaluate(Utc::now()) {
+            println!("Attack {} on {}: {}", event.event, event.prefix, event.reasons.join(", "));
+            if let Err(e) = self.transport.send(ALERTS, Some(event.prefix.as_bytes()), &event.to_json()).await {
+                println!("Error publishing alert: {:?}", e);
+            }
+            if let Some(url) = &self.webhook {
+                let request = self
+                    .client
+                    .post(url)
+                    .header(reqwest::header::CONTENT_TYPE, "application/json")
+                    .body(event.to_json());
+                match request.send().await {
+                    Ok(response) if !response.status().is_success() => {
+                        println!("Webhook {} answered {}", url, response.status());
+                    }
+                    Ok(_) => {}
+                    Err(e) => println!("Error calling webhook {}: {}", url, e),
+                }
+            }
+        }
+        Done::All
+    }
+}
+
+// Watch the enriched flows and publish attack events on the `alerts` topic and the webhook
+pub async fn run(transport: SharedTransport, detector: Detector) {
+    let mut subscription = transport
+        .subscribe(ENRICHER_TO_TSDB, DETECTOR_GROUP)
+        .await
+        .expect("Can't subscribe to specified topic");
+
+    let window = Duration::from_secs(detector.config.window_secs.max(1));
+    let client = reqwest::Client::builder()
+        .timeout(Duration::from_secs(5))
+        .build()
+        .expect("Can't build the webhook client");
+    let mut alerting = Alerting {
+        webhook: detector.config.webhook.clone(),
+        detector,
+        transport,
+        client,
+    };
+    consume_windows(subscription.as_mut(), window, &mut alerting).await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::process::flow::FlowRecord;
+
+    fn flow(dst_ip: &str, packets: u64) -> EnrichedFlow {
+        let record = FlowRecord {
+            src_ip: "203.0.113.1".to_string(),
+            dst_ip: dst_ip.to_string(),
+            protocol: IPPROTO_TCP,
+            packets,
+            bytes: packets * 100,
+            ..FlowRecord::default()
+        };
+        EnrichedFlow::new(&record, Utc::now())
+    }
+
+    fn static_thresholds() -> DetectorConfig {
+        DetectorConfig {
+            window_secs: 1,
+            thresholds: Thresholds { pps: 1000, bps: 0, fps: 0 },
+            baseline: BaselineConfig { enabled: false, ..BaselineConfig::default() },
+            update_secs: 2,
+            end_after_secs: 3,
+            ..DetectorConfig::default()
+        }
+    }
+
+    fn at(secs: i64) -> DateTime<Utc> {
+        DateTime::from_timestamp(1_700_000_000 + secs, 0).unwrap()
+    }
+
+    fn phases(events: &[AttackEvent]) -> Vec<(AttackPhase, &str)> {
+        events.iter().map(|event| (event.event, event.prefix.as_str())).collect()
+    }
+
+    #[test]
+    fn reports_the_start_updates_and_end_of_an_attack() {
+        let mut detector = Detector::new(static_thresholds()).unwrap();
+        detector.observe(&flow("198.51.100.7", 5000));
+        detector.observe(&flow("192.0.2.1", 10));
+        let events = detector.evaluate(at(0));
+        assert_eq!(phases(&events), [(AttackPhase::Start, "198.51.100.0/24")]);
+        assert_eq!(events[0].pps, 5000);
+        assert_eq!(events[0].reasons, ["pps 5000 above 1000"]);
+
+        detector.observe(&flow("198.51.100.9", 8000));
+        assert!(detector.evaluate(at(1)).is_empty());
+        detector.observe(&flow("198.51.100.9", 2000));
+        let events = detector.evaluate(at(2));
+        assert_eq!(phases(&events), [(AttackPhase::Update, "198.51.100.0/24")]);
+        assert_eq!(events[0].peak_pps, 8000);
+
+        // Quiet since 2s, ends after 3s below every threshold
+        assert!(detector.evaluate(at(3)).is_empty());
+        let events = detector.evaluate(at(5));
+        assert_eq!(phases(&events), [(AttackPhase::End, "198.51.100.0/24")]);
+        assert_eq!(events[0].duration_secs, 5);
+        assert!(detector.evaluate(at(6)).is_empty());
+    }
+
+    #[test]
+    fn compares_with_the_learned_baseline() {
+        let config = DetectorConfig {
+            thresholds: Thresholds { pps: 0, bps: 0, fps: 0 },
+            baseline: BaselineConfig { enabled: true, alpha: 0.5, factor: 5.0, min_pps: 100, warmup_windows: 3 },
+            ..static_thresholds()
+        };
+        let mut detector = Detector::new(config).unwrap();
+        for secs in 0..3 {
+            detector.observe(&flow("198.51.100.7", 200));
+            assert!(detector.evaluate(at(secs)).is_empty());
+        }
+        // Within 5x of the baseline of 200 pps, and the baseline follows
+        detector.observe(&flow("198.51.100.7", 900));
+        assert!(detector.evaluate(at(3)).is_empty());
+        let baseline = detector.baselines.values().next().unwrap().pps;
+        assert_eq!(baseline, 550.0);
+
+        detector.observe(&flow("198.51.100.7", 3000));
+        let events = detector.evaluate(at(4));
+        assert_eq!(phases(&events), [(AttackPhase::Start, "198.51.100.0/24")]);
+        assert!(events[0].reasons[0].starts_with("pps 3000 is 5.5x the baseline 550"), "{:?}", events[0].reasons);
+        // Attack traffic isn't learned
+        assert_eq!(detector.baselines.values().next().unwrap().pps, 550.0);
+    }
+
+    #[test]
+    fn watches_only_protected_networks() {
+        let config = DetectorConfig { protected: vec!["192.0.2.0/24".to_string()], ..static_thresholds() };
+        let mut detector = Detector::new(config).unwrap();
+        detector.observe(&flow("198.51.100.7", 5000));
+        detector.observe(&flow("192.0.2.1", 5000));
+        assert_eq!(phases(&detector.evaluate(at(0))), [(AttackPhase::Start, "192.0.2.0/24")]);
+
+        let config = DetectorConfig { protected: vec!["192.0.2.0/24".to_string(), "2001:db8::/32".to_string()], ..static_thresholds() };
+        assert!(Detector::new(config).err().unwrap().starts_with("detector.protected: \"2001:db8::/32\""));
+    }
+
+    #[test]
+    fn spreads_long_records_over_their_duration() {
+        let mut detector = Detector::new(static_thresholds()).unwrap();
+        // 60s of 100 pps exported on the active timeout is no burst of 6000 pps
+        let mut long = flow("198.51.100.7", 6000);
+        long.fields.first_switched = 1_000;
+        long.fields.last_switched = 61_000;
+        detector.observe(&long);
+        assert_eq!(detector.window.values().next().unwrap().packets, 100);
+        assert!(detector.evaluate(at(0)).is_empty());
+
+        let mut huge = flow("198.51.100.7", 1);
+        huge.fields.packets = u64::MAX;
+        huge.fields.bytes = u64::MAX;
+        detector.observe(&huge);
+        detector.observe(&huge);
+        assert_eq!(detector.window.values().next().unwrap().bytes, u64::MAX);
+        assert_eq!(phases(&detector.evaluate(at(1))), [(AttackPhase::Start, "198.51.100.0/24")]);
+    }
+}
@@ -1,43 +1,374 @@
 use chrono::Utc;
-use netflow_parser::static_versions::v5::{FlowSet, Header};
-use netflow_parser::variable_versions::common::FieldValue;
+use netflow_parser::static_versions::v5::FlowSet as FlowSetV5;
+use netflow_parser::variable_versions::common::{DataNumber, FieldValue};
 use netflow_parser::variable_versions::ipfix_lookup::IPFixField;
 use netflow_parser::variable_versions::v9_lookup::V9Field;
 use netflow_parser::{NetflowPacketResult, NetflowParser};
-use netflow_parser::static_versions::v5::FlowSet as FlowSetV5;
-use netflow_parser::variable_versions::v9::{FlowSet as FlowSetV9, TemplateField as TemplateFieldV9};
-use netflow_parser::variable_versions::ipfix::{FlowSet as FlowSetIPFix, TemplateField as TemplateFieldIPFix};
-use std::collections::HashMap;
-use serde_json::json;
-use crate::db::cidr_lookup::CidrLookup;
-use crate::db::ip_lookup::{is_private_ip, IPtype};
+use std::collections::{BTreeMap, HashMap};
+use std::io;
 use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use serde::{Deserialize, Serialize};
+use tokio::task::JoinHandle;
+use crate::config::Config;
+use crate::db::bgp_lookup::{self, RouteLookup};
+use crate::db::bmp;
+use crate::db::{archive, clickhouse, influx_db, prometheus};
+use crate::db::cidr_lookup::CidrLookup;
+use crate::db::interface_lookup::{self, InterfaceLookup};
+use crate::db::ip_lookup::{classify_direction, direction_from_home};
+use crate::db::reverse_dns::ReverseDns;
+use crate::db::service_lookup::{protocol_name, ServiceLookup};
+use crate::db::threat_lookup::{self, ThreatLookup};
+use crate::process::aggregate::{self, Aggregators, WorkerAggregators};
+use crate::process::detector::{self, Detector};
+use crate::process::{accounting, anomaly, stitcher};
+use crate::process::flow::{EnrichedFlow, FlowRecord};
+use crate::process::inventory::{self, Inventory, PacketInfo};
+use crate::process::listener::SourceFilter;
+use crate::process::router::Router;
+use crate::process::rules::{self, RuleTagger};
+use crate::process::tenant::{self, Tenant};
+use crate::transport::{SharedTransport, TransportError, ENRICHER_TO_TSDB, LISTENER_TO_ENRICHER, THREAT_MATCHES};
+
+pub const ENRICHER_GROUP: &str = "test-group";
+
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct EnricherConfig {
+    // Number of enrichment tasks
+    pub workers: usize,
+    // IP range to country and IP range to AS tables
+    pub country_map: String,
+    pub as_map: String,
+    // Publish every flow on enricher-to-tsdb, or only where the routes send them
+    pub publish_all: bool,
+}
+
+impl Default for EnricherConfig {
+    fn default() -> Self {
+        EnricherConfig {
+            workers: 10,
+            country_map: "map/ip2country-v4.tsv".to_string(),
+            as_map: "map/ip2asn-v4.tsv".to_string(),
+            publish_all: true,
+        }
+    }
+}
+
+
+// Tables used to enrich flows, loaded once and shared by every worker
+pub struct Lookups {
+    pub cidr: CidrLookup,
+    pub routes: Option<RouteLookup>,
+    pub services: ServiceLookup,
+    pub interfaces: InterfaceLookup,
+    pub threats: ThreatLookup,
+    pub reverse_dns: Option<ReverseDns>,
+    pub rules: RuleTagger,
+    pub router: Router,
+    pub publish_all: bool,
+    pub inventory: Inventory,
+    // Flows that couldn't be published since the last report, with the last error, by topic
+    failed_sends: Mutex<HashMap<String, (u64, String)>>,
+    // v9 and IPFIX templates are only valid for the exporter that sent them
+    parsers: Mutex<HashMap<String, Arc<Mutex<NetflowParser>>>>,
+}
+
+impl Lookups {
+    pub fn load(config: &Config) -> Result<Self, String> {
+        Ok(Lookups {
+            cidr: CidrLookup::new(&config.enricher.country_map, &config.enricher.as_map),
+            routes: config.bgp.as_ref().map(RouteLookup::new),
+            services: ServiceLookup::new(&config.services),
+            interfaces: InterfaceLookup::new(&config.interfaces),
+            threats: ThreatLookup::new(&config.threats),
+            reverse_dns: config.reverse_dns.as_ref().map(ReverseDns::new),
+            rules: RuleTagger::new(&config.rules)?,
+            router: Router::new(&config.routes)?,
+            publish_all: config.enricher.publish_all,
+            inventory: Inventory::default(),
+            failed_sends: Mutex::new(HashMap::new()),
+            parsers: Mutex::new(HashMap::new()),
+        })
+    }
+
+    fn parser(&self, exporter: &str) -> Arc<Mutex<NetflowParser>> {
+        self.parsers.lock().unwrap().entry(exporter.to_string()).or_default().clone()
+    }
+}
+
+
+// Load the lookup tables once and start `workers` enrichment tasks sharing them,
+// fails when the configuration can't be used
+pub fn spawn_workers(transport: SharedTransport, config: &Config) -> io::Result<Vec<JoinHandle<()>>> {
+    let invalid = |e: String| io::Error::new(io::ErrorKind::InvalidInput, e);
+    let lookups = Arc::new(Lookups::load(config).map_err(invalid)?);
+    if let (Some(routes), Some(bgp)) = (&lookups.routes, &config.bgp) {
+        tokio::spawn(bgp_lookup::run_refresher(routes.clone(), bgp.clone()));
+        if let Some(listen) = bgp.bmp_listen {
+            let sources = SourceFilter::new(&config.listener).map_err(invalid)?;
+            tokio::spawn(bmp::run_listener(routes.clone(), listen, Arc::new(sources)));
+        }
+    }
+    tokio::spawn(run_send_reporter(lookups.clone()));
+    if let Some(snmp) = &config.interfaces.snmp {
+        tokio::spawn(interface_lookup::run_poller(lookups.interfaces.clone(), snmp.clone()));
+    }
+    if !config.threats.lists.is_empty() {
+        tokio::spawn(threat_lookup::run_refresher(lookups.threats.clone(), config.threats.clone()));
+    }
+    if config.rules.file.is_some() {
+        tokio::spawn(rules::run_reloader(lookups.rules.clone()));
+    }
+    if let Some(inventory) = &config.inventory {
+        tokio::spawn(inventory::run(transport.clone(), lookups.inventory.clone(), inventory.clone()));
+    }
+    let aggregators = if config.aggregates.is_enabled() {
+        let aggregators = Aggregators::new(&config.aggregates);
+        tokio::spawn(aggregate::run_flusher(transport.clone(), aggregators.clone(), config.aggregates.window_secs));
+        Some(aggregators)
+    } else {
+        None
+    };
+
+    let tenants: Vec<Arc<Tenant>> = config
+        .tenants
+        .iter()
+        .map(|config| Tenant::new(config).map(Arc::new))
+        .collect::<Result<_, _>>()
+        .map_err(invalid)?;
+    for (tenant, tenant_config) in tenants.iter().zip(&config.tenants) {
+        if tenant_config.rules.file.is_some() {
+            tokio::spawn(rules::run_reloader(tenant.rules.clone()));
+        }
+    }
+    if config.tenants.iter().any(|tenant| tenant.max_flows_per_sec > 0) {
+        tokio::spawn(tenant::run_reporter(tenants.clone()));
+    }
+
+    // Every tenant has tasks of its own, a flood from one exporter only backs up its tenant
+    let scopes = std::iter::repeat_n(None, config.enricher.workers).chain(
+        tenants
+            .iter()
+            .zip(&config.tenants)
+            .flat_map(|(tenant, tenant_config)| std::iter::repeat_n(Some(tenant.clone()), tenant_config.workers)),
+    );
+    Ok(scopes
+        .map(|tenant| {
+            let transport = transport.clone();
+            let lookups = lookups.clone();
+            let aggregators = aggregators.as_ref().map(Aggregators::worker);
+            tokio::spawn(async move {
+                run(transport, lookups, aggregators, tenant).await;
+            })
+        })
+        .collect())
+}
+
+// Start the writers and stages configured to consume the enriched flows, fails when
+// their configuration can't be used
+pub fn spawn_sinks(transport: SharedTransport, config: &Config) -> io::Result<()> {
+    if let Some(influx) = &config.influx {
+        tokio::spawn(influx_db::run_tenant_writers(transport.clone(), influx.clone(), config.tenants.clone()));
+        tokio::spawn(influx_db::run_writer(transport.clone(), influx.clone()));
+    }
+    if let Some(clickhouse) = &config.clickhouse {
+        tokio::spawn(clickhouse::run_writer(transport.clone(), clickhouse.clone()));
+    }
+    if let Some(archive) = &config.archive {
+        tokio::spawn(archive::run_writer(transport.clone(), archive.clone()));
+    }
+    if let Some(detector) = &config.detector {
+        let detector = Detector::new(detector.clone()).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        tokio::spawn(detector::run(transport.clone(), detector));
+    }
+    if let Some(anomaly) = &config.anomaly {
+        tokio::spawn(anomaly::run(transport.clone(), anomaly.clone()));
+    }
+    if let Some(accounting) = &config.accounting {
+        tokio::spawn(accounting::run(transport.clone(), accounting.clone()));
+    }
+    if let Some(stitcher) = &config.stitcher {
+        tokio::spawn(stitcher::run(transport.clone(), stitcher.clone()));
+    }
+    if let Some(prometheus) = &config.prometheus {
+        tokio::spawn(prometheus::run(transport, prometheus.clone()));
+    }
+    Ok(())
+}
+
+
+// Consume raw datagrams from the listener and publish the enriched flows for the tsdb,
+// those of a tenant when given
+pub async fn run(transport: SharedTransport, lookups: Arc<Lookups>, aggregators: Option<WorkerAggregators>, tenant: Option<Arc<Tenant>>) {
+    let input_topic = tenant.as_ref().map_or(LISTENER_TO_ENRICHER, |tenant| tenant.input_topic.as_str());
+    let output_topic = tenant.as_ref().map_or(ENRICHER_TO_TSDB, |tenant| tenant.output_topic.as_str());
+    let mut subscription = transport
+        .subscribe(input_topic, ENRICHER_GROUP)
+        .await
+        .expect("Can't subscribe to specified topic");
+
+    loop {
+        match subscription.recv().await {
+            Err(TransportError::Closed) => break,
+            Err(e) => println!("Error receiving message: {:?}", e),
+            Ok(record) => {
+                // The listener keys every datagram with the exporter address
+                let exporter = record.key_str().unwrap_or("Unknown").to_string();
+                if !tenant.as_ref().is_none_or(|tenant| tenant.admit()) {
+                    if let Err(e) = subscription.commit(&record).await {
+                        println!("Error committing message: {:?}", e);
+                    }
+                    continue;
+                }
+                let flows = enrich_packet(&record.payload, &exporter, &lookups, tenant.as_deref()).await;
+                if let Some(tenant) = &tenant {
+                    tenant.charge(flows.len());
+                }
+                if let Some(aggregators) = &aggregators {
+                    aggregators.observe(&flows);
+                }
+                for flow in flows {
+                    let payload = flow.to_json();
+                    if lookups.publish_all {
+                        publish(&transport, &lookups, output_topic, &payload).await;
+                    }
+                    // Security follows flows touching blocklisted addresses on their own topic
+                    if flow.tag("src_threat_list").is_some() || flow.tag("dst_threat_list").is_some() {
+                        publish(&transport, &lookups, THREAT_MATCHES, &payload).await;
+                    }
+                    for (topic, payload) in lookups.router.route(&flow, &payload) {
+                        publish(&transport, &lookups, &topic, &payload).await;
+                    }
+                }
 
+                if let Err(e) = subscription.commit(&record).await {
+                    println!("Error committing message: {:?}", e);
+                }
+            }
+        }
+    }
+}
 
-pub async fn enrich_packet(payload: Vec<u8>, cidr_lookup: CidrLookup) -> Vec<Vec<u8>> {
-    let mut enriched_packets: Vec<Vec<u8>> = Vec::new();
+// A flow that can't be published, e.g. the broker timed out or a route's topic doesn't
+// exist, is counted and skipped, the task goes on with the next one
+async fn publish(transport: &SharedTransport, lookups: &Lookups, topic: &str, payload: &[u8]) {
+    if let Err(e) = transport.send(topic, None, payload).await {
+        let mut failed = lookups.failed_sends.lock().unwrap();
+        let (count, error) = failed.entry(topic.to_string()).or_default();
+        *count += 1;
+        *error = e.to_string();
+    }
+}
 
-    let mut parser = NetflowParser::default();
-    for packet_result in parser.parse_bytes(&payload) {
+// Report the flows that couldn't be published every minute
+async fn run_send_reporter(lookups: Arc<Lookups>) {
+    let mut ticker = tokio::time::interval(Duration::from_secs(60));
+    ticker.tick().await;
+
+    loop {
+        ticker.tick().await;
+        let failed = std::mem::take(&mut *lookups.failed_sends.lock().unwrap());
+        for (topic, (count, error)) in failed {
+            println!("Failed to publish {} flows on {} in the last minute, last error: {}", count, topic, error);
+        }
+    }
+}
+
+
+pub async fn enrich_packet(payload: &[u8], exporter: &str, lookups: &Lookups, tenant: Option<&Tenant>) -> Vec<EnrichedFlow> {
+    let mut records: Vec<FlowRecord> = Vec::new();
+
+    let (packets, templates) = {
+        let parser = lookups.parser(exporter);
+        let mut parser = parser.lock().unwrap();
+        let packets = parser.parse_bytes(payload);
+        let templates = parser.v9_parser.templates.len()
+            + parser.v9_parser.options_templates.len()
+            + parser.ipfix_parser.templates.len()
+            + parser.ipfix_parser.options_templates.len();
+        (packets, templates)
+    };
+    for packet_result in packets {
         match packet_result {
             NetflowPacketResult::V5(packet) => {
                 println!("Parsing NetFlow v5 with {} flows", packet.flowsets.len());
-                for flow in &packet.flowsets {
-                    enrich_flow_v5(flow, &cidr_lookup, &mut enriched_packets);
-                }
+                let header = &packet.header;
+                let flows = packet.flowsets.len() as u32;
+                // The top two bits of the interval are the sampling mode
+                let interval = (header.sampling_interval & 0x3fff) as u64;
+                lookups.inventory.observe(exporter, &PacketInfo {
+                    version: 5,
+                    domain: (header.engine_type as u32) << 8 | header.engine_id as u32,
+                    sequence: header.flow_sequence,
+                    next_sequence: header.flow_sequence.wrapping_add(flows),
+                    flows: flows as u64,
+                    sampling_rate: (interval > 0).then_some(interval),
+                    templates,
+                });
+                records.extend(packet.flowsets.iter().map(record_from_v5));
             },
             NetflowPacketResult::V9(packet) => {
                 println!("Parsing NetFlow v9 with {} flows", packet.flowsets.len());
+                let mut flows = 0;
+                let mut sampling_rate = None;
                 for flow in &packet.flowsets {
-                    // enrich_flow_v9(flow, &flow.body.templates, &cidr_lookup, &mut enriched_packets);
-                    enrich_flow_v9(flow, &cidr_lookup, &mut enriched_packets);
+                    if let Some(data) = &flow.body.data {
+                        flows += data.data_fields.len() as u64;
+                        sampling_rate = sampling_rate.or(data.data_fields.iter().find_map(v9_sampling_rate));
+                        records.extend(data.data_fields.iter().map(record_from_v9));
+                    }
+                    if let Some(options) = &flow.body.options_data {
+                        sampling_rate = sampling_rate.or(options.options_fields.iter().find_map(|field| {
+                            let rate = matches!(field.field_type, V9Field::SamplingInterval | V9Field::FlowSamplerRandomInterval)
+                                .then(|| field.field_value.iter().fold(0u64, |rate, byte| rate << 8 | *byte as u64))?;
+                            (rate > 0).then_some(rate)
+                        }));
+                    }
                 }
+                // v9 counts packets
+                let header = &packet.header;
+                lookups.inventory.observe(exporter, &PacketInfo {
+                    version: 9,
+                    domain: header.source_id,
+                    sequence: header.sequence_number,
+                    next_sequence: header.sequence_number.wrapping_add(1),
+                    flows,
+                    sampling_rate,
+                    templates,
+                });
             },
             NetflowPacketResult::IPFix(packet) => {
                 println!("Parsing IPFIX with {} flows", packet.flowsets.len());
+                let mut flows = 0;
+                let mut data_records = 0;
+                let mut sampling_rate = None;
                 for flow in &packet.flowsets {
-                    enrich_flow_ipfix(flow, &cidr_lookup, &mut enriched_packets);
+                    if let Some(data) = &flow.body.data {
+                        flows += data.data_fields.len() as u64;
+                        data_records += data.data_fields.len() as u32;
+                        sampling_rate = sampling_rate.or(data.data_fields.iter().find_map(ipfix_sampling_rate));
+                        records.extend(data.data_fields.iter().map(record_from_ipfix));
+                    }
+                    if let Some(options) = &flow.body.options_data {
+                        data_records += options.data_fields.len() as u32;
+                        sampling_rate = sampling_rate.or(options.data_fields.iter().find_map(ipfix_sampling_rate));
+                    }
                 }
+                // IPFIX counts data records, options included
+                let header = &packet.header;
+                lookups.inventory.observe(exporter, &PacketInfo {
+                    version: 10,
+                    domain: header.observation_domain_id,
+                    sequence: header.sequence_number,
+                    next_sequence: header.sequence_number.wrapping_add(data_records),
+                    flows,
+                    sampling_rate,
+                    templates,
+                });
             },
             _ => {
                 // Handle other versions or unsupported cases
@@ -46,150 +377,251 @@ pub async fn enrich_packet(payload: Vec<u8>, cidr_lookup: CidrLookup) -> Vec<Vec
         }
     }
 
-    enriched_packets
+    records.iter().filter_map(|record| enrich_flow(record, exporter, lookups, tenant)).collect()
 }
 
 
-// NetFlow v5
-fn enrich_flow_v5(flow: &netflow_parser::static_versions::v5::FlowSet, 
-                  cidr_lookup: &CidrLookup, 
-                  enriched_packets: &mut Vec<Vec<u8>>) {
-    
-    let src_ip = flow.src_addr.to_string();
-    let dst_ip = flow.dst_addr.to_string();
-    let src_country = cidr_lookup.lookup_country(&src_ip).unwrap_or(&"Unknown".to_string()).clone();
-    let dst_country = cidr_lookup.lookup_country(&dst_ip).unwrap_or(&"Unknown".to_string()).clone();
-    let (src_asn, src_as_name) = cidr_lookup.lookup_as(&src_ip).unwrap_or(&(String::from("Unknown"), String::from("Unknown"))).clone();
-    let (dst_asn, dst_as_name) = cidr_lookup.lookup_as(&dst_ip).unwrap_or(&(String::from("Unknown"), String::from("Unknown"))).clone();
-    
-    let packet_type = match (is_private_ip(&src_ip), is_private_ip(&dst_ip)) {
-        (true, true) => Some(IPtype::Incoming),
-        (true, _) => Some(IPtype::Outgoing),
-        (_, true) => Some(IPtype::Incoming),
-        (_, _) => Some(IPtype::Outgoing)
+// Attach everything we know about the addresses and ports of a flow,
+// None when a rule drops it
+pub fn enrich_flow(record: &FlowRecord, exporter: &str, lookups: &Lookups, tenant: Option<&Tenant>) -> Option<EnrichedFlow> {
+    let mut flow = EnrichedFlow::new(record, Utc::now());
+    flow.set_tag("exporter", exporter);
+    if let Some(tenant) = tenant {
+        flow.set_tag("tenant", tenant.name.as_str());
+    }
+    add_geo_tags(&mut flow, record, &lookups.cidr, tenant);
+    if let Some(routes) = &lookups.routes {
+        add_route_tags(&mut flow, record, routes);
+    }
+    add_service_tags(&mut flow, record, &lookups.services);
+    add_interface_tags(&mut flow, record, exporter, &lookups.interfaces);
+    // Rules see the AS and interface tags, dropped flows cost no blocklist or DNS lookups
+    let rules = tenant.map_or(&lookups.rules, |tenant| &tenant.rules);
+    if !rules.apply(&mut flow) {
+        return None;
+    }
+    add_threat_tags(&mut flow, record, &lookups.threats);
+    if let Some(reverse_dns) = &lookups.reverse_dns {
+        add_hostname_tags(&mut flow, record, reverse_dns);
+    }
+    println!("{:?}", flow);
+    Some(flow)
+}
+
+
+// Country, AS and direction
+fn add_geo_tags(flow: &mut EnrichedFlow, record: &FlowRecord, cidr_lookup: &CidrLookup, tenant: Option<&Tenant>) {
+    let unknown = (String::from("Unknown"), String::from("Unknown"));
+    let src_country = cidr_lookup.lookup_country(&record.src_ip).map_or("Unknown", |c| c.as_str());
+    let dst_country = cidr_lookup.lookup_country(&record.dst_ip).map_or("Unknown", |c| c.as_str());
+    let (src_asn, src_as_name) = cidr_lookup.lookup_as(&record.src_ip).unwrap_or(&unknown);
+    let (dst_asn, dst_as_name) = cidr_lookup.lookup_as(&record.dst_ip).unwrap_or(&unknown);
+    // A tenant's home networks, the private ranges otherwise
+    let home = tenant.and_then(|tenant| Some((tenant.is_home(&record.src_ip)?, tenant.is_home(&record.dst_ip)?)));
+    let packet_type = match home {
+        Some((src_home, dst_home)) => direction_from_home(src_home, dst_home),
+        None => classify_direction(&record.src_ip, &record.dst_ip),
     };
 
-    if let Some(packet_type) = packet_type {
-        let time = Utc::now();
-        let enriched_data = json!({
-            "measurement": "netflow",
-            "tags": {
-                "src_ip": src_ip.clone(),
-                "dst_ip": dst_ip.clone(),
-                "src_country": src_country.clone(),
-                "dst_country": dst_country.clone(),
-                "src_asn": src_asn.clone(),
-                "src_as_name": src_as_name.clone(),
-                "dst_asn": dst_asn.clone(),
-                "dst_as_name": dst_as_name.clone(),
-                "type": format!("{:?}", packet_type).clone()
-            },
-            "fields": {
-                "packets": flow.d_pkts,
-                "bytes": flow.d_octets,
-                "first_switched": flow.first,
-                "last_switched": flow.last
-            },
-            "time": time
-        });
-        println!("{:?}", enriched_data);
-        let buf = serde_json::to_vec(&enriched_data).unwrap();
-        enriched_packets.push(buf);
+    flow.set_tag("src_country", src_country);
+    flow.set_tag("dst_country", dst_country);
+    flow.set_tag("src_asn", src_asn.as_str());
+    flow.set_tag("src_as_name", src_as_name.as_str());
+    flow.set_tag("dst_asn", dst_asn.as_str());
+    flow.set_tag("dst_as_name", dst_as_name.as_str());
+    flow.set_tag("type", format!("{:?}", packet_type));
+}
+
+
+// Announced prefix, AS path and communities of the route to each address in effect now
+fn add_route_tags(flow: &mut EnrichedFlow, record: &FlowRecord, routes: &RouteLookup) {
+    for (side, ip) in [("src", &record.src_ip), ("dst", &record.dst_ip)] {
+        let found = match routes.lookup(ip) {
+            Some(found) => found,
+            None => continue,
+        };
+        let route = &found.route;
+        flow.set_tag(&format!("{}_bgp_prefix", side), found.prefix);
+        flow.set_tag(&format!("{}_as_path", side), route.as_path_string());
+        flow.set_tag(&format!("{}_peer_as", side), route.peer_as.to_string());
+        if let Some(origin) = route.origin_as {
+            flow.set_tag(&format!("{}_origin_as", side), origin.to_string());
+        }
+        if let Some(next_hop_as) = route.next_hop_as() {
+            flow.set_tag(&format!("{}_next_hop_as", side), next_hop_as.to_string());
+        }
+        if !route.communities.is_empty() {
+            flow.set_tag(&format!("{}_communities", side), route.communities_string());
+        }
     }
 }
 
 
-// NetFlow v9
-fn enrich_flow_v9(flow: &netflow_parser::variable_versions::v9::FlowSet, 
-                  cidr_lookup: &CidrLookup, 
-                  enriched_packets: &mut Vec<Vec<u8>>) {
-
-    if let Some(f) = &flow.body.data {
-        for data_record in &f.data_fields {
-            let mut src_ip = String::new();
-            let mut dst_ip = String::new();
-            let mut packets = 0;
-            let mut bytes = 0;
-            let mut first_switched = 0;
-            let mut last_switched = 0;
-
-            for (_, (_, (field_type, field_value))) in data_record.iter().enumerate() {
-                match field_type {
-                    V9Field::Ipv4SrcAddr => {
-                        src_ip = extract_ip_address(&field_value).unwrap_or("Unknown".to_string());
-                    },
-                    V9Field::Ipv4DstAddr => {
-                        dst_ip = extract_ip_address(&field_value).unwrap_or("Unknown".to_string());
-                    },
-                    V9Field::InPkts => {
-                        packets = match field_value {
-                            FieldValue::Float64(val) => *val as u64,
-                            _ => 0
-                        };
-                    },
-                    V9Field::InBytes => {
-                        bytes = match field_value {
-                            FieldValue::Float64(val) => *val as u32,
-                            _ => 0
-                        };
-                    }, 
-                    V9Field::FirstSwitched => {
-                        first_switched = match field_value {
-                            FieldValue::Float64(val) => *val as u64,
-                            _ => 0
-                        };
-                    },
-                    V9Field::LastSwitched => {
-                        last_switched = match field_value {
-                            FieldValue::Float64(val) => *val as u64,
-                            _ => 0
-                        };
-                    },
-                    _ => { }
-                }
+// Protocol name and the application on the well known side of the connection
+fn add_service_tags(flow: &mut EnrichedFlow, record: &FlowRecord, services: &ServiceLookup) {
+    let protocol = protocol_name(record.protocol);
+    match services.classify(record.protocol, record.src_port, record.dst_port) {
+        Some(service) => {
+            flow.set_tag("service", service.name);
+            flow.set_tag("app_port", service.port.to_string());
+        }
+        None => {
+            // ICMP, GRE, ... have no ports, the protocol is the application
+            flow.set_tag("service", protocol.as_str());
+            flow.set_tag("app_port", "0");
+        }
+    }
+    flow.set_tag("protocol_name", protocol);
+}
+
+
+// Blocklists the source and destination are on, only set for listed addresses
+fn add_threat_tags(flow: &mut EnrichedFlow, record: &FlowRecord, threats: &ThreatLookup) {
+    for (side, ip) in [("src", &record.src_ip), ("dst", &record.dst_ip)] {
+        if let Some(found) = threats.lookup(ip) {
+            flow.set_tag(&format!("{}_threat_list", side), found.lists.join(","));
+            if !found.categories.is_empty() {
+                flow.set_tag(&format!("{}_threat_category", side), found.categories.join(","));
             }
+        }
+    }
+}
 
-            let src_country = cidr_lookup.lookup_country(&src_ip).unwrap_or(&"Unknown".to_string()).clone();
-            let dst_country = cidr_lookup.lookup_country(&dst_ip).unwrap_or(&"Unknown".to_string()).clone();
-            let (src_asn, src_as_name) = cidr_lookup.lookup_as(&src_ip).unwrap_or(&(String::from("Unknown"), String::from("Unknown"))).clone();
-            let (dst_asn, dst_as_name) = cidr_lookup.lookup_as(&dst_ip).unwrap_or(&(String::from("Unknown"), String::from("Unknown"))).clone();
-
-            let packet_type = match (is_private_ip(&src_ip), is_private_ip(&dst_ip)) {
-                (true, true) => Some(IPtype::Incoming),
-                (true, _) => Some(IPtype::Outgoing),
-                (_, true) => Some(IPtype::Incoming),
-                (_, _) => Some(IPtype::Outgoing)
-            };
-
-            if packet_type.is_some() {
-                let time = Utc::now();
-                let enriched_data = json!({
-                    "measurement": "netflow",
-                    "tags": {
-                        "src_ip": src_ip.clone(),
-                        "dst_ip": dst_ip.clone(),
-                        "src_country": src_country.clone(),
-                        "dst_country": dst_country.clone(),
-                        "src_asn": src_asn.clone(),
-                        "src_as_name": src_as_name.clone(),
-                        "dst_asn": dst_asn.clone(),
-                        "dst_as_name": dst_as_name.clone(),
-                        "type": format!("{:?}", packet_type.unwrap()).clone()
-                    },
-                    "fields": {
-                        "packets": packets,
-                        "bytes": bytes,
-                        "first_switched": first_switched,
-                        "last_switched": last_switched
-                    },
-                    "time": time
-                });
-                println!("{:?}", enriched_data);
-                let buf = serde_json::to_vec(&enriched_data).unwrap();
-                enriched_packets.push(buf);
+
+// PTR names of the addresses, only the ones already resolved so DNS never slows enrichment down
+fn add_hostname_tags(flow: &mut EnrichedFlow, record: &FlowRecord, reverse_dns: &ReverseDns) {
+    if let Some(name) = reverse_dns.lookup(&record.src_ip) {
+        flow.set_tag("src_hostname", name);
+    }
+    if let Some(name) = reverse_dns.lookup(&record.dst_ip) {
+        flow.set_tag("dst_hostname", name);
+    }
+}
+
+
+// Name, description and speed of the input and output interface on the exporter
+fn add_interface_tags(flow: &mut EnrichedFlow, record: &FlowRecord, exporter: &str, interfaces: &InterfaceLookup) {
+    let exporter: IpAddr = match exporter.parse() {
+        Ok(exporter) => exporter,
+        Err(_) => return,
+    };
+    interfaces.note_exporter(exporter);
+
+    for (prefix, if_index) in [("in_if", record.input_if), ("out_if", record.output_if)] {
+        // ifIndex 0 means the exporter didn't say
+        if if_index == 0 {
+            continue;
+        }
+        match interfaces.lookup(exporter, if_index) {
+            Some(info) => {
+                flow.set_tag(&format!("{}_name", prefix), info.name);
+                flow.set_tag(&format!("{}_desc", prefix), info.description);
+                flow.set_tag(&format!("{}_speed", prefix), info.speed.to_string());
             }
+            None => flow.set_tag(&format!("{}_name", prefix), format!("ifIndex {}", if_index)),
+        }
+    }
+}
+
+
+// NetFlow v5
+fn record_from_v5(flow: &FlowSetV5) -> FlowRecord {
+    FlowRecord {
+        src_ip: flow.src_addr.to_string(),
+        dst_ip: flow.dst_addr.to_string(),
+        src_port: flow.src_port,
+        dst_port: flow.dst_port,
+        protocol: flow.protocol_number,
+        tcp_flags: flow.tcp_flags,
+        packets: flow.d_pkts as u64,
+        bytes: flow.d_octets as u64,
+        first_switched: flow.first.as_millis() as u64,
+        last_switched: flow.last.as_millis() as u64,
+        input_if: flow.input as u32,
+        output_if: flow.output as u32,
+    }
+}
+
+
+// NetFlow v9
+fn record_from_v9(data_record: &BTreeMap<usize, (V9Field, FieldValue)>) -> FlowRecord {
+    let mut record = FlowRecord::default();
+
+    for (field_type, field_value) in data_record.values() {
+        match field_type {
+            V9Field::Ipv4SrcAddr => {
+                record.src_ip = extract_ip_address(field_value).unwrap_or("Unknown".to_string());
+            },
+            V9Field::Ipv4DstAddr => {
+                record.dst_ip = extract_ip_address(field_value).unwrap_or("Unknown".to_string());
+            },
+            V9Field::L4SrcPort => record.src_port = extract_number(field_value) as u16,
+            V9Field::L4DstPort => record.dst_port = extract_number(field_value) as u16,
+            V9Field::Protocol => record.protocol = extract_number(field_value) as u8,
+            V9Field::TcpFlags => record.tcp_flags = extract_number(field_value) as u8,
+            V9Field::InPkts => record.packets = extract_number(field_value),
+            V9Field::InBytes => record.bytes = extract_number(field_value),
+            V9Field::FirstSwitched => record.first_switched = extract_number(field_value),
+            V9Field::LastSwitched => record.last_switched = extract_number(field_value),
+            V9Field::InputSnmp => record.input_if = extract_number(field_value) as u32,
+            V9Field::OutputSnmp => record.output_if = extract_number(field_value) as u32,
+            _ => { }
+        }
+    }
+    record
+}
+
+
+// IPFIX
+fn record_from_ipfix(data_record: &BTreeMap<usize, (IPFixField, FieldValue)>) -> FlowRecord {
+    let mut record = FlowRecord::default();
+
+    for (field_type, field_value) in data_record.values() {
+        match field_type {
+            IPFixField::SourceIpv4address => {
+                record.src_ip = extract_ip_address(field_value).unwrap_or("Unknown".to_string());
+            },
+            IPFixField::DestinationIpv4address => {
+                record.dst_ip = extract_ip_address(field_value).unwrap_or("Unknown".to_string());
+            },
+            IPFixField::SourceTransportPort => record.src_port = extract_number(field_value) as u16,
+            IPFixField::DestinationTransportPort => record.dst_port = extract_number(field_value) as u16,
+            IPFixField::ProtocolIdentifier => record.protocol = extract_number(field_value) as u8,
+            IPFixField::TcpControlBits => record.tcp_flags = extract_number(field_value) as u8,
+            IPFixField::PacketDeltaCount => record.packets = extract_number(field_value),
+            IPFixField::OctetDeltaCount => record.bytes = extract_number(field_value),
+            IPFixField::FlowStartSeconds => record.first_switched = extract_millis(field_value, 1000),
+            IPFixField::FlowEndSeconds => record.last_switched = extract_millis(field_value, 1000),
+            IPFixField::FlowStartMilliseconds
+            | IPFixField::FlowStartSysUpTime => record.first_switched = extract_millis(field_value, 1),
+            IPFixField::FlowEndMilliseconds
+            | IPFixField::FlowEndSysUpTime => record.last_switched = extract_millis(field_value, 1),
+            IPFixField::IngressInterface => record.input_if = extract_number(field_value) as u32,
+            IPFixField::EgressInterface => record.output_if = extract_number(field_value) as u32,
+            _ => { }
         }
     }
+    record
+}
+
+
+// Sampling interval announced in a v9 record, 1 in N packets
+fn v9_sampling_rate(data_record: &BTreeMap<usize, (V9Field, FieldValue)>) -> Option<u64> {
+    data_record.values().find_map(|(field_type, field_value)| match field_type {
+        V9Field::SamplingInterval | V9Field::FlowSamplerRandomInterval => Some(extract_number(field_value)).filter(|rate| *rate > 0),
+        _ => None,
+    })
+}
+
+
+// Sampling interval announced in an IPFIX record, 1 in N packets
+fn ipfix_sampling_rate(data_record: &BTreeMap<usize, (IPFixField, FieldValue)>) -> Option<u64> {
+    data_record.values().find_map(|(field_type, field_value)| match field_type {
+        IPFixField::SamplingInterval | IPFixField::SamplerRandomInterval | IPFixField::SamplingPacketInterval => {
+            Some(extract_number(field_value)).filter(|rate| *rate > 0)
+        }
+        _ => None,
+    })
 }
 
 
@@ -202,94 +634,63 @@ fn extract_ip_address(field_val: &FieldValue) -> Option<String> {
 }
 
 
-// IPFIX
-fn enrich_flow_ipfix(flow: &netflow_parser::variable_versions::ipfix::FlowSet, 
-                     cidr_lookup: &CidrLookup, 
-                     enriched_packets: &mut Vec<Vec<u8>>) {
-    if let Some(f) = &flow.body.data {
-        for data_record in &f.data_fields {
-            let mut src_ip = String::new();
-            let mut dst_ip = String::new();
-            let mut packets = 0;
-            let mut bytes = 0;
-            let mut first_switched = 0;
-            let mut last_switched = 0;
-
-            for (_, (_, (field_type, field_value))) in data_record.iter().enumerate() {
-                match field_type {
-                    IPFixField::SourceIpv4address => {
-                        src_ip = extract_ip_address(&field_value).unwrap_or("Unknown".to_string());
-                    },
-                    IPFixField::DestinationIpv4address => {
-                        dst_ip = extract_ip_address(&field_value).unwrap_or("Unknown".to_string());
-                    },
-                    IPFixField::PacketDeltaCount => {
-                        packets = match field_value {
-                            FieldValue::Float64(val) => *val as u64,
-                            _ => 0
-                        };
-                    },
-                    IPFixField::OctetDeltaCount => {
-                        bytes = match field_value {
-                            FieldValue::Float64(val) => *val as u32,
-                            _ => 0
-                        };
-                    }, 
-                    IPFixField::FlowStartSeconds => {
-                        first_switched = match field_value {
-                            FieldValue::Float64(val) => *val as u64,
-                            _ => 0
-                        };
-                    },
-                    IPFixField::FlowEndSeconds => {
-                        last_switched = match field_value {
-                            FieldValue::Float64(val) => *val as u64,
-                            _ => 0
-                        };
-                    },
-                    _ => { }
-                }
-            }
+// A flow start or end in milliseconds, whether the parser decoded the element as a
+// duration or left the number in the element's unit of `unit_ms` milliseconds
+fn extract_millis(field_val: &FieldValue, unit_ms: u64) -> u64 {
+    match field_val {
+        FieldValue::Duration(d) => d.as_millis() as u64,
+        _ => extract_number(field_val).saturating_mul(unit_ms),
+    }
+}
 
-            let src_country = cidr_lookup.lookup_country(&src_ip).unwrap_or(&"Unknown".to_string()).clone();
-            let dst_country = cidr_lookup.lookup_country(&dst_ip).unwrap_or(&"Unknown".to_string()).clone();
-            let (src_asn, src_as_name) = cidr_lookup.lookup_as(&src_ip).unwrap_or(&(String::from("Unknown"), String::from("Unknown"))).clone();
-            let (dst_asn, dst_as_name) = cidr_lookup.lookup_as(&dst_ip).unwrap_or(&(String::from("Unknown"), String::from("Unknown"))).clone();
-
-            let packet_type = match (is_private_ip(&src_ip), is_private_ip(&dst_ip)) {
-                (true, true) => Some(IPtype::Incoming),
-                (true, _) => Some(IPtype::Outgoing),
-                (_, true) => Some(IPtype::Incoming),
-                (_, _) => Some(IPtype::Outgoing)
-            };
-
-            if packet_type.is_some() {
-                let time = Utc::now();
-                let enriched_data = json!({
-                    "measurement": "netflow",
-                    "tags": {
-                        "src_ip": src_ip.clone(),
-                        "dst_ip": dst_ip.clone(),
-                        "src_country": src_country.clone(),
-                        "dst_country": dst_country.clone(),
-                        "src_asn": src_asn.clone(),
-                        "src_as_name": src_as_name.clone(),
-                        "dst_asn": dst_asn.clone(),
-                        "dst_as_name": dst_as_name.clone(),
-                        "type": format!("{:?}", packet_type.unwrap()).clone()
-                    },
-                    "fields": {
-                        "packets": packets,
-                        "bytes": bytes,
-                        "first_switched": first_switched,
-                        "last_switched": last_switched
-                    },
-                    "time": time
-                });
-                println!("{:?}", enriched_data);
-                let buf = serde_json::to_vec(&enriched_data).unwrap();
-                enriched_packets.push(buf);
-            }
-        }
+
+// Extract a counter, port, flag or time from a field value, durations become milliseconds
+fn extract_number(field_val: &FieldValue) -> u64 {
+    match field_val {
+        FieldValue::DataNumber(number) => match number {
+            DataNumber::U8(n) => *n as u64,
+            DataNumber::U16(n) => *n as u64,
+            DataNumber::U24(n) => *n as u64,
+            DataNumber::U32(n) => *n as u64,
+            DataNumber::U64(n) => *n,
+            DataNumber::U128(n) => *n as u64,
+            DataNumber::I24(n) => *n as u64,
+            DataNumber::I32(n) => *n as u64,
+        },
+        FieldValue::Float64(n) => *n as u64,
+        FieldValue::Duration(d) => d.as_millis() as u64,
+        FieldValue::ProtocolType(p) => *p as u64,
+        _ => 0
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn ipfix_times_are_milliseconds() {
+        let record = |fields: [(IPFixField, FieldValue); 2]| {
+            let data_record = fields.into_iter().enumerate().collect::<BTreeMap<_, _>>();
+            let record = record_from_ipfix(&data_record);
+            (record.first_switched, record.last_switched)
+        };
+        let seconds = record([
+            (IPFixField::FlowStartSeconds, FieldValue::Duration(Duration::from_secs(1_700_000_000))),
+            (IPFixField::FlowEndSeconds, FieldValue::DataNumber(DataNumber::U32(1_700_000_002))),
+        ]);
+        assert_eq!(seconds, (1_700_000_000_000, 1_700_000_002_000));
+        let millis = record([
+            (IPFixField::FlowStartMilliseconds, FieldValue::Duration(Duration::from_millis(1_700_000_000_250))),
+            (IPFixField::FlowEndMilliseconds, FieldValue::DataNumber(DataNumber::U64(1_700_000_000_500))),
+        ]);
+        assert_eq!(millis, (1_700_000_000_250, 1_700_000_000_500));
+        let uptime = record([
+            (IPFixField::FlowStartSysUpTime, FieldValue::DataNumber(DataNumber::U32(5_000))),
+            (IPFixField::FlowEndSysUpTime, FieldValue::DataNumber(DataNumber::U32(7_500))),
+        ]);
+        assert_eq!(uptime, (5_000, 7_500));
+    }
+}
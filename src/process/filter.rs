@@ -0,0 +1,508 @@
+// Filter expressions over enriched flows, in the style of nfdump and BPF:
+//
+//   dst net 203.0.113.0/24 and proto udp and src port 53
+//   not (country CN or as 4134) and bytes > 1M
+//   tag customer = "acme corp" or direction in
+//
+// Address, port, as and country predicates take an optional src/dst and match either
+// side without it. `and` binds tighter than `or`, `not` tighter than both.
+
+use std::fmt;
+use std::net::Ipv4Addr;
+
+use cidr::Ipv4Cidr;
+
+use crate::db::ip_lookup::IPtype;
+use crate::db::service_lookup::protocol_name;
+use crate::process::flow::EnrichedFlow;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Side {
+    Src,
+    Dst,
+    Either,
+}
+
+impl Side {
+    fn test<T>(self, src: T, dst: T, matches: impl Fn(T) -> bool) -> bool {
+        match self {
+            Side::Src => matches(src),
+            Side::Dst => matches(dst),
+            Side::Either => matches(src) || matches(dst),
+        }
+    }
+
+    // Tag and field name prefixes of the side
+    pub fn prefixes(self) -> &'static [&'static str] {
+        match self {
+            Side::Src => &["src"],
+            Side::Dst => &["dst"],
+            Side::Either => &["src", "dst"],
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Counter {
+    Bytes,
+    Packets,
+}
+
+impl Counter {
+    pub fn name(self) -> &'static str {
+        match self {
+            Counter::Bytes => "bytes",
+            Counter::Packets => "packets",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Comparison {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+impl Comparison {
+    fn test(self, value: u64, operand: u64) -> bool {
+        match self {
+            Comparison::Eq => value == operand,
+            Comparison::Ne => value != operand,
+            Comparison::Lt => value < operand,
+            Comparison::Le => value <= operand,
+            Comparison::Gt => value > operand,
+            Comparison::Ge => value >= operand,
+        }
+    }
+
+    pub fn negate(self) -> Self {
+        match self {
+            Comparison::Eq => Comparison::Ne,
+            Comparison::Ne => Comparison::Eq,
+            Comparison::Lt => Comparison::Ge,
+            Comparison::Le => Comparison::Gt,
+            Comparison::Gt => Comparison::Le,
+            Comparison::Ge => Comparison::Lt,
+        }
+    }
+
+    pub fn symbol(self) -> &'static str {
+        match self {
+            Comparison::Eq => "=",
+            Comparison::Ne => "!=",
+            Comparison::Lt => "<",
+            Comparison::Le => "<=",
+            Comparison::Gt => ">",
+            Comparison::Ge => ">=",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Filter {
+    And(Box<Filter>, Box<Filter>),
+    Or(Box<Filter>, Box<Filter>),
+    Not(Box<Filter>),
+    // Every flow
+    Any,
+    // `host` is a /32
+    Address(Side, Ipv4Cidr),
+    // Inclusive range
+    Port(Side, u16, u16),
+    Asn(Side, u32),
+    Country(Side, String),
+    Protocol(u8),
+    Exporter(Ipv4Cidr),
+    Counter(Counter, Comparison, u64),
+    // A tag with the value, or set at all
+    Tag(String, Option<String>),
+    Direction(IPtype),
+}
+
+impl Filter {
+    pub fn parse(text: &str) -> Result<Filter, String> {
+        let mut parser = Parser { tokens: tokenize(text)?, position: 0 };
+        if parser.tokens.is_empty() {
+            return Err("empty filter".to_string());
+        }
+        let filter = parser.or()?;
+        match parser.peek() {
+            None => Ok(filter),
+            Some(token) => Err(format!("expected and/or before {}", token)),
+        }
+    }
+
+    pub fn matches(&self, flow: &EnrichedFlow) -> bool {
+        match self {
+            Filter::And(left, right) => left.matches(flow) && right.matches(flow),
+            Filter::Or(left, right) => left.matches(flow) || right.matches(flow),
+            Filter::Not(filter) => !filter.matches(flow),
+            Filter::Any => true,
+            Filter::Address(side, cidr) => side.test(flow.src_addr(), flow.dst_addr(), |ip| ip.is_some_and(|ip| cidr.contains(&ip))),
+            Filter::Port(side, first, last) => {
+                side.test(flow.fields.src_port, flow.fields.dst_port, |port| (*first..=*last).contains(&port))
+            }
+            Filter::Asn(side, asn) => side.test("src_asn", "dst_asn", |tag| {
+                flow.tag(tag).and_then(|value| value.parse::<u32>().ok()) == Some(*asn)
+            }),
+            Filter::Country(side, country) => side.test("src_country", "dst_country", |tag| {
+                flow.tag(tag).is_some_and(|value| value.eq_ignore_ascii_case(country))
+            }),
+            Filter::Protocol(protocol) => flow.fields.protocol == *protocol,
+            Filter::Exporter(cidr) => flow
+                .tag("exporter")
+                .and_then(|exporter| exporter.parse::<Ipv4Addr>().ok())
+                .is_some_and(|exporter| cidr.contains(&exporter)),
+            Filter::Counter(counter, comparison, operand) => {
+                let value = match counter {
+                    Counter::Bytes => flow.fields.bytes,
+                    Counter::Packets => flow.fields.packets,
+                };
+                comparison.test(value, *operand)
+            }
+            Filter::Tag(name, value) => match (flow.tag(name), value) {
+                (Some(tag), Some(value)) => tag == value,
+                (Some(_), None) => true,
+                (None, _) => false,
+            },
+            Filter::Direction(direction) => flow.direction() == Some(*direction),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Token {
+    Word(String),
+    // A quoted string, never a keyword
+    Quoted(String),
+    Symbol(&'static str),
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Token::Word(word) => write!(f, "{}", word),
+            Token::Quoted(text) => write!(f, "\"{}\"", text),
+            Token::Symbol(symbol) => write!(f, "{}", symbol),
+        }
+    }
+}
+
+// Longest first so "<=" isn't read as "<"
+const SYMBOLS: [&str; 12] = ["&&", "||", "!=", "==", "<=", ">=", "(", ")", "!", "=", "<", ">"];
+
+fn tokenize(text: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = Vec::new();
+    let mut rest = text.trim_start();
+    while !rest.is_empty() {
+        if let Some(symbol) = SYMBOLS.iter().find(|symbol| rest.starts_with(**symbol)) {
+            tokens.push(Token::Symbol(symbol));
+            rest = &rest[symbol.len()..];
+        } else if let Some(quote) = rest.chars().next().filter(|c| *c == '"' || *c == '\'') {
+            let end = rest[1..].find(quote).ok_or_else(|| format!("unterminated string {}", rest))?;
+            tokens.push(Token::Quoted(rest[1..end + 1].to_string()));
+            rest = &rest[end + 2..];
+        } else {
+            let end = rest
+                .find(|c: char| c.is_whitespace() || "()!=<>&|\"'".contains(c))
+                .unwrap_or(rest.len());
+            if end == 0 {
+                return Err(format!("unexpected {}", rest));
+            }
+            tokens.push(Token::Word(rest[..end].to_string()));
+            rest = &rest[end..];
+        }
+        rest = rest.trim_start();
+    }
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    position: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.position)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.position).cloned();
+        self.position += 1;
+        token
+    }
+
+    // Consume the next token when it is one of the keywords or symbols
+    fn accept(&mut self, options: &[&str]) -> bool {
+        let found = match self.peek() {
+            Some(Token::Word(word)) => options.iter().any(|option| word.eq_ignore_ascii_case(option)),
+            Some(Token::Symbol(symbol)) => options.contains(symbol),
+            _ => false,
+        };
+        if found {
+            self.position += 1;
+        }
+        found
+    }
+
+    // A keyword or value
+    fn value(&mut self, what: &str) -> Result<String, String> {
+        match self.next() {
+            Some(Token::Word(word)) | Some(Token::Quoted(word)) => Ok(word),
+            Some(token) => Err(format!("expected {}, found {}", what, token)),
+            None => Err(format!("expected {} at the end of the filter", what)),
+        }
+    }
+
+    fn comparison(&mut self) -> Option<Comparison> {
+        let comparison = match self.peek() {
+            Some(Token::Symbol("=")) | Some(Token::Symbol("==")) => Comparison::Eq,
+            Some(Token::Symbol("!=")) => Comparison::Ne,
+            Some(Token::Symbol("<")) => Comparison::Lt,
+            Some(Token::Symbol("<=")) => Comparison::Le,
+            Some(Token::Symbol(">")) => Comparison::Gt,
+            Some(Token::Symbol(">=")) => Comparison::Ge,
+            _ => return None,
+        };
+        self.position += 1;
+        Some(comparison)
+    }
+
+    fn or(&mut self) -> Result<Filter, String> {
+        let mut filter = self.and()?;
+        while self.accept(&["or", "||"]) {
+            filter = Filter::Or(Box::new(filter), Box::new(self.and()?));
+        }
+        Ok(filter)
+    }
+
+    fn and(&mut self) -> Result<Filter, String> {
+        let mut filter = self.not()?;
+        while self.accept(&["and", "&&"]) {
+            filter = Filter::And(Box::new(filter), Box::new(self.not()?));
+        }
+        Ok(filter)
+    }
+
+    fn not(&mut self) -> Result<Filter, String> {
+        if self.accept(&["not", "!"]) {
+            return Ok(Filter::Not(Box::new(self.not()?)));
+        }
+        if self.accept(&["("]) {
+            let filter = self.or()?;
+            if !self.accept(&[")"]) {
+                return Err("missing )".to_string());
+            }
+            return Ok(filter);
+        }
+        self.predicate()
+    }
+
+    fn predicate(&mut self) -> Result<Filter, String> {
+        let side = if self.accept(&["src"]) {
+            Side::Src
+        } else if self.accept(&["dst"]) {
+            Side::Dst
+        } else {
+            Side::Either
+        };
+        let keyword = self.value("a filter keyword")?.to_lowercase();
+        let filter = match keyword.as_str() {
+            "host" | "ip" => {
+                let value = self.value("an address")?;
+                let ip: Ipv4Addr = value.parse().map_err(|_| format!("invalid address {}", value))?;
+                Filter::Address(side, Ipv4Cidr::new_host(ip))
+            }
+            "net" => Filter::Address(side, self.network()?),
+            "port" => self.port(side)?,
+            "as" | "asn" => {
+                let value = self.value("an AS number")?;
+                let number = value.strip_prefix("AS").or_else(|| value.strip_prefix("as")).unwrap_or(&value);
+                Filter::Asn(side, number.parse().map_err(|_| format!("invalid AS number {}", value))?)
+            }
+            "country" => Filter::Country(side, self.value("a country code")?.to_uppercase()),
+            _ if side != Side::Either => return Err(format!("{} can't follow src or dst", keyword)),
+            "proto" | "protocol" => Filter::Protocol(parse_protocol(&self.value("a protocol")?)?),
+            "exporter" => Filter::Exporter(self.network()?),
+            "bytes" | "packets" => {
+                let counter = if keyword == "bytes" { Counter::Bytes } else { Counter::Packets };
+                let comparison = self.comparison().unwrap_or(Comparison::Eq);
+                Filter::Counter(counter, comparison, parse_count(&self.value("a number")?)?)
+            }
+            "tag" => {
+                let name = self.value("a tag name")?;
+                match self.comparison() {
+                    None => Filter::Tag(name, None),
+                    Some(Comparison::Eq) => Filter::Tag(name, Some(self.value("a tag value")?)),
+                    Some(Comparison::Ne) => Filter::Not(Box::new(Filter::Tag(name, Some(self.value("a tag value")?)))),
+                    Some(comparison) => return Err(format!("tags can't be compared with {}", comparison.symbol())),
+                }
+            }
+            "direction" | "dir" => match self.value("in or out")?.to_lowercase().as_str() {
+                "in" | "incoming" => Filter::Direction(IPtype::Incoming),
+                "out" | "outgoing" => Filter::Direction(IPtype::Outgoing),
+                other => return Err(format!("invalid direction {}", other)),
+            },
+            "any" => Filter::Any,
+            _ => return Err(format!("unknown filter keyword {}", keyword)),
+        };
+        Ok(filter)
+    }
+
+    // "192.0.2.0/24", a bare address is a /32
+    fn network(&mut self) -> Result<Ipv4Cidr, String> {
+        let value = self.value("a network")?;
+        match value.parse::<Ipv4Addr>() {
+            Ok(ip) => Ok(Ipv4Cidr::new_host(ip)),
+            Err(_) => value.parse().map_err(|e| format!("invalid network {}: {}", value, e)),
+        }
+    }
+
+    // "port 53", "port 8000-8100" or "port > 1023"
+    fn port(&mut self, side: Side) -> Result<Filter, String> {
+        let comparison = self.comparison().unwrap_or(Comparison::Eq);
+        let value = self.value("a port")?;
+        let invalid = || format!("invalid port {}", value);
+        if let Some((first, last)) = value.split_once('-') {
+            let first: u16 = first.parse().map_err(|_| invalid())?;
+            let last: u16 = last.parse().map_err(|_| invalid())?;
+            if comparison != Comparison::Eq || first > last {
+                return Err(invalid());
+            }
+            return Ok(Filter::Port(side, first, last));
+        }
+        let port: u16 = value.parse().map_err(|_| invalid())?;
+        let (first, last) = match comparison {
+            Comparison::Eq | Comparison::Ne => (port, port),
+            Comparison::Lt => (0, port.checked_sub(1).ok_or_else(invalid)?),
+            Comparison::Le => (0, port),
+            Comparison::Gt => (port.checked_add(1).ok_or_else(invalid)?, u16::MAX),
+            Comparison::Ge => (port, u16::MAX),
+        };
+        let filter = Filter::Port(side, first, last);
+        Ok(if comparison == Comparison::Ne { Filter::Not(Box::new(filter)) } else { filter })
+    }
+}
+
+// Protocol number or the name `protocol_name` gives it
+pub fn parse_protocol(value: &str) -> Result<u8, String> {
+    if let Ok(number) = value.parse::<u8>() {
+        return Ok(number);
+    }
+    let name = value.to_lowercase();
+    (0..=255u8)
+        .find(|number| protocol_name(*number) == name)
+        .ok_or_else(|| format!("unknown protocol {}", value))
+}
+
+// "1500", "10k", "2M" or "1G", in powers of 1000 like nfdump
+fn parse_count(value: &str) -> Result<u64, String> {
+    let (number, multiplier) = match value.char_indices().last() {
+        Some((index, 'k')) | Some((index, 'K')) => (&value[..index], 1_000),
+        Some((index, 'm')) | Some((index, 'M')) => (&value[..index], 1_000_000),
+        Some((index, 'g')) | Some((index, 'G')) => (&value[..index], 1_000_000_000),
+        _ => (value, 1),
+    };
+    number
+        .parse::<u64>()
+        .ok()
+        .and_then(|number| number.checked_mul(multiplier))
+        .ok_or_else(|| format!("invalid number {}", value))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::process::flow::FlowRecord;
+
+    fn parse(text: &str) -> Filter {
+        Filter::parse(text).unwrap()
+    }
+
+    fn port(side: Side, first: u16, last: u16) -> Box<Filter> {
+        Box::new(Filter::Port(side, first, last))
+    }
+
+    #[test]
+    fn and_binds_tighter_than_or_and_not_tighter_than_both() {
+        let udp = || Box::new(Filter::Protocol(17));
+        assert_eq!(
+            parse("proto udp or port 53 and not port 80"),
+            Filter::Or(udp(), Box::new(Filter::And(port(Side::Either, 53, 53), Box::new(Filter::Not(port(Side::Either, 80, 80))))))
+        );
+        assert_eq!(
+            parse("(proto udp || port 53) && ! port 80"),
+            Filter::And(Box::new(Filter::Or(udp(), port(Side::Either, 53, 53))), Box::new(Filter::Not(port(Side::Either, 80, 80))))
+        );
+        assert_eq!(parse("not not any"), Filter::Not(Box::new(Filter::Not(Box::new(Filter::Any)))));
+    }
+
+    #[test]
+    fn parses_predicates() {
+        assert_eq!(parse("src port != 22"), Filter::Not(port(Side::Src, 22, 22)));
+        assert_eq!(parse("dst port 8000-8100"), *port(Side::Dst, 8000, 8100));
+        assert_eq!(parse("port < 1024"), *port(Side::Either, 0, 1023));
+        assert_eq!(parse("port >= 1024"), *port(Side::Either, 1024, u16::MAX));
+        assert_eq!(parse("port > 1023"), *port(Side::Either, 1024, u16::MAX));
+        assert_eq!(parse("bytes > 1M"), Filter::Counter(Counter::Bytes, Comparison::Gt, 1_000_000));
+        assert_eq!(parse("src as AS15169"), Filter::Asn(Side::Src, 15169));
+        assert_eq!(parse("dst net 10.0.0.0/8"), Filter::Address(Side::Dst, "10.0.0.0/8".parse().unwrap()));
+        assert_eq!(parse("tag customer = \"acme corp\""), Filter::Tag("customer".to_string(), Some("acme corp".to_string())));
+        assert_eq!(
+            parse("tag customer != 'and'"),
+            Filter::Not(Box::new(Filter::Tag("customer".to_string(), Some("and".to_string()))))
+        );
+        assert_eq!(parse("country cn"), Filter::Country(Side::Either, "CN".to_string()));
+    }
+
+    #[test]
+    fn explains_what_is_wrong() {
+        let error = |text: &str| Filter::parse(text).unwrap_err();
+        assert_eq!(error(""), "empty filter");
+        assert_eq!(error("src port"), "expected a port at the end of the filter");
+        assert_eq!(error("port 53 and"), "expected a filter keyword at the end of the filter");
+        assert_eq!(error("(port 53"), "missing )");
+        assert_eq!(error("tag customer = \"acme"), "unterminated string \"acme");
+        assert_eq!(error("port 53 port 80"), "expected and/or before port");
+        assert_eq!(error("port 0-65536"), "invalid port 0-65536");
+        assert_eq!(error("port > 65535"), "invalid port 65535");
+        assert_eq!(error("src proto tcp"), "proto can't follow src or dst");
+        assert_eq!(error("tag customer < 3"), "tags can't be compared with <");
+    }
+
+    #[test]
+    fn matches_either_side() {
+        let record = FlowRecord {
+            src_ip: "192.0.2.1".to_string(),
+            dst_ip: "198.51.100.7".to_string(),
+            src_port: 53,
+            dst_port: 40000,
+            protocol: 17,
+            bytes: 1500,
+            ..FlowRecord::default()
+        };
+        let mut flow = EnrichedFlow::new(&record, chrono::Utc::now());
+        flow.set_tag("src_asn", "64500");
+        flow.set_tag("dst_country", "NL");
+        let matches = |text: &str| parse(text).matches(&flow);
+
+        assert!(matches("src host 192.0.2.1"));
+        assert!(!matches("dst host 192.0.2.1"));
+        assert!(matches("host 192.0.2.1") && matches("host 198.51.100.7"));
+        assert!(matches("net 198.51.100.0/24") && !matches("src net 198.51.100.0/24"));
+        assert!(matches("src port 53 and dst port > 1023"));
+        assert!(!matches("dst port 53"));
+        assert!(matches("port 53") && matches("port 39000-41000"));
+        assert!(matches("as 64500") && !matches("dst as 64500"));
+        assert!(matches("country nl") && !matches("src country NL"));
+        assert!(matches("proto udp and bytes >= 1k and bytes < 2k"));
+        assert!(!matches("not (proto udp or port 80)"));
+        assert!(matches("tag dst_country") && !matches("tag customer"));
+        assert!(matches("tag customer != acme"));
+    }
+}
@@ -0,0 +1,158 @@
+use std::collections::BTreeMap;
+use std::net::Ipv4Addr;
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::db::ip_lookup::IPtype;
+
+// One flow as found in a NetFlow v5/v9 or IPFIX record, before enrichment
+#[derive(Debug, Clone, Default)]
+pub struct FlowRecord {
+    pub src_ip: String,
+    pub dst_ip: String,
+    pub src_port: u16,
+    pub dst_port: u16,
+    pub protocol: u8,
+    pub tcp_flags: u8,
+    pub packets: u64,
+    pub bytes: u64,
+    // Milliseconds, since exporter boot for v5/v9 and since the epoch for IPFIX
+    pub first_switched: u64,
+    pub last_switched: u64,
+    // SNMP ifIndex of the input and output interface
+    pub input_if: u32,
+    pub output_if: u32,
+}
+
+// Numeric values of an enriched flow, written as InfluxDB fields
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct FlowFields {
+    pub packets: u64,
+    pub bytes: u64,
+    pub first_switched: u64,
+    pub last_switched: u64,
+    pub src_port: u16,
+    pub dst_port: u16,
+    pub protocol: u8,
+    pub tcp_flags: u8,
+    pub input_if: u32,
+    pub output_if: u32,
+}
+
+// What the enricher publishes on `enricher-to-tsdb`. The JSON layout is the one
+// telegraf's json parser expects: string tags, numeric fields and a timestamp.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct EnrichedFlow {
+    pub measurement: String,
+    pub tags: BTreeMap<String, String>,
+    pub fields: FlowFields,
+    pub time: DateTime<Utc>,
+}
+
+impl EnrichedFlow {
+    pub fn new(record: &FlowRecord, time: DateTime<Utc>) -> Self {
+        let mut tags = BTreeMap::new();
+        tags.insert("src_ip".to_string(), record.src_ip.clone());
+        tags.insert("dst_ip".to_string(), record.dst_ip.clone());
+
+        EnrichedFlow {
+            measurement: "netflow".to_string(),
+            tags,
+            fields: FlowFields {
+                packets: record.packets,
+                bytes: record.bytes,
+                first_switched: record.first_switched,
+                last_switched: record.last_switched,
+                src_port: record.src_port,
+                dst_port: record.dst_port,
+                protocol: record.protocol,
+                tcp_flags: record.tcp_flags,
+                input_if: record.input_if,
+                output_if: record.output_if,
+            },
+            time,
+        }
+    }
+
+    pub fn tag(&self, name: &str) -> Option<&str> {
+        self.tags.get(name).map(|t| t.as_str())
+    }
+
+    pub fn set_tag(&mut self, name: &str, value: impl Into<String>) {
+        self.tags.insert(name.to_string(), value.into());
+    }
+
+    pub fn src_addr(&self) -> Option<Ipv4Addr> {
+        self.tag("src_ip")?.parse().ok()
+    }
+
+    pub fn dst_addr(&self) -> Option<Ipv4Addr> {
+        self.tag("dst_ip")?.parse().ok()
+    }
+
+    pub fn direction(&self) -> Option<IPtype> {
+        match self.tag("type")? {
+            "Incoming" => Some(IPtype::Incoming),
+            "Outgoing" => Some(IPtype::Outgoing),
+            _ => None,
+        }
+    }
+
+    pub fn to_json(&self) -> Vec<u8> {
+        serde_json::to_vec(self).expect("EnrichedFlow can always be serialized")
+    }
+
+    pub fn from_json(payload: &[u8]) -> Option<Self> {
+        serde_json::from_slice(payload).ok()
+    }
+}
+
+// A summary computed over a window of flows, published on `enricher-aggregates`.
+// Same layout as EnrichedFlow, but the measurement names what was aggregated.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Aggregate {
+    pub measurement: String,
+    pub tags: BTreeMap<String, String>,
+    pub fields: BTreeMap<String, u64>,
+    // Serialized sketches behind the fields, so aggregates of several enrichers can be merged
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub sketches: BTreeMap<String, String>,
+    pub time: DateTime<Utc>,
+}
+
+impl Aggregate {
+    pub fn new(measurement: &str, time: DateTime<Utc>) -> Self {
+        Aggregate {
+            measurement: measurement.to_string(),
+            tags: BTreeMap::new(),
+            fields: BTreeMap::new(),
+            sketches: BTreeMap::new(),
+            time,
+        }
+    }
+
+    pub fn tag(mut self, name: &str, value: impl Into<String>) -> Self {
+        self.tags.insert(name.to_string(), value.into());
+        self
+    }
+
+    pub fn field(mut self, name: &str, value: u64) -> Self {
+        self.fields.insert(name.to_string(), value);
+        self
+    }
+
+    pub fn sketch(mut self, name: &str, value: String) -> Self {
+        self.sketches.insert(name.to_string(), value);
+        self
+    }
+
+    pub fn to_json(&self) -> Vec<u8> {
+        serde_json::to_vec(self).expect("Aggregate can always be serialized")
+    }
+
+    pub fn from_json(payload: &[u8]) -> Option<Self> {
+        serde_json::from_slice(payload).ok()
+    }
+}
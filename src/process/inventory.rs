@@ -0,0 +1,428 @@
+// What the enricher knows about the exporters sending to us: protocol versions,
+// observation domains with their sequence numbers, templates, flow and sampling rates.
+// Served over HTTP, published as the `exporter_stats` measurement, and exporters that
+// stop sending raise an event on `exporter-events`.
+
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+use crate::process::flow::Aggregate;
+use crate::transport::{SharedTransport, ENRICHER_AGGREGATES, EXPORTER_EVENTS};
+
+const MAX_REQUEST_LEN: usize = 8192;
+// Packets of one exporter are handled by several enrichment tasks and can be seen out of
+// order; sequences skipped are only lost once they stay missing this long
+const REORDER_SECS: i64 = 10;
+// Gaps waiting for their packets per domain, the oldest count as lost beyond this
+const MAX_GAPS: usize = 256;
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct InventoryConfig {
+    // Serves the inventory as JSON on /exporters
+    pub listen: Option<SocketAddr>,
+    // exporter_stats is published, and silence checked, this often
+    pub interval_secs: u64,
+    // An exporter is silent once nothing arrived for this long
+    pub silent_secs: u64,
+    // Every event is also POSTed as JSON to this URL
+    pub webhook: Option<String>,
+}
+
+impl Default for InventoryConfig {
+    fn default() -> Self {
+        InventoryConfig {
+            listen: None,
+            interval_secs: 60,
+            silent_secs: 300,
+            webhook: None,
+        }
+    }
+}
+
+// Header of one parsed NetFlow/IPFIX packet
+#[derive(Debug, Clone, Copy)]
+pub struct PacketInfo {
+    pub version: u16,
+    // v5 engine type and id, v9 source id, IPFIX observation domain
+    pub domain: u32,
+    pub sequence: u32,
+    // The sequence number the next packet of the domain should carry
+    pub next_sequence: u32,
+    pub flows: u64,
+    pub sampling_rate: Option<u64>,
+    // Templates the exporter defined so far
+    pub templates: usize,
+}
+
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct DomainStatus {
+    pub sequence: u32,
+    // Packets (v9) or flows (v5, IPFIX) missing from the sequence
+    pub lost: u64,
+    #[serde(skip)]
+    next: u32,
+    #[serde(skip)]
+    gaps: Vec<Gap>,
+}
+
+// Sequences a later packet skipped, waiting for the packets carrying them
+#[derive(Debug, Clone, Copy)]
+struct Gap {
+    start: u32,
+    len: u32,
+    since: DateTime<Utc>,
+}
+
+impl DomainStatus {
+    // The packet carrying `sequence` up to `next`: moves the sequence on, leaving a gap
+    // when it skipped some, or fills a gap a later packet left
+    fn observe(&mut self, sequence: u32, next: u32, known: bool, now: DateTime<Utc>) {
+        let ahead = sequence.wrapping_sub(self.next);
+        if !known || ahead == 0 {
+            self.sequence = sequence;
+            self.next = next;
+            return;
+        }
+        if ahead < u32::MAX / 2 {
+            self.gaps.push(Gap { start: self.next, len: ahead, since: now });
+            self.sequence = sequence;
+            self.next = next;
+            return;
+        }
+        let len = next.wrapping_sub(sequence);
+        let Some(index) = self.gaps.iter().position(|gap| sequence.wrapping_sub(gap.start) < gap.len) else {
+            // Behind and in no gap: the exporter restarted
+            self.sequence = sequence;
+            self.next = next;
+            return;
+        };
+        let gap = self.gaps.remove(index);
+        let before = sequence.wrapping_sub(gap.start);
+        if before > 0 {
+            self.gaps.push(Gap { len: before, ..gap });
+        }
+        if let Some(after) = (gap.len - before).checked_sub(len).filter(|after| *after > 0) {
+            self.gaps.push(Gap { start: next, len: after, ..gap });
+        }
+    }
+
+    // Counts the gaps that stayed open too long, or beyond MAX_GAPS, as lost
+    fn expire(&mut self, now: DateTime<Utc>) -> u64 {
+        let overdue = now - chrono::Duration::seconds(REORDER_SECS);
+        self.gaps.sort_by_key(|gap| gap.since);
+        let excess = self.gaps.len().saturating_sub(MAX_GAPS);
+        let expired = self.gaps.iter().enumerate().take_while(|(index, gap)| *index < excess || gap.since <= overdue).count();
+        let lost: u64 = self.gaps.drain(..expired).map(|gap| gap.len as u64).sum();
+        self.lost += lost;
+        lost
+    }
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct ExporterStatus {
+    pub exporter: String,
+    pub first_seen: DateTime<Utc>,
+    pub last_seen: DateTime<Utc>,
+    pub versions: BTreeSet<u16>,
+    pub domains: BTreeMap<u32, DomainStatus>,
+    pub templates: usize,
+    pub datagrams: u64,
+    pub flows: u64,
+    // Over the last interval
+    pub flows_per_sec: f64,
+    pub sampling_rate: Option<u64>,
+    pub silent: bool,
+    // Since the last interval
+    #[serde(skip)]
+    window: Counts,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct Counts {
+    datagrams: u64,
+    flows: u64,
+    lost: u64,
+}
+
+impl ExporterStatus {
+    fn new(exporter: &str, now: DateTime<Utc>) -> Self {
+        ExporterStatus {
+            exporter: exporter.to_string(),
+            first_seen: now,
+            last_seen: now,
+            versions: BTreeSet::new(),
+            domains: BTreeMap::new(),
+            templates: 0,
+            datagrams: 0,
+            flows: 0,
+            flows_per_sec: 0.0,
+            sampling_rate: None,
+            silent: false,
+            window: Counts::default(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ExporterPhase {
+    Silent,
+    Resumed,
+}
+
+// What is published on the `exporter-events` topic and sent to the webhook
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ExporterEvent {
+    pub exporter: String,
+    pub event: ExporterPhase,
+    pub last_seen: DateTime<Utc>,
+    pub time: DateTime<Utc>,
+}
+
+impl ExporterEvent {
+    pub fn to_json(&self) -> Vec<u8> {
+        serde_json::to_vec(self).expect("ExporterEvent can always be serialized")
+    }
+}
+
+// Shared by every enrichment task
+#[derive(Debug, Clone, Default)]
+pub struct Inventory {
+    exporters: Arc<Mutex<HashMap<String, ExporterStatus>>>,
+}
+
+impl Inventory {
+    pub fn observe(&self, exporter: &str, packet: &PacketInfo) {
+        let now = Utc::now();
+        let mut exporters = self.exporters.lock().unwrap();
+        let status = exporters.entry(exporter.to_string()).or_insert_with(|| ExporterStatus::new(exporter, now));
+        status.last_seen = now;
+        status.versions.insert(packet.version);
+        status.templates = packet.templates;
+        status.datagrams += 1;
+        status.flows += packet.flows;
+        status.window.datagrams += 1;
+        status.window.flows += packet.flows;
+        if packet.sampling_rate.is_some() {
+            status.sampling_rate = packet.sampling_rate;
+        }
+
+        let known = status.domains.contains_key(&packet.domain);
+        let domain = status.domains.entry(packet.domain).or_default();
+        domain.observe(packet.sequence, packet.next_sequence, known, now);
+        status.window.lost += domain.expire(now);
+    }
+
+    pub fn snapshot(&self) -> Vec<ExporterStatus> {
+        let mut exporters: Vec<ExporterStatus> = self.exporters.lock().unwrap().values().cloned().collect();
+        exporters.sort_by(|a, b| a.exporter.cmp(&b.exporter));
+        exporters
+    }
+
+    // Close the interval: rates, exporter_stats points and the exporters that went silent
+    // or came back
+    fn evaluate(&self, now: DateTime<Utc>, elapsed: Duration, silent_after: chrono::Duration) -> (Vec<Aggregate>, Vec<ExporterEvent>) {
+        let mut points = Vec::new();
+        let mut events = Vec::new();
+        let mut exporters = self.exporters.lock().unwrap();
+        for status in exporters.values_mut() {
+            for domain in status.domains.values_mut() {
+                status.window.lost += domain.expire(now);
+            }
+            let window = std::mem::take(&mut status.window);
+            status.flows_per_sec = window.flows as f64 / elapsed.as_secs_f64().max(1.0);
+
+            let silent = now - status.last_seen >= silent_after;
+            if silent != status.silent {
+                status.silent = silent;
+                events.push(ExporterEvent {
+                    exporter: status.exporter.clone(),
+                    event: if silent { ExporterPhase::Silent } else { ExporterPhase::Resumed },
+                    last_seen: status.last_seen,
+                    time: now,
+                });
+            }
+
+            let versions: Vec<String> = status.versions.iter().map(|version| version.to_string()).collect();
+            points.push(
+                Aggregate::new("exporter_stats", now)
+                    .tag("exporter", status.exporter.as_str())
+                    .tag("versions", versions.join(","))
+                    .field("datagrams", window.datagrams)
+                    .field("flows", window.flows)
+                    .field("flows_per_sec", status.flows_per_sec.round() as u64)
+                    .field("lost", window.lost)
+                    .field("domains", status.domains.len() as u64)
+                    .field("templates", status.templates as u64)
+                    .field("sampling_rate", status.sampling_rate.unwrap_or(1))
+                    .field("seconds_since_seen", (now - status.last_seen).num_seconds().max(0) as u64)
+                    .field("silent", silent as u64),
+            );
+        }
+        (points, events)
+    }
+}
+
+// Publish exporter_stats and the silent/resumed events every interval
+pub async fn run(transport: SharedTransport, inventory: Inventory, config: InventoryConfig) {
+    if let Some(listen) = config.listen {
+        tokio::spawn(run_endpoint(inventory.clone(), listen));
+    }
+    let client = reqwest::Client::builder()
+        .timeout(Duration::from_secs(5))
+        .build()
+        .expect("Can't build the webhook client");
+    let interval = Duration::from_secs(config.interval_secs.max(1));
+    let silent_after = chrono::Duration::seconds(config.silent_secs as i64);
+    let mut ticker = tokio::time::interval(interval);
+    ticker.tick().await;
+
+    let mut last = Instant::now();
+    loop {
+        ticker.tick().await;
+        let (points, events) = inventory.evaluate(Utc::now(), last.elapsed(), silent_after);
+        last = Instant::now();
+
+        for point in points {
+            if let Err(e) = transport.send(ENRICHER_AGGREGATES, None, &point.to_json()).await {
+                println!("Error publishing exporter stats: {:?}", e);
+            }
+        }
+        for event in events {
+            match event.event {
+                ExporterPhase::Silent => println!("Exporter {} went silent, last seen {}", event.exporter, event.last_seen),
+                ExporterPhase::Resumed => println!("Exporter {} is sending again", event.exporter),
+            }
+            if let Err(e) = transport.send(EXPORTER_EVENTS, Some(event.exporter.as_bytes()), &event.to_json()).await {
+                println!("Error publishing exporter event: {:?}", e);
+            }
+            if let Some(url) = &config.webhook {
+                let request = client
+                    .post(url)
+                    .header(reqwest::header::CONTENT_TYPE, "application/json")
+                    .body(event.to_json());
+                match request.send().await {
+                    Ok(response) if !response.status().is_success() => {
+                        println!("Webhook {} answered {}", url, response.status());
+                    }
+                    Ok(_) => {}
+                    Err(e) => println!("Error calling webhook {}: {}", url, e),
+                }
+            }
+        }
+    }
+}
+
+async fn run_endpoint(inventory: Inventory, listen: SocketAddr) {
+    let listener = match TcpListener::bind(listen).await {
+        Ok(listener) => listener,
+        Err(e) => {
+            println!("Can't serve the exporter inventory on {}: {}", listen, e);
+            return;
+        }
+    };
+    println!("Serving the exporter inventory on http://{}/exporters", listen);
+
+    loop {
+        let (stream, _) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                println!("Inventory accept failed: {}", e);
+                continue;
+            }
+        };
+        let inventory = inventory.clone();
+        tokio::spawn(async move {
+            if let Err(e) = serve_request(stream, &inventory).await {
+                println!("Inventory request failed: {}", e);
+            }
+        });
+    }
+}
+
+// GET /exporters lists every exporter, /exporters/<address> one of them
+async fn serve_request(mut stream: TcpStream, inventory: &Inventory) -> std::io::Result<()> {
+    let mut request = Vec::new();
+    let mut buffer = [0u8; 1024];
+    while !request.windows(4).any(|w| w == b"\r\n\r\n") && request.len() < MAX_REQUEST_LEN {
+        let read = stream.read(&mut buffer).await?;
+        if read == 0 {
+            break;
+        }
+        request.extend_from_slice(&buffer[..read]);
+    }
+    let request = String::from_utf8_lossy(&request);
+    let path = request.split_whitespace().nth(1).unwrap_or("");
+    let path = path.split('?').next().unwrap_or("").trim_end_matches('/');
+
+    let body = match path.strip_prefix("/exporters") {
+        Some(_) if !request.starts_with("GET ") => None,
+        Some("") => Some(serde_json::to_string(&inventory.snapshot())),
+        Some(rest) => rest.strip_prefix('/').and_then(|exporter| {
+            let status = inventory.exporters.lock().unwrap().get(exporter).cloned()?;
+            Some(serde_json::to_string(&status))
+        }),
+        None => None,
+    };
+    let response = match body {
+        Some(Ok(body)) => format!(
+            "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            body.len(),
+            body
+        ),
+        _ => "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_string(),
+    };
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn packet(sequence: u32, flows: u64) -> PacketInfo {
+        PacketInfo {
+            version: 10,
+            domain: 1,
+            sequence,
+            next_sequence: sequence.wrapping_add(flows as u32),
+            flows,
+            sampling_rate: None,
+            templates: 0,
+        }
+    }
+
+    #[test]
+    fn reordered_packets_are_not_lost() {
+        let inventory = Inventory::default();
+        // Two tasks: 10 and 20 overtake 5 and 15, 25 never arrives
+        for sequence in [0, 10, 20, 5, 15, 30] {
+            inventory.observe("192.0.2.1", &packet(sequence, 5));
+        }
+        let lost = |inventory: &Inventory| inventory.snapshot()[0].domains[&1].lost;
+        assert_eq!(lost(&inventory), 0);
+
+        let later = Utc::now() + chrono::Duration::seconds(REORDER_SECS + 1);
+        let (points, _) = inventory.evaluate(later, Duration::from_secs(60), chrono::Duration::seconds(300));
+        assert_eq!(lost(&inventory), 5);
+        assert_eq!(points[0].fields["lost"], 5);
+    }
+
+    #[test]
+    fn a_restart_is_not_lost() {
+        let mut domain = DomainStatus::default();
+        let now = Utc::now();
+        domain.observe(1000, 1010, false, now);
+        domain.observe(0, 10, true, now);
+        domain.observe(10, 20, true, now);
+        assert_eq!(domain.expire(now + chrono::Duration::seconds(REORDER_SECS + 1)), 0);
+    }
+}
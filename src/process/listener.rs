@@ -0,0 +1,413 @@
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use cidr::IpCidr;
+use serde::{Deserialize, Serialize};
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc;
+use tokio::task::JoinSet;
+
+use crate::process::tenant::{TenantConfig, TenantResolver};
+use crate::process::token_bucket::TokenBucket;
+use crate::process::flow::Aggregate;
+use crate::transport::{SharedTransport, ENRICHER_AGGREGATES, LISTENER_TO_ENRICHER};
+
+
+const BUF_SIZE: usize = 65535;
+// Datagrams of one input topic waiting for the transport
+const FORWARD_QUEUE_LEN: usize = 4096;
+
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct ListenerConfig {
+    // UDP port the exporters send to, on every address
+    pub port: u16,
+    // Addresses and ports to bind instead of `port`
+    pub bind: Vec<BindConfig>,
+    // Exporter addresses or networks accepted, every source when empty
+    pub allow: Vec<String>,
+    // Exporters refused even when allowed
+    pub deny: Vec<String>,
+    // Datagrams per second of every exporter, 0 is no limit
+    pub max_datagrams_per_sec: u64,
+    // Datagrams an exporter may send at once, max_datagrams_per_sec when 0
+    pub burst: u64,
+}
+
+impl Default for ListenerConfig {
+    fn default() -> Self {
+        ListenerConfig {
+            port: 2055,
+            bind: Vec::new(),
+            allow: Vec::new(),
+            deny: Vec::new(),
+            max_datagrams_per_sec: 0,
+            burst: 0,
+        }
+    }
+}
+
+impl ListenerConfig {
+    // `--port` listens on that port of every address, whatever was bound
+    pub fn set_port(&mut self, port: u16) {
+        self.port = port;
+        self.bind.clear();
+    }
+
+    // What to bind, `port` on every address unless `bind` is set
+    pub fn binds(&self) -> Vec<BindConfig> {
+        if !self.bind.is_empty() {
+            return self.bind.clone();
+        }
+        vec![BindConfig { address: SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), self.port), protocol: ProtocolHint::Any }]
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct BindConfig {
+    // "192.0.2.10:2055", "0.0.0.0:4739"
+    pub address: SocketAddr,
+    // Datagrams of other protocols are rejected
+    #[serde(default)]
+    pub protocol: ProtocolHint,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum ProtocolHint {
+    #[default]
+    Any,
+    NetflowV5,
+    NetflowV9,
+    Ipfix,
+}
+
+impl ProtocolHint {
+    fn name(self) -> &'static str {
+        match self {
+            ProtocolHint::Any => "flows",
+            ProtocolHint::NetflowV5 => "NetFlow v5",
+            ProtocolHint::NetflowV9 => "NetFlow v9",
+            ProtocolHint::Ipfix => "IPFIX",
+        }
+    }
+
+    // NetFlow and IPFIX datagrams start with their version
+    fn accepts(self, datagram: &[u8]) -> bool {
+        let version = match datagram {
+            [high, low, ..] => u16::from_be_bytes([*high, *low]),
+            _ => return false,
+        };
+        match self {
+            ProtocolHint::Any => true,
+            ProtocolHint::NetflowV5 => version == 5,
+            ProtocolHint::NetflowV9 => version == 9,
+            ProtocolHint::Ipfix => version == 10,
+        }
+    }
+}
+
+// An address or network, v4 or v6
+fn parse_networks(networks: &[String]) -> Result<Vec<IpCidr>, String> {
+    networks
+        .iter()
+        .map(|network| match network.parse::<IpAddr>() {
+            Ok(ip) => Ok(IpCidr::new_host(ip)),
+            Err(_) => network.parse().map_err(|e| format!("invalid network {}: {}", network, e)),
+        })
+        .collect()
+}
+
+// The sources `allow` and `deny` accept, for the exporters and the BMP routers
+#[derive(Debug, Default)]
+pub struct SourceFilter {
+    allow: Vec<IpCidr>,
+    deny: Vec<IpCidr>,
+}
+
+impl SourceFilter {
+    pub fn new(config: &ListenerConfig) -> Result<Self, String> {
+        Ok(SourceFilter {
+            allow: parse_networks(&config.allow).map_err(|e| format!("listener.allow: {}", e))?,
+            deny: parse_networks(&config.deny).map_err(|e| format!("listener.deny: {}", e))?,
+        })
+    }
+
+    // Sources connecting over IPv4 to a dual stack socket show up as mapped addresses
+    pub fn accepts(&self, source: IpAddr) -> bool {
+        let source = source.to_canonical();
+        let listed = |networks: &[IpCidr]| networks.iter().any(|network| network.contains(&source));
+        !listed(&self.deny) && (self.allow.is_empty() || listed(&self.allow))
+    }
+}
+
+// Which exporters may send and how much, shared by the sockets
+#[derive(Default)]
+struct Admission {
+    sources: SourceFilter,
+    rate: u64,
+    burst: u64,
+    buckets: Mutex<HashMap<IpAddr, TokenBucket>>,
+    denied: AtomicU64,
+    limited: AtomicU64,
+    mismatched: AtomicU64,
+}
+
+impl Admission {
+    fn new(config: &ListenerConfig) -> Result<Self, String> {
+        Ok(Admission {
+            sources: SourceFilter::new(config)?,
+            rate: config.max_datagrams_per_sec,
+            burst: if config.burst == 0 { config.max_datagrams_per_sec } else { config.burst },
+            ..Admission::default()
+        })
+    }
+
+    // `exporter` is canonical, the buckets of mapped and plain IPv4 addresses are the same
+    fn admit(&self, exporter: IpAddr, protocol: ProtocolHint, datagram: &[u8]) -> bool {
+        if !self.sources.accepts(exporter) {
+            self.denied.fetch_add(1, Ordering::Relaxed);
+            return false;
+        }
+        if !protocol.accepts(datagram) {
+            self.mismatched.fetch_add(1, Ordering::Relaxed);
+            return false;
+        }
+        if self.rate > 0 {
+            let mut buckets = self.buckets.lock().unwrap();
+            let bucket = buckets.entry(exporter).or_insert_with(|| TokenBucket::new(self.rate, self.burst));
+            if !bucket.try_take(1) {
+                self.limited.fetch_add(1, Ordering::Relaxed);
+                return false;
+            }
+        }
+        true
+    }
+}
+
+// Hands the datagrams of one input topic to the transport on a task of its own. When
+// the topic backs up, e.g. a tenant's enrichment tasks are flooded, only its own
+// datagrams are dropped and the sockets keep serving the other topics.
+struct Forwarder {
+    queue: mpsc::Sender<(String, Vec<u8>)>,
+    dropped: AtomicU64,
+}
+
+impl Forwarder {
+    fn spawn(topic: String, transport: SharedTransport) -> Self {
+        let (queue, mut datagrams) = mpsc::channel::<(String, Vec<u8>)>(FORWARD_QUEUE_LEN);
+        tokio::spawn(async move {
+            while let Some((key, datagram)) = datagrams.recv().await {
+                if let Err(e) = transport.send(&topic, Some(key.as_bytes()), &datagram).await {
+                    println!("Error producing to {}: {}", topic, e);
+                }
+            }
+        });
+        Forwarder { queue, dropped: AtomicU64::new(0) }
+    }
+
+    fn forward(&self, key: String, datagram: &[u8]) {
+        if self.queue.try_send((key, datagram.to_vec())).is_err() {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+// The rejected and dropped datagrams since the listener started: a listener_stats point
+// and a listener_queue point per input topic
+fn stats(admission: &Admission, forwarders: &HashMap<String, Forwarder>, now: DateTime<Utc>) -> Vec<Aggregate> {
+    let mut points = vec![Aggregate::new("listener_stats", now)
+        .field("denied", admission.denied.load(Ordering::Relaxed))
+        .field("limited", admission.limited.load(Ordering::Relaxed))
+        .field("mismatched", admission.mismatched.load(Ordering::Relaxed))];
+    for (topic, forwarder) in forwarders {
+        points.push(
+            Aggregate::new("listener_queue", now)
+                .tag("topic", topic.as_str())
+                .field("dropped", forwarder.dropped.load(Ordering::Relaxed)),
+        );
+    }
+    points
+}
+
+// Publish the counters and log what they grew by every minute, and forget the buckets
+// of exporters that went quiet
+async fn run_reporter(admission: Arc<Admission>, forwarders: Arc<HashMap<String, Forwarder>>, transport: SharedTransport) {
+    let mut ticker = tokio::time::interval(Duration::from_secs(60));
+    ticker.tick().await;
+
+    let mut last: HashMap<(String, String), u64> = HashMap::new();
+    loop {
+        ticker.tick().await;
+        admission.buckets.lock().unwrap().retain(|_, bucket| !bucket.full());
+        let points = stats(&admission, &forwarders, Utc::now());
+
+        let mut grown = |point: &Aggregate, field: &str| {
+            let total = point.fields[field];
+            let id = (point.tags.get("topic").cloned().unwrap_or_default(), field.to_string());
+            total - last.insert(id, total).unwrap_or(0)
+        };
+        for point in &points {
+            if point.measurement == "listener_stats" {
+                let (denied, limited, mismatched) = (grown(point, "denied"), grown(point, "limited"), grown(point, "mismatched"));
+                if denied + limited + mismatched > 0 {
+                    println!(
+                        "Rejected datagrams in the last minute: {} from exporters not allowed, {} over the rate limit, {} of another protocol",
+                        denied, limited, mismatched
+                    );
+                }
+            } else {
+                let dropped = grown(point, "dropped");
+                if dropped > 0 {
+                    println!("Dropped {} datagrams for {} in the last minute, its consumers fall behind", dropped, point.tags["topic"]);
+                }
+            }
+        }
+
+        for point in points {
+            if let Err(e) = transport.send(ENRICHER_AGGREGATES, None, &point.to_json()).await {
+                println!("Error publishing listener stats: {:?}", e);
+            }
+        }
+    }
+}
+
+// Receive NetFlow/IPFIX datagrams and forward them untouched to the enricher.
+// The exporter address is used as the record key so the enricher knows who sent it.
+// Datagrams of a tenant's exporters or ports go to the tenant's topic.
+pub async fn listen(config: &ListenerConfig, tenants: &[TenantConfig], transport: SharedTransport) -> std::io::Result<()> {
+    let resolver = TenantResolver::new(tenants).map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+    let resolver = Arc::new(resolver);
+    let admission = Admission::new(config).map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+    let admission = Arc::new(admission);
+    let mut binds = config.binds();
+    for port in tenants.iter().flat_map(|tenant| tenant.ports.iter().copied()) {
+        if !binds.iter().any(|bind| bind.address.port() == port) {
+            binds.push(BindConfig { address: SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), port), protocol: ProtocolHint::Any });
+        }
+    }
+
+    let topics = std::iter::once(LISTENER_TO_ENRICHER.to_string()).chain(tenants.iter().map(TenantConfig::input_topic));
+    let forwarders: Arc<HashMap<String, Forwarder>> =
+        Arc::new(topics.map(|topic| (topic.clone(), Forwarder::spawn(topic, transport.clone()))).collect());
+
+    let mut receivers = JoinSet::new();
+    for bind in binds {
+        let socket = UdpSocket::bind(bind.address).await?;
+        println!("Listening for {} on {}", bind.protocol.name(), socket.local_addr()?);
+        receivers.spawn(receive(socket, bind.protocol, admission.clone(), resolver.clone(), forwarders.clone()));
+    }
+    tokio::spawn(run_reporter(admission, forwarders, transport));
+    match receivers.join_next().await {
+        Some(result) => result.map_err(std::io::Error::other)?,
+        None => Ok(()),
+    }
+}
+
+async fn receive(
+    socket: UdpSocket,
+    protocol: ProtocolHint,
+    admission: Arc<Admission>,
+    resolver: Arc<TenantResolver>,
+    forwarders: Arc<HashMap<String, Forwarder>>,
+) -> std::io::Result<()> {
+    let port = socket.local_addr()?.port();
+    let mut buf = vec![0u8; BUF_SIZE];
+    loop {
+        let (amt, src) = socket.recv_from(&mut buf).await?;
+        // Exporters sending over IPv4 to a dual stack socket show up as mapped addresses
+        let exporter = src.ip().to_canonical();
+        if !admission.admit(exporter, protocol, &buf[..amt]) {
+            continue;
+        }
+        let topic = resolver.input_topic(exporter, port);
+        if let Some(forwarder) = forwarders.get(topic) {
+            forwarder.forward(exporter.to_string(), &buf[..amt]);
+        }
+    }
+}
+
+// Errors in the listener section, for `Config::check`
+pub fn check(config: &ListenerConfig) -> Vec<String> {
+    let mut problems = Vec::new();
+    if config.bind.is_empty() && config.port == 0 {
+        problems.push("listener.port must not be 0".to_string());
+    }
+    for (index, bind) in config.bind.iter().enumerate() {
+        if bind.address.port() == 0 {
+            problems.push(format!("listener.bind: {} needs a port", bind.address));
+        }
+        if config.bind[..index].iter().any(|other| other.address == bind.address) {
+            problems.push(format!("listener.bind: {} is bound twice", bind.address));
+        }
+    }
+    for (name, networks) in [("allow", &config.allow), ("deny", &config.deny)] {
+        if let Err(e) = parse_networks(networks) {
+            problems.push(format!("listener.{}: {}", name, e));
+        }
+    }
+    if config.burst > 0 && config.max_datagrams_per_sec == 0 {
+        problems.push("listener.burst needs max_datagrams_per_sec".to_string());
+    }
+    problems
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::ChannelTransport;
+
+    #[test]
+    fn invalid_networks_fail() {
+        let config = ListenerConfig { allow: vec!["10.0.0.0/33".to_string()], ..ListenerConfig::default() };
+        assert!(Admission::new(&config).err().unwrap().starts_with("listener.allow: "));
+    }
+
+    #[tokio::test]
+    async fn counts_rejected_datagrams_since_startup() {
+        let config = ListenerConfig {
+            deny: vec!["192.0.2.0/24".to_string()],
+            max_datagrams_per_sec: 1,
+            ..ListenerConfig::default()
+        };
+        let admission = Admission::new(&config).unwrap();
+        let v5 = [0u8, 5, 0, 0];
+        let mapped: IpAddr = "::ffff:198.51.100.1".parse().unwrap();
+        assert!(admission.admit(mapped.to_canonical(), ProtocolHint::Any, &v5));
+        // The mapped and the plain address share a bucket
+        assert!(!admission.admit("198.51.100.1".parse().unwrap(), ProtocolHint::Any, &v5));
+        assert!(!admission.admit("192.0.2.1".parse().unwrap(), ProtocolHint::Any, &v5));
+
+        let forwarders = HashMap::new();
+        for _ in 0..2 {
+            let points = stats(&admission, &forwarders, Utc::now());
+            assert_eq!(points[0].fields["denied"], 1);
+            assert_eq!(points[0].fields["limited"], 1);
+        }
+    }
+
+    #[tokio::test]
+    async fn a_backed_up_topic_drops_only_its_own_datagrams() {
+        let transport: SharedTransport = Arc::new(ChannelTransport::new(1));
+        // Nobody reads the flooded topic, its group fills up and the forwarder blocks
+        let _flooded_subscription = transport.subscribe("flooded", "g").await.unwrap();
+        let mut quiet = transport.subscribe("quiet", "g").await.unwrap();
+        let flooded = Forwarder::spawn("flooded".to_string(), transport.clone());
+        let other = Forwarder::spawn("quiet".to_string(), transport.clone());
+
+        for _ in 0..FORWARD_QUEUE_LEN + 10 {
+            flooded.forward("192.0.2.1".to_string(), b"flood");
+        }
+        other.forward("192.0.2.2".to_string(), b"datagram");
+        let record = tokio::time::timeout(Duration::from_secs(1), quiet.recv()).await.unwrap().unwrap();
+        assert_eq!(record.payload, b"datagram");
+        assert_eq!(record.key_str(), Some("192.0.2.2"));
+        assert!(flooded.dropped.load(Ordering::Relaxed) > 0);
+        assert_eq!(other.dropped.load(Ordering::Relaxed), 0);
+    }
+}
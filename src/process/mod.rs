@@ -1 +1,17 @@
-pub mod enricher;
\ No newline at end of file
+pub mod accounting;
+pub mod aggregate;
+pub mod anomaly;
+pub mod api;
+pub mod cardinality;
+pub mod detector;
+pub mod enricher;
+pub mod filter;
+pub mod flow;
+pub mod inventory;
+pub mod listener;
+pub mod router;
+pub mod rules;
+pub mod stitcher;
+pub mod tenant;
+pub mod token_bucket;
+pub mod topn;
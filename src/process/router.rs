@@ -0,0 +1,209 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use influxdb::Query;
+use serde::{Deserialize, Serialize};
+
+use crate::db::influx_db::enriched_to_query;
+use crate::process::filter::Filter;
+use crate::process::flow::EnrichedFlow;
+
+// Columns of the csv format
+const CSV_TAGS: [&str; 8] = ["exporter", "src_ip", "dst_ip", "src_asn", "dst_asn", "src_country", "dst_country", "service"];
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    // The EnrichedFlow layout every consumer of enricher-to-tsdb reads
+    #[default]
+    Json,
+    // InfluxDB line protocol, named like telegraf names the JSON
+    Line,
+    // One row per flow without header, see CSV_TAGS
+    Csv,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct RouteConfig {
+    pub name: String,
+    // Filter expression like "dst net 203.0.113.0/24 and proto udp", see process::filter.
+    // Every flow matches when it is empty.
+    pub filter: String,
+    // Matching flows are published to each of them, "{name}" is replaced by the value
+    // of the flow's tag, flows without the tag skip that topic
+    pub topics: Vec<String>,
+    pub format: Format,
+    // Publish one in `sample_rate` matching flows, tagged with the rate
+    pub sample_rate: u64,
+}
+
+impl Default for RouteConfig {
+    fn default() -> Self {
+        RouteConfig {
+            name: String::new(),
+            filter: String::new(),
+            topics: Vec::new(),
+            format: Format::Json,
+            sample_rate: 1,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Route {
+    filter: Option<Filter>,
+    topics: Vec<String>,
+    format: Format,
+    sample_rate: u64,
+    matched: AtomicU64,
+}
+
+impl Route {
+    fn compile(config: &RouteConfig) -> Result<Route, String> {
+        if config.topics.is_empty() {
+            return Err("topics must be set".to_string());
+        }
+        for topic in &config.topics {
+            if topic.matches('{').count() != topic.matches('}').count() {
+                return Err(format!("unbalanced braces in topic {}", topic));
+            }
+        }
+        if config.sample_rate == 0 {
+            return Err("sample_rate must be at least 1".to_string());
+        }
+        let filter = match config.filter.trim() {
+            "" => None,
+            filter => Some(Filter::parse(filter)?),
+        };
+        Ok(Route {
+            filter,
+            topics: config.topics.clone(),
+            format: config.format,
+            sample_rate: config.sample_rate,
+            matched: AtomicU64::new(0),
+        })
+    }
+
+    // Systematic sampling, the first of every `sample_rate` matching flows
+    fn take(&self, flow: &EnrichedFlow) -> bool {
+        if !self.filter.as_ref().is_none_or(|filter| filter.matches(flow)) {
+            return false;
+        }
+        self.sample_rate == 1 || self.matched.fetch_add(1, Ordering::Relaxed).is_multiple_of(self.sample_rate)
+    }
+}
+
+// Extra topics of the enriched flows, shared by every enrichment task
+#[derive(Debug, Default)]
+pub struct Router {
+    routes: Vec<Route>,
+}
+
+impl Router {
+    // Fails on the first route that doesn't compile
+    pub fn new(configs: &[RouteConfig]) -> Result<Self, String> {
+        let routes = configs
+            .iter()
+            .enumerate()
+            .map(|(index, config)| {
+                Route::compile(config).map_err(|e| format!("routes: route {} ({}): {}", index + 1, config.name, e))
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(Router { routes })
+    }
+
+    // Topic and payload of every route the flow takes. `json` is the flow as published
+    // on enricher-to-tsdb.
+    pub fn route(&self, flow: &EnrichedFlow, json: &[u8]) -> Vec<(String, Vec<u8>)> {
+        let mut outputs = Vec::new();
+        for route in &self.routes {
+            if !route.take(flow) {
+                continue;
+            }
+            let sampled;
+            let flow = if route.sample_rate > 1 {
+                let mut copy = flow.clone();
+                copy.set_tag("sample_rate", route.sample_rate.to_string());
+                sampled = copy;
+                &sampled
+            } else {
+                flow
+            };
+            let payload = match route.format {
+                Format::Json if route.sample_rate == 1 => json.to_vec(),
+                Format::Json => flow.to_json(),
+                Format::Line => match enriched_to_query(&flow.to_json()).and_then(|query| query.build().ok()) {
+                    Some(query) => query.get().into_bytes(),
+                    None => continue,
+                },
+                Format::Csv => csv_row(flow, route.sample_rate),
+            };
+            for template in &route.topics {
+                if let Some(topic) = expand_topic(template, flow) {
+                    outputs.push((topic, payload.clone()));
+                }
+            }
+        }
+        outputs
+    }
+}
+
+// Replace "{tag}" by the flow's tag value, made safe for Kafka topic names and directories
+fn expand_topic(template: &str, flow: &EnrichedFlow) -> Option<String> {
+    let mut topic = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        let end = start + rest[start..].find('}')?;
+        topic.push_str(&rest[..start]);
+        let value = flow.tag(&rest[start + 1..end]).filter(|value| !value.is_empty())?;
+        topic.extend(value.chars().map(|c| if c.is_ascii_alphanumeric() || c == '-' || c == '_' { c } else { '_' }));
+        rest = &rest[end + 1..];
+    }
+    topic.push_str(rest);
+    Some(topic)
+}
+
+fn csv_row(flow: &EnrichedFlow, sample_rate: u64) -> Vec<u8> {
+    let fields = &flow.fields;
+    let mut row = vec![flow.time.to_rfc3339()];
+    row.extend(CSV_TAGS.iter().map(|tag| flow.tag(tag).unwrap_or("").to_string()));
+    row.extend([
+        fields.src_port.to_string(),
+        fields.dst_port.to_string(),
+        fields.protocol.to_string(),
+        fields.tcp_flags.to_string(),
+        fields.packets.to_string(),
+        fields.bytes.to_string(),
+        sample_rate.to_string(),
+    ]);
+    let mut writer = csv::Writer::from_writer(Vec::new());
+    writer.write_record(&row).expect("csv rows can always be written to memory");
+    writer.into_inner().unwrap_or_default()
+}
+
+// Errors in the configured routes, for `Config::check`
+pub fn check(routes: &[RouteConfig]) -> Vec<String> {
+    routes
+        .iter()
+        .enumerate()
+        .filter_map(|(index, route)| {
+            Route::compile(route).err().map(|e| format!("routes: route {} ({}): {}", index + 1, route.name, e))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn route(name: &str, filter: &str) -> RouteConfig {
+        RouteConfig { name: name.to_string(), filter: filter.to_string(), topics: vec!["flows-{service}".to_string()], ..RouteConfig::default() }
+    }
+
+    #[test]
+    fn invalid_route_fails() {
+        assert!(Router::new(&[route("web", "dst port 443")]).is_ok());
+        let e = Router::new(&[route("web", "dst port 443"), route("broken", "dst port")]).unwrap_err();
+        assert!(e.starts_with("routes: route 2 (broken): "), "{}", e);
+    }
+}
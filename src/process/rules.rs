@@ -0,0 +1,392 @@
+use std::collections::{BTreeMap, HashSet};
+use std::fs;
+use std::net::Ipv4Addr;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+
+use cidr::Ipv4Cidr;
+use serde::{Deserialize, Serialize};
+
+use crate::process::filter::{self, Filter};
+use crate::process::flow::EnrichedFlow;
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct RulesConfig {
+    // Evaluated in order, before the rules of `file`
+    pub rules: Vec<RuleConfig>,
+    // YAML list of rules, re-read when it changed
+    pub file: Option<String>,
+    // What happens to flows no rule kept or dropped
+    pub default_action: Action,
+    pub reload_secs: u64,
+}
+
+impl Default for RulesConfig {
+    fn default() -> Self {
+        RulesConfig {
+            rules: Vec::new(),
+            file: None,
+            default_action: Action::Keep,
+            reload_secs: 30,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Action {
+    #[default]
+    Keep,
+    Drop,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+#[serde(default)]
+pub struct RuleConfig {
+    pub name: String,
+    // Every condition given must hold, an empty match matches every flow
+    #[serde(rename = "match")]
+    pub matches: MatchConfig,
+    // Tags written on matching flows
+    pub set: BTreeMap<String, String>,
+    // Keep or drop matching flows without looking at later rules,
+    // rules without action only set tags
+    pub action: Option<Action>,
+}
+
+// A flow matches a condition when any of its values does. `prefix`, `asn`,
+// `port` and `interface` match either side of the flow.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+#[serde(default)]
+pub struct MatchConfig {
+    pub prefix: Vec<String>,
+    pub src_prefix: Vec<String>,
+    pub dst_prefix: Vec<String>,
+    pub asn: Vec<u32>,
+    pub src_asn: Vec<u32>,
+    pub dst_asn: Vec<u32>,
+    // Ports or "first-last" ranges
+    pub port: Vec<Scalar>,
+    pub src_port: Vec<Scalar>,
+    pub dst_port: Vec<Scalar>,
+    // Protocol numbers or names like tcp
+    pub protocol: Vec<Scalar>,
+    // Exporter addresses or networks
+    pub exporter: Vec<String>,
+    // ifIndexes or interface names
+    pub interface: Vec<Scalar>,
+    pub in_if: Vec<Scalar>,
+    pub out_if: Vec<Scalar>,
+    // Filter expression like "dst net 10.0.0.0/8 and proto udp", see process::filter
+    pub filter: Option<String>,
+}
+
+// A YAML number or string
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(untagged)]
+pub enum Scalar {
+    Number(u64),
+    Text(String),
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Side {
+    Src,
+    Dst,
+    Either,
+}
+
+impl Side {
+    fn test<T>(self, src: T, dst: T, matches: impl Fn(T) -> bool) -> bool {
+        match self {
+            Side::Src => matches(src),
+            Side::Dst => matches(dst),
+            Side::Either => matches(src) || matches(dst),
+        }
+    }
+}
+
+// Merged, sorted address ranges
+#[derive(Debug, Default)]
+struct PrefixSet {
+    ranges: Vec<(Ipv4Addr, Ipv4Addr)>,
+}
+
+impl PrefixSet {
+    fn parse(networks: &[String]) -> Result<Self, String> {
+        let mut ranges = Vec::with_capacity(networks.len());
+        for network in networks {
+            let cidr: Ipv4Cidr = network.parse().map_err(|e| format!("invalid prefix {}: {}", network, e))?;
+            ranges.push((cidr.first_address(), cidr.last_address()));
+        }
+        ranges.sort();
+        let mut merged: Vec<(Ipv4Addr, Ipv4Addr)> = Vec::with_capacity(ranges.len());
+        for (start, end) in ranges {
+            match merged.last_mut() {
+                Some(last) if u32::from(start) <= u32::from(last.1).saturating_add(1) => last.1 = last.1.max(end),
+                _ => merged.push((start, end)),
+            }
+        }
+        Ok(PrefixSet { ranges: merged })
+    }
+
+    fn contains(&self, ip: Ipv4Addr) -> bool {
+        let index = self.ranges.partition_point(|(start, _)| *start <= ip);
+        index > 0 && self.ranges[index - 1].1 >= ip
+    }
+}
+
+#[derive(Debug)]
+enum Condition {
+    Prefix(Side, PrefixSet),
+    Asn(Side, HashSet<u32>),
+    Port(Side, Vec<(u16, u16)>),
+    Protocol(HashSet<u8>),
+    Exporter(PrefixSet),
+    // ifIndexes and names, Src is the input and Dst the output interface
+    Interface(Side, HashSet<u32>, HashSet<String>),
+    Filter(Filter),
+}
+
+impl Condition {
+    fn matches(&self, flow: &EnrichedFlow) -> bool {
+        match self {
+            Condition::Prefix(side, set) => side.test(flow.src_addr(), flow.dst_addr(), |ip| ip.is_some_and(|ip| set.contains(ip))),
+            Condition::Asn(side, asns) => side.test("src_asn", "dst_asn", |tag| {
+                flow.tag(tag).and_then(|asn| asn.parse().ok()).is_some_and(|asn| asns.contains(&asn))
+            }),
+            Condition::Port(side, ranges) => side.test(flow.fields.src_port, flow.fields.dst_port, |port| {
+                ranges.iter().any(|(first, last)| (*first..=*last).contains(&port))
+            }),
+            Condition::Protocol(protocols) => protocols.contains(&flow.fields.protocol),
+            Condition::Exporter(set) => flow
+                .tag("exporter")
+                .and_then(|exporter| exporter.parse().ok())
+                .is_some_and(|exporter| set.contains(exporter)),
+            Condition::Interface(side, indexes, names) => side.test(
+                (flow.fields.input_if, "in_if_name"),
+                (flow.fields.output_if, "out_if_name"),
+                |(index, tag)| indexes.contains(&index) || flow.tag(tag).is_some_and(|name| names.contains(name)),
+            ),
+            Condition::Filter(filter) => filter.matches(flow),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Rule {
+    conditions: Vec<Condition>,
+    set: Vec<(String, String)>,
+    action: Option<Action>,
+}
+
+impl Rule {
+    fn compile(config: &RuleConfig) -> Result<Rule, String> {
+        let m = &config.matches;
+        let mut conditions = Vec::new();
+        for (side, prefixes) in [(Side::Either, &m.prefix), (Side::Src, &m.src_prefix), (Side::Dst, &m.dst_prefix)] {
+            if !prefixes.is_empty() {
+                conditions.push(Condition::Prefix(side, PrefixSet::parse(prefixes)?));
+            }
+        }
+        for (side, asns) in [(Side::Either, &m.asn), (Side::Src, &m.src_asn), (Side::Dst, &m.dst_asn)] {
+            if !asns.is_empty() {
+                conditions.push(Condition::Asn(side, asns.iter().copied().collect()));
+            }
+        }
+        for (side, ports) in [(Side::Either, &m.port), (Side::Src, &m.src_port), (Side::Dst, &m.dst_port)] {
+            if !ports.is_empty() {
+                conditions.push(Condition::Port(side, ports.iter().map(parse_ports).collect::<Result<_, _>>()?));
+            }
+        }
+        if !m.protocol.is_empty() {
+            conditions.push(Condition::Protocol(m.protocol.iter().map(parse_protocol).collect::<Result<_, _>>()?));
+        }
+        if !m.exporter.is_empty() {
+            conditions.push(Condition::Exporter(PrefixSet::parse(&m.exporter)?));
+        }
+        for (side, interfaces) in [(Side::Either, &m.interface), (Side::Src, &m.in_if), (Side::Dst, &m.out_if)] {
+            if interfaces.is_empty() {
+                continue;
+            }
+            let mut indexes = HashSet::new();
+            let mut names = HashSet::new();
+            for interface in interfaces {
+                match interface {
+                    Scalar::Number(index) => {
+                        indexes.insert(u32::try_from(*index).map_err(|_| format!("invalid ifIndex {}", index))?);
+                    }
+                    Scalar::Text(name) => {
+                        names.insert(name.clone());
+                    }
+                }
+            }
+            conditions.push(Condition::Interface(side, indexes, names));
+        }
+        if let Some(filter) = &m.filter {
+            conditions.push(Condition::Filter(Filter::parse(filter)?));
+        }
+
+        Ok(Rule {
+            conditions,
+            set: config.set.iter().map(|(tag, value)| (tag.clone(), value.clone())).collect(),
+            action: config.action,
+        })
+    }
+}
+
+// Port 443 or range "8000-8100"
+fn parse_ports(port: &Scalar) -> Result<(u16, u16), String> {
+    let invalid = || format!("invalid port {:?}", port);
+    match port {
+        Scalar::Number(port) => u16::try_from(*port).map(|port| (port, port)).map_err(|_| invalid()),
+        Scalar::Text(text) => {
+            let (first, last) = text.split_once('-').unwrap_or((text, text));
+            let first: u16 = first.trim().parse().map_err(|_| invalid())?;
+            let last: u16 = last.trim().parse().map_err(|_| invalid())?;
+            (first <= last).then_some((first, last)).ok_or_else(invalid)
+        }
+    }
+}
+
+// Protocol number or the name `protocol_name` gives it
+fn parse_protocol(protocol: &Scalar) -> Result<u8, String> {
+    match protocol {
+        Scalar::Number(number) => u8::try_from(*number).map_err(|_| format!("invalid protocol {}", number)),
+        Scalar::Text(name) => filter::parse_protocol(name),
+    }
+}
+
+fn compile_rules(configs: &[RuleConfig]) -> Result<Vec<Rule>, String> {
+    configs
+        .iter()
+        .enumerate()
+        .map(|(index, config)| {
+            Rule::compile(config).map_err(|e| format!("rule {} ({}): {}", index + 1, config.name, e))
+        })
+        .collect()
+}
+
+// Rules of the rules file
+pub fn load_file(path: &str) -> Result<Vec<RuleConfig>, String> {
+    let text = fs::read_to_string(path).map_err(|e| format!("can't read {}: {}", path, e))?;
+    serde_yaml::from_str(&text).map_err(|e| format!("can't parse {}: {}", path, e))
+}
+
+// Errors in the configured rules, for `Config::check`
+pub fn check(config: &RulesConfig) -> Vec<String> {
+    let mut problems = Vec::new();
+    if let Err(e) = compile_rules(&config.rules) {
+        problems.push(format!("rules: {}", e));
+    }
+    if let Some(file) = &config.file {
+        if let Err(e) = load_file(file).and_then(|rules| compile_rules(&rules)) {
+            problems.push(format!("rules.file: {}", e));
+        }
+    }
+    if config.reload_secs == 0 {
+        problems.push("rules.reload_secs must be at least 1".to_string());
+    }
+    problems
+}
+
+#[derive(Debug, Default)]
+struct RuleSet {
+    inline: Vec<Rule>,
+    from_file: Vec<Rule>,
+    modified: Option<SystemTime>,
+}
+
+// Tags flows and decides which ones are kept. Cloning is cheap and every clone
+// sees the rules the reloader re-reads.
+#[derive(Clone, Debug)]
+pub struct RuleTagger {
+    config: RulesConfig,
+    rules: Arc<RwLock<RuleSet>>,
+}
+
+impl RuleTagger {
+    // Fails on a broken inline rule, a broken rules file only logs and is retried on reload
+    pub fn new(config: &RulesConfig) -> Result<Self, String> {
+        let inline = compile_rules(&config.rules).map_err(|e| format!("rules: {}", e))?;
+        let tagger = RuleTagger {
+            config: config.clone(),
+            rules: Arc::new(RwLock::new(RuleSet { inline, ..RuleSet::default() })),
+        };
+        tagger.reload();
+        Ok(tagger)
+    }
+
+    // Set the tags of every matching rule, false when the flow is dropped
+    pub fn apply(&self, flow: &mut EnrichedFlow) -> bool {
+        let rules = self.rules.read().unwrap();
+        for rule in rules.inline.iter().chain(rules.from_file.iter()) {
+            if !rule.conditions.iter().all(|condition| condition.matches(flow)) {
+                continue;
+            }
+            for (tag, value) in &rule.set {
+                flow.set_tag(tag, value.as_str());
+            }
+            if let Some(action) = rule.action {
+                return action == Action::Keep;
+            }
+        }
+        self.config.default_action == Action::Keep
+    }
+
+    // Re-read the rules file when it changed, a broken file keeps the rules loaded before
+    pub fn reload(&self) {
+        let file = match &self.config.file {
+            Some(file) => file,
+            None => return,
+        };
+        let modified = fs::metadata(file).and_then(|m| m.modified()).ok();
+        if modified.is_some() && self.rules.read().unwrap().modified == modified {
+            return;
+        }
+        match load_file(file).and_then(|rules| compile_rules(&rules)) {
+            Ok(rules) => {
+                println!("Loaded {} rules from {}", rules.len(), file);
+                let mut current = self.rules.write().unwrap();
+                current.from_file = rules;
+                current.modified = modified;
+            }
+            Err(e) => {
+                println!("Keeping the previous rules: {}", e);
+                self.rules.write().unwrap().modified = modified;
+            }
+        }
+    }
+}
+
+// Pick up edits of the rules file every `reload_secs`
+pub async fn run_reloader(tagger: RuleTagger) {
+    let mut ticker = tokio::time::interval(Duration::from_secs(tagger.config.reload_secs.max(1)));
+    ticker.tick().await;
+
+    loop {
+        ticker.tick().await;
+        tagger.reload();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn invalid_inline_rule_fails() {
+        let rule = |prefix: &str| RuleConfig {
+            name: prefix.to_string(),
+            matches: MatchConfig { prefix: vec![prefix.to_string()], ..MatchConfig::default() },
+            ..RuleConfig::default()
+        };
+        let mut config = RulesConfig { rules: vec![rule("10.0.0.0/8")], ..RulesConfig::default() };
+        assert!(RuleTagger::new(&config).is_ok());
+
+        config.rules.push(rule("10.0.0.0/33"));
+        let e = RuleTagger::new(&config).unwrap_err();
+        assert!(e.starts_with("rules: rule 2 (10.0.0.0/33)"), "{}", e);
+    }
+}
@@ -0,0 +1,382 @@
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::time::Duration;
+
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use tokio::time::Instant;
+
+use crate::process::flow::EnrichedFlow;
+use crate::transport::{consume_windows, Done, SharedTransport, Windowed, CONVERSATIONS, ENRICHER_TO_TSDB};
+
+pub const STITCHER_GROUP: &str = "ta-stitcher";
+
+const PROTOCOL_TCP: u8 = 6;
+const TCP_SYN: u8 = 0x02;
+// Handshakes slower than this are not measured, the start times are from unrelated records
+const MAX_RTT_MS: u64 = 10_000;
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct StitcherConfig {
+    // How long after its first record a conversation is published, records of
+    // both directions seen until then are part of it
+    pub window_secs: u64,
+    // Conversations open at once, the oldest are published early beyond this
+    pub max_pending: usize,
+    // Publish conversations of which only one direction was seen
+    pub emit_unmatched: bool,
+}
+
+impl Default for StitcherConfig {
+    fn default() -> Self {
+        StitcherConfig {
+            window_secs: 30,
+            max_pending: 1_000_000,
+            emit_unmatched: true,
+        }
+    }
+}
+
+// Both directions of a session, published on the `conversations` topic
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Conversation {
+    pub time: DateTime<Utc>,
+    pub protocol: u8,
+    pub client_ip: String,
+    pub client_port: u16,
+    pub server_ip: String,
+    pub server_port: u16,
+    // Client to server and server to client
+    pub client_bytes: u64,
+    pub client_packets: u64,
+    pub server_bytes: u64,
+    pub server_packets: u64,
+    // First to last packet of either direction, in milliseconds
+    pub duration_ms: u64,
+    // SYN to SYN/ACK, when one exporter saw both directions of a TCP handshake
+    pub rtt_ms: Option<u64>,
+    // False when only one direction was seen within `window_secs`
+    pub bidirectional: bool,
+    // Tags of the flows, src_/dst_ renamed to client_/server_
+    pub tags: BTreeMap<String, String>,
+}
+
+impl Conversation {
+    pub fn to_json(&self) -> Vec<u8> {
+        serde_json::to_vec(self).expect("Conversation can always be serialized")
+    }
+
+    // `forward` goes from the client to the server
+    fn new(forward: Option<&EnrichedFlow>, reverse: Option<&EnrichedFlow>) -> Option<Self> {
+        let any = forward.or(reverse)?;
+        let (client_ip, client_port, server_ip, server_port) = match forward {
+            Some(flow) => (flow.tag("src_ip")?, flow.fields.src_port, flow.tag("dst_ip")?, flow.fields.dst_port),
+            None => (any.tag("dst_ip")?, any.fields.dst_port, any.tag("src_ip")?, any.fields.src_port),
+        };
+
+        let mut tags = BTreeMap::new();
+        // The forward tags win, the reverse flow only fills in what is missing
+        for (flow, swap) in [(reverse, true), (forward, false)] {
+            if let Some(flow) = flow {
+                for (name, value) in &flow.tags {
+                    tags.insert(role_tag(name, swap), value.clone());
+                }
+            }
+        }
+        // Per flow direction, meaningless for a conversation
+        tags.remove("type");
+
+        let flows = || forward.into_iter().chain(reverse);
+        let first = flows().map(|flow| flow.fields.first_switched).min().unwrap_or(0);
+        let last = flows().map(|flow| flow.fields.last_switched).max().unwrap_or(0);
+        Some(Conversation {
+            time: flows().map(|flow| flow.time).max().unwrap_or(any.time),
+            protocol: any.fields.protocol,
+            client_ip: client_ip.to_string(),
+            client_port,
+            server_ip: server_ip.to_string(),
+            server_port,
+            client_bytes: forward.map_or(0, |flow| flow.fields.bytes),
+            client_packets: forward.map_or(0, |flow| flow.fields.packets),
+            server_bytes: reverse.map_or(0, |flow| flow.fields.bytes),
+            server_packets: reverse.map_or(0, |flow| flow.fields.packets),
+            duration_ms: last.saturating_sub(first),
+            rtt_ms: forward.zip(reverse).and_then(|(forward, reverse)| handshake_rtt(forward, reverse)),
+            bidirectional: forward.is_some() && reverse.is_some(),
+            tags,
+        })
+    }
+}
+
+// src_country -> client_country, swapped for flows from the server
+fn role_tag(name: &str, swap: bool) -> String {
+    let (src, dst) = if swap { ("server_", "client_") } else { ("client_", "server_") };
+    if let Some(rest) = name.strip_prefix("src_") {
+        format!("{}{}", src, rest)
+    } else if let Some(rest) = name.strip_prefix("dst_") {
+        format!("{}{}", dst, rest)
+    } else if let Some(rest) = name.strip_prefix("in_if") {
+        // The interface the client's packets came in on is the one the server's leave through
+        format!("{}{}", if swap { "out_if" } else { "in_if" }, rest)
+    } else if let Some(rest) = name.strip_prefix("out_if") {
+        format!("{}{}", if swap { "in_if" } else { "out_if" }, rest)
+    } else {
+        name.to_string()
+    }
+}
+
+// Time between the client's SYN and the server's answer at the exporter. Flow start
+// times are only comparable between records of the same exporter.
+fn handshake_rtt(forward: &EnrichedFlow, reverse: &EnrichedFlow) -> Option<u64> {
+    if forward.fields.protocol != PROTOCOL_TCP || forward.tag("exporter") != reverse.tag("exporter") {
+        return None;
+    }
+    if forward.fields.tcp_flags & TCP_SYN == 0 || reverse.fields.tcp_flags & TCP_SYN == 0 {
+        return None;
+    }
+    // Start times in whole seconds, e.g. IPFIX flowStartSeconds, can't time a handshake
+    if forward.fields.first_switched.is_multiple_of(1000) && reverse.fields.first_switched.is_multiple_of(1000) {
+        return None;
+    }
+    let rtt = reverse.fields.first_switched.checked_sub(forward.fields.first_switched)?;
+    (rtt <= MAX_RTT_MS).then_some(rtt)
+}
+
+// Protocol and both endpoints, the same for the two directions
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct ConversationKey {
+    protocol: u8,
+    low: (String, u16),
+    high: (String, u16),
+}
+
+impl ConversationKey {
+    fn of(flow: &EnrichedFlow) -> Option<Self> {
+        let src = (flow.tag("src_ip")?.to_string(), flow.fields.src_port);
+        let dst = (flow.tag("dst_ip")?.to_string(), flow.fields.dst_port);
+        let (low, high) = if src <= dst { (src, dst) } else { (dst, src) };
+        Some(ConversationKey { protocol: flow.fields.protocol, low, high })
+    }
+}
+
+struct Pending {
+    // The direction seen first and, once it came, the opposite one
+    first: EnrichedFlow,
+    second: Option<EnrichedFlow>,
+    // Offset of the first record, where consuming has to resume to publish the conversation
+    offset: i64,
+}
+
+// Pairs the records of both directions of a 5-tuple. A conversation is published
+// `window_secs` after its first record, records of either direction arriving until
+// then (active timeouts) are added to it.
+pub struct Stitcher {
+    config: StitcherConfig,
+    pending: HashMap<ConversationKey, Pending>,
+    // Arrival of the first record of every pending conversation, oldest first
+    expiry: VecDeque<(Instant, ConversationKey)>,
+}
+
+impl Stitcher {
+    pub fn new(config: StitcherConfig) -> Self {
+        Stitcher {
+            config,
+            pending: HashMap::new(),
+            expiry: VecDeque::new(),
+        }
+    }
+
+    pub fn observe(&mut self, flow: EnrichedFlow, offset: i64, now: Instant) {
+        let key = match ConversationKey::of(&flow) {
+            Some(key) => key,
+            None => return,
+        };
+        if let Some(pending) = self.pending.get_mut(&key) {
+            let same_direction = pending.first.tag("src_ip") == flow.tag("src_ip") && pending.first.fields.src_port == flow.fields.src_port;
+            match (same_direction, &mut pending.second) {
+                (true, _) => merge(&mut pending.first, &flow),
+                (false, Some(second)) => merge(second, &flow),
+                (false, second) => *second = Some(flow),
+            }
+            return;
+        }
+
+        self.expiry.push_back((now, key.clone()));
+        self.pending.insert(key, Pending { first: flow, second: None, offset });
+    }
+
+    // Offset of the first record of the oldest conversation not published yet
+    pub fn pending_from(&self) -> Option<i64> {
+        self.expiry.front().and_then(|(_, key)| self.pending.get(key)).map(|pending| pending.offset)
+    }
+
+    // Conversations whose window ended, and the oldest ones beyond `max_pending`
+    pub fn expire(&mut self, now: Instant) -> Vec<Conversation> {
+        let window = Duration::from_secs(self.config.window_secs);
+        let mut expired = Vec::new();
+        while let Some((arrived, _)) = self.expiry.front() {
+            if *arrived + window > now && self.pending.len() <= self.config.max_pending {
+                break;
+            }
+            let (_, key) = self.expiry.pop_front().unwrap();
+            let pending = match self.pending.remove(&key) {
+                Some(pending) => pending,
+                None => continue,
+            };
+            let (forward, reverse) = match pending.second {
+                Some(second) => {
+                    let (forward, reverse) = roles(pending.first, second);
+                    (Some(forward), Some(reverse))
+                }
+                None if self.config.emit_unmatched => one_side(pending.first),
+                None => continue,
+            };
+            expired.extend(Conversation::new(forward.as_ref(), reverse.as_ref()));
+        }
+        expired
+    }
+}
+
+fn merge(into: &mut EnrichedFlow, flow: &EnrichedFlow) {
+    let fields = &mut into.fields;
+    fields.bytes += flow.fields.bytes;
+    fields.packets += flow.fields.packets;
+    fields.tcp_flags |= flow.fields.tcp_flags;
+    fields.first_switched = fields.first_switched.min(flow.fields.first_switched);
+    fields.last_switched = fields.last_switched.max(flow.fields.last_switched);
+    into.time = into.time.max(flow.time);
+}
+
+// Which of two opposite flows the client sent: the one towards the service port
+// the enricher recognized, else the one that started first, else the one towards
+// the lower port
+fn roles(a: EnrichedFlow, b: EnrichedFlow) -> (EnrichedFlow, EnrichedFlow) {
+    let towards_service = |flow: &EnrichedFlow| {
+        flow.tag("app_port").and_then(|port| port.parse::<u16>().ok()).filter(|port| *port != 0) == Some(flow.fields.dst_port)
+    };
+    let a_first = match (towards_service(&a), towards_service(&b)) {
+        (true, false) => true,
+        (false, true) => false,
+        _ if a.tag("exporter") == b.tag("exporter") && a.fields.first_switched != b.fields.first_switched => {
+            a.fields.first_switched < b.fields.first_switched
+        }
+        _ => a.fields.dst_port <= b.fields.dst_port,
+    };
+    if a_first {
+        (a, b)
+    } else {
+        (b, a)
+    }
+}
+
+// A flow without its reverse is from the client unless it comes from the service port
+fn one_side(flow: EnrichedFlow) -> (Option<EnrichedFlow>, Option<EnrichedFlow>) {
+    let app_port = flow.tag("app_port").and_then(|port| port.parse::<u16>().ok()).filter(|port| *port != 0);
+    let from_server = match app_port {
+        Some(port) => port == flow.fields.src_port && port != flow.fields.dst_port,
+        None => flow.fields.src_port < flow.fields.dst_port,
+    };
+    if from_server {
+        (None, Some(flow))
+    } else {
+        (Some(flow), None)
+    }
+}
+
+// Publishes the conversations whose window ended
+struct Publishing {
+    stitcher: Stitcher,
+    transport: SharedTransport,
+}
+
+#[async_trait]
+impl Windowed for Publishing {
+    type Item = EnrichedFlow;
+
+    fn observe(&mut self, flow: EnrichedFlow, offset: i64) {
+        self.stitcher.observe(flow, offset, Instant::now());
+    }
+
+    async fn flush(&mut self) -> Done {
+        for conversation in self.stitcher.expire(Instant::now()) {
+            let key = conversation.client_ip.clone();
+            if let Err(e) = self.transport.send(CONVERSATIONS, Some(key.as_bytes()), &conversation.to_json()).await {
+                println!("Error publishing conversation: {:?}", e);
+            }
+        }
+        // Conversations still waiting for their window are stitched again after a restart
+        self.stitcher.pending_from().map_or(Done::All, Done::Before)
+    }
+}
+
+// Stitch the enriched flows into conversations and publish them on the `conversations` topic
+pub async fn run(transport: SharedTransport, config: StitcherConfig) {
+    let mut subscription = transport
+        .subscribe(ENRICHER_TO_TSDB, STITCHER_GROUP)
+        .await
+        .expect("Can't subscribe to specified topic");
+
+    // Give up on waiting flows about once a second
+    let mut publishing = Publishing { stitcher: Stitcher::new(config), transport };
+    consume_windows(subscription.as_mut(), Duration::from_secs(1), &mut publishing).await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::process::flow::FlowRecord;
+
+    fn flow(src: (&str, u16), dst: (&str, u16)) -> EnrichedFlow {
+        let record = FlowRecord {
+            src_ip: src.0.to_string(),
+            src_port: src.1,
+            dst_ip: dst.0.to_string(),
+            dst_port: dst.1,
+            protocol: PROTOCOL_TCP,
+            bytes: 100,
+            packets: 1,
+            ..FlowRecord::default()
+        };
+        EnrichedFlow::new(&record, Utc::now())
+    }
+
+    #[test]
+    fn pending_from_the_oldest_open_conversation() {
+        let mut stitcher = Stitcher::new(StitcherConfig { window_secs: 30, ..StitcherConfig::default() });
+        let start = Instant::now();
+        let client = ("192.0.2.1", 40000);
+        let server = ("198.51.100.1", 443);
+        stitcher.observe(flow(client, server), 5, start);
+        stitcher.observe(flow(("192.0.2.2", 40000), server), 6, start + Duration::from_secs(10));
+        stitcher.observe(flow(server, client), 7, start + Duration::from_secs(20));
+        assert_eq!(stitcher.pending_from(), Some(5));
+
+        let expired = stitcher.expire(start + Duration::from_secs(30));
+        assert_eq!(expired.len(), 1);
+        assert_eq!(expired[0].client_ip, "192.0.2.1");
+        assert_eq!(stitcher.pending_from(), Some(6));
+
+        assert_eq!(stitcher.expire(start + Duration::from_secs(40)).len(), 1);
+        assert_eq!(stitcher.pending_from(), None);
+    }
+
+    #[test]
+    fn handshake_rtt_needs_millisecond_starts() {
+        let client = ("192.0.2.1", 40000);
+        let server = ("198.51.100.1", 443);
+        let handshake = |forward_start: u64, reverse_start: u64| {
+            let mut forward = flow(client, server);
+            let mut reverse = flow(server, client);
+            for (flow, start, flags) in [(&mut forward, forward_start, TCP_SYN), (&mut reverse, reverse_start, TCP_SYN | 0x10)] {
+                flow.set_tag("exporter", "203.0.113.1");
+                flow.fields.first_switched = start;
+                flow.fields.tcp_flags = flags;
+            }
+            handshake_rtt(&forward, &reverse)
+        };
+        assert_eq!(handshake(1_700_000_000_250, 1_700_000_000_262), Some(12));
+        assert_eq!(handshake(1_700_000_000_000, 1_700_000_001_000), None);
+        assert_eq!(handshake(1_700_000_000_262, 1_700_000_000_250), None);
+    }
+}
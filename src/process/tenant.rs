@@ -0,0 +1,239 @@
+// Business units sharing the collector. A tenant owns the datagrams of its exporters
+// and listener ports: they go through their own topic and enrichment tasks, with the
+// tenant's rules, home networks, output topic and flow rate quota.
+
+use std::collections::HashSet;
+use std::net::{IpAddr, Ipv4Addr};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use cidr::Ipv4Cidr;
+use serde::{Deserialize, Serialize};
+
+use crate::process::rules::{self, RuleTagger, RulesConfig};
+use crate::process::token_bucket::TokenBucket;
+use crate::transport::{ENRICHER_TO_TSDB, LISTENER_TO_ENRICHER};
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct TenantConfig {
+    // Part of topic names, letters, digits, - and _
+    pub name: String,
+    // Exporter addresses or networks, and listener ports. A datagram belongs to the first
+    // tenant whose lists it matches, an empty list matches everything.
+    pub exporters: Vec<String>,
+    pub ports: Vec<u16>,
+    // Networks flows are incoming to or outgoing from, the private ranges when empty
+    pub home_prefixes: Vec<String>,
+    // Used instead of the top level rules
+    pub rules: RulesConfig,
+    // Where the enriched flows go, enricher-to-tsdb when not set
+    pub topic: Option<String>,
+    // Bucket of the influx section the flows of `topic` are written to
+    pub influx_bucket: Option<String>,
+    // Flows per second, datagrams over the quota are dropped. 0 is no limit.
+    pub max_flows_per_sec: u64,
+    // Enrichment tasks of the tenant
+    pub workers: usize,
+}
+
+impl Default for TenantConfig {
+    fn default() -> Self {
+        TenantConfig {
+            name: String::new(),
+            exporters: Vec::new(),
+            ports: Vec::new(),
+            home_prefixes: Vec::new(),
+            rules: RulesConfig::default(),
+            topic: None,
+            influx_bucket: None,
+            max_flows_per_sec: 0,
+            workers: 2,
+        }
+    }
+}
+
+impl TenantConfig {
+    // Raw datagrams of the tenant's exporters
+    pub fn input_topic(&self) -> String {
+        format!("{}-{}", LISTENER_TO_ENRICHER, self.name)
+    }
+
+    pub fn output_topic(&self) -> &str {
+        self.topic.as_deref().unwrap_or(ENRICHER_TO_TSDB)
+    }
+}
+
+// An address or network, "192.0.2.1" or "192.0.2.0/24"
+fn parse_networks(networks: &[String]) -> Result<Vec<Ipv4Cidr>, String> {
+    networks
+        .iter()
+        .map(|network| match network.parse::<Ipv4Addr>() {
+            Ok(ip) => Ok(Ipv4Cidr::new_host(ip)),
+            Err(_) => network.parse().map_err(|e| format!("invalid network {}: {}", network, e)),
+        })
+        .collect()
+}
+
+fn contains(networks: &[Ipv4Cidr], ip: Ipv4Addr) -> bool {
+    networks.iter().any(|network| network.contains(&ip))
+}
+
+// Picks the input topic of every datagram in the listener
+#[derive(Debug)]
+pub struct TenantResolver {
+    tenants: Vec<(Vec<Ipv4Cidr>, Vec<u16>, String)>,
+}
+
+impl TenantResolver {
+    // Fails on the first tenant with an invalid exporter
+    pub fn new(configs: &[TenantConfig]) -> Result<Self, String> {
+        let tenants = configs
+            .iter()
+            .map(|config| {
+                let exporters = parse_networks(&config.exporters).map_err(|e| format!("tenants.{}: {}", config.name, e))?;
+                Ok((exporters, config.ports.clone(), config.input_topic()))
+            })
+            .collect::<Result<_, String>>()?;
+        Ok(TenantResolver { tenants })
+    }
+
+    pub fn input_topic(&self, exporter: IpAddr, port: u16) -> &str {
+        let exporter = match exporter {
+            IpAddr::V4(ip) => Some(ip),
+            IpAddr::V6(ip) => ip.to_ipv4_mapped(),
+        };
+        self.tenants
+            .iter()
+            .find(|(exporters, ports, _)| {
+                (exporters.is_empty() || exporter.is_some_and(|ip| contains(exporters, ip)))
+                    && (ports.is_empty() || ports.contains(&port))
+            })
+            .map_or(LISTENER_TO_ENRICHER, |(_, _, topic)| topic.as_str())
+    }
+}
+
+// A tenant as the enrichment tasks see it
+#[derive(Debug)]
+pub struct Tenant {
+    pub name: String,
+    pub input_topic: String,
+    pub output_topic: String,
+    home_prefixes: Vec<Ipv4Cidr>,
+    pub rules: RuleTagger,
+    quota: Option<Mutex<TokenBucket>>,
+    dropped: AtomicU64,
+}
+
+impl Tenant {
+    pub fn new(config: &TenantConfig) -> Result<Self, String> {
+        Ok(Tenant {
+            name: config.name.clone(),
+            input_topic: config.input_topic(),
+            output_topic: config.output_topic().to_string(),
+            home_prefixes: parse_networks(&config.home_prefixes).map_err(|e| format!("tenants.{}: {}", config.name, e))?,
+            rules: RuleTagger::new(&config.rules).map_err(|e| format!("tenants.{}.{}", config.name, e))?,
+            quota: (config.max_flows_per_sec > 0)
+                .then(|| Mutex::new(TokenBucket::new(config.max_flows_per_sec, config.max_flows_per_sec))),
+            dropped: AtomicU64::new(0),
+        })
+    }
+
+    // None leaves the direction to the private ranges
+    pub fn is_home(&self, ip: &str) -> Option<bool> {
+        if self.home_prefixes.is_empty() {
+            return None;
+        }
+        Some(ip.parse().is_ok_and(|ip| contains(&self.home_prefixes, ip)))
+    }
+
+    // Whether the next datagram fits the quota. Checked before parsing, so a flood
+    // costs no more than reading it off the topic.
+    pub fn admit(&self) -> bool {
+        let admitted = self.quota.as_ref().is_none_or(|quota| quota.lock().unwrap().available());
+        if !admitted {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+        admitted
+    }
+
+    // Count the flows of an admitted datagram against the quota
+    pub fn charge(&self, flows: usize) {
+        if let Some(quota) = &self.quota {
+            quota.lock().unwrap().charge(flows as u64);
+        }
+    }
+}
+
+// Report the datagrams dropped over the quotas every minute
+pub async fn run_reporter(tenants: Vec<Arc<Tenant>>) {
+    let mut ticker = tokio::time::interval(Duration::from_secs(60));
+    ticker.tick().await;
+
+    loop {
+        ticker.tick().await;
+        for tenant in &tenants {
+            let dropped = tenant.dropped.swap(0, Ordering::Relaxed);
+            if dropped > 0 {
+                println!("Tenant {} is over its flow quota, dropped {} datagrams in the last minute", tenant.name, dropped);
+            }
+        }
+    }
+}
+
+// Errors in the configured tenants, for `Config::check`
+pub fn check(tenants: &[TenantConfig], has_influx: bool) -> Vec<String> {
+    let mut problems = Vec::new();
+    let mut names = HashSet::new();
+    for tenant in tenants {
+        let name = &tenant.name;
+        if name.is_empty() || !name.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_') {
+            problems.push(format!("tenants: name {:?} must be letters, digits, - and _", name));
+        }
+        if !names.insert(name) {
+            problems.push(format!("tenants: {} is configured twice", name));
+        }
+        if tenant.exporters.is_empty() && tenant.ports.is_empty() {
+            problems.push(format!("tenants.{}: exporters or ports must be set", name));
+        }
+        for networks in [&tenant.exporters, &tenant.home_prefixes] {
+            if let Err(e) = parse_networks(networks) {
+                problems.push(format!("tenants.{}: {}", name, e));
+            }
+        }
+        problems.extend(rules::check(&tenant.rules).into_iter().map(|problem| format!("tenants.{}.{}", name, problem)));
+        if tenant.influx_bucket.is_some() {
+            if tenant.topic.is_none() {
+                problems.push(format!("tenants.{}.influx_bucket needs a topic of its own", name));
+            }
+            if !has_influx {
+                problems.push(format!("tenants.{}.influx_bucket needs the influx section", name));
+            }
+        }
+        if tenant.workers == 0 {
+            problems.push(format!("tenants.{}.workers must be at least 1", name));
+        }
+    }
+    problems
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn invalid_networks_fail() {
+        let config = TenantConfig { name: "retail".to_string(), exporters: vec!["10.1.0.0/16".to_string()], ..TenantConfig::default() };
+        let resolver = TenantResolver::new(std::slice::from_ref(&config)).unwrap();
+        assert_eq!(resolver.input_topic("10.1.2.3".parse().unwrap(), 2055), "listener-to-enricher-retail");
+        assert_eq!(resolver.input_topic("::ffff:10.1.2.3".parse().unwrap(), 2055), "listener-to-enricher-retail");
+        assert_eq!(resolver.input_topic("10.2.0.1".parse().unwrap(), 2055), LISTENER_TO_ENRICHER);
+        assert!(Tenant::new(&config).is_ok());
+
+        let bad_exporter = TenantConfig { exporters: vec!["10.1.0.0/33".to_string()], ..config.clone() };
+        assert!(TenantResolver::new(&[bad_exporter]).unwrap_err().starts_with("tenants.retail: "));
+        let bad_home = TenantConfig { home_prefixes: vec!["198.51.100.0/24x".to_string()], ..config };
+        assert!(Tenant::new(&bad_home).unwrap_err().starts_with("tenants.retail: "));
+    }
+}
@@ -0,0 +1,56 @@
+use std::time::Instant;
+
+// `rate` tokens per second, up to `burst` saved up. Taking more than there is leaves
+// a debt that has to be paid back before anything is admitted again.
+#[derive(Debug)]
+pub struct TokenBucket {
+    rate: f64,
+    burst: f64,
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(rate: u64, burst: u64) -> Self {
+        TokenBucket {
+            rate: rate as f64,
+            burst: burst.max(1) as f64,
+            tokens: burst.max(1) as f64,
+            updated: Instant::now(),
+        }
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        self.tokens = (self.tokens + now.duration_since(self.updated).as_secs_f64() * self.rate).min(self.burst);
+        self.updated = now;
+    }
+
+    // Whether there is a token left, without taking it
+    pub fn available(&mut self) -> bool {
+        self.refill();
+        self.tokens >= 1.0
+    }
+
+    // Whether nothing was taken for as long as it takes to refill
+    pub fn full(&mut self) -> bool {
+        self.refill();
+        self.tokens >= self.burst
+    }
+
+    // Take `count` tokens when there are that many
+    pub fn try_take(&mut self, count: u64) -> bool {
+        self.refill();
+        if self.tokens < count as f64 {
+            return false;
+        }
+        self.tokens -= count as f64;
+        true
+    }
+
+    // Take `count` tokens, going into debt when there aren't enough
+    pub fn charge(&mut self, count: u64) {
+        self.refill();
+        self.tokens -= count as f64;
+    }
+}
@@ -0,0 +1,182 @@
+use std::any::Any;
+use std::collections::HashMap;
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::process::aggregate::Aggregator;
+use crate::process::flow::{Aggregate, EnrichedFlow};
+use crate::sketch::{CountMin, SpaceSaving};
+
+pub const TOPN_MEASUREMENT: &str = "topn";
+
+// What the top-N lists can be kept for
+pub const TOPN_DIMENSIONS: [&str; 8] = [
+    "src_ip",
+    "dst_ip",
+    "src_as",
+    "dst_as",
+    "src_country",
+    "dst_country",
+    "port",
+    "conversation",
+];
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct TopNConfig {
+    // Entries published per dimension and window
+    pub n: usize,
+    // Keys tracked per dimension, the lists are exact for keys above 1/capacity of the bytes
+    pub capacity: usize,
+    pub dimensions: Vec<String>,
+    // Size of the sketches estimating packets and flows of the top keys
+    pub count_min_width: usize,
+    pub count_min_depth: usize,
+}
+
+impl Default for TopNConfig {
+    fn default() -> Self {
+        TopNConfig {
+            n: 10,
+            capacity: 1000,
+            dimensions: TOPN_DIMENSIONS.iter().map(|d| d.to_string()).collect(),
+            count_min_width: 2048,
+            count_min_depth: 4,
+        }
+    }
+}
+
+struct Dimension {
+    name: String,
+    // Host names of tracked addresses, for the src_ip and dst_ip dimensions
+    hostnames: HashMap<String, String>,
+    bytes: SpaceSaving,
+    packets: CountMin,
+    flows: CountMin,
+}
+
+// Heaviest talkers by bytes per dimension. Published as one `topn` point per entry
+// with the dimension and key as tags.
+pub struct TopTalkers {
+    n: usize,
+    dimensions: Vec<Dimension>,
+}
+
+impl TopTalkers {
+    pub fn new(config: &TopNConfig) -> Self {
+        let dimensions = config
+            .dimensions
+            .iter()
+            .filter(|name| {
+                let known = TOPN_DIMENSIONS.contains(&name.as_str());
+                if !known {
+                    println!("Ignoring unknown top-N dimension {:?}", name);
+                }
+                known
+            })
+            .map(|name| Dimension {
+                name: name.clone(),
+                hostnames: HashMap::new(),
+                bytes: SpaceSaving::new(config.capacity),
+                packets: CountMin::new(config.count_min_width, config.count_min_depth),
+                flows: CountMin::new(config.count_min_width, config.count_min_depth),
+            })
+            .collect();
+        TopTalkers { n: config.n, dimensions }
+    }
+}
+
+impl Aggregator for TopTalkers {
+    fn observe(&mut self, flow: &EnrichedFlow) {
+        for dimension in self.dimensions.iter_mut() {
+            if let Some(key) = dimension_key(&dimension.name, flow) {
+                dimension.bytes.add(&key, flow.fields.bytes);
+                dimension.packets.add(&key, flow.fields.packets);
+                dimension.flows.add(&key, 1);
+                if let Some(hostname) = hostname_tag(&dimension.name, flow) {
+                    // Bounded like the tracked keys, names of evicted keys may linger for the window
+                    if dimension.hostnames.len() < 2 * dimension.bytes.len().max(1) || dimension.hostnames.contains_key(&key) {
+                        dimension.hostnames.insert(key, hostname.to_string());
+                    }
+                }
+            }
+        }
+    }
+
+    fn merge(&mut self, other: &mut dyn Aggregator) {
+        let Some(other) = other.as_any_mut().downcast_mut::<TopTalkers>() else {
+            return;
+        };
+        for (into, from) in self.dimensions.iter_mut().zip(other.dimensions.iter_mut()) {
+            into.bytes.merge(&from.bytes);
+            // Same config, same sketch sizes
+            let _ = into.packets.merge(&from.packets);
+            let _ = into.flows.merge(&from.flows);
+            into.hostnames.extend(from.hostnames.drain());
+            from.bytes.clear();
+            from.packets.clear();
+            from.flows.clear();
+        }
+    }
+
+    fn flush(&mut self, time: DateTime<Utc>) -> Vec<Aggregate> {
+        let mut aggregates = Vec::new();
+        for dimension in self.dimensions.iter_mut() {
+            for (rank, hitter) in dimension.bytes.top(self.n).into_iter().enumerate() {
+                let mut aggregate = Aggregate::new(TOPN_MEASUREMENT, time);
+                if let Some(hostname) = dimension.hostnames.get(&hitter.key) {
+                    aggregate = aggregate.tag("hostname", hostname.as_str());
+                }
+                aggregates.push(
+                    aggregate
+                        .tag("dimension", dimension.name.as_str())
+                        .tag("key", hitter.key.as_str())
+                        .field("rank", rank as u64 + 1)
+                        .field("bytes", hitter.count)
+                        .field("bytes_error", hitter.error)
+                        .field("packets", dimension.packets.estimate(&hitter.key))
+                        .field("flows", dimension.flows.estimate(&hitter.key)),
+                );
+            }
+            dimension.hostnames.clear();
+            dimension.bytes.clear();
+            dimension.packets.clear();
+            dimension.flows.clear();
+        }
+        aggregates
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+fn hostname_tag<'a>(dimension: &str, flow: &'a EnrichedFlow) -> Option<&'a str> {
+    match dimension {
+        "src_ip" => flow.tag("src_hostname"),
+        "dst_ip" => flow.tag("dst_hostname"),
+        _ => None,
+    }
+}
+
+fn dimension_key(dimension: &str, flow: &EnrichedFlow) -> Option<String> {
+    let tag = |name: &str| flow.tag(name).filter(|value| !value.is_empty()).map(|value| value.to_string());
+    match dimension {
+        "src_ip" | "dst_ip" | "src_country" | "dst_country" => tag(dimension),
+        // AS names are what the dashboards show, the number is the fallback
+        "src_as" => tag("src_as_name").or_else(|| tag("src_asn")),
+        "dst_as" => tag("dst_as_name").or_else(|| tag("dst_asn")),
+        "port" => match (tag("protocol_name"), tag("app_port")) {
+            (Some(protocol), Some(port)) if port != "0" => Some(format!("{}/{}", protocol, port)),
+            (Some(protocol), _) => Some(protocol),
+            _ => None,
+        },
+        // Both directions of a conversation count for the same pair
+        "conversation" => {
+            let (src, dst) = (tag("src_ip")?, tag("dst_ip")?);
+            Some(if src <= dst { format!("{} <-> {}", src, dst) } else { format!("{} <-> {}", dst, src) })
+        }
+        _ => None,
+    }
+}
@@ -0,0 +1,81 @@
+use serde::{Deserialize, Serialize};
+
+// Count-Min sketch: over-estimates a key's total by at most 2/width of the
+// stream total with probability 1 - 0.5^depth
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CountMin {
+    width: usize,
+    depth: usize,
+    table: Vec<u64>,
+}
+
+impl CountMin {
+    pub fn new(width: usize, depth: usize) -> Self {
+        let width = width.max(1);
+        let depth = depth.max(1);
+        CountMin {
+            width,
+            depth,
+            table: vec![0; width * depth],
+        }
+    }
+
+    fn cell(&self, row: usize, key: &str) -> usize {
+        // Every row hashes with its own seed
+        row * self.width + (super::hash(row as u64, key.as_bytes()) % self.width as u64) as usize
+    }
+
+    pub fn add(&mut self, key: &str, weight: u64) {
+        for row in 0..self.depth {
+            let cell = self.cell(row, key);
+            self.table[cell] = self.table[cell].saturating_add(weight);
+        }
+    }
+
+    pub fn estimate(&self, key: &str) -> u64 {
+        (0..self.depth).map(|row| self.table[self.cell(row, key)]).min().unwrap_or(0)
+    }
+
+    // Sketches of the same size from different enrichers add up cell by cell
+    pub fn merge(&mut self, other: &CountMin) -> Result<(), String> {
+        if self.width != other.width || self.depth != other.depth {
+            return Err(format!(
+                "can't merge a {}x{} count-min sketch into a {}x{} one",
+                other.depth, other.width, self.depth, self.width
+            ));
+        }
+        for (cell, value) in self.table.iter_mut().zip(&other.table) {
+            *cell = cell.saturating_add(*value);
+        }
+        Ok(())
+    }
+
+    pub fn clear(&mut self) {
+        self.table.iter_mut().for_each(|cell| *cell = 0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cells_are_stable() {
+        let sketch = CountMin::new(2048, 4);
+        let cells: Vec<usize> = (0..4).map(|row| sketch.cell(row, "192.0.2.1")).collect();
+        assert_eq!(cells, [1121, 2750, 4474, 7183]);
+    }
+
+    #[test]
+    fn estimates_never_undercount() {
+        let mut sketch = CountMin::new(64, 4);
+        for i in 0..1000u64 {
+            sketch.add(&format!("key-{}", i), i);
+        }
+        let mut other = CountMin::new(64, 4);
+        other.add("key-7", 100);
+        sketch.merge(&other).unwrap();
+        assert!(sketch.estimate("key-7") >= 107);
+        assert!(sketch.merge(&CountMin::new(32, 4)).is_err());
+    }
+}
@@ -0,0 +1,128 @@
+// HyperLogLog distinct counter with 2^precision one byte registers. The standard
+// error is 1.04/sqrt(2^precision), about 3% at precision 10 and 1.6% at 12.
+// Sketches with the same precision merge into the sketch of the union.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HyperLogLog {
+    precision: u8,
+    registers: Vec<u8>,
+}
+
+pub const MIN_PRECISION: u8 = 4;
+pub const MAX_PRECISION: u8 = 16;
+// Part of the format, registers only merge with sketches hashed the same way
+const SEED: u64 = 0;
+
+impl HyperLogLog {
+    pub fn new(precision: u8) -> Self {
+        let precision = precision.clamp(MIN_PRECISION, MAX_PRECISION);
+        HyperLogLog {
+            precision,
+            registers: vec![0; 1 << precision],
+        }
+    }
+
+    pub fn add(&mut self, value: impl AsRef<[u8]>) {
+        let hash = super::hash(SEED, value.as_ref());
+
+        let index = (hash >> (64 - self.precision)) as usize;
+        // The guard bit caps the rank when the remaining bits are all zero
+        let rest = (hash << self.precision) | (1 << (self.precision - 1));
+        let rank = rest.leading_zeros() as u8 + 1;
+        if rank > self.registers[index] {
+            self.registers[index] = rank;
+        }
+    }
+
+    pub fn estimate(&self) -> u64 {
+        let m = self.registers.len() as f64;
+        let alpha = match self.registers.len() {
+            16 => 0.673,
+            32 => 0.697,
+            64 => 0.709,
+            _ => 0.7213 / (1.0 + 1.079 / m),
+        };
+        let sum: f64 = self.registers.iter().map(|r| 2f64.powi(-(*r as i32))).sum();
+        let estimate = alpha * m * m / sum;
+
+        // Small cardinalities are better counted by the empty registers
+        let zeros = self.registers.iter().filter(|r| **r == 0).count();
+        if estimate <= 2.5 * m && zeros > 0 {
+            (m * (m / zeros as f64).ln()).round() as u64
+        } else {
+            estimate.round() as u64
+        }
+    }
+
+    pub fn merge(&mut self, other: &HyperLogLog) -> Result<(), String> {
+        if self.precision != other.precision {
+            return Err(format!(
+                "can't merge a precision {} HyperLogLog into a precision {} one",
+                other.precision, self.precision
+            ));
+        }
+        for (register, other) in self.registers.iter_mut().zip(&other.registers) {
+            *register = (*register).max(*other);
+        }
+        Ok(())
+    }
+
+    pub fn precision(&self) -> u8 {
+        self.precision
+    }
+
+    // Precision byte followed by the registers, hex encoded
+    pub fn to_hex(&self) -> String {
+        let mut hex = format!("{:02x}", self.precision);
+        for register in &self.registers {
+            hex.push_str(&format!("{:02x}", register));
+        }
+        hex
+    }
+
+    pub fn from_hex(hex: &str) -> Option<Self> {
+        if !hex.len().is_multiple_of(2) || !hex.is_ascii() {
+            return None;
+        }
+        let bytes: Vec<u8> = (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).ok())
+            .collect::<Option<_>>()?;
+        let (precision, registers) = bytes.split_first()?;
+        if !(MIN_PRECISION..=MAX_PRECISION).contains(precision) || registers.len() != 1 << precision {
+            return None;
+        }
+        Some(HyperLogLog {
+            precision: *precision,
+            registers: registers.to_vec(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn registers_are_stable() {
+        let mut hll = HyperLogLog::new(MIN_PRECISION);
+        for ip in ["192.0.2.1", "192.0.2.2", "198.51.100.7"] {
+            hll.add(ip);
+        }
+        assert_eq!(hll.to_hex(), "0400000000000000000002000201000000");
+        assert_eq!(HyperLogLog::from_hex(&hll.to_hex()), Some(hll));
+    }
+
+    #[test]
+    fn estimates_and_merges() {
+        let mut first = HyperLogLog::new(12);
+        let mut second = HyperLogLog::new(12);
+        for i in 0..20_000 {
+            first.add(format!("10.0.{}.{}", i / 256, i % 256));
+            second.add(format!("10.1.{}.{}", i / 256, i % 256));
+        }
+        first.merge(&second).unwrap();
+        let estimate = first.estimate() as f64;
+        assert!((estimate - 40_000.0).abs() < 40_000.0 * 0.05, "estimate {}", estimate);
+        assert!(first.merge(&HyperLogLog::new(10)).is_err());
+    }
+}
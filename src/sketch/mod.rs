@@ -0,0 +1,33 @@
+// Fixed-size summaries of the flow stream, so aggregates cost the same memory
+// whatever the number of distinct addresses, ports or ASNs.
+
+pub mod count_min;
+pub mod hyperloglog;
+pub mod space_saving;
+
+pub use count_min::CountMin;
+pub use hyperloglog::HyperLogLog;
+pub use space_saving::SpaceSaving;
+
+use std::hash::Hasher;
+
+use twox_hash::XxHash64;
+
+// Sketches of several enrichers are merged, and may be built by different releases,
+// so they hash with a fixed algorithm instead of std's DefaultHasher
+fn hash(seed: u64, bytes: &[u8]) -> u64 {
+    let mut hasher = XxHash64::with_seed(seed);
+    hasher.write(bytes);
+    hasher.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hash_is_xxhash64() {
+        assert_eq!(hash(0, b""), 0xef46_db37_51d8_e999);
+        assert_eq!(hash(0, b"abc"), 0x44bc_2cf5_ad77_0999);
+    }
+}
@@ -0,0 +1,139 @@
+use std::collections::{BTreeSet, HashMap};
+
+// Space-Saving heavy hitters: keeps `capacity` counters, a key that is not tracked
+// replaces the smallest one and inherits its count as error. Every key whose total
+// is above total/capacity is guaranteed to be tracked.
+#[derive(Debug, Clone)]
+pub struct SpaceSaving {
+    capacity: usize,
+    counters: HashMap<String, Counter>,
+    // (count, key), to find the smallest counter
+    order: BTreeSet<(u64, String)>,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct Counter {
+    count: u64,
+    error: u64,
+}
+
+// One of the heaviest keys; its true total is between count - error and count
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HeavyHitter {
+    pub key: String,
+    pub count: u64,
+    pub error: u64,
+}
+
+impl SpaceSaving {
+    pub fn new(capacity: usize) -> Self {
+        SpaceSaving {
+            capacity: capacity.max(1),
+            counters: HashMap::new(),
+            order: BTreeSet::new(),
+        }
+    }
+
+    pub fn add(&mut self, key: &str, weight: u64) {
+        if let Some(counter) = self.counters.get_mut(key) {
+            self.order.remove(&(counter.count, key.to_string()));
+            counter.count = counter.count.saturating_add(weight);
+            self.order.insert((counter.count, key.to_string()));
+            return;
+        }
+
+        let mut counter = Counter { count: weight, error: 0 };
+        if self.counters.len() >= self.capacity {
+            if let Some((min, victim)) = self.order.pop_first() {
+                self.counters.remove(&victim);
+                counter = Counter { count: min.saturating_add(weight), error: min };
+            }
+        }
+        self.counters.insert(key.to_string(), counter);
+        self.order.insert((counter.count, key.to_string()));
+    }
+
+    // Counts and errors of another summary add up key by key, its keys compete for
+    // the counters like new ones
+    pub fn merge(&mut self, other: &SpaceSaving) {
+        for (key, theirs) in &other.counters {
+            if let Some(counter) = self.counters.get_mut(key) {
+                self.order.remove(&(counter.count, key.clone()));
+                counter.count = counter.count.saturating_add(theirs.count);
+                counter.error = counter.error.saturating_add(theirs.error);
+                self.order.insert((counter.count, key.clone()));
+                continue;
+            }
+            let mut counter = *theirs;
+            if self.counters.len() >= self.capacity {
+                if let Some((min, victim)) = self.order.pop_first() {
+                    self.counters.remove(&victim);
+                    counter = Counter { count: min.saturating_add(theirs.count), error: min.saturating_add(theirs.error) };
+                }
+            }
+            self.counters.insert(key.clone(), counter);
+            self.order.insert((counter.count, key.clone()));
+        }
+    }
+
+    // The `n` heaviest keys, heaviest first
+    pub fn top(&self, n: usize) -> Vec<HeavyHitter> {
+        self.order
+            .iter()
+            .rev()
+            .take(n)
+            .map(|(count, key)| HeavyHitter {
+                key: key.clone(),
+                count: *count,
+                error: self.counters[key].error,
+            })
+            .collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.counters.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.counters.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.counters.clear();
+        self.order.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_the_heaviest_keys() {
+        let mut summary = SpaceSaving::new(2);
+        summary.add("a", 10);
+        summary.add("b", 5);
+        summary.add("c", 1);
+        // c took the place of b and inherited its count as error
+        assert_eq!(summary.top(2), [
+            HeavyHitter { key: "a".to_string(), count: 10, error: 0 },
+            HeavyHitter { key: "c".to_string(), count: 6, error: 5 },
+        ]);
+    }
+
+    #[test]
+    fn counts_saturate() {
+        let mut summary = SpaceSaving::new(1);
+        summary.add("a", u64::MAX - 1);
+        summary.add("a", 10);
+        summary.add("b", 10);
+        assert_eq!(summary.top(1), [HeavyHitter { key: "b".to_string(), count: u64::MAX, error: u64::MAX }]);
+
+        let mut other = SpaceSaving::new(1);
+        other.add("b", u64::MAX);
+        summary.merge(&other);
+        other.add("c", 1);
+        summary.merge(&other);
+        assert_eq!(summary.top(1)[0].count, u64::MAX);
+    }
+}
@@ -0,0 +1,208 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex as StdMutex, Weak};
+
+use async_trait::async_trait;
+use tokio::sync::mpsc::{self, Receiver, Sender};
+use tokio::sync::Mutex;
+
+use super::{Record, Subscription, Transport, TransportError};
+
+// In-process transport for running every stage inside a single binary.
+// Each (topic, group) pair gets a bounded queue, so a slow stage applies
+// backpressure instead of growing memory without limit. A group lives as long as
+// one of its subscriptions, so a stage that went away doesn't stall the producer.
+pub struct ChannelTransport {
+    capacity: usize,
+    topics: StdMutex<HashMap<String, Topic>>,
+}
+
+struct Topic {
+    groups: HashMap<String, Group>,
+    // Records sent before anyone subscribed, handed to the first group
+    backlog: VecDeque<Record>,
+    // Records pushed out of the full backlog
+    dropped: u64,
+    next_offset: i64,
+}
+
+impl Topic {
+    fn new() -> Self {
+        Topic {
+            groups: HashMap::new(),
+            backlog: VecDeque::new(),
+            dropped: 0,
+            next_offset: 0,
+        }
+    }
+}
+
+// The subscriptions own the receiver, once they are all dropped the queue closes
+struct Group {
+    sender: Sender<Record>,
+    receiver: Weak<Mutex<Receiver<Record>>>,
+}
+
+impl ChannelTransport {
+    pub fn new(capacity: usize) -> Self {
+        ChannelTransport {
+            capacity,
+            topics: StdMutex::new(HashMap::new()),
+        }
+    }
+}
+
+impl Default for ChannelTransport {
+    fn default() -> Self {
+        ChannelTransport::new(10_000)
+    }
+}
+
+#[async_trait]
+impl Transport for ChannelTransport {
+    async fn send(&self, topic: &str, key: Option<&[u8]>, payload: &[u8]) -> Result<(), TransportError> {
+        let (record, senders) = {
+            let mut topics = self.topics.lock().unwrap();
+            let entry = topics.entry(topic.to_string()).or_insert_with(Topic::new);
+            let record = Record {
+                topic: topic.to_string(),
+                key: key.map(|k| k.to_vec()),
+                payload: payload.to_vec(),
+                partition: 0,
+                offset: entry.next_offset,
+            };
+            entry.next_offset += 1;
+
+            entry.groups.retain(|_, group| group.receiver.strong_count() > 0);
+            if entry.groups.is_empty() {
+                if entry.backlog.len() >= self.capacity {
+                    entry.backlog.pop_front();
+                    if entry.dropped == 0 {
+                        println!("Topic {} has no subscriber and its backlog is full, dropping the oldest records", topic);
+                    }
+                    entry.dropped += 1;
+                }
+                entry.backlog.push_back(record);
+                return Ok(());
+            }
+            let senders: Vec<Sender<Record>> = entry.groups.values().map(|g| g.sender.clone()).collect();
+            (record, senders)
+        };
+
+        // A send fails when the group's last subscription went away meanwhile,
+        // the next send forgets the group
+        for sender in senders {
+            let _ = sender.send(record.clone()).await;
+        }
+        Ok(())
+    }
+
+    async fn subscribe(&self, topic: &str, group: &str) -> Result<Box<dyn Subscription>, TransportError> {
+        let (sender, receiver, backlog) = {
+            let mut topics = self.topics.lock().unwrap();
+            let entry = topics.entry(topic.to_string()).or_insert_with(Topic::new);
+            let existing = entry.groups.get(group).and_then(|g| Some((g.sender.clone(), g.receiver.upgrade()?)));
+            match existing {
+                Some((sender, receiver)) => (sender, receiver, VecDeque::new()),
+                None => {
+                    let (sender, receiver) = mpsc::channel(self.capacity);
+                    let receiver = Arc::new(Mutex::new(receiver));
+                    entry.groups.insert(group.to_string(), Group { sender: sender.clone(), receiver: Arc::downgrade(&receiver) });
+                    if entry.dropped > 0 {
+                        println!("Dropped {} records sent to {} before it had a subscriber", entry.dropped, topic);
+                        entry.dropped = 0;
+                    }
+                    (sender, receiver, std::mem::take(&mut entry.backlog))
+                }
+            }
+        };
+
+        let subscription = ChannelSubscription { receiver };
+        for record in backlog {
+            sender.send(record).await.map_err(|_| TransportError::Closed)?;
+        }
+        Ok(Box::new(subscription))
+    }
+}
+
+pub struct ChannelSubscription {
+    receiver: Arc<Mutex<Receiver<Record>>>,
+}
+
+#[async_trait]
+impl Subscription for ChannelSubscription {
+    async fn recv(&mut self) -> Result<Record, TransportError> {
+        self.receiver.lock().await.recv().await.ok_or(TransportError::Closed)
+    }
+
+    async fn commit(&mut self, _record: &Record) -> Result<(), TransportError> {
+        // Nothing survives a restart, so there is nothing to commit
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    async fn recv(subscription: &mut Box<dyn Subscription>) -> Record {
+        tokio::time::timeout(Duration::from_secs(1), subscription.recv()).await.expect("no record").unwrap()
+    }
+
+    #[tokio::test]
+    async fn backlog_goes_to_the_first_group() {
+        let transport = ChannelTransport::new(2);
+        for payload in [b"a", b"b", b"c"] {
+            transport.send("t", None, payload).await.unwrap();
+        }
+        let mut subscription = transport.subscribe("t", "g").await.unwrap();
+        // The oldest record didn't fit the backlog
+        assert_eq!(recv(&mut subscription).await.payload, b"b");
+        let record = recv(&mut subscription).await;
+        assert_eq!((record.payload.as_slice(), record.offset), (b"c".as_slice(), 2));
+    }
+
+    #[tokio::test]
+    async fn every_group_sees_every_record() {
+        let transport = ChannelTransport::new(10);
+        let mut first = transport.subscribe("t", "one").await.unwrap();
+        let mut second = transport.subscribe("t", "two").await.unwrap();
+        transport.send("t", Some(b"key"), b"x").await.unwrap();
+        for subscription in [&mut first, &mut second] {
+            let record = recv(subscription).await;
+            assert_eq!(record.key_str(), Some("key"));
+            assert_eq!(record.payload, b"x");
+        }
+    }
+
+    #[tokio::test]
+    async fn a_group_splits_the_records() {
+        let transport = ChannelTransport::new(10);
+        let mut first = transport.subscribe("t", "g").await.unwrap();
+        let mut second = transport.subscribe("t", "g").await.unwrap();
+        transport.send("t", None, b"x").await.unwrap();
+        transport.send("t", None, b"y").await.unwrap();
+        let mut offsets = vec![recv(&mut first).await.offset, recv(&mut second).await.offset];
+        offsets.sort();
+        assert_eq!(offsets, [0, 1]);
+    }
+
+    #[tokio::test]
+    async fn dropped_subscription_does_not_block_send() {
+        let transport = ChannelTransport::new(1);
+        let subscription = transport.subscribe("t", "g").await.unwrap();
+        drop(subscription);
+        let sends = async {
+            for _ in 0..3 {
+                transport.send("t", None, b"x").await.unwrap();
+            }
+        };
+        tokio::time::timeout(Duration::from_secs(1), sends).await.expect("send blocked");
+
+        // Without a subscriber the records went to the backlog, a new subscription
+        // of the group gets what fit
+        let mut subscription = transport.subscribe("t", "g").await.unwrap();
+        assert_eq!(recv(&mut subscription).await.offset, 2);
+    }
+}
@@ -0,0 +1,561 @@
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex as StdMutex};
+use std::time::Duration;
+
+use async_trait::async_trait;
+
+use super::{Record, Subscription, Transport, TransportError};
+
+// Local segment log: every topic is a directory of append-only segment files
+// named after the offset of their first record, e.g.
+//
+//   <dir>/listener-to-enricher/00000000000000000000.log
+//   <dir>/listener-to-enricher/00000000000000052113.log
+//   <dir>/listener-to-enricher/test-group.offset
+//
+// A record is stored as [key len][key][payload len][payload] with big-endian u32
+// lengths, a key length of u32::MAX means "no key". Consumer groups keep the next
+// offset to read in `<group>.offset`. One process writes a topic at a time, it holds
+// an exclusive lock on `<topic>/writer.lock` for as long as it runs.
+//
+// The segment files are plain std::fs, every access runs on the blocking pool so a
+// slow disk doesn't hold up the async workers.
+const NO_KEY: u32 = u32::MAX;
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+type Entry = (Option<Vec<u8>>, Vec<u8>);
+type ReaderMap = HashMap<(String, String), Arc<StdMutex<SegmentReader>>>;
+
+pub struct FileTransport {
+    log: Arc<SegmentLog>,
+}
+
+struct SegmentLog {
+    dir: PathBuf,
+    segment_bytes: u64,
+    retain_segments: usize,
+    writers: StdMutex<HashMap<String, Arc<StdMutex<SegmentWriter>>>>,
+    readers: StdMutex<ReaderMap>,
+}
+
+impl FileTransport {
+    pub fn new(dir: &str, segment_bytes: u64, retain_segments: usize) -> io::Result<Self> {
+        fs::create_dir_all(dir)?;
+        let log = SegmentLog {
+            dir: PathBuf::from(dir),
+            segment_bytes,
+            retain_segments: retain_segments.max(1),
+            writers: StdMutex::new(HashMap::new()),
+            readers: StdMutex::new(HashMap::new()),
+        };
+        Ok(FileTransport { log: Arc::new(log) })
+    }
+}
+
+async fn blocking<T, F>(work: F) -> Result<T, TransportError>
+where
+    T: Send + 'static,
+    F: FnOnce() -> io::Result<T> + Send + 'static,
+{
+    let result = tokio::task::spawn_blocking(work).await.map_err(io::Error::other)?;
+    Ok(result?)
+}
+
+impl SegmentLog {
+    fn writer(&self, topic: &str) -> io::Result<Arc<StdMutex<SegmentWriter>>> {
+        let mut writers = self.writers.lock().unwrap();
+        if let Some(writer) = writers.get(topic) {
+            return Ok(writer.clone());
+        }
+        let writer = SegmentWriter::open(&self.dir.join(topic), self.segment_bytes, self.retain_segments)?;
+        let writer = Arc::new(StdMutex::new(writer));
+        writers.insert(topic.to_string(), writer.clone());
+        Ok(writer)
+    }
+
+    fn reader(&self, topic: &str, group: &str) -> io::Result<Arc<StdMutex<SegmentReader>>> {
+        let mut readers = self.readers.lock().unwrap();
+        let id = (topic.to_string(), group.to_string());
+        if let Some(reader) = readers.get(&id) {
+            return Ok(reader.clone());
+        }
+        let reader = SegmentReader::open(&self.dir.join(topic), topic, group)?;
+        let reader = Arc::new(StdMutex::new(reader));
+        readers.insert(id, reader.clone());
+        Ok(reader)
+    }
+}
+
+#[async_trait]
+impl Transport for FileTransport {
+    async fn send(&self, topic: &str, key: Option<&[u8]>, payload: &[u8]) -> Result<(), TransportError> {
+        let log = self.log.clone();
+        let topic = topic.to_string();
+        let key = key.map(<[u8]>::to_vec);
+        let payload = payload.to_vec();
+        blocking(move || {
+            let writer = log.writer(&topic)?;
+            let mut writer = writer.lock().unwrap();
+            writer.append(key.as_deref(), &payload)
+        })
+        .await?;
+        Ok(())
+    }
+
+    async fn subscribe(&self, topic: &str, group: &str) -> Result<Box<dyn Subscription>, TransportError> {
+        let log = self.log.clone();
+        let topic = topic.to_string();
+        let group = group.to_string();
+        let reader = blocking(move || log.reader(&topic, &group)).await?;
+        Ok(Box::new(FileSubscription { reader }))
+    }
+}
+
+pub struct FileSubscription {
+    reader: Arc<StdMutex<SegmentReader>>,
+}
+
+#[async_trait]
+impl Subscription for FileSubscription {
+    async fn recv(&mut self) -> Result<Record, TransportError> {
+        loop {
+            let reader = self.reader.clone();
+            if let Some(record) = blocking(move || reader.lock().unwrap().next_record()).await? {
+                return Ok(record);
+            }
+            tokio::time::sleep(POLL_INTERVAL).await;
+        }
+    }
+
+    async fn commit(&mut self, record: &Record) -> Result<(), TransportError> {
+        let reader = self.reader.clone();
+        let offset = record.offset + 1;
+        blocking(move || reader.lock().unwrap().commit(offset)).await
+    }
+}
+
+fn segment_path(dir: &Path, base_offset: i64) -> PathBuf {
+    dir.join(format!("{:020}.log", base_offset))
+}
+
+// Base offsets of the segments in a topic directory, oldest first
+fn list_segments(dir: &Path) -> io::Result<Vec<i64>> {
+    let mut segments = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let name = entry?.file_name();
+        let name = name.to_string_lossy();
+        if let Some(base) = name.strip_suffix(".log").and_then(|b| b.parse::<i64>().ok()) {
+            segments.push(base);
+        }
+    }
+    segments.sort();
+    Ok(segments)
+}
+
+// Reads one record, returns None if the file ends before a full record is available
+fn read_record<R: Read>(reader: &mut R) -> io::Result<Option<Entry>> {
+    let mut len = [0u8; 4];
+    if !read_full(reader, &mut len)? {
+        return Ok(None);
+    }
+    let key_len = u32::from_be_bytes(len);
+    let key = if key_len == NO_KEY {
+        None
+    } else {
+        let mut key = vec![0u8; key_len as usize];
+        if !read_full(reader, &mut key)? {
+            return Ok(None);
+        }
+        Some(key)
+    };
+
+    if !read_full(reader, &mut len)? {
+        return Ok(None);
+    }
+    let mut payload = vec![0u8; u32::from_be_bytes(len) as usize];
+    if !read_full(reader, &mut payload)? {
+        return Ok(None);
+    }
+    Ok(Some((key, payload)))
+}
+
+fn read_full<R: Read>(reader: &mut R, buf: &mut [u8]) -> io::Result<bool> {
+    match reader.read_exact(buf) {
+        Ok(()) => Ok(true),
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+struct SegmentWriter {
+    dir: PathBuf,
+    segment_bytes: u64,
+    retain_segments: usize,
+    file: File,
+    size: u64,
+    next_offset: i64,
+    // Locked for as long as the writer lives
+    _lock: File,
+}
+
+impl SegmentWriter {
+    fn open(dir: &Path, segment_bytes: u64, retain_segments: usize) -> io::Result<Self> {
+        fs::create_dir_all(dir)?;
+        // Another writer would append to the segment we are about to truncate
+        let lock = OpenOptions::new().create(true).truncate(false).write(true).open(dir.join("writer.lock"))?;
+        if let Err(e) = lock.try_lock() {
+            return Err(match e {
+                fs::TryLockError::WouldBlock => io::Error::new(
+                    io::ErrorKind::WouldBlock,
+                    format!("{} is written by another process", dir.display()),
+                ),
+                fs::TryLockError::Error(e) => e,
+            });
+        }
+
+        let base = list_segments(dir)?.last().copied().unwrap_or(0);
+        let path = segment_path(dir, base);
+
+        // Count the records already in the last segment to find the next offset
+        let mut next_offset = base;
+        let mut valid_len = 0;
+        if path.exists() {
+            let mut reader = BufReader::new(File::open(&path)?);
+            while read_record(&mut reader)?.is_some() {
+                next_offset += 1;
+                valid_len = reader.stream_position()?;
+            }
+        }
+
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        // Drop a half written record left behind by a crash
+        file.set_len(valid_len)?;
+
+        Ok(SegmentWriter {
+            dir: dir.to_path_buf(),
+            segment_bytes,
+            retain_segments,
+            file,
+            size: valid_len,
+            next_offset,
+            _lock: lock,
+        })
+    }
+
+    fn append(&mut self, key: Option<&[u8]>, payload: &[u8]) -> io::Result<i64> {
+        let mut buf = Vec::with_capacity(8 + key.map_or(0, |k| k.len()) + payload.len());
+        match key {
+            Some(key) => {
+                buf.extend_from_slice(&(key.len() as u32).to_be_bytes());
+                buf.extend_from_slice(key);
+            }
+            None => buf.extend_from_slice(&NO_KEY.to_be_bytes()),
+        }
+        buf.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+        buf.extend_from_slice(payload);
+
+        if self.size > 0 && self.size + buf.len() as u64 > self.segment_bytes {
+            self.roll()?;
+        }
+
+        self.file.write_all(&buf)?;
+        self.size += buf.len() as u64;
+        let offset = self.next_offset;
+        self.next_offset += 1;
+        Ok(offset)
+    }
+
+    fn roll(&mut self) -> io::Result<()> {
+        self.file.sync_data()?;
+        self.file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(segment_path(&self.dir, self.next_offset))?;
+        self.size = 0;
+
+        let segments = list_segments(&self.dir)?;
+        if segments.len() > self.retain_segments {
+            for base in &segments[..segments.len() - self.retain_segments] {
+                fs::remove_file(segment_path(&self.dir, *base))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+struct SegmentReader {
+    dir: PathBuf,
+    topic: String,
+    group: String,
+    segment: Option<(i64, BufReader<File>)>,
+    next_offset: i64,
+}
+
+impl SegmentReader {
+    fn open(dir: &Path, topic: &str, group: &str) -> io::Result<Self> {
+        fs::create_dir_all(dir)?;
+        let next_offset = match fs::read_to_string(dir.join(format!("{}.offset", group))) {
+            Ok(s) => s.trim().parse().unwrap_or(0),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => 0,
+            Err(e) => return Err(e),
+        };
+        Ok(SegmentReader {
+            dir: dir.to_path_buf(),
+            topic: topic.to_string(),
+            group: group.to_string(),
+            segment: None,
+            next_offset,
+        })
+    }
+
+    // Opens the segment holding `next_offset` and skips to it
+    fn seek(&mut self) -> io::Result<bool> {
+        let segments = list_segments(&self.dir)?;
+        let base = match segments.iter().rev().find(|b| **b <= self.next_offset) {
+            Some(base) => *base,
+            None => match segments.first() {
+                // Older segments were deleted by retention, continue from the oldest one
+                Some(first) => {
+                    self.next_offset = *first;
+                    *first
+                }
+                None => return Ok(false),
+            },
+        };
+
+        let mut reader = BufReader::new(File::open(segment_path(&self.dir, base))?);
+        let mut offset = base;
+        while offset < self.next_offset {
+            let position = reader.stream_position()?;
+            if read_record(&mut reader)?.is_none() {
+                reader.seek(SeekFrom::Start(position))?;
+                break;
+            }
+            offset += 1;
+        }
+        self.next_offset = offset;
+        self.segment = Some((base, reader));
+        Ok(true)
+    }
+
+    fn next_record(&mut self) -> io::Result<Option<Record>> {
+        if self.segment.is_none() && !self.seek()? {
+            return Ok(None);
+        }
+        let (base, reader) = self.segment.as_mut().unwrap();
+        let base = *base;
+        let position = reader.stream_position()?;
+        match read_record(reader)? {
+            Some((key, payload)) => {
+                let record = Record {
+                    topic: self.topic.clone(),
+                    key,
+                    payload,
+                    partition: 0,
+                    offset: self.next_offset,
+                };
+                self.next_offset += 1;
+                Ok(Some(record))
+            }
+            None => {
+                reader.seek(SeekFrom::Start(position))?;
+                // The writer may have rolled over to a new segment
+                if base != self.next_offset && list_segments(&self.dir)?.contains(&self.next_offset) {
+                    self.segment = None;
+                    return self.next_record();
+                }
+                Ok(None)
+            }
+        }
+    }
+
+    fn commit(&self, offset: i64) -> io::Result<()> {
+        let path = self.dir.join(format!("{}.offset", self.group));
+        let tmp = self.dir.join(format!("{}.offset.tmp", self.group));
+        fs::write(&tmp, offset.to_string())?;
+        fs::rename(tmp, path)
+    }
+}
+
+// Reads a topic of a segment log from `from_offset` up to where it ended when the
+// scanner was opened, without touching any consumer group offsets.
+pub struct LogScanner {
+    dir: PathBuf,
+    topic: String,
+    // Segments to read with their length at the time the scan started
+    segments: Vec<(i64, u64)>,
+    current: Option<io::Take<BufReader<File>>>,
+    from_offset: i64,
+    next_offset: i64,
+}
+
+impl LogScanner {
+    pub fn open(log_dir: &str, topic: &str, from_offset: i64) -> io::Result<Self> {
+        let dir = Path::new(log_dir).join(topic);
+        let mut segments = Vec::new();
+        for base in list_segments(&dir)? {
+            segments.push((base, fs::metadata(segment_path(&dir, base))?.len()));
+        }
+        // Skip segments that end before the requested offset
+        while segments.len() > 1 && segments[1].0 <= from_offset {
+            segments.remove(0);
+        }
+        Ok(LogScanner {
+            dir,
+            topic: topic.to_string(),
+            segments,
+            current: None,
+            from_offset,
+            next_offset: 0,
+        })
+    }
+}
+
+impl Iterator for LogScanner {
+    type Item = io::Result<Record>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if self.current.is_none() {
+                if self.segments.is_empty() {
+                    return None;
+                }
+                let (base, len) = self.segments.remove(0);
+                match File::open(segment_path(&self.dir, base)) {
+                    Ok(file) => self.current = Some(BufReader::new(file).take(len)),
+                    Err(e) => return Some(Err(e)),
+                }
+                self.next_offset = base;
+            }
+
+            match read_record(self.current.as_mut().unwrap()) {
+                Ok(Some((key, payload))) => {
+                    let offset = self.next_offset;
+                    self.next_offset += 1;
+                    if offset < self.from_offset {
+                        continue;
+                    }
+                    return Some(Ok(Record {
+                        topic: self.topic.clone(),
+                        key,
+                        payload,
+                        partition: 0,
+                        offset,
+                    }));
+                }
+                Ok(None) => self.current = None,
+                Err(e) => return Some(Err(e)),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    use super::*;
+
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> Self {
+            let nanos = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_nanos();
+            let dir = std::env::temp_dir().join(format!("file-transport-{}-{}-{}", name, std::process::id(), nanos));
+            fs::create_dir_all(&dir).unwrap();
+            TempDir(dir)
+        }
+
+        fn path(&self) -> &str {
+            self.0.to_str().unwrap()
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    async fn recv(subscription: &mut Box<dyn Subscription>) -> Record {
+        tokio::time::timeout(Duration::from_secs(2), subscription.recv()).await.expect("no record").unwrap()
+    }
+
+    #[tokio::test]
+    async fn resumes_after_the_committed_offset() {
+        let dir = TempDir::new("resume");
+        {
+            let transport = FileTransport::new(dir.path(), 1 << 20, 2).unwrap();
+            for payload in [b"a", b"b", b"c"] {
+                transport.send("t", Some(b"key"), payload).await.unwrap();
+            }
+            let mut subscription = transport.subscribe("t", "g").await.unwrap();
+            let first = recv(&mut subscription).await;
+            assert_eq!((first.key_str(), first.payload.as_slice(), first.offset), (Some("key"), b"a".as_slice(), 0));
+            let second = recv(&mut subscription).await;
+            subscription.commit(&second).await.unwrap();
+        }
+
+        let transport = FileTransport::new(dir.path(), 1 << 20, 2).unwrap();
+        transport.send("t", None, b"d").await.unwrap();
+        let mut subscription = transport.subscribe("t", "g").await.unwrap();
+        let third = recv(&mut subscription).await;
+        assert_eq!((third.payload.as_slice(), third.offset), (b"c".as_slice(), 2));
+        let fourth = recv(&mut subscription).await;
+        assert_eq!((fourth.key, fourth.payload.as_slice(), fourth.offset), (None, b"d".as_slice(), 3));
+    }
+
+    #[tokio::test]
+    async fn groups_read_independently_across_segments() {
+        let dir = TempDir::new("groups");
+        // Every record gets a segment of its own
+        let transport = FileTransport::new(dir.path(), 1, 10).unwrap();
+        let mut first = transport.subscribe("t", "one").await.unwrap();
+        for payload in [b"a", b"b", b"c"] {
+            transport.send("t", None, payload).await.unwrap();
+        }
+        assert_eq!(list_segments(&dir.0.join("t")).unwrap(), [0, 1, 2]);
+        let mut second = transport.subscribe("t", "two").await.unwrap();
+        for subscription in [&mut first, &mut second] {
+            for offset in 0..3 {
+                assert_eq!(recv(subscription).await.offset, offset);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn drops_a_half_written_record() {
+        let dir = TempDir::new("torn");
+        {
+            let transport = FileTransport::new(dir.path(), 1 << 20, 2).unwrap();
+            transport.send("t", None, b"a").await.unwrap();
+        }
+        let segment = segment_path(&dir.0.join("t"), 0);
+        let mut file = OpenOptions::new().append(true).open(&segment).unwrap();
+        file.write_all(&NO_KEY.to_be_bytes()).unwrap();
+        file.write_all(&100u32.to_be_bytes()).unwrap();
+
+        let transport = FileTransport::new(dir.path(), 1 << 20, 2).unwrap();
+        transport.send("t", None, b"b").await.unwrap();
+        let mut subscription = transport.subscribe("t", "g").await.unwrap();
+        recv(&mut subscription).await;
+        let record = recv(&mut subscription).await;
+        assert_eq!((record.payload.as_slice(), record.offset), (b"b".as_slice(), 1));
+    }
+
+    #[tokio::test]
+    async fn one_writer_per_topic() {
+        let dir = TempDir::new("lock");
+        let first = FileTransport::new(dir.path(), 1 << 20, 2).unwrap();
+        first.send("t", None, b"a").await.unwrap();
+        let second = FileTransport::new(dir.path(), 1 << 20, 2).unwrap();
+        assert!(second.send("t", None, b"b").await.is_err());
+        second.send("other", None, b"b").await.unwrap();
+
+        drop(first);
+        second.send("t", None, b"b").await.unwrap();
+    }
+}
@@ -0,0 +1,71 @@
+use std::time::Duration;
+
+use async_trait::async_trait;
+use rdkafka::consumer::{CommitMode, Consumer, StreamConsumer};
+use rdkafka::producer::{FutureProducer, FutureRecord};
+use rdkafka::util::Timeout;
+use rdkafka::{Message, Offset, TopicPartitionList};
+
+use super::{Record, Subscription, Transport, TransportError};
+use crate::kafka::{consumer, producer};
+
+pub struct KafkaTransport {
+    brokers: String,
+    producer: FutureProducer,
+}
+
+impl KafkaTransport {
+    pub fn new(brokers: &str) -> Self {
+        KafkaTransport {
+            brokers: brokers.to_string(),
+            producer: producer::create(brokers),
+        }
+    }
+}
+
+#[async_trait]
+impl Transport for KafkaTransport {
+    async fn send(&self, topic: &str, key: Option<&[u8]>, payload: &[u8]) -> Result<(), TransportError> {
+        let mut record = FutureRecord::to(topic).payload(payload);
+        if let Some(key) = key {
+            record = record.key(key);
+        }
+
+        self.producer
+            .send(record, Timeout::After(Duration::from_secs(2)))
+            .await
+            .map(|_| ())
+            .map_err(|(e, _)| TransportError::Kafka(e))
+    }
+
+    async fn subscribe(&self, topic: &str, group: &str) -> Result<Box<dyn Subscription>, TransportError> {
+        let consumer = consumer::create(&self.brokers, group);
+        consumer.subscribe(&[topic])?;
+        Ok(Box::new(KafkaSubscription { consumer }))
+    }
+}
+
+pub struct KafkaSubscription {
+    consumer: StreamConsumer,
+}
+
+#[async_trait]
+impl Subscription for KafkaSubscription {
+    async fn recv(&mut self) -> Result<Record, TransportError> {
+        let message = self.consumer.recv().await?;
+        Ok(Record {
+            topic: message.topic().to_string(),
+            key: message.key().map(|k| k.to_vec()),
+            payload: message.payload().map(|p| p.to_vec()).unwrap_or_default(),
+            partition: message.partition(),
+            offset: message.offset(),
+        })
+    }
+
+    async fn commit(&mut self, record: &Record) -> Result<(), TransportError> {
+        let mut tpl = TopicPartitionList::new();
+        tpl.add_partition_offset(&record.topic, record.partition, Offset::Offset(record.offset + 1))?;
+        self.consumer.commit(&tpl, CommitMode::Async)?;
+        Ok(())
+    }
+}
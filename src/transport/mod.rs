@@ -0,0 +1,160 @@
+// Transports move records between the pipeline stages (listener -> enricher -> tsdb).
+// Kafka is what the docker-compose setup uses, the channel transport keeps everything
+// inside one process and the file transport writes an append-only segment log to disk.
+
+pub mod channel;
+pub mod file;
+pub mod kafka;
+pub mod window;
+
+use std::fmt;
+use std::io;
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use clap::ValueEnum;
+use rdkafka::error::KafkaError;
+use serde::{Deserialize, Serialize};
+
+pub use channel::ChannelTransport;
+pub use file::{FileTransport, LogScanner};
+pub use kafka::KafkaTransport;
+pub use window::{consume_windows, Done, Windowed};
+
+// Raw NetFlow/IPFIX datagrams, keyed by exporter address
+pub const LISTENER_TO_ENRICHER: &str = "listener-to-enricher";
+// Enriched flows in the JSON format telegraf reads
+pub const ENRICHER_TO_TSDB: &str = "enricher-to-tsdb";
+// Per window summaries (top-N, ...) for the tsdb, one measurement each
+pub const ENRICHER_AGGREGATES: &str = "enricher-aggregates";
+// Attack start/update/end events of the detector, keyed by the attacked prefix
+pub const ALERTS: &str = "alerts";
+// Enriched flows with a blocklisted source or destination, same format as enricher-to-tsdb
+pub const THREAT_MATCHES: &str = "threat-matches";
+// Traffic that left its learned baseline, keyed by "<dimension>:<value>"
+pub const ANOMALIES: &str = "anomalies";
+// Both directions of a session stitched together, keyed by the client address
+pub const CONVERSATIONS: &str = "conversations";
+// Exporters that went silent or came back, keyed by the exporter address
+pub const EXPORTER_EVENTS: &str = "exporter-events";
+
+#[derive(Debug, Clone)]
+pub struct Record {
+    pub topic: String,
+    pub key: Option<Vec<u8>>,
+    pub payload: Vec<u8>,
+    // Position of the record in its partition, used when committing
+    pub partition: i32,
+    pub offset: i64,
+}
+
+impl Record {
+    pub fn key_str(&self) -> Option<&str> {
+        self.key.as_deref().and_then(|k| std::str::from_utf8(k).ok())
+    }
+}
+
+#[derive(Debug)]
+pub enum TransportError {
+    Kafka(KafkaError),
+    Io(io::Error),
+    Closed,
+}
+
+impl fmt::Display for TransportError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TransportError::Kafka(e) => write!(f, "kafka error: {}", e),
+            TransportError::Io(e) => write!(f, "io error: {}", e),
+            TransportError::Closed => write!(f, "transport closed"),
+        }
+    }
+}
+
+impl std::error::Error for TransportError {}
+
+impl From<KafkaError> for TransportError {
+    fn from(e: KafkaError) -> Self {
+        TransportError::Kafka(e)
+    }
+}
+
+impl From<io::Error> for TransportError {
+    fn from(e: io::Error) -> Self {
+        TransportError::Io(e)
+    }
+}
+
+#[async_trait]
+pub trait Transport: Send + Sync {
+    async fn send(&self, topic: &str, key: Option<&[u8]>, payload: &[u8]) -> Result<(), TransportError>;
+
+    // Subscriptions sharing a group split the records of a topic between them,
+    // every group sees every record (same semantics as Kafka consumer groups).
+    async fn subscribe(&self, topic: &str, group: &str) -> Result<Box<dyn Subscription>, TransportError>;
+}
+
+#[async_trait]
+pub trait Subscription: Send {
+    async fn recv(&mut self) -> Result<Record, TransportError>;
+
+    // Marks the record and everything before it as processed
+    async fn commit(&mut self, record: &Record) -> Result<(), TransportError>;
+}
+
+pub type SharedTransport = Arc<dyn Transport>;
+
+#[derive(ValueEnum, Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum TransportKind {
+    Kafka,
+    Channel,
+    File,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct TransportConfig {
+    pub kind: TransportKind,
+    // Kafka bootstrap servers
+    pub brokers: String,
+    // Segment log directory, size at which a new segment starts and how many are kept
+    pub log_dir: String,
+    pub segment_bytes: u64,
+    pub retain_segments: usize,
+    // Queue size per topic of the channel transport
+    pub channel_capacity: usize,
+}
+
+impl Default for TransportConfig {
+    fn default() -> Self {
+        TransportConfig {
+            kind: TransportKind::Kafka,
+            brokers: "localhost:9092".to_string(),
+            log_dir: "data/log".to_string(),
+            segment_bytes: 64 * 1024 * 1024,
+            retain_segments: 16,
+            channel_capacity: 10_000,
+        }
+    }
+}
+
+pub fn build(config: &TransportConfig) -> io::Result<SharedTransport> {
+    let transport: SharedTransport = match config.kind {
+        TransportKind::Kafka => Arc::new(KafkaTransport::new(&config.brokers)),
+        TransportKind::Channel => Arc::new(ChannelTransport::new(config.channel_capacity)),
+        TransportKind::File => Arc::new(FileTransport::new(&config.log_dir, config.segment_bytes, config.retain_segments)?),
+    };
+    Ok(transport)
+}
+
+// For the commands running a single stage, the channel transport can't reach the others
+pub fn build_standalone(config: &TransportConfig) -> io::Result<SharedTransport> {
+    if config.kind == TransportKind::Channel {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "the channel transport only works when every stage runs in one process, use `ta run`",
+        ));
+    }
+    build(config)
+}
@@ -0,0 +1,134 @@
+// The consume loop of the stages that summarize records over a window (detector,
+// anomaly, stitcher, accounting, ...): parse every record as JSON, hand it to the
+// stage, flush the stage every window and commit what the flush is done with.
+
+use std::time::Duration;
+
+use async_trait::async_trait;
+use serde::de::DeserializeOwned;
+use tokio::time::Instant;
+
+use super::{Record, Subscription, TransportError};
+
+// How far a flush got through the records observed so far
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Done {
+    // Every record received
+    All,
+    // The records before this offset, later ones are still held in memory
+    Before(i64),
+    // None, the flush failed or everything is still pending
+    Nothing,
+}
+
+#[async_trait]
+pub trait Windowed: Send {
+    type Item: DeserializeOwned;
+
+    // `offset` is the record's, for stages that commit only part of what they saw
+    fn observe(&mut self, item: Self::Item, offset: i64);
+
+    // Flush before the window ends, e.g. when a batch is full
+    fn full(&self) -> bool {
+        false
+    }
+
+    async fn flush(&mut self) -> Done;
+}
+
+// Runs until the transport is closed
+pub async fn consume_windows<W: Windowed>(subscription: &mut dyn Subscription, every: Duration, stage: &mut W) {
+    let mut deadline = Instant::now() + every;
+    let mut last: Option<Record> = None;
+    let mut committed = None;
+    loop {
+        match tokio::time::timeout_at(deadline, subscription.recv()).await {
+            Ok(Ok(record)) => {
+                match serde_json::from_slice(&record.payload) {
+                    Ok(item) => stage.observe(item, record.offset),
+                    Err(_) => println!("Skipping malformed {} record at offset {}", record.topic, record.offset),
+                }
+                last = Some(record);
+                if Instant::now() < deadline && !stage.full() {
+                    continue;
+                }
+            }
+            Ok(Err(TransportError::Closed)) => return,
+            Ok(Err(e)) => {
+                println!("Error receiving message: {:?}", e);
+                if Instant::now() < deadline {
+                    continue;
+                }
+            }
+            Err(_) => {}
+        }
+
+        // Windows missed while a flush took long are skipped, not flushed empty
+        let now = Instant::now();
+        while deadline <= now {
+            deadline += every;
+        }
+        let done = stage.flush().await;
+
+        let Some(last) = &last else {
+            continue;
+        };
+        let offset = match done {
+            Done::All => last.offset,
+            Done::Before(offset) => (offset - 1).min(last.offset),
+            Done::Nothing => continue,
+        };
+        if committed.is_some_and(|committed| offset <= committed) {
+            continue;
+        }
+        let record = Record { key: None, payload: Vec::new(), offset, ..last.clone() };
+        match subscription.commit(&record).await {
+            Ok(()) => committed = Some(offset),
+            Err(e) => println!("Error committing message: {:?}", e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::{ChannelTransport, Transport};
+
+    struct Sum {
+        total: u64,
+        flushed: Vec<u64>,
+        done: Done,
+    }
+
+    #[async_trait]
+    impl Windowed for Sum {
+        type Item = u64;
+
+        fn observe(&mut self, item: u64, _offset: i64) {
+            self.total += item;
+        }
+
+        fn full(&self) -> bool {
+            self.total >= 100
+        }
+
+        async fn flush(&mut self) -> Done {
+            self.flushed.push(std::mem::take(&mut self.total));
+            self.done
+        }
+    }
+
+    #[tokio::test]
+    async fn flushes_every_window_and_when_full() {
+        let transport = ChannelTransport::new(10);
+        let mut subscription = transport.subscribe("t", "g").await.unwrap();
+        for payload in ["1", "2", "not json", "100", "3"] {
+            transport.send("t", None, payload.as_bytes()).await.unwrap();
+        }
+        let mut sum = Sum { total: 0, flushed: Vec::new(), done: Done::All };
+        let window = Duration::from_millis(200);
+        let _ = tokio::time::timeout(window * 5 / 2, consume_windows(subscription.as_mut(), window, &mut sum)).await;
+        // 100 fills the batch, 3 waits for the window, then a window goes by empty
+        assert_eq!(sum.flushed, [103, 3, 0]);
+    }
+}